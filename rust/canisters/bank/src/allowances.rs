@@ -0,0 +1,107 @@
+use crate::StorableIcrcId;
+use cts_lib::{
+    icrc::{IcrcId, Icrc2Allowance},
+    types::Cycles,
+    tools::time_nanos_u64,
+};
+use ic_stable_structures::{StableBTreeMap, memory_manager::VirtualMemory, DefaultMemoryImpl, Storable, storable::Bound};
+use std::borrow::Cow;
+
+
+
+#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+pub struct AllowanceKey {
+    pub account: IcrcId,
+    pub spender: IcrcId,
+}
+impl Storable for AllowanceKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut v = Vec::<u8>::new();
+        v.extend(StorableIcrcId(self.account).to_bytes().iter());
+        v.extend(StorableIcrcId(self.spender).to_bytes().iter());
+        Cow::Owned(v)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            account: StorableIcrcId::from_bytes(Cow::Borrowed(&bytes[..62])).0,
+            spender: StorableIcrcId::from_bytes(Cow::Borrowed(&bytes[62..])).0,
+        }
+    }
+    const BOUND: Bound = {
+        Bound::Bounded{
+            max_size: 124,
+            is_fixed_size: true
+        }
+    };
+}
+
+
+#[derive(Clone, Copy)]
+pub struct StorableAllowance {
+    pub allowance: Cycles,
+    pub expires_at: Option<u64>,
+}
+impl Storable for StorableAllowance {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut v = Vec::<u8>::new();
+        v.extend(self.allowance.to_be_bytes());
+        v.push(self.expires_at.is_some() as u8);
+        v.extend(self.expires_at.unwrap_or(0).to_be_bytes());
+        Cow::Owned(v)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            allowance: u128::from_be_bytes(bytes[..16].try_into().unwrap()),
+            expires_at: if bytes[16] == 1 { Some(u64::from_be_bytes(bytes[17..25].try_into().unwrap())) } else { None },
+        }
+    }
+    const BOUND: Bound = {
+        Bound::Bounded{
+            max_size: 25,
+            is_fixed_size: true
+        }
+    };
+}
+
+
+pub type Allowances = StableBTreeMap<AllowanceKey, StorableAllowance, VirtualMemory<DefaultMemoryImpl>>;
+
+
+
+// an expired allowance counts as a zero allowance.
+pub fn allowance(allowances: &Allowances, account: IcrcId, spender: IcrcId) -> Icrc2Allowance {
+    match allowances.get(&AllowanceKey{ account, spender }) {
+        Some(a) if a.expires_at.map_or(true, |expires_at| expires_at > time_nanos_u64()) => {
+            Icrc2Allowance{
+                allowance: a.allowance,
+                expires_at: a.expires_at,
+            }
+        }
+        _ => Icrc2Allowance{
+            allowance: 0,
+            expires_at: None,
+        }
+    }
+}
+
+pub fn set_allowance(allowances: &mut Allowances, account: IcrcId, spender: IcrcId, set_allowance: Cycles, expires_at: Option<u64>) {
+    let key = AllowanceKey{ account, spender };
+    if set_allowance == 0 {
+        allowances.remove(&key);
+    } else {
+        allowances.insert(key, StorableAllowance{ allowance: set_allowance, expires_at });
+    }
+}
+
+// make sure to check that the allowance is not expired before calling this function.
+pub fn subtract_allowance(allowances: &mut Allowances, account: IcrcId, spender: IcrcId, sub_cycles: Cycles) {
+    let key = AllowanceKey{ account, spender };
+    if let Some(mut a) = allowances.get(&key) {
+        a.allowance = a.allowance.saturating_sub(sub_cycles);
+        if a.allowance == 0 {
+            allowances.remove(&key);
+        } else {
+            allowances.insert(key, a);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use crate::{CB_DATA, LOGS, Logs, solvency_audit::log_replay_next_block, topup_subscriptions::canister_cycles_balance};
+use cts_lib::{
+    tools::localkey::refcell::{with, with_mut},
+    types::{
+        Cycles,
+        CallError,
+        CanisterCode,
+        bank::{log_types::Log, archive::*, icrc3::{FeeCollectorChanges, fee_collector_changes_of_the_blocks}},
+    },
+    management_canister::{
+        CanisterIdRecord,
+        create_canister,
+        install_code,
+        ManagementCanisterCreateCanisterQuest,
+        ManagementCanisterOptionalCanisterSettings,
+        InstallCodeQuest,
+        InstallCodeMode,
+    },
+    consts::TRILLION,
+};
+use ic_cdk::api::{
+    call::{call, call_with_payment128},
+    canister_balance128,
+};
+use candid::{
+    Principal,
+    CandidType,
+    Deserialize,
+    encode_one,
+};
+use std::{
+    cmp::min,
+    time::Duration,
+};
+
+
+#[cfg(not(debug_assertions))]
+pub const ARCHIVE_BLOCKS_CHUNK_SIZE: u64 = 2_000;
+#[cfg(debug_assertions)]
+pub const ARCHIVE_BLOCKS_CHUNK_SIZE: u64 = 4;
+
+#[cfg(not(debug_assertions))]
+pub const ARCHIVE_BLOCKS_RHYTHM: Duration = Duration::from_secs(60 * 10);
+#[cfg(debug_assertions)]
+pub const ARCHIVE_BLOCKS_RHYTHM: Duration = Duration::from_secs(10);
+
+#[cfg(not(debug_assertions))]
+pub const COMPACT_LOGS_CHUNK_SIZE: u64 = 10_000;
+#[cfg(debug_assertions)]
+pub const COMPACT_LOGS_CHUNK_SIZE: u64 = 2;
+
+pub const MINIMUM_LEFTOVER_CYCLES_AFTER_AN_ARCHIVE_CREATION: Cycles = 5 * TRILLION; // on top of the total_supply. the archive top-ups keep this too.
+
+// an archive gets a top-up on the archive_blocks timer when its cycles-balance is below this.
+pub const ARCHIVE_MINIMUM_CYCLES_BALANCE: Cycles = 3 * TRILLION;
+pub const ARCHIVE_TOPUP_CYCLES: Cycles = 2 * TRILLION;
+
+
+#[derive(CandidType, Deserialize)]
+pub struct ArchivesData {
+    pub config: ArchiveConfig,
+    pub archive_canister_code: CanisterCode,
+    pub archive_canisters: Vec<ArchiveCanisterData>,
+    // the LOGS stable-vec holds the blocks that are not archived yet starting at the logs_vec_offset index.
+    // the blocks before the logs_vec_offset are in the archive-canisters and get cleared out by the compact_logs function.
+    pub first_local_block_id: u64,
+    pub logs_vec_offset: u64,
+    pub archiving_lock: bool,
+    pub create_archive_canister_temp_holder: Option<Principal>,
+    #[serde(default)]
+    pub compact_logs_moved: Option<u64>, // some while the compact_logs is in the middle. the number of local blocks that are moved to the front.
+    #[serde(default)]
+    pub topup_lock: bool,
+}
+
+impl Default for ArchivesData {
+    fn default() -> Self {
+        Self {
+            config: ArchiveConfig::default(),
+            archive_canister_code: CanisterCode::empty(),
+            archive_canisters: Vec::new(),
+            first_local_block_id: 0,
+            logs_vec_offset: 0,
+            archiving_lock: false,
+            create_archive_canister_temp_holder: None,
+            compact_logs_moved: None,
+            topup_lock: false,
+        }
+    }
+}
+
+impl ArchivesData {
+    pub fn log_length(&self, logs: &Logs) -> u64 {
+        self.first_local_block_id + self.local_logs_length(logs)
+    }
+    pub fn local_logs_length(&self, logs: &Logs) -> u64 {
+        logs.len() - self.logs_vec_offset
+    }
+    // none if the block is archived or does not exist yet.
+    pub fn get_local_log(&self, logs: &Logs, block_id: u64) -> Option<Log> {
+        if block_id < self.first_local_block_id || block_id >= self.log_length(logs) {
+            return None;
+        }
+        let local_index: u64 = block_id - self.first_local_block_id;
+        // the blocks that the compact_logs moved are at the front.
+        if self.compact_logs_moved.map_or(false, |moved| local_index < moved) {
+            return logs.get(local_index);
+        }
+        logs.get(self.logs_vec_offset + local_index)
+    }
+}
+
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum CreateArchiveCanisterError {
+    ArchiveCanisterCodeNotSet,
+    CyclesBalanceTooLow{ cycles_balance_minus_total_supply: i128 },
+    CreateCanisterCallError(CallError),
+    InstallCodeCandidError(String),
+    InstallCodeCallError(CallError),
+}
+
+
+pub async fn archive_blocks() {
+    let mut archive_n: u64 = match with_mut(&CB_DATA, |cb_data| {
+        with(&LOGS, |logs| {
+            let local_logs_length: u64 = cb_data.archives.local_logs_length(logs);
+            if cb_data.archives.archiving_lock == true {
+                return None;
+            }
+            // a compact_logs that did not finish before an upgrade goes on before any more archiving.
+            if cb_data.archives.compact_logs_moved.is_some() {
+                cb_data.archives.archiving_lock = true;
+                ic_cdk_timers::set_timer(Duration::ZERO, compact_logs);
+                return None;
+            }
+            if local_logs_length < cb_data.archives.config.trigger_threshold {
+                return None;
+            }
+            // always keep the latest block on the bank for the phash of the next block and for the tip-certificate.
+            // and keep the blocks that the solvency-audit log-replay did not replay yet.
+            let mut archive_n: u64 = min(cb_data.archives.config.num_blocks_to_archive, local_logs_length.saturating_sub(1));
+            if let Some(log_replay_next_block) = log_replay_next_block(cb_data) {
+                archive_n = min(archive_n, log_replay_next_block.saturating_sub(cb_data.archives.first_local_block_id));
+            }
+            if archive_n == 0 {
+                return None;
+            }
+            cb_data.archives.archiving_lock = true;
+            Some(archive_n)
+        })
+    }) {
+        Some(n) => n,
+        None => return,
+    };
+
+    ic_cdk::print(&format!("go for the archive-blocks. archiving {} blocks.", archive_n));
+
+    while archive_n > 0 {
+        let archive_canister_id: Principal = match with(&CB_DATA, |cb_data| {
+            cb_data.archives.archive_canisters
+                .last()
+                .and_then(|archive_canister| {
+                    if archive_canister.is_full { None } else { Some(archive_canister.canister_id) }
+                })
+        }) {
+            Some(c) => c,
+            None => match create_archive_canister().await {
+                Ok(c) => c,
+                Err(e) => {
+                    ic_cdk::print(&format!("create archive canister error: {:?}", e));
+                    break;
+                }
+            }
+        };
+
+        let (first_block_id, blocks, fee_collector_changes): (u64, Vec<Log>, FeeCollectorChanges) = with(&CB_DATA, |cb_data| {
+            with(&LOGS, |logs| {
+                let archive_canister: &ArchiveCanisterData = cb_data.archives.archive_canisters.last().unwrap();
+                let chunk_length: u64 = min(
+                    min(archive_n, ARCHIVE_BLOCKS_CHUNK_SIZE),
+                    cb_data.archives.config.max_blocks_per_archive.saturating_sub(archive_canister.length)
+                );
+                (
+                    cb_data.archives.first_local_block_id,
+                    (cb_data.archives.logs_vec_offset..cb_data.archives.logs_vec_offset + chunk_length)
+                        .map(|i| logs.get(i).unwrap())
+                        .collect(),
+                    fee_collector_changes_of_the_blocks(&cb_data.fee_collector_changes, cb_data.archives.first_local_block_id, cb_data.archives.first_local_block_id + chunk_length),
+                )
+            })
+        });
+
+        if blocks.len() == 0 {
+            with_mut(&CB_DATA, |cb_data| {
+                cb_data.archives.archive_canisters.last_mut().unwrap().is_full = true;
+            });
+            continue;
+        }
+
+        let chunk_length: u64 = blocks.len() as u64;
+
+        match call::<(AppendBlocksQuest,), (Result<(), AppendBlocksError>,)>(
+            archive_canister_id,
+            "append_blocks",
+            (AppendBlocksQuest{
+                first_block_id,
+                blocks,
+                fee_collector_changes,
+            },),
+        ).await {
+            Ok((Ok(()),)) => {
+                with_mut(&CB_DATA, |cb_data| {
+                    cb_data.archives.first_local_block_id += chunk_length;
+                    cb_data.archives.logs_vec_offset += chunk_length;
+                    cb_data.archives.archive_canisters.last_mut().unwrap().length += chunk_length;
+                });
+                archive_n -= chunk_length;
+            }
+            Ok((Err(AppendBlocksError::ArchiveIsFull),)) => {
+                with_mut(&CB_DATA, |cb_data| {
+                    cb_data.archives.archive_canisters.last_mut().unwrap().is_full = true;
+                });
+            }
+            Ok((Err(append_blocks_error),)) => {
+                ic_cdk::print(&format!("append_blocks error: {:?}", append_blocks_error));
+                break;
+            }
+            Err(call_error) => {
+                ic_cdk::print(&format!("append_blocks call error: {:?}", call_error));
+                break;
+            }
+        }
+    }
+
+    // the compact_logs starts once there are at least as many archived blocks in the front of the LOGS stable-vec as there are local blocks.
+    // this way each archived block costs at most one move.
+    // the compact_logs keeps the archiving_lock till it is done bc the archiving changes the logs_vec_offset.
+    let compact_logs_is_due: bool = with_mut(&CB_DATA, |cb_data| {
+        with(&LOGS, |logs| {
+            if cb_data.archives.logs_vec_offset > 0
+            && cb_data.archives.logs_vec_offset >= cb_data.archives.local_logs_length(logs) {
+                cb_data.archives.compact_logs_moved = Some(0);
+                return true;
+            }
+            cb_data.archives.archiving_lock = false;
+            false
+        })
+    });
+    if compact_logs_is_due {
+        ic_cdk_timers::set_timer(Duration::ZERO, compact_logs);
+    }
+
+    ic_cdk::print("done with the archive-blocks");
+}
+
+
+// moves the local blocks to the front of the LOGS stable-vec and pops the rest, one chunk per message-execution.
+// the blocks that get pushed in the middle get moved too. the moves only write over the archived blocks and the local blocks that are moved already.
+// the pops start when all local blocks are moved, and each pop takes one off the logs_vec_offset so that the local blocks that get pushed after it keep their index.
+fn compact_logs() {
+    let is_complete: bool = with(&LOGS, |logs| {
+        with_mut(&CB_DATA, |cb_data| {
+            let archives_data: &mut ArchivesData = &mut cb_data.archives;
+            let local_logs_length: u64 = archives_data.local_logs_length(logs);
+            let mut moved: u64 = archives_data.compact_logs_moved.unwrap_or(0);
+            let mut chunk_size: u64 = COMPACT_LOGS_CHUNK_SIZE;
+            while chunk_size > 0 && moved < local_logs_length {
+                logs.set(moved, &logs.get(archives_data.logs_vec_offset + moved).unwrap());
+                moved += 1;
+                chunk_size -= 1;
+            }
+            archives_data.compact_logs_moved = Some(moved);
+            while chunk_size > 0 && moved == local_logs_length && archives_data.logs_vec_offset > 0 {
+                logs.pop();
+                archives_data.logs_vec_offset -= 1;
+                chunk_size -= 1;
+            }
+            if moved == local_logs_length && archives_data.logs_vec_offset == 0 {
+                archives_data.compact_logs_moved = None;
+                archives_data.archiving_lock = false;
+                return true;
+            }
+            false
+        })
+    });
+    if is_complete == false {
+        ic_cdk_timers::set_timer(Duration::ZERO, compact_logs);
+    }
+}
+
+
+// the archives burn cycles for the storage. the bank tops them up out of its cycles over the total_supply.
+pub async fn top_up_archives() {
+    let archive_canisters: Vec<Principal> = match with_mut(&CB_DATA, |cb_data| {
+        if cb_data.archives.topup_lock == true {
+            return None;
+        }
+        cb_data.archives.topup_lock = true;
+        Some(cb_data.archives.archive_canisters.iter().map(|archive_canister| archive_canister.canister_id).collect())
+    }) {
+        Some(archive_canisters) => archive_canisters,
+        None => return,
+    };
+
+    for canister_id in archive_canisters {
+        match canister_cycles_balance(canister_id, None).await {
+            Ok(cycles_balance) => {
+                if cycles_balance >= ARCHIVE_MINIMUM_CYCLES_BALANCE {
+                    continue;
+                }
+            }
+            Err(call_error) => {
+                ic_cdk::print(&format!("archive canister_status call error: {:?}", call_error));
+                continue;
+            }
+        }
+        let cycles_balance_minus_total_supply: i128 = with(&CB_DATA, |cb_data| {
+            (canister_balance128() as i128).saturating_sub(cb_data.total_supply as i128)
+        });
+        if cycles_balance_minus_total_supply < (ARCHIVE_TOPUP_CYCLES + MINIMUM_LEFTOVER_CYCLES_AFTER_AN_ARCHIVE_CREATION) as i128 {
+            ic_cdk::print(&format!("cycles balance too low for the archive top-ups. cycles_balance_minus_total_supply: {}", cycles_balance_minus_total_supply));
+            break;
+        }
+        if let Err(call_error) = call_with_payment128::<_, ()>(
+            Principal::management_canister(),
+            "deposit_cycles",
+            (CanisterIdRecord{ canister_id },),
+            ARCHIVE_TOPUP_CYCLES,
+        ).await {
+            ic_cdk::print(&format!("archive deposit_cycles call error: {:?}", call_error));
+        }
+    }
+
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.archives.topup_lock = false;
+    });
+}
+
+
+async fn create_archive_canister() -> Result<Principal/*saves the archive-canister-data in the CB_DATA*/, CreateArchiveCanisterError> {
+
+    let archive_canister_code: CanisterCode = with(&CB_DATA, |cb_data| {
+        cb_data.archives.archive_canister_code.clone()
+    });
+
+    if archive_canister_code.module().len() == 0 {
+        return Err(CreateArchiveCanisterError::ArchiveCanisterCodeNotSet);
+    }
+
+    let canister_id: Principal = match with_mut(&CB_DATA, |cb_data| { cb_data.archives.create_archive_canister_temp_holder.take() }) {
+        Some(canister_id) => canister_id,
+        None => {
+            let (cycles_balance_minus_total_supply, cycles_for_archive_creation): (i128, Cycles) = with(&CB_DATA, |cb_data| {
+                (
+                    (canister_balance128() as i128).saturating_sub(cb_data.total_supply as i128),
+                    cb_data.archives.config.cycles_for_archive_creation,
+                )
+            });
+            if cycles_balance_minus_total_supply < (cycles_for_archive_creation + MINIMUM_LEFTOVER_CYCLES_AFTER_AN_ARCHIVE_CREATION) as i128 {
+                return Err(CreateArchiveCanisterError::CyclesBalanceTooLow{ cycles_balance_minus_total_supply });
+            }
+            create_canister(
+                ManagementCanisterCreateCanisterQuest{
+                    settings: Some(ManagementCanisterOptionalCanisterSettings{
+                        controllers: Some(vec![ic_cdk::api::id()]),
+                        compute_allocation: None,
+                        memory_allocation: None,
+                        freezing_threshold: None,
+                    }),
+                },
+                cycles_for_archive_creation,
+            ).await.map_err(CreateArchiveCanisterError::CreateCanisterCallError)?
+        }
+    };
+
+    let (first_block_id, max_blocks): (u64, u64) = with(&CB_DATA, |cb_data| {
+        (cb_data.archives.first_local_block_id, cb_data.archives.config.max_blocks_per_archive)
+    });
+
+    let arg: Vec<u8> = match encode_one(BankArchiveInit{
+        bank_id: ic_cdk::api::id(),
+        first_block_id,
+        max_blocks,
+    }) {
+        Ok(b) => b,
+        Err(e) => {
+            with_mut(&CB_DATA, |cb_data| { cb_data.archives.create_archive_canister_temp_holder = Some(canister_id); });
+            return Err(CreateArchiveCanisterError::InstallCodeCandidError(format!("{:?}", e)));
+        }
+    };
+
+    match install_code(
+        InstallCodeQuest{
+            mode: InstallCodeMode::install,
+            canister_id,
+            wasm_module: archive_canister_code.module(),
+            arg: &arg,
+        }
+    ).await {
+        Ok(()) => {
+            with_mut(&CB_DATA, |cb_data| {
+                cb_data.archives.archive_canisters.push(
+                    ArchiveCanisterData{
+                        canister_id,
+                        start: first_block_id,
+                        length: 0,
+                        is_full: false,
+                        module_hash: archive_canister_code.module_hash().clone(),
+                    }
+                );
+            });
+            Ok(canister_id)
+        }
+        Err(install_code_call_error) => {
+            with_mut(&CB_DATA, |cb_data| { cb_data.archives.create_archive_canister_temp_holder = Some(canister_id); });
+            Err(CreateArchiveCanisterError::InstallCodeCallError(install_code_call_error))
+        }
+    }
+}
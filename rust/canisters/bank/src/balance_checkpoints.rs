@@ -0,0 +1,206 @@
+use crate::{CB_DATA, CYCLES_BALANCES, LOGS, USER_LOGS_INDEX, BALANCE_CHECKPOINTS, CBData, CyclesBalances, cycles_balance};
+use cts_lib::{
+    tools::{
+        localkey::refcell::{with, with_mut},
+        call_error_as_u32_and_string,
+    },
+    types::{
+        Cycles,
+        bank::{
+            BlockOrTime,
+            BalanceOfAt,
+            BalanceOfAtError,
+            log_types::{Log, UserLogKey},
+            icrc3::{StartAndLength, fee_collector_of_the_block, block_log_of_an_icrc3_value},
+            index::Icrc3GetBlocksSponse,
+        },
+    },
+    icrc::IcrcId,
+};
+use ic_stable_structures::{StableBTreeMap, memory_manager::VirtualMemory, DefaultMemoryImpl};
+use ic_cdk::api::call::call;
+use candid::Principal;
+use std::collections::BTreeMap;
+
+
+// an account gets a new checkpoint on its next block after this many blocks since its last checkpoint.
+// so the replay from the nearest checkpoint is at most this many blocks.
+#[cfg(not(debug_assertions))]
+pub const BALANCE_CHECKPOINT_INTERVAL: u64 = 1_000;
+#[cfg(debug_assertions)]
+pub const BALANCE_CHECKPOINT_INTERVAL: u64 = 3;
+
+pub const MAX_BALANCE_OF_AT_REPLAY_LENGTH: usize = 5_000;
+
+// the balance of the account right after the block.
+pub type BalanceCheckpoints = StableBTreeMap<UserLogKey, Cycles, VirtualMemory<DefaultMemoryImpl>>;
+
+
+fn latest_checkpoint(balance_checkpoints: &BalanceCheckpoints, account: IcrcId, at_block: u64) -> Option<(u64, Cycles)> {
+    balance_checkpoints
+        .range(UserLogKey{ account, block: 0 }..=UserLogKey{ account, block: at_block })
+        .rev()
+        .next()
+        .map(|(key, balance)| (key.block, balance))
+}
+
+// the balance as of the latest block. the cycles of the cycles_out and create_canister calls that are waiting on their call are still in the balance until their block.
+fn ledger_balance(cycles_balances: &CyclesBalances, cb_data: &CBData, account: IcrcId) -> Cycles {
+    cycles_balance(cycles_balances, account).saturating_add(cb_data.pending_debits.get(&account).copied().unwrap_or(0))
+}
+
+fn checkpoint_is_due(balance_checkpoints: &BalanceCheckpoints, account: IcrcId, block: u64) -> bool {
+    match latest_checkpoint(balance_checkpoints, account, block) {
+        None => true,
+        Some((checkpoint_block, _)) => block - checkpoint_block >= BALANCE_CHECKPOINT_INTERVAL,
+    }
+}
+
+// call this right after the log push, in the same message-execution, so that the current balances are the balances right after the block.
+// the fee-collector's fee blocks are not in its user-logs-index, so they get the same interval here and the balance_of_at replay finds them by the fee_collector_changes.
+pub fn checkpoint_balances(block: u64, log: &Log) {
+    with(&CYCLES_BALANCES, |cycles_balances| {
+        with(&CB_DATA, |cb_data| {
+            with_mut(&BALANCE_CHECKPOINTS, |balance_checkpoints| {
+                for account in log.tx.op.icrc_ids() {
+                    if checkpoint_is_due(balance_checkpoints, account, block) {
+                        balance_checkpoints.insert(UserLogKey{ account, block }, ledger_balance(cycles_balances, cb_data, account));
+                    }
+                }
+                if log.effective_fee() > 0 {
+                    if let Some(fee_collector) = fee_collector_of_the_block(&cb_data.fee_collector_changes, block) {
+                        if checkpoint_is_due(balance_checkpoints, *fee_collector, block) {
+                            balance_checkpoints.insert(UserLogKey{ account: *fee_collector, block }, ledger_balance(cycles_balances, cb_data, *fee_collector));
+                        }
+                    }
+                }
+            });
+        });
+    });
+}
+
+
+pub async fn balance_of_at(icrc_id: IcrcId, at: BlockOrTime) -> Result<BalanceOfAt, BalanceOfAtError> {
+    let log_length: u64 = with(&LOGS, |logs| with(&CB_DATA, |cb_data| cb_data.archives.log_length(logs)));
+
+    let block: u64 = match at {
+        BlockOrTime::Block(block) => {
+            if block >= log_length as u128 {
+                return Err(BalanceOfAtError::BlockNotFound{ log_length: log_length as u128 });
+            }
+            block as u64
+        }
+        BlockOrTime::TimeNanos(time_nanos) => match last_block_at_or_before_time(time_nanos, log_length).await? {
+            Some(block) => block,
+            None => return Ok(BalanceOfAt{ balance: 0, block: None }),
+        }
+    };
+
+    let checkpoint: Option<(u64, Cycles)> = with(&BALANCE_CHECKPOINTS, |balance_checkpoints| latest_checkpoint(balance_checkpoints, icrc_id, block));
+    let (replay_start, mut balance): (u64, Cycles) = match checkpoint {
+        Some((checkpoint_block, balance)) => (checkpoint_block + 1, balance),
+        None => (0, 0),
+    };
+
+    // the fee-collections after the balance_checkpoints_start_block are in the checkpoints.
+    with(&CB_DATA, |cb_data| {
+        let checked_end: u64 = std::cmp::min(block + 1, cb_data.balance_checkpoints_start_block.unwrap_or(0));
+        let fee_collector_changes = &cb_data.fee_collector_changes;
+        for (i, (start, fee_collector)) in fee_collector_changes.iter().enumerate() {
+            let end: u64 = fee_collector_changes.get(i + 1).map(|(next_start, _)| *next_start).unwrap_or(u64::MAX);
+            if fee_collector.as_ref() == Some(&icrc_id) && *start < checked_end && end > replay_start {
+                return Err(BalanceOfAtError::FeeCollectorHistoryNotAvailable);
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut replay_blocks: Vec<u64> = with(&USER_LOGS_INDEX, |user_logs_index| {
+        user_logs_index
+            .range(UserLogKey{ account: icrc_id, block: replay_start }..=UserLogKey{ account: icrc_id, block })
+            .map(|(key, _)| key.block)
+            .take(MAX_BALANCE_OF_AT_REPLAY_LENGTH + 1)
+            .collect()
+    });
+
+    // the fee blocks of the fee-collector since its checkpoint.
+    // the first fee block at or after checkpoint_block + BALANCE_CHECKPOINT_INTERVAL gets a checkpoint, so the fee blocks without a checkpoint are before that.
+    // with no checkpoint, the first fee block after the balance_checkpoints_start_block would have a checkpoint.
+    let fee_collector_blocks: Vec<u64> = match checkpoint {
+        None => Vec::new(),
+        Some((checkpoint_block, _)) => with(&CB_DATA, |cb_data| {
+            (replay_start..std::cmp::min(block + 1, checkpoint_block + BALANCE_CHECKPOINT_INTERVAL))
+                .filter(|b| fee_collector_of_the_block(&cb_data.fee_collector_changes, *b) == Some(&icrc_id))
+                .collect()
+        }),
+    };
+    replay_blocks.extend_from_slice(&fee_collector_blocks);
+    replay_blocks.sort_unstable();
+    replay_blocks.dedup();
+
+    if replay_blocks.len() > MAX_BALANCE_OF_AT_REPLAY_LENGTH {
+        return Err(BalanceOfAtError::ReplayTooLong{ max_replay_length: MAX_BALANCE_OF_AT_REPLAY_LENGTH as u64 });
+    }
+
+    for (replay_block, log) in replay_blocks.iter().zip(get_logs(&replay_blocks).await?) {
+        let (mut credit, debit) = log.balance_change(&icrc_id);
+        if fee_collector_blocks.binary_search(replay_block).is_ok() {
+            credit = credit.saturating_add(log.effective_fee());
+        }
+        balance = balance.saturating_add(credit).saturating_sub(debit);
+    }
+
+    Ok(BalanceOfAt{ balance, block: Some(block as u128) })
+}
+
+// the block timestamps only go up so we can do a binary search.
+async fn last_block_at_or_before_time(time_nanos: u64, log_length: u64) -> Result<Option<u64>, BalanceOfAtError> {
+    let (mut lo, mut hi): (u64, u64) = (0, log_length); // the first block after the time is in lo..=hi
+    while lo < hi {
+        let mid: u64 = lo + (hi - lo) / 2;
+        let log: Log = get_logs(&[mid]).await?.pop().unwrap();
+        if log.ts <= time_nanos {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    Ok(lo.checked_sub(1))
+}
+
+// gets the logs of the blocks in the same order. calls the archive-canisters for the archived blocks.
+async fn get_logs(blocks: &[u64]) -> Result<Vec<Log>, BalanceOfAtError> {
+    let mut logs_map: BTreeMap<u64, Log> = BTreeMap::new();
+    let mut archived_blocks: BTreeMap<Principal, Vec<u64>> = BTreeMap::new();
+    with(&LOGS, |logs| {
+        with(&CB_DATA, |cb_data| {
+            for block in blocks.iter().copied() {
+                match cb_data.archives.get_local_log(logs, block) {
+                    Some(log) => { logs_map.insert(block, log); }
+                    None => {
+                        if let Some(archive) = cb_data.archives.archive_canisters.iter().find(|a| a.start <= block && block < a.start + a.length) {
+                            archived_blocks.entry(archive.canister_id).or_default().push(block);
+                        }
+                    }
+                }
+            }
+        });
+    });
+
+    for (archive, archive_blocks) in archived_blocks.into_iter() {
+        let q: Vec<StartAndLength> = archive_blocks.iter().map(|b| StartAndLength{ start: *b as u128, length: 1 }).collect();
+        let sponse: Icrc3GetBlocksSponse = match call::<(Vec<StartAndLength>,), (Icrc3GetBlocksSponse,)>(archive, "icrc3_get_blocks", (q,)).await {
+            Ok((s,)) => s,
+            Err(call_error) => return Err(BalanceOfAtError::ArchiveCallError{ archive, call_error: call_error_as_u32_and_string(call_error) }),
+        };
+        for block_with_id in sponse.blocks.iter() {
+            let (log, _fee_col) = block_log_of_an_icrc3_value(&block_with_id.block)
+                .map_err(|error| BalanceOfAtError::ArchiveBlockError{ block: block_with_id.id, error })?;
+            logs_map.insert(block_with_id.id as u64, log);
+        }
+    }
+
+    blocks.iter()
+        .map(|block| logs_map.remove(block).ok_or(BalanceOfAtError::ArchiveBlockError{ block: *block as u128, error: "The block is missing from the archive's icrc3_get_blocks sponse.".to_string() }))
+        .collect()
+}
@@ -0,0 +1,305 @@
+use crate::{CB_DATA, ICRC1_SYMBOL, ICRC1_DECIMALS};
+use cts_lib::{
+    tools::localkey::refcell::with,
+    icrc::{Icrc1TransferQuest, Icrc2ApproveQuest, Icrc2TransferFromQuest, icrc21::*},
+    types::bank::{BANK_TRANSFER_FEE, CyclesOutQuest, MintCyclesQuest, MintCyclesWithIcpApprovalQuest, CreateCanisterQuest, EscrowLockQuest, EscrowId, CreatePaymentStreamQuest, PaymentStreamId},
+};
+use candid::{CandidType, Deserialize, decode_one, utils::ArgumentDecoder};
+use serde_bytes::ByteBuf;
+
+
+const ICP_DECIMALS: u8 = 8;
+
+
+pub fn consent_message(q: &Icrc21ConsentMessageRequest) -> Icrc21ConsentMessageResponse {
+    let language = ConsentLanguage::from_tag(&q.user_preferences.metadata.language);
+    let message: ConsentMessageFields = match q.method.as_str() {
+        "icrc1_transfer" => icrc1_transfer_consent_message(decode_arg(&q.arg)?, language),
+        "icrc1_transfer_and_notify" => icrc1_transfer_and_notify_consent_message(decode_arg(&q.arg)?, language),
+        "cycles_out" => cycles_out_consent_message(decode_arg(&q.arg)?, language),
+        "mint_cycles" => mint_cycles_consent_message(decode_arg(&q.arg)?, language),
+        "create_canister" => create_canister_consent_message(decode_arg(&q.arg)?, language),
+        "icrc2_approve" => icrc2_approve_consent_message(decode_arg(&q.arg)?, language),
+        "icrc2_transfer_from" => icrc2_transfer_from_consent_message(decode_arg(&q.arg)?, language),
+        "icrc4_transfer_batch" => icrc4_transfer_batch_consent_message(decode_arg(&q.arg)?, language),
+        "mint_cycles_with_icp_approval" => mint_cycles_with_icp_approval_consent_message(decode_arg(&q.arg)?, language),
+        "escrow_lock" => escrow_lock_consent_message(decode_arg(&q.arg)?, language),
+        "escrow_claim" => {
+            let (escrow_id, _preimage): (EscrowId, ByteBuf) = decode_arg_tuple(&q.arg)?;
+            escrow_claim_consent_message(escrow_id, language)
+        }
+        "escrow_refund" => escrow_refund_consent_message(decode_arg(&q.arg)?, language),
+        "create_payment_stream" => create_payment_stream_consent_message(decode_arg(&q.arg)?, language),
+        "withdraw_payment_stream" => withdraw_payment_stream_consent_message(decode_arg(&q.arg)?, language),
+        "cancel_payment_stream" => cancel_payment_stream_consent_message(decode_arg(&q.arg)?, language),
+        _ => return Err(unsupported_canister_call(format!("There is no consent message for the method: {}", q.method))),
+    };
+    Ok(consent_info(message, &q.user_preferences, language))
+}
+
+fn decode_arg<T: CandidType + for<'de> Deserialize<'de>>(arg: &[u8]) -> Result<T, Icrc21Error> {
+    decode_one(arg).map_err(|e| unsupported_canister_call(format!("Could not decode the arg: {}", e)))
+}
+
+// for the methods with more than one arg.
+fn decode_arg_tuple<T: for<'de> ArgumentDecoder<'de>>(arg: &[u8]) -> Result<T, Icrc21Error> {
+    candid::decode_args(arg).map_err(|e| unsupported_canister_call(format!("Could not decode the args: {}", e)))
+}
+
+fn cycles_str(cycles: u128) -> String {
+    format!("{} {}", quantums_as_token_str(cycles, ICRC1_DECIMALS), ICRC1_SYMBOL)
+}
+
+fn from_subaccount_field(from_subaccount: Option<&[u8]>, language: ConsentLanguage) -> Option<(String, String)> {
+    from_subaccount.map(|subaccount| {
+        (language.text("From subaccount", "Desde la subcuenta").to_string(), hex::encode(subaccount))
+    })
+}
+
+fn memo_field(memo: Option<&[u8]>) -> Option<(String, String)> {
+    memo.map(|memo| ("Memo".to_string(), hex::encode(memo)))
+}
+
+fn time_nanos_field(label: (&'static str, &'static str), time_nanos: u64, language: ConsentLanguage) -> (String, String) {
+    (
+        language.text(label.0, label.1).to_string(),
+        format!("{} {}", time_nanos, language.text("nanoseconds since the epoch", "nanosegundos desde la época")),
+    )
+}
+
+
+fn icrc1_transfer_consent_message(q: Icrc1TransferQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Amount", "Cantidad").to_string(), cycles_str(q.amount)),
+        (language.text("To", "Para").to_string(), icrc_id_as_text(&q.to)),
+        (language.text("Fee", "Comisión").to_string(), cycles_str(q.fee.unwrap_or(BANK_TRANSFER_FEE))),
+    ];
+    fields.extend(from_subaccount_field(q.from_subaccount.as_deref().map(|s| &s[..]), language));
+    fields.extend(memo_field(q.memo.as_deref()));
+    ConsentMessageFields{
+        title: language.text("Transfer cycles", "Transferir ciclos").to_string(),
+        fields,
+    }
+}
+
+fn icrc1_transfer_and_notify_consent_message(q: Icrc1TransferQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut message = icrc1_transfer_consent_message(q, language);
+    message.title = language.text("Transfer cycles and notify the receiving canister", "Transferir ciclos y notificar al canister receptor").to_string();
+    message
+}
+
+fn cycles_out_consent_message(q: CyclesOutQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Cycles", "Ciclos").to_string(), cycles_str(q.cycles)),
+        (language.text("To canister", "Para el canister").to_string(), q.for_canister.to_text()),
+        (language.text("Fee", "Comisión").to_string(), cycles_str(q.fee.unwrap_or(BANK_TRANSFER_FEE))),
+    ];
+    fields.extend(from_subaccount_field(q.from_subaccount.as_deref().map(|s| &s[..]), language));
+    fields.extend(memo_field(q.memo.as_deref()));
+    ConsentMessageFields{
+        title: language.text("Send cycles to a canister", "Enviar ciclos a un canister").to_string(),
+        fields,
+    }
+}
+
+fn mint_cycles_consent_message(q: MintCyclesQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Burn ICP", "Quemar ICP").to_string(), format!("{} ICP", quantums_as_token_str(q.burn_icp, ICP_DECIMALS))),
+        (language.text("ICP transfer fee", "Comisión de transferencia de ICP").to_string(), format!("{} ICP", quantums_as_token_str(q.burn_icp_transfer_fee, ICP_DECIMALS))),
+        (language.text("Mint cycles to", "Acuñar ciclos para").to_string(), icrc_id_as_text(&q.to)),
+        (language.text("Fee", "Comisión").to_string(), cycles_str(q.fee.unwrap_or(BANK_TRANSFER_FEE))),
+    ];
+    fields.extend(memo_field(q.memo.as_deref()));
+    ConsentMessageFields{
+        title: language.text("Mint cycles with ICP", "Acuñar ciclos con ICP").to_string(),
+        fields,
+    }
+}
+
+fn create_canister_consent_message(q: CreateCanisterQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Cycles for the new canister", "Ciclos para el nuevo canister").to_string(), cycles_str(q.cycles)),
+        (language.text("Fee", "Comisión").to_string(), cycles_str(q.fee.unwrap_or(BANK_TRANSFER_FEE))),
+    ];
+    if let Some(controllers) = q.creation_args.as_ref().and_then(|a| a.settings.as_ref()).and_then(|s| s.controllers.as_ref()) {
+        fields.push((
+            language.text("Controllers", "Controladores").to_string(), 
+            controllers.iter().map(|p| p.to_text()).collect::<Vec<String>>().join(", ")
+        ));
+    }
+    fields.extend(from_subaccount_field(q.from_subaccount.as_deref().map(|s| &s[..]), language));
+    fields.extend(memo_field(q.memo.as_deref()));
+    ConsentMessageFields{
+        title: language.text("Create a canister", "Crear un canister").to_string(),
+        fields,
+    }
+}
+
+fn icrc2_approve_consent_message(q: Icrc2ApproveQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Spender", "Gastador").to_string(), icrc_id_as_text(&q.spender)),
+        (language.text("Allowance", "Asignación").to_string(), cycles_str(q.amount)),
+        (language.text("Fee", "Comisión").to_string(), cycles_str(q.fee.unwrap_or(BANK_TRANSFER_FEE))),
+    ];
+    if let Some(expected_allowance) = q.expected_allowance {
+        fields.push((language.text("Expected current allowance", "Asignación actual esperada").to_string(), cycles_str(expected_allowance)));
+    }
+    if let Some(expires_at) = q.expires_at {
+        fields.push(time_nanos_field(("Expires at", "Vence el"), expires_at, language));
+    }
+    fields.extend(from_subaccount_field(q.from_subaccount.as_deref().map(|s| &s[..]), language));
+    fields.extend(memo_field(q.memo.as_deref()));
+    ConsentMessageFields{
+        title: language.text("Approve a spender", "Aprobar a un gastador").to_string(),
+        fields,
+    }
+}
+
+fn icrc2_transfer_from_consent_message(q: Icrc2TransferFromQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Amount", "Cantidad").to_string(), cycles_str(q.amount)),
+        (language.text("From", "Desde").to_string(), icrc_id_as_text(&q.from)),
+        (language.text("To", "Para").to_string(), icrc_id_as_text(&q.to)),
+        (language.text("Fee", "Comisión").to_string(), cycles_str(q.fee.unwrap_or(BANK_TRANSFER_FEE))),
+    ];
+    if let Some(spender_subaccount) = q.spender_subaccount.as_deref() {
+        fields.push((language.text("Spender subaccount", "Subcuenta del gastador").to_string(), hex::encode(spender_subaccount)));
+    }
+    fields.extend(memo_field(q.memo.as_deref()));
+    ConsentMessageFields{
+        title: language.text("Transfer cycles from an approved account", "Transferir ciclos desde una cuenta aprobada").to_string(),
+        fields,
+    }
+}
+
+fn icrc4_transfer_batch_consent_message(q: Vec<Icrc1TransferQuest>, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Transfers", "Transferencias").to_string(), q.len().to_string()),
+        (language.text("Total amount", "Cantidad total").to_string(), cycles_str(q.iter().fold(0, |sum: u128, t| sum.saturating_add(t.amount)))),
+        (language.text("Total fees", "Comisiones totales").to_string(), cycles_str(q.iter().fold(0, |sum: u128, t| sum.saturating_add(t.fee.unwrap_or(BANK_TRANSFER_FEE))))),
+    ];
+    for (i, t) in q.iter().enumerate() {
+        let mut transfer = format!("{} {} {}", cycles_str(t.amount), language.text("to", "para"), icrc_id_as_text(&t.to));
+        if let Some(from_subaccount) = t.from_subaccount.as_deref() {
+            transfer.push_str(&format!(", {} {}", language.text("from subaccount", "desde la subcuenta"), hex::encode(from_subaccount)));
+        }
+        fields.push((format!("{} {}", language.text("Transfer", "Transferencia"), i + 1), transfer));
+    }
+    ConsentMessageFields{
+        title: language.text("Transfer cycles in a batch", "Transferir ciclos en lote").to_string(),
+        fields,
+    }
+}
+
+fn mint_cycles_with_icp_approval_consent_message(q: MintCyclesWithIcpApprovalQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Burn ICP", "Quemar ICP").to_string(), format!("{} ICP", quantums_as_token_str(q.burn_icp, ICP_DECIMALS))),
+        (language.text("ICP transfer fee", "Comisión de transferencia de ICP").to_string(), format!("{} ICP", quantums_as_token_str(q.burn_icp_transfer_fee, ICP_DECIMALS))),
+        (language.text("Mint cycles to", "Acuñar ciclos para").to_string(), icrc_id_as_text(&q.to)),
+        (language.text("Fee", "Comisión").to_string(), cycles_str(q.fee.unwrap_or(BANK_TRANSFER_FEE))),
+    ];
+    if let Some(from_subaccount) = q.from_subaccount.as_deref() {
+        fields.push((language.text("ICP from subaccount", "ICP desde la subcuenta").to_string(), hex::encode(from_subaccount)));
+    }
+    fields.extend(memo_field(q.memo.as_deref()));
+    ConsentMessageFields{
+        title: language.text("Mint cycles with approved ICP", "Acuñar ciclos con ICP aprobado").to_string(),
+        fields,
+    }
+}
+
+fn escrow_lock_consent_message(q: EscrowLockQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Amount", "Cantidad").to_string(), cycles_str(q.amount)),
+        (language.text("Claimable by", "Reclamable por").to_string(), icrc_id_as_text(&q.to)),
+        (language.text("Hashlock", "Hashlock").to_string(), hex::encode(*q.hashlock)),
+        time_nanos_field(("Refundable after", "Reembolsable después de"), q.timeout_nanos, language),
+        (language.text("Fee", "Comisión").to_string(), cycles_str(q.fee.unwrap_or(BANK_TRANSFER_FEE))),
+    ];
+    fields.extend(from_subaccount_field(q.from_subaccount.as_deref().map(|s| &s[..]), language));
+    ConsentMessageFields{
+        title: language.text("Lock cycles in an escrow", "Bloquear ciclos en un depósito en garantía").to_string(),
+        fields,
+    }
+}
+
+// the escrow fields for the claim and the refund, if the escrow is still open.
+fn escrow_fields(escrow_id: EscrowId, language: ConsentLanguage) -> Vec<(String, String)> {
+    let mut fields = vec![
+        (language.text("Escrow", "Depósito en garantía").to_string(), escrow_id.to_string()),
+    ];
+    with(&CB_DATA, |cb_data| {
+        if let Some(escrow) = cb_data.escrows.escrows.get(&escrow_id) {
+            fields.push((language.text("Amount", "Cantidad").to_string(), cycles_str(escrow.amount)));
+            fields.push((language.text("From", "Desde").to_string(), icrc_id_as_text(&escrow.from)));
+            fields.push((language.text("To", "Para").to_string(), icrc_id_as_text(&escrow.to)));
+        }
+    });
+    fields
+}
+
+fn escrow_claim_consent_message(escrow_id: EscrowId, language: ConsentLanguage) -> ConsentMessageFields {
+    ConsentMessageFields{
+        title: language.text("Claim an escrow", "Reclamar un depósito en garantía").to_string(),
+        fields: escrow_fields(escrow_id, language),
+    }
+}
+
+fn escrow_refund_consent_message(escrow_id: EscrowId, language: ConsentLanguage) -> ConsentMessageFields {
+    ConsentMessageFields{
+        title: language.text("Refund an escrow", "Reembolsar un depósito en garantía").to_string(),
+        fields: escrow_fields(escrow_id, language),
+    }
+}
+
+fn create_payment_stream_consent_message(q: CreatePaymentStreamQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Rate per second", "Tasa por segundo").to_string(), cycles_str(q.rate_per_second)),
+        (language.text("To", "Para").to_string(), icrc_id_as_text(&q.to)),
+    ];
+    match q.cap {
+        Some(cap) => {
+            fields.push((language.text("Cap, locked now", "Tope, bloqueado ahora").to_string(), cycles_str(cap)));
+            fields.push((language.text("Fee", "Comisión").to_string(), cycles_str(q.fee.unwrap_or(BANK_TRANSFER_FEE))));
+        }
+        None => {
+            fields.push((language.text("Cap", "Tope").to_string(), language.text("None, the stream pays out of your balance", "Ninguno, el flujo paga de su saldo").to_string()));
+        }
+    }
+    fields.extend(from_subaccount_field(q.from_subaccount.as_deref().map(|s| &s[..]), language));
+    ConsentMessageFields{
+        title: language.text("Create a payment stream", "Crear un flujo de pagos").to_string(),
+        fields,
+    }
+}
+
+// the stream fields for the withdraw and the cancel, if the stream is still open.
+fn payment_stream_fields(stream_id: PaymentStreamId, language: ConsentLanguage) -> Vec<(String, String)> {
+    let mut fields = vec![
+        (language.text("Payment stream", "Flujo de pagos").to_string(), stream_id.to_string()),
+    ];
+    with(&CB_DATA, |cb_data| {
+        if let Some(stream) = cb_data.payment_streams.streams.get(&stream_id) {
+            fields.push((language.text("From", "Desde").to_string(), icrc_id_as_text(&stream.from)));
+            fields.push((language.text("To", "Para").to_string(), icrc_id_as_text(&stream.to)));
+            fields.push((language.text("Rate per second", "Tasa por segundo").to_string(), cycles_str(stream.rate_per_second)));
+        }
+    });
+    fields
+}
+
+fn withdraw_payment_stream_consent_message(stream_id: PaymentStreamId, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = payment_stream_fields(stream_id, language);
+    fields.push((language.text("Fee", "Comisión").to_string(), cycles_str(BANK_TRANSFER_FEE)));
+    ConsentMessageFields{
+        title: language.text("Withdraw from a payment stream", "Retirar de un flujo de pagos").to_string(),
+        fields,
+    }
+}
+
+fn cancel_payment_stream_consent_message(stream_id: PaymentStreamId, language: ConsentLanguage) -> ConsentMessageFields {
+    ConsentMessageFields{
+        title: language.text("Cancel a payment stream", "Cancelar un flujo de pagos").to_string(),
+        fields: payment_stream_fields(stream_id, language),
+    }
+}
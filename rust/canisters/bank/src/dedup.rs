@@ -1,41 +1,141 @@
 use crate::{MAX_LEN_OF_THE_DEDUP_MAP, TX_WINDOW_NANOS, PERMITTED_DRIFT_NANOS};
 use cts_lib::{
     tools::time_nanos_u64,
-    icrc::{Icrc1TransferError, BlockId},
+    icrc::{Icrc1TransferError, Icrc2ApproveError, Icrc2TransferFromError, BlockId},
+    types::bank::{CyclesInError, CyclesOutError, CreateCanisterError, EscrowLockError},
+};
+use std::{
+    collections::HashMap,
+    cell::Cell,
 };
-use std::collections::HashMap;
 use candid::Principal;
 
 
 
 pub type DedupMap = HashMap<
-    (Principal/*caller*/, [u8; 32]/*structural-hash*/), 
+    (Principal/*caller*/, [u8; 32]/*structural-hash*/),
     (BlockId/*block-index*/, u64/*created-at-time-of-the-request used for pruning*/)
 >;
 
 
-pub fn check_for_dup(dedup_map: &mut DedupMap, caller: Principal, created_at_time: u64, q_structural_hash: [u8; 32]) -> Result<(), Icrc1TransferError> {
+pub enum CheckForDupError {
+    TooOld,
+    CreatedInFuture{ ledger_time: u64 },
+    Duplicate{ duplicate_of: BlockId },
+    TemporarilyUnavailable,
+}
+
+impl From<CheckForDupError> for Icrc1TransferError {
+    fn from(e: CheckForDupError) -> Self {
+        match e {
+            CheckForDupError::TooOld => Self::TooOld,
+            CheckForDupError::CreatedInFuture{ ledger_time } => Self::CreatedInFuture{ ledger_time },
+            CheckForDupError::Duplicate{ duplicate_of } => Self::Duplicate{ duplicate_of: duplicate_of.into() },
+            CheckForDupError::TemporarilyUnavailable => Self::TemporarilyUnavailable,
+        }
+    }
+}
+
+impl From<CheckForDupError> for Icrc2ApproveError {
+    fn from(e: CheckForDupError) -> Self {
+        match e {
+            CheckForDupError::TooOld => Self::TooOld,
+            CheckForDupError::CreatedInFuture{ ledger_time } => Self::CreatedInFuture{ ledger_time },
+            CheckForDupError::Duplicate{ duplicate_of } => Self::Duplicate{ duplicate_of: duplicate_of.into() },
+            CheckForDupError::TemporarilyUnavailable => Self::TemporarilyUnavailable,
+        }
+    }
+}
+
+impl From<CheckForDupError> for Icrc2TransferFromError {
+    fn from(e: CheckForDupError) -> Self {
+        match e {
+            CheckForDupError::TooOld => Self::TooOld,
+            CheckForDupError::CreatedInFuture{ ledger_time } => Self::CreatedInFuture{ ledger_time },
+            CheckForDupError::Duplicate{ duplicate_of } => Self::Duplicate{ duplicate_of: duplicate_of.into() },
+            CheckForDupError::TemporarilyUnavailable => Self::TemporarilyUnavailable,
+        }
+    }
+}
+
+impl From<CheckForDupError> for CyclesInError {
+    fn from(e: CheckForDupError) -> Self {
+        match e {
+            CheckForDupError::TooOld => Self::TooOld,
+            CheckForDupError::CreatedInFuture{ ledger_time } => Self::CreatedInFuture{ ledger_time },
+            CheckForDupError::Duplicate{ duplicate_of } => Self::Duplicate{ duplicate_of },
+            CheckForDupError::TemporarilyUnavailable => Self::TemporarilyUnavailable,
+        }
+    }
+}
+
+impl From<CheckForDupError> for CyclesOutError {
+    fn from(e: CheckForDupError) -> Self {
+        match e {
+            CheckForDupError::TooOld => Self::TooOld,
+            CheckForDupError::CreatedInFuture{ ledger_time } => Self::CreatedInFuture{ ledger_time },
+            CheckForDupError::Duplicate{ duplicate_of } => Self::Duplicate{ duplicate_of },
+            CheckForDupError::TemporarilyUnavailable => Self::TemporarilyUnavailable,
+        }
+    }
+}
+
+
+impl From<CheckForDupError> for CreateCanisterError {
+    fn from(e: CheckForDupError) -> Self {
+        match e {
+            CheckForDupError::TooOld => Self::TooOld,
+            CheckForDupError::CreatedInFuture{ ledger_time } => Self::CreatedInFuture{ ledger_time },
+            CheckForDupError::Duplicate{ duplicate_of } => Self::Duplicate{ duplicate_of },
+            CheckForDupError::TemporarilyUnavailable => Self::TemporarilyUnavailable,
+        }
+    }
+}
+
+impl From<CheckForDupError> for EscrowLockError {
+    fn from(e: CheckForDupError) -> Self {
+        match e {
+            CheckForDupError::TooOld => Self::TooOld,
+            CheckForDupError::CreatedInFuture{ ledger_time } => Self::CreatedInFuture{ ledger_time },
+            CheckForDupError::Duplicate{ duplicate_of } => Self::Duplicate{ duplicate_of },
+            CheckForDupError::TemporarilyUnavailable => Self::TemporarilyUnavailable,
+        }
+    }
+}
+
+
+pub fn check_for_dup(dedup_map: &mut DedupMap, caller: Principal, created_at_time: u64, q_structural_hash: [u8; 32]) -> Result<(), CheckForDupError> {
     prune_dedup_map(dedup_map);
     let time_nanos_u64: u64 = time_nanos_u64();
     if created_at_time < time_nanos_u64 - TX_WINDOW_NANOS - PERMITTED_DRIFT_NANOS {
-        return Err(Icrc1TransferError::TooOld);
+        return Err(CheckForDupError::TooOld);
     }
     if created_at_time > time_nanos_u64 + PERMITTED_DRIFT_NANOS {
-        return Err(Icrc1TransferError::CreatedInFuture{ ledger_time: time_nanos_u64 });
+        return Err(CheckForDupError::CreatedInFuture{ ledger_time: time_nanos_u64 });
     }
     if let Some((i, _)) = dedup_map.get(&(caller, q_structural_hash)) {
-        return Err(Icrc1TransferError::Duplicate{ duplicate_of: (*i).into() });
+        return Err(CheckForDupError::Duplicate{ duplicate_of: *i });
     }
     if dedup_map.len() >= MAX_LEN_OF_THE_DEDUP_MAP {
-        return Err(Icrc1TransferError::TemporarilyUnavailable);
+        return Err(CheckForDupError::TemporarilyUnavailable);
     }
     Ok(())
 }
 
 
+thread_local!{
+    // the time is the same for the whole message-execution so the map only needs one prune per message-execution. 
+    // the batch-transfers check many transfers within one message-execution.
+    static LAST_PRUNE_TIME_NANOS: Cell<u64> = Cell::new(0);
+}
+
 fn prune_dedup_map(dedup_map: &mut DedupMap) {
     let time_nanos_u64 = time_nanos_u64();
+    if LAST_PRUNE_TIME_NANOS.with(|t| t.get()) == time_nanos_u64 {
+        return;
+    }
+    LAST_PRUNE_TIME_NANOS.with(|t| t.set(time_nanos_u64));
     dedup_map.retain(|_, (_, created_at_time)| {
         *created_at_time >= time_nanos_u64 - TX_WINDOW_NANOS - PERMITTED_DRIFT_NANOS
     });
-}
\ No newline at end of file
+}
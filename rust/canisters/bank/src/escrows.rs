@@ -0,0 +1,66 @@
+use cts_lib::{
+    types::bank::{Escrow, EscrowId, EscrowLockQuest, ESCROW_MEMO_TAG},
+    icrc::{IcrcId, BlockId},
+    consts::{NANOS_IN_A_SECOND, SECONDS_IN_A_DAY},
+};
+use candid::{Principal, CandidType, Deserialize};
+use serde_bytes::ByteArray;
+use std::collections::BTreeMap;
+
+
+pub const MAX_OPEN_ESCROWS: usize = 50_000;
+pub const MAX_OPEN_ESCROWS_PER_USER: usize = 500;
+pub const MAX_ESCROW_TIMEOUT_DURATION_NANOS: u64 = (NANOS_IN_A_SECOND * SECONDS_IN_A_DAY * 30) as u64;
+
+// the locked cycles of all of the open escrows are held in this subaccount of the bank.
+pub const ESCROW_SUBACCOUNT: [u8; 32] = *b"CYCLES-BANK-HTLC-ESCROW-ACCOUNT\0";
+
+pub fn escrow_icrc_id() -> IcrcId {
+    IcrcId{ owner: ic_cdk::api::id(), subaccount: Some(ByteArray::new(ESCROW_SUBACCOUNT)) }
+}
+
+// the blocks of an escrow have the escrow-memo-tag and the escrow-id as the memo.
+pub fn escrow_memo(escrow_id: EscrowId) -> Vec<u8> {
+    [&ESCROW_MEMO_TAG[..], &escrow_id.to_be_bytes()[..]].concat()
+}
+
+// the user transfers can't use the escrow-memo-tag.
+pub fn memo_has_the_escrow_tag(memo: &[u8]) -> bool {
+    memo.starts_with(&ESCROW_MEMO_TAG[..])
+}
+
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct EscrowsData {
+    pub next_id: EscrowId,
+    pub escrows: BTreeMap<EscrowId, Escrow>, // the open escrows. the escrow gets removed on the claim or the refund.
+}
+
+impl EscrowsData {
+    pub fn user_open_escrows_count(&self, user: Principal) -> usize {
+        self.escrows.values().filter(|e| e.from.owner == user).count()
+    }
+    pub fn next_id(&mut self) -> EscrowId {
+        let id: EscrowId = self.next_id;
+        self.next_id += 1;
+        id
+    }
+    pub fn add_escrow(&mut self, id: EscrowId, from: IcrcId, q: EscrowLockQuest, lock_block: BlockId) {
+        self.escrows.insert(
+            id,
+            Escrow{
+                id,
+                from,
+                to: q.to,
+                amount: q.amount,
+                hashlock: q.hashlock,
+                timeout_nanos: q.timeout_nanos,
+                lock_block,
+            }
+        );
+    }
+    // the open escrows that the account locked or can claim.
+    pub fn account_escrows(&self, icrc_id: IcrcId) -> Vec<Escrow> {
+        self.escrows.values().filter(|e| e.from == icrc_id || e.to == icrc_id).cloned().collect()
+    }
+}
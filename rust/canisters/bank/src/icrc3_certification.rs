@@ -1,19 +1,19 @@
-use crate::Logs;
+use crate::{Logs, CBData};
 use ic_cdk::api::set_certified_data;
 use ic_certified_map::{HashTree, fork_hash, labeled_hash, leaf_hash, fork, labeled};
 use serde::Serialize;
 use serde_bytes::ByteBuf;
-use cts_lib::types::bank::icrc3::icrc3_value_of_a_block_log;
+use cts_lib::types::bank::icrc3::{icrc3_value_of_a_block_log, fee_collector_of_the_block};
 
 
 const LABEL_LAST_BLOCK_INDEX: &[u8; 16] = b"last_block_index";
 const LABEL_LAST_BLOCK_HASH: &[u8; 15] = b"last_block_hash";
 
 
-pub fn set_root_hash(logs: &Logs) {    
+pub fn set_root_hash(logs: &Logs, cb_data: &CBData) {    
     if logs.len() != 0 {
-        let last_block_index = logs.len() - 1;
-        let last_block_hash = icrc3_value_of_a_block_log(&logs.get(last_block_index).unwrap()).hash(); // unwrap ok bc we check the length of the logs first.
+        let last_block_index = cb_data.archives.log_length(logs) - 1;
+        let last_block_hash = icrc3_value_of_a_block_log(&cb_data.archives.get_local_log(logs, last_block_index).unwrap(), fee_collector_of_the_block(&cb_data.fee_collector_changes, last_block_index)).hash(); // unwrap ok bc we check the length of the logs first. the latest block is never archived.
         let last_block_index_leb128 = {
             let mut v = Vec::new();
             let _ = leb128::write::unsigned(&mut v, last_block_index); // ignore result for now, since this function is called in the callback after the cycles-out deposit-cycles call, we don't want to error here. Either way there's no common reason for it to fail. 
@@ -27,12 +27,12 @@ pub fn set_root_hash(logs: &Logs) {
     }
 }
 
-pub fn make_data_certificate_hash_tree(logs: &Logs) -> Option<ByteBuf> { // cbor hashtree. none if logs.len == 0 
+pub fn make_data_certificate_hash_tree(logs: &Logs, cb_data: &CBData) -> Option<ByteBuf> { // cbor hashtree. none if logs.len == 0 
     if logs.len() == 0 {
         return None;
     }
-    let last_block_index = logs.len() - 1;
-    let last_block_hash = icrc3_value_of_a_block_log(&logs.get(last_block_index).unwrap()).hash();
+    let last_block_index = cb_data.archives.log_length(logs) - 1;
+    let last_block_hash = icrc3_value_of_a_block_log(&cb_data.archives.get_local_log(logs, last_block_index).unwrap(), fee_collector_of_the_block(&cb_data.fee_collector_changes, last_block_index)).hash();
     let last_block_index_leb128 = {
         let mut v = Vec::new();
         leb128::write::unsigned(&mut v, last_block_index).unwrap(); // unwrap ok bc this is only called in icrc3_get_tip_certificate which is a query call.
@@ -4,44 +4,61 @@
 use std::{
     collections::{
         HashMap,
+        HashSet,
+        BTreeMap,
+        BTreeSet,
     },
     cell::RefCell,
-    borrow::Cow,
     time::Duration,
+    ops::Bound,
 };
 use cts_lib::{
     icrc::{
         IcrcId,
+        IcrcSubaccount,
         Icrc1TransferQuest,
         Icrc1TransferError,
+        Icrc2ApproveQuest,
+        Icrc2ApproveError,
+        Icrc2TransferFromQuest,
+        Icrc2TransferFromError,
+        Icrc2AllowanceQuest,
+        Icrc2Allowance,
         BlockId,
-        IcrcSubaccount,
-        ICRC_DEFAULT_SUBACCOUNT,
+        StorableIcrcId,
         IcrcMetadataValue,
+        icrc4::{Icrc4TransferBatchError, Icrc4TransferBatchResults},
+        icrc21::{Icrc21ConsentMessageRequest, Icrc21ConsentMessageResponse},
     },
     tools::{
         localkey::refcell::{with, with_mut},
         time_nanos_u64,
         principal_icp_subaccount,
-        principal_as_thirty_bytes,
-        thirty_bytes_as_principal,
         call_error_as_u32_and_string,
         sns_validation_string,
         structural_hash,
+        sha256,
+        caller_is_sns_governance_guard,
+        upgrade_canisters::*,
     },
-    management_canister::CanisterIdRecord,
+    management_canister::{CanisterIdRecord, ManagementCanisterOptionalCanisterSettings},
     types::{
         Cycles,
-        bank::{*, log_types::*, icrc3::*},
+        CanisterCode,
+        bank::{*, log_types::*, icrc3::*, archive::*},
     },
     cmc::{
         ledger_topup_cycles_cmc_icp_transfer,
+        ledger_topup_cycles_cmc_icp_transfer_from,
         ledger_topup_cycles_cmc_notify,
         LedgerTopupCyclesCmcNotifyError,
-        CmcNotifyError
+        CmcNotifyError,
+        CmcCreateCanisterArgs,
+        CmcCreateCanisterError,
+        cmc_create_canister,
     },
-    ic_ledger_types::{IcpBlockHeight, IcpTokens},
-    consts::{MiB, KiB, TRILLION, NANOS_IN_A_SECOND, SECONDS_IN_A_DAY, SECONDS_IN_A_MINUTE},
+    ic_ledger_types::{IcpBlockHeight, IcpTokens, MAINNET_CYCLES_MINTING_CANISTER_ID},
+    consts::{MiB, KiB, TRILLION, NANOS_IN_A_SECOND, SECONDS_IN_A_DAY, SECONDS_IN_A_MINUTE, MAINNET_CM_MAIN},
 };
 use ic_cdk::{
     init,
@@ -54,10 +71,11 @@ use ic_cdk::{
         call::{
             msg_cycles_available128,
             msg_cycles_accept128,
+            msg_cycles_refunded128,
             call_with_payment128,
+            call,
             reply,
         },
-        is_controller,
         canister_balance128,
     },
     trap
@@ -67,7 +85,7 @@ use candid::{
     CandidType,
     Deserialize,
 };
-use ic_stable_structures::{StableBTreeMap, StableVec, memory_manager::VirtualMemory, DefaultMemoryImpl, Storable, storable::Bound};
+use ic_stable_structures::{StableBTreeMap, StableVec, memory_manager::VirtualMemory, DefaultMemoryImpl};
 use canister_tools::{
     self,
     MemoryId,
@@ -82,13 +100,63 @@ use dedup::{check_for_dup, DedupMap};
 mod icrc3_certification;
 use icrc3_certification::{set_root_hash, make_data_certificate_hash_tree};
 
+mod allowances;
+use allowances::{Allowances, allowance, set_allowance, subtract_allowance};
+
+mod archive;
+use archive::{ArchivesData, archive_blocks, top_up_archives, ARCHIVE_BLOCKS_RHYTHM};
+
+mod user_logs_index;
+use user_logs_index::{UserLogsIndex, UserLogsIndexBackfill, index_log, start_user_logs_index_backfill_if_not_complete};
+
+mod consent_messages;
+
+mod topup_subscriptions;
+use topup_subscriptions::{TopupSubscriptionsData, check_topup_subscriptions, CHECK_TOPUP_SUBSCRIPTIONS_RHYTHM, MINIMUM_TOPUP_CYCLES, MAX_TOPUP_SUBSCRIPTIONS, MAX_TOPUP_SUBSCRIPTIONS_PER_USER};
+
+mod balance_checkpoints;
+use balance_checkpoints::{BalanceCheckpoints, checkpoint_balances};
+
+mod escrows;
+use escrows::{EscrowsData, escrow_icrc_id, escrow_memo, memo_has_the_escrow_tag, MAX_OPEN_ESCROWS, MAX_OPEN_ESCROWS_PER_USER, MAX_ESCROW_TIMEOUT_DURATION_NANOS};
+
+mod payment_streams;
+use payment_streams::{PaymentStreamsData, payment_streams_icrc_id, payment_stream_memo, payment_stream_status, withdrawable, unstreamed_remainder, MAX_PAYMENT_STREAMS, MAX_PAYMENT_STREAMS_PER_USER};
+
+mod solvency_audit;
+use solvency_audit::{SolvencyAuditData, solvency_audit, track_balance_change, outgoing_operations_are_frozen, start_log_replay_if_none, SOLVENCY_AUDIT_RHYTHM};
+
 // --------- TYPES -----------
 
 #[derive(CandidType, Deserialize)]
 pub struct CBData {
     users_mint_cycles: HashMap<Principal, MintCyclesMidCallData>,
     total_supply: Cycles,
-    icrc1_transfer_dedup_map: DedupMap,
+    icrc1_transfer_dedup_map: DedupMap, // used by the icrc1_transfer, icrc2_approve, icrc2_transfer_from, cycles_in, and cycles_out methods.
+    #[serde(default)]
+    archives: ArchivesData,
+    #[serde(default)]
+    fee_collector_changes: FeeCollectorChanges,
+    #[serde(default)]
+    cycles_out_dedup_in_progress: HashSet<(Principal, [u8; 32])>, // cycles_out and create_canister requests with a created_at_time that are waiting on the deposit_cycles or cmc create_canister call.
+    #[serde(default)]
+    user_logs_index_backfill: UserLogsIndexBackfill, // the default is not-complete so that the upgrade from the heap user-logs-pointers rebuilds the index.
+    #[serde(default)]
+    topup_subscriptions: TopupSubscriptionsData,
+    #[serde(default)]
+    pending_debits: BTreeMap<IcrcId, Cycles>, // the cycles subtracted by the cycles_out and create_canister calls that are waiting on their call and are not in a block yet.
+    #[serde(default)]
+    balance_checkpoints_start_block: Option<u64>, // the first block with the balance-checkpoints. None until the post_upgrade sets it.
+    #[serde(default)]
+    solvency_audit: SolvencyAuditData,
+    #[serde(default)]
+    transfer_notifications: BTreeMap<u64, TransferNotification>, // the latest MAX_TRANSFER_NOTIFICATIONS icrc1_transfer_and_notify blocks.
+    #[serde(default)]
+    transfer_notification_receivers: BTreeSet<Principal>, // the canisters that the icrc1_transfer_and_notify can call. set by the sns-governance.
+    #[serde(default)]
+    escrows: EscrowsData,
+    #[serde(default)]
+    payment_streams: PaymentStreamsData,
 }
 
 impl CBData {
@@ -97,48 +165,35 @@ impl CBData {
             users_mint_cycles: HashMap::new(),    
             total_supply: 0,
             icrc1_transfer_dedup_map: DedupMap::new(),
+            archives: ArchivesData::default(),
+            fee_collector_changes: FeeCollectorChanges::new(),
+            cycles_out_dedup_in_progress: HashSet::new(),
+            user_logs_index_backfill: UserLogsIndexBackfill{ is_complete: true, next_block: 0 },
+            topup_subscriptions: TopupSubscriptionsData::default(),
+            pending_debits: BTreeMap::new(),
+            balance_checkpoints_start_block: Some(0),
+            solvency_audit: SolvencyAuditData::new_bank(),
+            transfer_notifications: BTreeMap::new(),
+            transfer_notification_receivers: BTreeSet::new(),
+            escrows: EscrowsData::default(),
+            payment_streams: PaymentStreamsData::default(),
         }
     }
 }
 
-#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
-pub struct StorableIcrcId(pub IcrcId);
-impl Storable for StorableIcrcId {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        let mut v = Vec::<u8>::new();
-        v.extend(&principal_as_thirty_bytes(&self.0.owner));
-        v.extend(self.0.effective_subaccount());
-        Cow::Owned(v)
-    }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        let owner = thirty_bytes_as_principal(&bytes[..30].try_into().unwrap());
-        let subaccount: IcrcSubaccount = ByteArray::new(bytes[30..].try_into().unwrap());
-        Self(IcrcId{ owner, subaccount: if subaccount == *ICRC_DEFAULT_SUBACCOUNT { None } else { Some(subaccount) }})
-    }
-    const BOUND: Bound = {
-        Bound::Bounded{
-            max_size: 62,
-            is_fixed_size: true
-        }
-    };
-}
-impl From<IcrcId> for StorableIcrcId {
-    fn from(icrc_id: IcrcId) -> Self {
-        Self(icrc_id)
-    }
-}
-
 type CyclesBalances = StableBTreeMap<StorableIcrcId, Cycles, VirtualMemory<DefaultMemoryImpl>>;
 type Logs = StableVec<Log, VirtualMemory<DefaultMemoryImpl>>;
-type UserLogsPointers = HashMap<IcrcId, Vec<u32>>;
 
 // --------- CONSTS --------
 
 pub const CB_DATA_MEMORY_ID: MemoryId = MemoryId::new(0);
 pub const CYCLES_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(1);
 //pub const OLD_LOGS_MEMORY_ID: MemoryId = MemoryId::new(2);
-pub const USER_LOGS_POINTERS_MEMORY_ID: MemoryId = MemoryId::new(3);
+//pub const OLD_USER_LOGS_POINTERS_MEMORY_ID: MemoryId = MemoryId::new(3);
 pub const LOGS_MEMORY_ID: MemoryId = MemoryId::new(4);
+pub const ALLOWANCES_MEMORY_ID: MemoryId = MemoryId::new(5);
+pub const USER_LOGS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(6);
+pub const BALANCE_CHECKPOINTS_MEMORY_ID: MemoryId = MemoryId::new(7);
 
 pub const MINIMUM_BURN_ICP: u128 = 10_000_000/*0.1-icp*/; // When changing this value, change the frontcode burn-icp form field validator with the new value.
 pub const MAX_USERS_MINT_CYCLES: usize = 170;
@@ -155,14 +210,20 @@ pub const MAX_LEN_OF_THE_DEDUP_MAP: usize = 1_000_000;
 
 pub const ICRC3_GET_BLOCKS_CHUNK_SIZE: usize = 2_000;
 
+pub const ICRC4_MAXIMUM_BATCH_SIZE: usize = 500;
+
+pub const MAX_TRANSFER_NOTIFICATIONS: usize = 10_000;
+
 // --------- GLOBAL-STATE ----------
 
 thread_local!{
     pub static CB_DATA: RefCell<CBData> = RefCell::new(CBData::new());
-    pub static USER_LOGS_POINTERS: RefCell<UserLogsPointers> = RefCell::new(UserLogsPointers::new());
     // stable-structures
     pub static CYCLES_BALANCES: RefCell<CyclesBalances> = RefCell::new(CyclesBalances::init(get_virtual_memory(CYCLES_BALANCES_MEMORY_ID)));
+    pub static ALLOWANCES: RefCell<Allowances> = RefCell::new(Allowances::init(get_virtual_memory(ALLOWANCES_MEMORY_ID)));
     pub static LOGS: RefCell<Logs> = RefCell::new(Logs::init(get_virtual_memory(LOGS_MEMORY_ID)).unwrap());
+    pub static USER_LOGS_INDEX: RefCell<UserLogsIndex> = RefCell::new(UserLogsIndex::init(get_virtual_memory(USER_LOGS_INDEX_MEMORY_ID)));
+    pub static BALANCE_CHECKPOINTS: RefCell<BalanceCheckpoints> = RefCell::new(BalanceCheckpoints::init(get_virtual_memory(BALANCE_CHECKPOINTS_MEMORY_ID)));
     
 }
 
@@ -171,7 +232,15 @@ thread_local!{
 #[init]
 fn init() {
     canister_tools::init(&CB_DATA, CB_DATA_MEMORY_ID);
-    canister_tools::init(&USER_LOGS_POINTERS, USER_LOGS_POINTERS_MEMORY_ID);
+    
+    ic_cdk_timers::set_timer_interval(ARCHIVE_BLOCKS_RHYTHM, || {
+        ic_cdk::spawn(archive_blocks());
+        ic_cdk::spawn(top_up_archives());
+    });
+    
+    ic_cdk_timers::set_timer_interval(CHECK_TOPUP_SUBSCRIPTIONS_RHYTHM, || ic_cdk::spawn(check_topup_subscriptions()));
+    
+    ic_cdk_timers::set_timer_interval(SOLVENCY_AUDIT_RHYTHM, solvency_audit);
 } 
 
 #[pre_upgrade]
@@ -182,10 +251,40 @@ fn pre_upgrade() {
 #[post_upgrade]
 fn post_upgrade() { 
     canister_tools::post_upgrade(&CB_DATA, CB_DATA_MEMORY_ID, None::<fn(CBData) -> CBData>);    
-    canister_tools::post_upgrade(&USER_LOGS_POINTERS, USER_LOGS_POINTERS_MEMORY_ID, None::<fn(UserLogsPointers) -> UserLogsPointers>);
     
     // certify icrc3
-    with(&LOGS, set_root_hash);
+    with(&LOGS, |logs| {
+        with(&CB_DATA, |cb_data| {
+            set_root_hash(logs, cb_data);
+        });
+    });
+    
+    // the balance-checkpoints and the solvency-audit log-replay start on the first upgrade with them.
+    with(&LOGS, |logs| {
+        with_mut(&CB_DATA, |cb_data| {
+            let log_length: u64 = cb_data.archives.log_length(logs);
+            if cb_data.balance_checkpoints_start_block.is_none() {
+                cb_data.balance_checkpoints_start_block = Some(log_length);
+            }
+            start_log_replay_if_none(cb_data, log_length);
+        });
+    });
+    
+    // there are no outstanding calls during an upgrade.
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.archives.archiving_lock = false;
+        cb_data.archives.topup_lock = false;
+        cb_data.topup_subscriptions.check_lock = false;
+    });
+    ic_cdk_timers::set_timer_interval(ARCHIVE_BLOCKS_RHYTHM, || {
+        ic_cdk::spawn(archive_blocks());
+        ic_cdk::spawn(top_up_archives());
+    });
+    ic_cdk_timers::set_timer_interval(CHECK_TOPUP_SUBSCRIPTIONS_RHYTHM, || ic_cdk::spawn(check_topup_subscriptions()));
+    ic_cdk_timers::set_timer_interval(SOLVENCY_AUDIT_RHYTHM, solvency_audit);
+    
+    // resumes the user-logs-index backfill bc the timers cancel on upgrade.
+    start_user_logs_index_backfill_if_not_complete();
     
     // for any leftover ongoing mint-cycles bc the timers cancel on upgrade. 
     ic_cdk_timers::set_timer(Duration::from_secs(60), || {
@@ -224,26 +323,56 @@ fn cycles_balance(cycles_balances: &CyclesBalances, icrc_id: IcrcId) -> Cycles {
 
 fn add_cycles_balance(cycles_balances: &mut CyclesBalances, cb_data: &mut CBData, icrc_id: IcrcId, add_cycles: Cycles) {
     let icrc_id = icrc_id_as_storable(icrc_id);
-    cycles_balances.insert(
-        icrc_id,
-        cycles_balances.get(&icrc_id).unwrap_or(0).saturating_add(add_cycles)
-    );
+    let old_balance: Cycles = cycles_balances.get(&icrc_id).unwrap_or(0);
+    let new_balance: Cycles = old_balance.saturating_add(add_cycles);
+    cycles_balances.insert(icrc_id, new_balance);
+    track_balance_change(cb_data, icrc_id, old_balance, new_balance);
     cb_data.total_supply = cb_data.total_supply.saturating_add(add_cycles);
 }
 
 fn subtract_cycles_balance(cycles_balances: &mut CyclesBalances, cb_data: &mut CBData, icrc_id: IcrcId, sub_cycles: Cycles) {
     let icrc_id = icrc_id_as_storable(icrc_id);
-    cycles_balances.insert(
-        icrc_id,
-        cycles_balances.get(&icrc_id).unwrap_or(0).saturating_sub(sub_cycles)
-    );
+    let old_balance: Cycles = cycles_balances.get(&icrc_id).unwrap_or(0);
+    let new_balance: Cycles = old_balance.saturating_sub(sub_cycles);
+    cycles_balances.insert(icrc_id, new_balance);
+    track_balance_change(cb_data, icrc_id, old_balance, new_balance);
     cb_data.total_supply = cb_data.total_supply.saturating_sub(sub_cycles);    
 }
 
+// the balance-checkpoints add the pending-debits back to get the balances as of the latest block.
+fn add_pending_debit(cb_data: &mut CBData, icrc_id: IcrcId, cycles: Cycles) {
+    let pending: &mut Cycles = cb_data.pending_debits.entry(icrc_id).or_insert(0);
+    *pending = pending.saturating_add(cycles);
+}
+
+fn remove_pending_debit(cb_data: &mut CBData, icrc_id: IcrcId, cycles: Cycles) {
+    if let Some(pending) = cb_data.pending_debits.get_mut(&icrc_id) {
+        *pending = pending.saturating_sub(cycles);
+        if *pending == 0 {
+            cb_data.pending_debits.remove(&icrc_id);
+        }
+    }
+}
+
 fn get_latest_block_hash(logs: &Logs) -> Option<ByteArray<32>> {
     match logs.len() {
         0 => None,
-        x => Some(ByteArray::new(icrc3_value_of_a_block_log(&logs.get(x - 1).unwrap()).hash())),      
+        _ => with(&CB_DATA, |cb_data| {
+            let latest_block_id: u64 = cb_data.archives.log_length(logs) - 1;
+            Some(ByteArray::new(icrc3_value_of_a_block_log(&cb_data.archives.get_local_log(logs, latest_block_id).unwrap(), fee_collector_of_the_block(&cb_data.fee_collector_changes, latest_block_id)).hash()))
+        }),      
+    }
+}
+
+fn fee_collector(cb_data: &CBData) -> Option<IcrcId> {
+    cb_data.fee_collector_changes.last().and_then(|(_, fee_collector)| *fee_collector)
+}
+
+// call this in the same message-execution as the log push so that the fee goes to the fee-collector of that block. 
+// if there is no fee-collector the fee is burned.
+fn collect_fee(cycles_balances: &mut CyclesBalances, cb_data: &mut CBData, fee: Cycles) {
+    if let Some(fee_collector) = fee_collector(cb_data) {
+        add_cycles_balance(cycles_balances, cb_data, fee_collector, fee);
     }
 }
 
@@ -307,13 +436,34 @@ pub fn icrc1_supported_standards() -> Vec<SupportedStandard> {
             name: "ICRC-1".to_string(),
             url: "https://github.com/dfinity/ICRC-1".to_string(),
         },
+        SupportedStandard{
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
         SupportedStandard{
             name: "ICRC-3".to_string(),
             url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
         },
+        SupportedStandard{
+            name: "ICRC-4".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-4/ICRC-4.md".to_string(),
+        },
+        SupportedStandard{
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-10/ICRC-10.md".to_string(),
+        },
+        SupportedStandard{
+            name: "ICRC-21".to_string(),
+            url: "https://github.com/dfinity/wg-identity-authentication/blob/main/topics/ICRC-21/icrc_21_consent_msg.md".to_string(),
+        },
     ]
 }
 
+#[query]
+pub fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    icrc1_supported_standards()
+}
+
 
 #[query]
 pub fn icrc1_balance_of(icrc_id: IcrcId) -> Cycles {
@@ -325,7 +475,11 @@ pub fn icrc1_balance_of(icrc_id: IcrcId) -> Cycles {
 // make sure the icrc1_transfer method stays synchronous, within one single message execution. bc the transaction dedup check is only valid within it's message execution and we need it to be valid for the whole of the icrc1-transfer
 #[update]
 pub fn icrc1_transfer(q: Icrc1TransferQuest) -> Result<BlockId, Icrc1TransferError> {
-    let caller = caller();
+    icrc1_transfer_(caller(), q)
+}
+
+// the icrc4_transfer_batch uses this function for each transfer in the batch.
+fn icrc1_transfer_(caller: Principal, q: Icrc1TransferQuest) -> Result<BlockId, Icrc1TransferError> {
     let caller_icrc_id: IcrcId = IcrcId{ owner: caller, subaccount: q.from_subaccount };
     
     if let Some(created_at_time) = q.created_at_time {
@@ -338,6 +492,9 @@ pub fn icrc1_transfer(q: Icrc1TransferQuest) -> Result<BlockId, Icrc1TransferErr
         if memo.len() > 32 {
             trap("Max memo length is 32 bytes.");
         }
+        if memo_has_the_escrow_tag(memo) {
+            trap("The escrow-memo-tag is only for the escrow blocks.");
+        }
     }
     
     if let Some(quest_fee) = q.fee {
@@ -355,6 +512,7 @@ pub fn icrc1_transfer(q: Icrc1TransferQuest) -> Result<BlockId, Icrc1TransferErr
         with_mut(&CB_DATA, |cb_data| {
             subtract_cycles_balance(cycles_balances, cb_data, caller_icrc_id, q.amount.saturating_add(BANK_TRANSFER_FEE));
             add_cycles_balance(cycles_balances, cb_data, q.to, q.amount);
+            collect_fee(cycles_balances, cb_data, BANK_TRANSFER_FEE);
         });
         
         Ok(())
@@ -378,9 +536,19 @@ pub fn icrc1_transfer(q: Icrc1TransferQuest) -> Result<BlockId, Icrc1TransferErr
                             
             logs.push(&log).unwrap(); // if growfailed then trap and roll back the transfer.
             
-            set_root_hash(logs);
+            let block_height: u64 = with(&CB_DATA, |cb_data| {
+                set_root_hash(logs, cb_data);
+                
+                cb_data.archives.log_length(logs) - 1
+            });
+            
+            with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+                index_log(user_logs_index, block_height, &log);
+            });
+            
+            checkpoint_balances(block_height, &log);
             
-            logs.len() - 1
+            block_height
         })
     };
     
@@ -393,81 +561,152 @@ pub fn icrc1_transfer(q: Icrc1TransferQuest) -> Result<BlockId, Icrc1TransferErr
         });
     }
     
-    with_mut(&USER_LOGS_POINTERS, |user_logs_pointers| {
-        user_logs_pointers.entry(caller_icrc_id)
-        .or_default()
-        .push(block_height as u32);
-        if q.to != caller_icrc_id {
-            user_logs_pointers.entry(q.to)
-            .or_default()
-            .push(block_height as u32);
-        }
-    });
-    
     Ok(block_height as u128)
 }
 
 
 
+// ICRC-4 METHODS
 
+#[query]
+pub fn icrc4_maximum_batch_size() -> Option<u128> {
+    Some(ICRC4_MAXIMUM_BATCH_SIZE as u128)
+}
 
-const LOGS_CHUNK_SIZE: usize = (1*MiB + 512*KiB) / 400;
+// same as the icrc1_transfer, make sure the icrc4_transfer_batch method stays synchronous within one single message execution for the dedup checks.
+// each transfer gets its own fee-check, dedup-check, and block. a failed transfer does not stop the other transfers.
+#[update]
+pub fn icrc4_transfer_batch(q: Vec<Icrc1TransferQuest>) -> Icrc4TransferBatchResults {
+    if q.len() > ICRC4_MAXIMUM_BATCH_SIZE {
+        return q.iter().map(|_| Some(Err(Icrc4TransferBatchError::TooManyRequests{ limit: (ICRC4_MAXIMUM_BATCH_SIZE as u128).into() }))).collect();
+    }
+    let caller = caller();
+    q.into_iter().map(|transfer| {
+        // the icrc1_transfer traps on a bad memo, here we return the error for this transfer only.
+        if let Some(ref memo) = transfer.memo {
+            if memo.len() > 32 {
+                return Some(Err(Icrc4TransferBatchError::GenericError{ error_code: 0u32.into(), message: "Max memo length is 32 bytes.".to_string() }));
+            }
+            if memo_has_the_escrow_tag(memo) {
+                return Some(Err(Icrc4TransferBatchError::GenericError{ error_code: 0u32.into(), message: "The escrow-memo-tag is only for the escrow blocks.".to_string() }));
+            }
+        }
+        Some(icrc1_transfer_(caller, transfer).map_err(Icrc4TransferBatchError::from))
+    }).collect()
+}
 
 
 
-#[query]
-pub fn get_logs_backwards(icrc_id: IcrcId, opt_start_before_block: Option<u128>) -> GetLogsBackwardsSponse {
-    let mut v: Vec<(BlockId, Log)> = Vec::new();
-    let mut is_last_chunk = true;
-    with(&USER_LOGS_POINTERS, |user_logs_pointers| {
-        let list: &Vec<u32> = match user_logs_pointers.get(&icrc_id) {
-            Some(list) => list,
-            None => return,
-        };
-        let end_i: usize = if let Some(start_before_block) = opt_start_before_block {
-            list.binary_search(&(start_before_block as u32)).unwrap_or_else(|i|i)  
-        } else {
-            list.len()
-        };
-        let start_i: usize = end_i.saturating_sub(LOGS_CHUNK_SIZE);
-        is_last_chunk = start_i == 0; 
-        with(&LOGS, |logs| {
-            for block_height in list[start_i..end_i].iter() {
-                v.push((block_height.clone() as u128, logs.get(block_height.clone() as u64).unwrap()));     
-            }
+// TRANSFER-AND-NOTIFY
+
+// transfers to the q.to account and then calls the on_cycles_received(OnCyclesReceivedQuest) method on the q.to.owner canister.
+// the transfer is done in the first message-execution, same as the icrc1_transfer, and stays whether the notification goes through or not.
+// the bank waits for the reply of the on_cycles_received call, and a call that does not return blocks the stop_canister for the bank upgrades,
+// so the q.to.owner must be on the transfer-notification receivers list.
+// the sns-governance sets the list and the cm_main adds each cm_tc that it creates.
+#[update]
+pub async fn icrc1_transfer_and_notify(q: Icrc1TransferQuest) -> Result<TransferAndNotifySuccess, Icrc1TransferError> {
+    let caller_icrc_id: IcrcId = IcrcId{ owner: caller(), subaccount: q.from_subaccount };
+    let receiver: Principal = q.to.owner;
+    
+    if with(&CB_DATA, |cb_data| { cb_data.transfer_notification_receivers.contains(&receiver) }) == false {
+        return Err(Icrc1TransferError::GenericError{ error_code: 0u32.into(), message: "The receiver is not on the transfer-notification receivers list.".to_string() });
+    }
+    let (to_subaccount, amount, memo): (Option<IcrcSubaccount>, Cycles, Option<ByteBuf>) = (q.to.subaccount.clone(), q.amount, q.memo.clone());
+    
+    let block_id: BlockId = icrc1_transfer_(caller_icrc_id.owner, q)?;
+    
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.transfer_notifications.insert(block_id as u64, TransferNotification{
+            receiver,
+            timestamp_nanos: time_nanos_u64(),
+            outcome: None,
         });
+        if cb_data.transfer_notifications.len() > MAX_TRANSFER_NOTIFICATIONS {
+            cb_data.transfer_notifications.pop_first();
+        }
     });
-    GetLogsBackwardsSponse {
-        logs: v,
-        is_last_chunk,
-    }    
+    
+    let notification_outcome: TransferNotificationOutcome = match call::<(OnCyclesReceivedQuest,), ()>(
+        receiver,
+        "on_cycles_received",
+        (OnCyclesReceivedQuest{ block_id, from: caller_icrc_id, to_subaccount, amount, memo },),
+    ).await {
+        Ok(()) => TransferNotificationOutcome::Notified,
+        Err(call_error) => TransferNotificationOutcome::NotifyCallError(call_error_as_u32_and_string(call_error)),
+    };
+    
+    with_mut(&CB_DATA, |cb_data| {
+        if let Some(transfer_notification) = cb_data.transfer_notifications.get_mut(&(block_id as u64)) {
+            transfer_notification.outcome = Some(notification_outcome.clone());
+        }
+    });
+    
+    Ok(TransferAndNotifySuccess{
+        block_id,
+        notification_outcome,
+    })
+}
+
+#[query]
+pub fn view_transfer_notification(block_id: BlockId) -> Option<TransferNotification> {
+    with(&CB_DATA, |cb_data| {
+        cb_data.transfer_notifications.get(&(block_id as u64)).cloned()
+    })
 }
 
+#[query]
+pub fn sns_validate_controller_set_transfer_notification_receivers(receivers: Vec<Principal>) -> Result<String, String> {
+    Ok(sns_validation_string(receivers))
+}
+
+// replaces the list, so it must keep the cm_tc canisters that the cm_main added. the receivers must reply to the on_cycles_received calls.
+#[update]
+pub fn controller_set_transfer_notification_receivers(receivers: Vec<Principal>) {
+    caller_is_sns_governance_guard();
+    
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.transfer_notification_receivers = receivers.into_iter().collect();
+    });
+}
 
-// in case it gets too large before the scaling code is done.
+// the cm_main calls this after it creates a cm_tc.
 #[update]
-fn controller_clear_user_logs_pointers_cache() {
-    if is_controller(&caller()) == false {
-        trap("must be the controller for this method.");
+pub fn cm_main_add_transfer_notification_receiver(receiver: Principal) {
+    if caller() != MAINNET_CM_MAIN {
+        trap("Caller must be the cm_main.");
     }
-    with_mut(&USER_LOGS_POINTERS, |user_logs_pointers| {
-        user_logs_pointers.clear();
-        user_logs_pointers.shrink_to_fit();
-        *user_logs_pointers = UserLogsPointers::new();
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.transfer_notification_receivers.insert(receiver);
     });
 }
 
+#[query]
+pub fn view_transfer_notification_receivers() -> Vec<Principal> {
+    with(&CB_DATA, |cb_data| {
+        cb_data.transfer_notification_receivers.iter().copied().collect()
+    })
+}
 
-// cycles_in
 
 
+// ICRC-2 METHODS
+
+
+// same as the icrc1_transfer, make sure the icrc2_approve method stays synchronous within one single message execution for the dedup check.
 #[update]
-pub fn cycles_in(q: CyclesInQuest) -> Result<BlockId, CyclesInError> {
+pub fn icrc2_approve(q: Icrc2ApproveQuest) -> Result<BlockId, Icrc2ApproveError> {
+    let caller = caller();
+    let caller_icrc_id: IcrcId = IcrcId{ owner: caller, subaccount: q.from_subaccount };
     
-    if let Some(quest_fee) = q.fee {
-        if quest_fee != BANK_TRANSFER_FEE {
-            return Err(CyclesInError::BadFee{ expected_fee: BANK_TRANSFER_FEE });
-        }    
+    if q.spender.owner == caller {
+        trap("The spender cannot be the caller.");
+    }
+    
+    if let Some(created_at_time) = q.created_at_time {
+        with_mut(&CB_DATA, |cb_data| {
+            check_for_dup(&mut cb_data.icrc1_transfer_dedup_map, caller, created_at_time, structural_hash(&q).unwrap()) // unwrap ok bc this is within the first message-execution of the call-context.
+        })?; 
     }
     
     if let Some(ref memo) = q.memo {
@@ -476,66 +715,96 @@ pub fn cycles_in(q: CyclesInQuest) -> Result<BlockId, CyclesInError> {
         }
     }
     
-    if msg_cycles_available128() < q.cycles.saturating_add(BANK_TRANSFER_FEE) {
-        return Err(CyclesInError::MsgCyclesTooLow);
+    if let Some(quest_fee) = q.fee {
+        if quest_fee != BANK_TRANSFER_FEE {
+            return Err(Icrc2ApproveError::BadFee{ expected_fee: BANK_TRANSFER_FEE.into() });
+        }    
     }
     
-    msg_cycles_accept128(q.cycles.saturating_add(BANK_TRANSFER_FEE));
+    if let Some(expires_at) = q.expires_at {
+        if expires_at < time_nanos_u64() {
+            return Err(Icrc2ApproveError::Expired{ ledger_time: time_nanos_u64() });
+        }
+    }
     
-    with_mut(&CYCLES_BALANCES, |cycles_balances| {
-        with_mut(&CB_DATA, |cb_data| {
-            add_cycles_balance(cycles_balances, cb_data, q.to, q.cycles);
-        });
-    });
+    with_mut(&ALLOWANCES, |allowances| {
+        if let Some(expected_allowance) = q.expected_allowance {
+            let current_allowance: Cycles = allowance(allowances, caller_icrc_id, q.spender).allowance;
+            if expected_allowance != current_allowance {
+                return Err(Icrc2ApproveError::AllowanceChanged{ current_allowance: current_allowance.into() });
+            }
+        }
+        with_mut(&CYCLES_BALANCES, |cycles_balances| {
+            let caller_balance: Cycles = cycles_balance(cycles_balances, caller_icrc_id); 
+            if caller_balance < BANK_TRANSFER_FEE {
+                return Err(Icrc2ApproveError::InsufficientFunds{ balance: caller_balance.into() });
+            }
+            with_mut(&CB_DATA, |cb_data| {
+                subtract_cycles_balance(cycles_balances, cb_data, caller_icrc_id, BANK_TRANSFER_FEE);
+                collect_fee(cycles_balances, cb_data, BANK_TRANSFER_FEE);
+            });
+            Ok(())
+        })?;
+        set_allowance(allowances, caller_icrc_id, q.spender, q.amount, q.expires_at);
+        Ok(())
+    })?;
     
     let block_height: u64 = {
         with_mut(&LOGS, |logs| {
-                
             let log = Log{
                 phash: get_latest_block_hash(logs), 
                 ts: time_nanos_u64(),
                 fee: if q.fee.is_none() { Some(BANK_TRANSFER_FEE) } else { None },
                 tx: LogTX{
-                    op: Operation::Mint{ to: q.to, kind: MintKind::CyclesIn{ from_canister: caller() } },
+                    op: Operation::Approve{ from: caller_icrc_id, spender: q.spender, expected_allowance: q.expected_allowance, expires_at: q.expires_at },
                     fee: q.fee,
-                    amt: q.cycles,
-                    memo: q.memo,
-                    ts: None,
+                    amt: q.amount,
+                    memo: q.memo.clone(),
+                    ts: q.created_at_time,
                 }
             };
-                            
+            
             logs.push(&log).unwrap();
             
-            set_root_hash(logs);
+            let block_height: u64 = with(&CB_DATA, |cb_data| {
+                set_root_hash(logs, cb_data);
+                
+                cb_data.archives.log_length(logs) - 1
+            });
+            
+            with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+                index_log(user_logs_index, block_height, &log);
+            });
+            
+            checkpoint_balances(block_height, &log);
             
-            logs.len() - 1
+            block_height
         })
     };
     
-    with_mut(&USER_LOGS_POINTERS, |user_logs_pointers| {
-        user_logs_pointers.entry(q.to)
-        .or_default()
-        .push(block_height as u32);        
-    });
-
+    if let Some(created_at_time) = q.created_at_time {
+        with_mut(&CB_DATA, |cb_data| {
+            cb_data.icrc1_transfer_dedup_map.insert(
+                (caller, structural_hash(&q).unwrap()), // unwrap ok bc this is within the first message-execution of the call-context.
+                (block_height as u128, created_at_time),
+            );
+        });
+    }
+    
     Ok(block_height as u128)
-}  
-
-
-// cycles-out
-
-#[query]
-pub fn sns_validate_cycles_out(q: CyclesOutQuest) -> Result<String, String> {
-    Ok(sns_validation_string(q))    
 }
 
+
+// same as the icrc1_transfer, make sure the icrc2_transfer_from method stays synchronous within one single message execution for the dedup check.
 #[update]
-pub async fn cycles_out(q: CyclesOutQuest) -> Result<BlockId, CyclesOutError> {
-        
-    if let Some(quest_fee) = q.fee {
-        if quest_fee != BANK_TRANSFER_FEE {
-            return Err(CyclesOutError::BadFee{ expected_fee: BANK_TRANSFER_FEE });
-        }    
+pub fn icrc2_transfer_from(q: Icrc2TransferFromQuest) -> Result<BlockId, Icrc2TransferFromError> {
+    let caller = caller();
+    let spender_icrc_id: IcrcId = IcrcId{ owner: caller, subaccount: q.spender_subaccount };
+    
+    if let Some(created_at_time) = q.created_at_time {
+        with_mut(&CB_DATA, |cb_data| {
+            check_for_dup(&mut cb_data.icrc1_transfer_dedup_map, caller, created_at_time, structural_hash(&q).unwrap()) // unwrap ok bc this is within the first message-execution of the call-context.
+        })?; 
     }
     
     if let Some(ref memo) = q.memo {
@@ -544,70 +813,1063 @@ pub async fn cycles_out(q: CyclesOutQuest) -> Result<BlockId, CyclesOutError> {
         }
     }
     
-    let caller_icrc_id: IcrcId = IcrcId{ owner: caller(), subaccount: q.from_subaccount };
+    if let Some(quest_fee) = q.fee {
+        if quest_fee != BANK_TRANSFER_FEE {
+            return Err(Icrc2TransferFromError::BadFee{ expected_fee: BANK_TRANSFER_FEE.into() });
+        }    
+    }
     
-    with_mut(&CYCLES_BALANCES, |cycles_balances| {
-        let caller_balance: Cycles = cycles_balance(cycles_balances, caller_icrc_id); 
-        if caller_balance < q.cycles.saturating_add(BANK_TRANSFER_FEE) {
-            return Err(CyclesOutError::InsufficientFunds{ balance: caller_balance.into() })
-        }        
-        with_mut(&CB_DATA, |cb_data| {
-            subtract_cycles_balance(cycles_balances, cb_data, caller_icrc_id, q.cycles.saturating_add(BANK_TRANSFER_FEE));            
-        }); 
-        Ok(())
-    })?;
+    if q.amount > MAX_TRANSFER_FROM_AMOUNT {
+        return Err(Icrc2TransferFromError::GenericError{ error_code: 0u32.into(), message: format!("The max amount of an icrc2_transfer_from is {}.", MAX_TRANSFER_FROM_AMOUNT) });
+    }
     
-    let r = call_with_payment128::<_, ()>(
-        Principal::management_canister(),
-        "deposit_cycles",
-        (CanisterIdRecord{canister_id: q.for_canister},),
-        q.cycles
-    ).await;
+    with_mut(&ALLOWANCES, |allowances| {
+        with_mut(&CYCLES_BALANCES, |cycles_balances| {
+            // when the spender is the from-account, it is the same as an icrc1_transfer and there is no allowance to check.
+            if q.from != spender_icrc_id {
+                let from_allowance: Cycles = allowance(allowances, q.from, spender_icrc_id).allowance;
+                if from_allowance < q.amount.saturating_add(BANK_TRANSFER_FEE) {
+                    return Err(Icrc2TransferFromError::InsufficientAllowance{ allowance: from_allowance.into() });
+                }
+            }
+            let from_balance: Cycles = cycles_balance(cycles_balances, q.from); 
+            if from_balance < q.amount.saturating_add(BANK_TRANSFER_FEE) {
+                return Err(Icrc2TransferFromError::InsufficientFunds{ balance: from_balance.into() });
+            }
+            if q.from != spender_icrc_id {
+                subtract_allowance(allowances, q.from, spender_icrc_id, q.amount.saturating_add(BANK_TRANSFER_FEE));
+            }
+            with_mut(&CB_DATA, |cb_data| {
+                subtract_cycles_balance(cycles_balances, cb_data, q.from, q.amount.saturating_add(BANK_TRANSFER_FEE));
+                add_cycles_balance(cycles_balances, cb_data, q.to, q.amount);
+                collect_fee(cycles_balances, cb_data, BANK_TRANSFER_FEE);
+            });
+            Ok(())
+        })
+    })?;
     
-    match r {
-        Ok(()) => {
-            let block_height: u64 = {
-                with_mut(&LOGS, |logs| {
-                    let log = Log{
-                        phash: get_latest_block_hash(logs),
-                        ts: time_nanos_u64(),
-                        fee: if q.fee.is_none() { Some(BANK_TRANSFER_FEE) } else { None },
-                        tx: LogTX{
-                            op: Operation::Burn{ from: caller_icrc_id, for_canister: q.for_canister },
-                            fee: q.fee,
-                            amt: q.cycles.saturating_add(BANK_TRANSFER_FEE), // include the fee in the amount here because icrc1 does not have fees for a burn. so we put the amount here that is getting subtracted from the caller's account.
-                            memo: q.memo,
-                            ts: None,
-                        }
-                    };
-                    
-                    logs.push(&log).unwrap();
-                    
-                    set_root_hash(logs);
-                    
-                    logs.len() - 1
-                })
-            };
+    let block_height: u64 = {
+        with_mut(&LOGS, |logs| {
+            let log = Log{
+                phash: get_latest_block_hash(logs), 
+                ts: time_nanos_u64(),
+                fee: if q.fee.is_none() { Some(BANK_TRANSFER_FEE) } else { None },
+                tx: LogTX{
+                    op: Operation::XferFrom{ from: q.from, to: q.to, spender: spender_icrc_id },
+                    fee: q.fee,
+                    amt: q.amount,
+                    memo: q.memo.clone(),
+                    ts: q.created_at_time,
+                }
+            };
+            
+            logs.push(&log).unwrap();
+            
+            let block_height: u64 = with(&CB_DATA, |cb_data| {
+                set_root_hash(logs, cb_data);
+                
+                cb_data.archives.log_length(logs) - 1
+            });
+            
+            with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+                index_log(user_logs_index, block_height, &log);
+            });
+            
+            checkpoint_balances(block_height, &log);
+            
+            block_height
+        })
+    };
+    
+    if let Some(created_at_time) = q.created_at_time {
+        with_mut(&CB_DATA, |cb_data| {
+            cb_data.icrc1_transfer_dedup_map.insert(
+                (caller, structural_hash(&q).unwrap()), // unwrap ok bc this is within the first message-execution of the call-context.
+                (block_height as u128, created_at_time),
+            );
+        });
+    }
+    
+    Ok(block_height as u128)
+}
+
+
+#[query]
+pub fn icrc2_allowance(q: Icrc2AllowanceQuest) -> Icrc2Allowance {
+    with(&ALLOWANCES, |allowances| {
+        allowance(allowances, q.account, q.spender)
+    })
+}
+
+
+
+
+
+const LOGS_CHUNK_SIZE: usize = (1*MiB + 512*KiB) / 400;
+
+
+
+#[query]
+pub fn get_logs_backwards(icrc_id: IcrcId, opt_start_before_block: Option<u128>) -> GetLogsBackwardsSponse {
+    let mut v: Vec<(BlockId, Log)> = Vec::new();
+    let mut is_last_chunk = true;
+    with(&USER_LOGS_INDEX, |user_logs_index| {
+        with(&LOGS, |logs| {
+            with(&CB_DATA, |cb_data| {
+                // the blocks before the first_local_block_id are in the archive-canisters. use icrc3_get_blocks for those.
+                let start_block: u64 = cb_data.archives.first_local_block_id;
+                let end_block: u64 = opt_start_before_block.map(|b| std::cmp::min(b, u64::MAX as u128) as u64).unwrap_or(u64::MAX);
+                if end_block <= start_block {
+                    return;
+                }
+                let mut block_ids: Vec<u64> = Vec::new();
+                for (user_log_key, ()) in user_logs_index.range(UserLogKey{ account: icrc_id, block: start_block }..UserLogKey{ account: icrc_id, block: end_block }).rev() {
+                    if block_ids.len() == LOGS_CHUNK_SIZE {
+                        is_last_chunk = false;
+                        break;
+                    }
+                    block_ids.push(user_log_key.block);
+                }
+                for block_id in block_ids.into_iter().rev() {
+                    v.push((block_id as u128, cb_data.archives.get_local_log(logs, block_id).unwrap()));
+                }
+            });
+        });
+    });
+    GetLogsBackwardsSponse {
+        logs: v,
+        is_last_chunk,
+    }    
+}
+
+
+// the balance of the account right after the block, or right after the last block at or before the time.
+// replays the account's blocks from the nearest balance-checkpoint.
+#[query(composite = true)]
+pub async fn balance_of_at(icrc_id: IcrcId, at: BlockOrTime) -> Result<BalanceOfAt, BalanceOfAtError> {
+    balance_checkpoints::balance_of_at(icrc_id, at).await
+}
+
+
+#[cfg(not(debug_assertions))]
+const LIST_ACCOUNTS_CHUNK_SIZE: usize = 1_000;
+#[cfg(debug_assertions)]
+const LIST_ACCOUNTS_CHUNK_SIZE: usize = 2;
+
+// the cycles-balances are in the owner order so the accounts of an owner are one range of the map.
+// the accounts with a zero balance are skipped.
+fn owner_accounts_chunk(owner: Principal, opt_start_after: Option<IcrcSubaccount>) -> (Vec<(IcrcId, Cycles)>, bool) {
+    let mut v: Vec<(IcrcId, Cycles)> = Vec::new();
+    let mut is_last_chunk = true;
+    let start_bound: Bound<StorableIcrcId> = match opt_start_after {
+        Some(start_after) => Bound::Excluded(StorableIcrcId(IcrcId{ owner, subaccount: Some(start_after) })),
+        None => Bound::Included(StorableIcrcId(IcrcId{ owner, subaccount: None })),
+    };
+    let end_bound: Bound<StorableIcrcId> = Bound::Included(StorableIcrcId(IcrcId{ owner, subaccount: Some(ByteArray::new([u8::MAX; 32])) }));
+    with(&CYCLES_BALANCES, |cycles_balances| {
+        for (icrc_id, balance) in cycles_balances.range((start_bound, end_bound)) {
+            if balance == 0 {
+                continue;
+            }
+            if v.len() == LIST_ACCOUNTS_CHUNK_SIZE {
+                is_last_chunk = false;
+                break;
+            }
+            v.push((icrc_id.0, balance));
+        }
+    });
+    (v, is_last_chunk)
+}
+
+// the subaccounts of the owner that hold a balance, in the subaccount order.
+// for the next chunk, call again with the last subaccount of this chunk as the start_after.
+#[query]
+pub fn list_subaccounts(owner: Principal, opt_start_after: Option<IcrcSubaccount>) -> ListSubaccountsSponse {
+    let (accounts, is_last_chunk) = owner_accounts_chunk(owner, opt_start_after);
+    ListSubaccountsSponse {
+        subaccounts: accounts.into_iter().map(|(icrc_id, _)| icrc_id.effective_subaccount().clone()).collect(),
+        is_last_chunk,
+    }
+}
+
+// same as list_subaccounts with the balances.
+#[query]
+pub fn list_accounts(owner: Principal, opt_start_after: Option<IcrcSubaccount>) -> ListAccountsSponse {
+    let (accounts, is_last_chunk) = owner_accounts_chunk(owner, opt_start_after);
+    ListAccountsSponse {
+        accounts,
+        is_last_chunk,
+    }
+}
+
+
+// cycles_in
+
+
+#[update]
+pub fn cycles_in(q: CyclesInQuest) -> Result<BlockId, CyclesInError> {
+    
+    if let Some(created_at_time) = q.created_at_time {
+        with_mut(&CB_DATA, |cb_data| {
+            check_for_dup(&mut cb_data.icrc1_transfer_dedup_map, caller(), created_at_time, structural_hash(&q).unwrap()) // make sure the cycles_in method stays sync. // unwrap ok bc this is within the first message-execution of the call-context.
+        })?; 
+    }
+    
+    if let Some(quest_fee) = q.fee {
+        if quest_fee != BANK_TRANSFER_FEE {
+            return Err(CyclesInError::BadFee{ expected_fee: BANK_TRANSFER_FEE });
+        }    
+    }
+    
+    if let Some(ref memo) = q.memo {
+        if memo.len() > 32 {
+            trap("Max memo length is 32 bytes.");
+        }
+    }
+    
+    if msg_cycles_available128() < q.cycles.saturating_add(BANK_TRANSFER_FEE) {
+        return Err(CyclesInError::MsgCyclesTooLow);
+    }
+    
+    msg_cycles_accept128(q.cycles.saturating_add(BANK_TRANSFER_FEE));
+    
+    let block_height: u64 = cycles_in_(&q);
+    
+    if let Some(created_at_time) = q.created_at_time {
+        with_mut(&CB_DATA, |cb_data| {
+            cb_data.icrc1_transfer_dedup_map.insert(
+                (caller(), structural_hash(&q).unwrap()), // unwrap ok bc this is within the first message-execution of the call-context.
+                (block_height as u128, created_at_time),
+            );
+        });
+    }
+    
+    Ok(block_height as u128)
+}  
+
+// credits the q.cycles to the q.to account and logs the mint. call this after accepting the cycles and the fee.
+// the wallet_receive method uses this function too.
+fn cycles_in_(q: &CyclesInQuest) -> u64 {
+    with_mut(&CYCLES_BALANCES, |cycles_balances| {
+        with_mut(&CB_DATA, |cb_data| {
+            add_cycles_balance(cycles_balances, cb_data, q.to, q.cycles);
+            collect_fee(cycles_balances, cb_data, BANK_TRANSFER_FEE);
+        });
+    });
+    
+    let block_height: u64 = {
+        with_mut(&LOGS, |logs| {
+                
+            let log = Log{
+                phash: get_latest_block_hash(logs), 
+                ts: time_nanos_u64(),
+                fee: if q.fee.is_none() { Some(BANK_TRANSFER_FEE) } else { None },
+                tx: LogTX{
+                    op: Operation::Mint{ to: q.to, kind: MintKind::CyclesIn{ from_canister: caller() } },
+                    fee: q.fee,
+                    amt: q.cycles,
+                    memo: q.memo.clone(),
+                    ts: q.created_at_time,
+                }
+            };
+                            
+            logs.push(&log).unwrap();
+            
+            let block_height: u64 = with(&CB_DATA, |cb_data| {
+                set_root_hash(logs, cb_data);
+                
+                cb_data.archives.log_length(logs) - 1
+            });
+            
+            with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+                index_log(user_logs_index, block_height, &log);
+            });
+            
+            checkpoint_balances(block_height, &log);
+            
+            block_height
+        })
+    };
+    
+    block_height
+}
+
+// for the legacy cycles-wallets and the canisters that send cycles with the wallet_receive method. 
+// credits the attached cycles minus the fee to the to account, or to the caller's default account when there is no to account.
+// when the attached cycles are not more than the fee, the bank does not take the cycles.
+#[update]
+pub fn wallet_receive(to: Option<IcrcId>) -> WalletReceiveSponse {
+    let msg_cycles: Cycles = msg_cycles_available128();
+    if msg_cycles <= BANK_TRANSFER_FEE {
+        return WalletReceiveSponse{ accepted: 0, block_id: None };
+    }
+    let accepted: Cycles = msg_cycles_accept128(msg_cycles);
+    
+    let block_height: u64 = cycles_in_(&CyclesInQuest{
+        cycles: accepted - BANK_TRANSFER_FEE,
+        fee: None,
+        to: to.unwrap_or(IcrcId{ owner: caller(), subaccount: None }),
+        memo: None,
+        created_at_time: None,
+    });
+    
+    WalletReceiveSponse{
+        accepted: std::cmp::min(accepted, u64::MAX as u128) as u64,
+        block_id: Some(block_height as u128),
+    }
+}
+
+
+// cycles-out
+
+#[query]
+pub fn sns_validate_cycles_out(q: CyclesOutQuest) -> Result<String, String> {
+    Ok(sns_validation_string(q))    
+}
+
+#[update]
+pub async fn cycles_out(q: CyclesOutQuest) -> Result<BlockId, CyclesOutError> {
+    
+    // compute the structural-hash here in the first message-execution of the call-context.
+    let opt_dedup_key: Option<((Principal, [u8; 32]), u64)> = q.created_at_time.map(|created_at_time| {
+        ((caller(), structural_hash(&q).unwrap()), created_at_time) 
+    });
+    
+    if let Some((dedup_key, created_at_time)) = opt_dedup_key {
+        with_mut(&CB_DATA, |cb_data| {
+            check_for_dup(&mut cb_data.icrc1_transfer_dedup_map, dedup_key.0, created_at_time, dedup_key.1)?;
+            if cb_data.cycles_out_dedup_in_progress.contains(&dedup_key) {
+                return Err(CyclesOutError::TemporarilyUnavailable);
+            }
+            Ok(())
+        })?;
+    }
+        
+    if let Some(quest_fee) = q.fee {
+        if quest_fee != BANK_TRANSFER_FEE {
+            return Err(CyclesOutError::BadFee{ expected_fee: BANK_TRANSFER_FEE });
+        }    
+    }
+    
+    if let Some(ref memo) = q.memo {
+        if memo.len() > 32 {
+            trap("Max memo length is 32 bytes.");
+        }
+    }
+    
+    let caller_icrc_id: IcrcId = IcrcId{ owner: caller(), subaccount: q.from_subaccount };
+    
+    cycles_out_(caller_icrc_id, q, opt_dedup_key).await
+}
+
+// the topup-subscriptions use this function for the top-ups.
+async fn cycles_out_(caller_icrc_id: IcrcId, q: CyclesOutQuest, opt_dedup_key: Option<((Principal, [u8; 32]), u64)>) -> Result<BlockId, CyclesOutError> {
+    
+    if with(&CB_DATA, outgoing_operations_are_frozen) {
+        return Err(CyclesOutError::TemporarilyUnavailable);
+    }
+    
+    with_mut(&CYCLES_BALANCES, |cycles_balances| {
+        let caller_balance: Cycles = cycles_balance(cycles_balances, caller_icrc_id); 
+        if caller_balance < q.cycles.saturating_add(BANK_TRANSFER_FEE) {
+            return Err(CyclesOutError::InsufficientFunds{ balance: caller_balance.into() })
+        }        
+        with_mut(&CB_DATA, |cb_data| {
+            subtract_cycles_balance(cycles_balances, cb_data, caller_icrc_id, q.cycles.saturating_add(BANK_TRANSFER_FEE));            
+            add_pending_debit(cb_data, caller_icrc_id, q.cycles.saturating_add(BANK_TRANSFER_FEE));
+            if let Some((dedup_key, _)) = opt_dedup_key {
+                cb_data.cycles_out_dedup_in_progress.insert(dedup_key);
+            }
+        }); 
+        Ok(())
+    })?;
+    
+    let r = call_with_payment128::<_, ()>(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (CanisterIdRecord{canister_id: q.for_canister},),
+        q.cycles
+    ).await;
+    
+    with_mut(&CB_DATA, |cb_data| {
+        remove_pending_debit(cb_data, caller_icrc_id, q.cycles.saturating_add(BANK_TRANSFER_FEE));
+        if let Some((dedup_key, _)) = opt_dedup_key {
+            cb_data.cycles_out_dedup_in_progress.remove(&dedup_key);
+        }
+    });
+    
+    match r {
+        Ok(()) => {
+            with_mut(&CYCLES_BALANCES, |cycles_balances| {
+                with_mut(&CB_DATA, |cb_data| {
+                    collect_fee(cycles_balances, cb_data, BANK_TRANSFER_FEE);
+                });
+            });
+            
+            let block_height: u64 = {
+                with_mut(&LOGS, |logs| {
+                    let log = Log{
+                        phash: get_latest_block_hash(logs),
+                        ts: time_nanos_u64(),
+                        fee: if q.fee.is_none() { Some(BANK_TRANSFER_FEE) } else { None },
+                        tx: LogTX{
+                            op: Operation::Burn{ from: caller_icrc_id, for_canister: q.for_canister },
+                            fee: q.fee,
+                            amt: q.cycles.saturating_add(BANK_TRANSFER_FEE), // include the fee in the amount here because icrc1 does not have fees for a burn. so we put the amount here that is getting subtracted from the caller's account.
+                            memo: q.memo,
+                            ts: q.created_at_time,
+                        }
+                    };
+                    
+                    logs.push(&log).unwrap();
+                    
+                    let block_height: u64 = with(&CB_DATA, |cb_data| {
+                        set_root_hash(logs, cb_data);
+                        
+                        cb_data.archives.log_length(logs) - 1
+                    });
+                    
+                    with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+                        index_log(user_logs_index, block_height, &log);
+                    });
+                    
+                    checkpoint_balances(block_height, &log);
+                    
+                    block_height
+                })
+            };
                     
             
-            with_mut(&USER_LOGS_POINTERS, |user_logs_pointers| {
-                user_logs_pointers.entry(caller_icrc_id)
-                .or_default()
-                .push(block_height as u32);
-            });                    
+            if let Some((dedup_key, created_at_time)) = opt_dedup_key {
+                with_mut(&CB_DATA, |cb_data| {
+                    cb_data.icrc1_transfer_dedup_map.insert(
+                        dedup_key,
+                        (block_height as u128, created_at_time),
+                    );
+                });
+            }
             
             Ok(block_height as u128)
         }
-        Err(call_error) => {
+        Err(call_error) => {
+            with_mut(&CYCLES_BALANCES, |cycles_balances| {
+                with_mut(&CB_DATA, |cb_data| {
+                    add_cycles_balance(cycles_balances, cb_data, caller_icrc_id, q.cycles.saturating_add(BANK_TRANSFER_FEE));
+                })
+            });
+            Err(CyclesOutError::DepositCyclesCallError(call_error_as_u32_and_string(call_error)))
+        }
+    }
+}  
+
+
+// create_canister
+
+#[update]
+pub async fn create_canister(q: CreateCanisterQuest) -> Result<CreateCanisterSuccess, CreateCanisterError> {
+    
+    // compute the structural-hash here in the first message-execution of the call-context.
+    let opt_dedup_key: Option<((Principal, [u8; 32]), u64)> = q.created_at_time.map(|created_at_time| {
+        ((caller(), structural_hash(&q).unwrap()), created_at_time) 
+    });
+    
+    if let Some((dedup_key, created_at_time)) = opt_dedup_key {
+        with_mut(&CB_DATA, |cb_data| {
+            check_for_dup(&mut cb_data.icrc1_transfer_dedup_map, dedup_key.0, created_at_time, dedup_key.1)?;
+            if cb_data.cycles_out_dedup_in_progress.contains(&dedup_key) {
+                return Err(CreateCanisterError::TemporarilyUnavailable);
+            }
+            Ok(())
+        })?;
+    }
+    
+    if let Some(quest_fee) = q.fee {
+        if quest_fee != BANK_TRANSFER_FEE {
+            return Err(CreateCanisterError::BadFee{ expected_fee: BANK_TRANSFER_FEE });
+        }    
+    }
+    
+    if let Some(ref memo) = q.memo {
+        if memo.len() > 32 {
+            trap("Max memo length is 32 bytes.");
+        }
+    }
+    
+    if with(&CB_DATA, outgoing_operations_are_frozen) {
+        return Err(CreateCanisterError::TemporarilyUnavailable);
+    }
+    
+    let caller_icrc_id: IcrcId = IcrcId{ owner: caller(), subaccount: q.from_subaccount };
+    
+    with_mut(&CYCLES_BALANCES, |cycles_balances| {
+        let caller_balance: Cycles = cycles_balance(cycles_balances, caller_icrc_id); 
+        if caller_balance < q.cycles.saturating_add(BANK_TRANSFER_FEE) {
+            return Err(CreateCanisterError::InsufficientFunds{ balance: caller_balance.into() })
+        }        
+        with_mut(&CB_DATA, |cb_data| {
+            subtract_cycles_balance(cycles_balances, cb_data, caller_icrc_id, q.cycles.saturating_add(BANK_TRANSFER_FEE));            
+            add_pending_debit(cb_data, caller_icrc_id, q.cycles.saturating_add(BANK_TRANSFER_FEE));
+            if let Some((dedup_key, _)) = opt_dedup_key {
+                cb_data.cycles_out_dedup_in_progress.insert(dedup_key);
+            }
+        }); 
+        Ok(())
+    })?;
+    
+    // when there are no creation_args, the cmc creates the canister with the caller as the controller.
+    let creation_args: CmcCreateCanisterArgs = q.creation_args.clone().unwrap_or(CmcCreateCanisterArgs{
+        settings: Some(ManagementCanisterOptionalCanisterSettings{
+            controllers: Some(vec![caller()]),
+            compute_allocation: None,
+            memory_allocation: None,
+            freezing_threshold: None,
+        }),
+        subnet_selection: None,
+    });
+    
+    let r = cmc_create_canister(&creation_args, q.cycles).await;
+    
+    // the cycles that the cmc did not accept.
+    let cycles_refunded: Cycles = std::cmp::min(msg_cycles_refunded128(), q.cycles);
+    
+    with_mut(&CB_DATA, |cb_data| {
+        remove_pending_debit(cb_data, caller_icrc_id, q.cycles.saturating_add(BANK_TRANSFER_FEE));
+        if let Some((dedup_key, _)) = opt_dedup_key {
+            cb_data.cycles_out_dedup_in_progress.remove(&dedup_key);
+        }
+    });
+    
+    match r {
+        Ok(Ok(canister_id)) => {
+            with_mut(&CYCLES_BALANCES, |cycles_balances| {
+                with_mut(&CB_DATA, |cb_data| {
+                    add_cycles_balance(cycles_balances, cb_data, caller_icrc_id, cycles_refunded);
+                    collect_fee(cycles_balances, cb_data, BANK_TRANSFER_FEE);
+                });
+            });
+            
+            let block_height: u64 = {
+                with_mut(&LOGS, |logs| {
+                    let log = Log{
+                        phash: get_latest_block_hash(logs),
+                        ts: time_nanos_u64(),
+                        fee: if q.fee.is_none() { Some(BANK_TRANSFER_FEE) } else { None },
+                        tx: LogTX{
+                            op: Operation::Burn{ from: caller_icrc_id, for_canister: canister_id },
+                            fee: q.fee,
+                            amt: (q.cycles - cycles_refunded).saturating_add(BANK_TRANSFER_FEE), // include the fee in the amount here because icrc1 does not have fees for a burn. 
+                            memo: q.memo,
+                            ts: q.created_at_time,
+                        }
+                    };
+                    
+                    logs.push(&log).unwrap();
+                    
+                    let block_height: u64 = with(&CB_DATA, |cb_data| {
+                        set_root_hash(logs, cb_data);
+                        
+                        cb_data.archives.log_length(logs) - 1
+                    });
+                    
+                    with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+                        index_log(user_logs_index, block_height, &log);
+                    });
+                    
+                    checkpoint_balances(block_height, &log);
+                    
+                    block_height
+                })
+            };
+            
+            if let Some((dedup_key, created_at_time)) = opt_dedup_key {
+                with_mut(&CB_DATA, |cb_data| {
+                    cb_data.icrc1_transfer_dedup_map.insert(
+                        dedup_key,
+                        (block_height as u128, created_at_time),
+                    );
+                });
+            }
+            
+            Ok(CreateCanisterSuccess{
+                block_id: block_height as u128,
+                canister_id,
+            })
+        }
+        Ok(Err(CmcCreateCanisterError::Refunded{ create_error, .. })) => {
+            let opt_refund_block: Option<BlockId> = create_canister_refund(caller_icrc_id, q.cycles, cycles_refunded, q.memo);
+            Err(CreateCanisterError::CmcCreateCanisterError{ 
+                create_error, 
+                refund_amount: cycles_refunded, 
+                refund_block: opt_refund_block,
+            })
+        }
+        Err(call_error) => {
+            create_canister_refund(caller_icrc_id, q.cycles, cycles_refunded, q.memo);
+            Err(CreateCanisterError::CmcCreateCanisterCallError(call_error))
+        }
+    }
+}
+
+// refunds the cycles that came back from the cmc and the fee. 
+// if the cmc kept some of the cycles, burns them from the caller's account in a block and returns the block-id.
+fn create_canister_refund(caller_icrc_id: IcrcId, cycles: Cycles, cycles_refunded: Cycles, memo: Option<ByteBuf>) -> Option<BlockId> {
+    with_mut(&CYCLES_BALANCES, |cycles_balances| {
+        with_mut(&CB_DATA, |cb_data| {
+            add_cycles_balance(cycles_balances, cb_data, caller_icrc_id, cycles_refunded.saturating_add(BANK_TRANSFER_FEE));
+        })
+    });
+    
+    let cycles_kept_by_the_cmc: Cycles = cycles - cycles_refunded;
+    if cycles_kept_by_the_cmc == 0 {
+        return None;
+    }
+    
+    Some(with_mut(&LOGS, |logs| {
+        let log = Log{
+            phash: get_latest_block_hash(logs),
+            ts: time_nanos_u64(),
+            fee: None,
+            tx: LogTX{
+                op: Operation::Burn{ from: caller_icrc_id, for_canister: MAINNET_CYCLES_MINTING_CANISTER_ID },
+                fee: None,
+                amt: cycles_kept_by_the_cmc,
+                memo: memo,
+                ts: None,
+            }
+        };
+        
+        logs.push(&log).unwrap();
+        
+        let block_height: u64 = with(&CB_DATA, |cb_data| {
+            set_root_hash(logs, cb_data);
+            
+            cb_data.archives.log_length(logs) - 1
+        });
+        
+        with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+            index_log(user_logs_index, block_height, &log);
+        });
+        
+        checkpoint_balances(block_height, &log);
+        
+        block_height as u128
+    }))
+}
+
+
+
+// escrows
+
+// hash-time-locked escrows. the locked cycles are held in the escrow-subaccount of the bank.
+// the lock, the claim, and the refund are each an xfer block with the escrow_memo. the lock has the transfer fee, the claim and the refund are free.
+// make sure the escrow_lock method stays sync for the dedup check.
+#[update]
+pub fn escrow_lock(q: EscrowLockQuest) -> Result<EscrowLockSuccess, EscrowLockError> {
+    if let Some(created_at_time) = q.created_at_time {
+        with_mut(&CB_DATA, |cb_data| {
+            check_for_dup(&mut cb_data.icrc1_transfer_dedup_map, caller(), created_at_time, structural_hash(&q).unwrap()) // unwrap ok bc this is within the first message-execution of the call-context.
+        })?;
+    }
+    
+    if let Some(quest_fee) = q.fee {
+        if quest_fee != BANK_TRANSFER_FEE {
+            return Err(EscrowLockError::BadFee{ expected_fee: BANK_TRANSFER_FEE });
+        }
+    }
+    
+    let now: u64 = time_nanos_u64();
+    if q.timeout_nanos <= now {
+        return Err(EscrowLockError::TimeoutMustBeInTheFuture{ ledger_time: now });
+    }
+    if q.timeout_nanos - now > MAX_ESCROW_TIMEOUT_DURATION_NANOS {
+        return Err(EscrowLockError::MaxTimeoutDuration{ max_timeout_duration_nanos: MAX_ESCROW_TIMEOUT_DURATION_NANOS });
+    }
+    
+    let caller_icrc_id: IcrcId = IcrcId{ owner: caller(), subaccount: q.from_subaccount };
+    
+    with(&CB_DATA, |cb_data| {
+        if cb_data.escrows.escrows.len() >= MAX_OPEN_ESCROWS {
+            return Err(EscrowLockError::CBIsBusy);
+        }
+        if cb_data.escrows.user_open_escrows_count(caller_icrc_id.owner) >= MAX_OPEN_ESCROWS_PER_USER {
+            return Err(EscrowLockError::MaxOpenEscrowsPerUser{ max_open_escrows_per_user: MAX_OPEN_ESCROWS_PER_USER as u64 });
+        }
+        Ok(())
+    })?;
+    
+    with_mut(&CYCLES_BALANCES, |cycles_balances| {
+        let caller_balance: Cycles = cycles_balance(cycles_balances, caller_icrc_id); 
+        if caller_balance < q.amount.saturating_add(BANK_TRANSFER_FEE) {
+            return Err(EscrowLockError::InsufficientFunds{ balance: caller_balance })
+        }
+        with_mut(&CB_DATA, |cb_data| {
+            subtract_cycles_balance(cycles_balances, cb_data, caller_icrc_id, q.amount.saturating_add(BANK_TRANSFER_FEE));
+            add_cycles_balance(cycles_balances, cb_data, escrow_icrc_id(), q.amount);
+            collect_fee(cycles_balances, cb_data, BANK_TRANSFER_FEE);
+        });
+        Ok(())
+    })?;
+    
+    let escrow_id: EscrowId = with_mut(&CB_DATA, |cb_data| cb_data.escrows.next_id());
+    
+    let block_height: u64 = log_xfer(
+        caller_icrc_id,
+        escrow_icrc_id(),
+        q.amount,
+        if q.fee.is_none() { Some(BANK_TRANSFER_FEE) } else { None },
+        q.fee,
+        escrow_memo(escrow_id),
+        q.created_at_time,
+    );
+    
+    with_mut(&CB_DATA, |cb_data| {
+        if let Some(created_at_time) = q.created_at_time {
+            cb_data.icrc1_transfer_dedup_map.insert(
+                (caller_icrc_id.owner, structural_hash(&q).unwrap()), // unwrap ok bc this is within the first message-execution of the call-context.
+                (block_height as u128, created_at_time),
+            );
+        }
+        cb_data.escrows.add_escrow(escrow_id, caller_icrc_id, q, block_height as u128);
+    });
+    
+    Ok(EscrowLockSuccess{
+        escrow_id,
+        block_id: block_height as u128,
+    })
+}
+
+// anyone with the preimage can claim the escrow before the timeout. the cycles go to the escrow's to account.
+#[update]
+pub fn escrow_claim(escrow_id: EscrowId, preimage: ByteBuf) -> Result<BlockId, EscrowClaimError> {
+    let escrow: Escrow = with_mut(&CB_DATA, |cb_data| {
+        let escrow: &Escrow = cb_data.escrows.escrows.get(&escrow_id).ok_or(EscrowClaimError::EscrowNotFound)?;
+        if time_nanos_u64() >= escrow.timeout_nanos {
+            return Err(EscrowClaimError::EscrowTimedOut{ timeout_nanos: escrow.timeout_nanos });
+        }
+        if sha256(&preimage) != *escrow.hashlock {
+            return Err(EscrowClaimError::WrongPreimage);
+        }
+        Ok(cb_data.escrows.escrows.remove(&escrow_id).unwrap())
+    })?;
+    
+    Ok(release_escrow(&escrow, escrow.to) as u128)
+}
+
+// anyone can refund the escrow after the timeout. the cycles go back to the escrow's from account.
+#[update]
+pub fn escrow_refund(escrow_id: EscrowId) -> Result<BlockId, EscrowRefundError> {
+    let escrow: Escrow = with_mut(&CB_DATA, |cb_data| {
+        let escrow: &Escrow = cb_data.escrows.escrows.get(&escrow_id).ok_or(EscrowRefundError::EscrowNotFound)?;
+        if time_nanos_u64() < escrow.timeout_nanos {
+            return Err(EscrowRefundError::EscrowNotTimedOut{ timeout_nanos: escrow.timeout_nanos });
+        }
+        Ok(cb_data.escrows.escrows.remove(&escrow_id).unwrap())
+    })?;
+    
+    Ok(release_escrow(&escrow, escrow.from) as u128)
+}
+
+// the open escrows that the account locked or can claim.
+#[query]
+pub fn list_escrows(icrc_id: IcrcId) -> Vec<Escrow> {
+    with(&CB_DATA, |cb_data| cb_data.escrows.account_escrows(icrc_id))
+}
+
+// moves the escrow's cycles out of the escrow-subaccount. call this after removing the escrow.
+fn release_escrow(escrow: &Escrow, to: IcrcId) -> u64 {
+    with_mut(&CYCLES_BALANCES, |cycles_balances| {
+        with_mut(&CB_DATA, |cb_data| {
+            subtract_cycles_balance(cycles_balances, cb_data, escrow_icrc_id(), escrow.amount);
+            add_cycles_balance(cycles_balances, cb_data, to, escrow.amount);
+        });
+    });
+    log_xfer(escrow_icrc_id(), to, escrow.amount, Some(0), None, escrow_memo(escrow.id), None)
+}
+
+
+
+// payment-streams
+
+#[update]
+pub fn create_payment_stream(q: CreatePaymentStreamQuest) -> Result<CreatePaymentStreamSuccess, CreatePaymentStreamError> {
+    if let Some(quest_fee) = q.fee {
+        if quest_fee != BANK_TRANSFER_FEE {
+            return Err(CreatePaymentStreamError::BadFee{ expected_fee: BANK_TRANSFER_FEE });
+        }
+    }
+    if q.rate_per_second == 0 {
+        return Err(CreatePaymentStreamError::RateMustBeGreaterThanZero);
+    }
+    
+    let caller_icrc_id: IcrcId = IcrcId{ owner: caller(), subaccount: q.from_subaccount };
+    
+    with(&CB_DATA, |cb_data| {
+        if cb_data.payment_streams.streams.len() >= MAX_PAYMENT_STREAMS {
+            return Err(CreatePaymentStreamError::CBIsBusy);
+        }
+        if cb_data.payment_streams.user_streams_count(caller_icrc_id.owner) >= MAX_PAYMENT_STREAMS_PER_USER {
+            return Err(CreatePaymentStreamError::MaxPaymentStreamsPerUser{ max_payment_streams_per_user: MAX_PAYMENT_STREAMS_PER_USER as u64 });
+        }
+        Ok(())
+    })?;
+    
+    if let Some(cap) = q.cap {
+        with_mut(&CYCLES_BALANCES, |cycles_balances| {
+            let caller_balance: Cycles = cycles_balance(cycles_balances, caller_icrc_id); 
+            if caller_balance < cap.saturating_add(BANK_TRANSFER_FEE) {
+                return Err(CreatePaymentStreamError::InsufficientFunds{ balance: caller_balance })
+            }
+            with_mut(&CB_DATA, |cb_data| {
+                subtract_cycles_balance(cycles_balances, cb_data, caller_icrc_id, cap.saturating_add(BANK_TRANSFER_FEE));
+                add_cycles_balance(cycles_balances, cb_data, payment_streams_icrc_id(), cap);
+                collect_fee(cycles_balances, cb_data, BANK_TRANSFER_FEE);
+            });
+            Ok(())
+        })?;
+    }
+    
+    let stream_id: PaymentStreamId = with_mut(&CB_DATA, |cb_data| cb_data.payment_streams.next_id());
+    
+    let lock_block: Option<BlockId> = q.cap.map(|cap| {
+        log_xfer(
+            caller_icrc_id,
+            payment_streams_icrc_id(),
+            cap,
+            if q.fee.is_none() { Some(BANK_TRANSFER_FEE) } else { None },
+            q.fee,
+            payment_stream_memo(stream_id),
+            None,
+        ) as u128
+    });
+    
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.payment_streams.add_stream(stream_id, caller_icrc_id, q, time_nanos_u64());
+    });
+    
+    Ok(CreatePaymentStreamSuccess{
+        stream_id,
+        lock_block,
+    })
+}
+
+// the recipient withdraws the accrued cycles. the fee comes out of the withdrawn cycles.
+#[update]
+pub fn withdraw_payment_stream(stream_id: PaymentStreamId) -> Result<BlockId, WithdrawPaymentStreamError> {
+    with(&CB_DATA, |cb_data| {
+        let stream: &PaymentStream = cb_data.payment_streams.streams.get(&stream_id).ok_or(WithdrawPaymentStreamError::PaymentStreamNotFound)?;
+        if caller() != stream.to.owner {
+            return Err(WithdrawPaymentStreamError::CallerIsNotTheRecipient);
+        }
+        Ok(())
+    })?;
+    
+    let block_id: BlockId = payment_stream_withdraw_(stream_id, time_nanos_u64(), false)
+        .map_err(|withdrawable| WithdrawPaymentStreamError::WithdrawableIsNotMoreThanTheFee{ withdrawable, fee: BANK_TRANSFER_FEE })?;
+    
+    // a stream with a cap is done when the whole cap is withdrawn.
+    with_mut(&CB_DATA, |cb_data| {
+        if let Some(stream) = cb_data.payment_streams.streams.get(&stream_id) {
+            if stream.cap == Some(stream.withdrawn) {
+                cb_data.payment_streams.streams.remove(&stream_id);
+            }
+        }
+    });
+    
+    Ok(block_id)
+}
+
+// the sender cancels the stream. the accrued cycles go to the recipient, and the rest of the cap goes back to the sender.
+// when the accrued cycles are not more than the fee, they go to the recipient without a fee.
+#[update]
+pub fn cancel_payment_stream(stream_id: PaymentStreamId) -> Result<CancelPaymentStreamSuccess, CancelPaymentStreamError> {
+    with(&CB_DATA, |cb_data| {
+        let stream: &PaymentStream = cb_data.payment_streams.streams.get(&stream_id).ok_or(CancelPaymentStreamError::PaymentStreamNotFound)?;
+        if caller() != stream.from.owner {
+            return Err(CancelPaymentStreamError::CallerIsNotTheSender);
+        }
+        Ok(())
+    })?;
+    
+    let now: u64 = time_nanos_u64();
+    
+    // the error is only when there are no accrued cycles.
+    let withdraw_block: Option<BlockId> = payment_stream_withdraw_(stream_id, now, true).ok();
+    
+    let stream: PaymentStream = with_mut(&CB_DATA, |cb_data| cb_data.payment_streams.streams.remove(&stream_id).unwrap());
+    
+    let refund_block: Option<BlockId> = match unstreamed_remainder(&stream, now) {
+        0 => None,
+        refund => {
             with_mut(&CYCLES_BALANCES, |cycles_balances| {
                 with_mut(&CB_DATA, |cb_data| {
-                    add_cycles_balance(cycles_balances, cb_data, caller_icrc_id, q.cycles.saturating_add(BANK_TRANSFER_FEE));
-                })
+                    subtract_cycles_balance(cycles_balances, cb_data, payment_streams_icrc_id(), refund);
+                    add_cycles_balance(cycles_balances, cb_data, stream.from, refund);
+                });
             });
-            Err(CyclesOutError::DepositCyclesCallError(call_error_as_u32_and_string(call_error)))
+            Some(log_xfer(payment_streams_icrc_id(), stream.from, refund, Some(0), None, payment_stream_memo(stream_id), None) as u128)
         }
+    };
+    
+    Ok(CancelPaymentStreamSuccess{
+        withdraw_block,
+        refund_block,
+    })
+}
+
+// the open payment-streams that the account sends or receives, with the accrued cycles as of now.
+#[query]
+pub fn list_payment_streams(icrc_id: IcrcId) -> Vec<PaymentStreamStatus> {
+    let now: u64 = time_nanos_u64();
+    with(&CYCLES_BALANCES, |cycles_balances| {
+        with(&CB_DATA, |cb_data| {
+            cb_data.payment_streams.account_streams(icrc_id).iter().map(|stream| {
+                payment_stream_status(stream, now, cycles_balance(cycles_balances, stream.from))
+            }).collect()
+        })
+    })
+}
+
+// moves the withdrawable cycles of the stream to the recipient. the recipient pays the fee out of the withdrawable cycles.
+// returns the withdrawable cycles as the error when they are not more than the fee.
+// the settle of a cancel moves the withdrawable cycles without a fee when they are not more than the fee, and returns the error only when they are zero.
+fn payment_stream_withdraw_(stream_id: PaymentStreamId, now: u64, settle: bool) -> Result<BlockId, Cycles> {
+    let (source, to, withdrawable_cycles, fee): (IcrcId, IcrcId, Cycles, Cycles) = with_mut(&CYCLES_BALANCES, |cycles_balances| {
+        with_mut(&CB_DATA, |cb_data| {
+            let stream: &PaymentStream = cb_data.payment_streams.streams.get(&stream_id).unwrap();
+            // the cycles of a stream with a cap are in the payment-streams subaccount.
+            let source: IcrcId = if stream.cap.is_some() { payment_streams_icrc_id() } else { stream.from };
+            let to: IcrcId = stream.to;
+            let withdrawable_cycles: Cycles = withdrawable(stream, now, cycles_balance(cycles_balances, stream.from));
+            let fee: Cycles = if withdrawable_cycles > BANK_TRANSFER_FEE {
+                BANK_TRANSFER_FEE
+            } else if settle && withdrawable_cycles > 0 {
+                0
+            } else {
+                return Err(withdrawable_cycles);
+            };
+            cb_data.payment_streams.streams.get_mut(&stream_id).unwrap().withdrawn += withdrawable_cycles;
+            subtract_cycles_balance(cycles_balances, cb_data, source, withdrawable_cycles);
+            add_cycles_balance(cycles_balances, cb_data, to, withdrawable_cycles - fee);
+            if fee > 0 {
+                collect_fee(cycles_balances, cb_data, fee);
+            }
+            Ok((source, to, withdrawable_cycles, fee))
+        })
+    })?;
+    
+    Ok(log_xfer(source, to, withdrawable_cycles - fee, Some(fee), None, payment_stream_memo(stream_id), None) as u128)
+}
+
+
+
+// for the xfer blocks of the escrows and the payment-streams.
+fn log_xfer(from: IcrcId, to: IcrcId, amt: Cycles, fee: Option<Cycles>, tx_fee: Option<Cycles>, memo: Vec<u8>, created_at_time: Option<u64>) -> u64 {
+    with_mut(&LOGS, |logs| {
+        let log = Log{
+            phash: get_latest_block_hash(logs),
+            ts: time_nanos_u64(),
+            fee,
+            tx: LogTX{
+                op: Operation::Xfer{ from, to },
+                fee: tx_fee,
+                amt,
+                memo: Some(ByteBuf::from(memo)),
+                ts: created_at_time,
+            }
+        };
+        
+        logs.push(&log).unwrap();
+        
+        let block_height: u64 = with(&CB_DATA, |cb_data| {
+            set_root_hash(logs, cb_data);
+            
+            cb_data.archives.log_length(logs) - 1
+        });
+        
+        with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+            index_log(user_logs_index, block_height, &log);
+        });
+        
+        checkpoint_balances(block_height, &log);
+        
+        block_height
+    })
+}
+
+
+
+// topup-subscriptions
+
+// the bank checks the cycles-balance of the canister every CHECK_TOPUP_SUBSCRIPTIONS_RHYTHM 
+// and tops-up the canister with the topup_cycles from the caller's account when the balance is below the threshold.
+#[update]
+pub fn add_topup_subscription(q: AddTopupSubscriptionQuest) -> Result<TopupSubscriptionId, AddTopupSubscriptionError> {
+    if q.topup_cycles < MINIMUM_TOPUP_CYCLES {
+        return Err(AddTopupSubscriptionError::MinimumTopupCycles{ minimum_topup_cycles: MINIMUM_TOPUP_CYCLES });
     }
-}  
+    let subscriber: IcrcId = IcrcId{ owner: caller(), subaccount: q.from_subaccount };
+    with_mut(&CB_DATA, |cb_data| {
+        if cb_data.topup_subscriptions.subscriptions.len() >= MAX_TOPUP_SUBSCRIPTIONS {
+            return Err(AddTopupSubscriptionError::CBIsBusy);
+        }
+        if cb_data.topup_subscriptions.user_subscriptions_count(subscriber.owner) >= MAX_TOPUP_SUBSCRIPTIONS_PER_USER {
+            return Err(AddTopupSubscriptionError::MaxTopupSubscriptionsPerUser{ max_topup_subscriptions_per_user: MAX_TOPUP_SUBSCRIPTIONS_PER_USER as u64 });
+        }
+        if let Some(canister_status_via) = q.canister_status_via {
+            if cb_data.topup_subscriptions.canister_status_via_canisters.contains(&canister_status_via) == false {
+                return Err(AddTopupSubscriptionError::CanisterStatusViaIsNotOnTheList);
+            }
+        }
+        Ok(cb_data.topup_subscriptions.add_subscription(subscriber, q))
+    })
+}
+
+#[update]
+pub fn cancel_topup_subscription(id: TopupSubscriptionId) -> Result<(), CancelTopupSubscriptionError> {
+    with_mut(&CB_DATA, |cb_data| {
+        match cb_data.topup_subscriptions.subscriptions.get(&id) {
+            Some(subscription) if subscription.subscriber.owner == caller() => {
+                cb_data.topup_subscriptions.subscriptions.remove(&id);
+                Ok(())
+            }
+            _ => Err(CancelTopupSubscriptionError::TopupSubscriptionNotFound),
+        }
+    })
+}
+
+// the caller's subscriptions of all subaccounts, with the block-ids of the latest top-ups.
+#[query]
+pub fn list_topup_subscriptions() -> Vec<TopupSubscription> {
+    with(&CB_DATA, |cb_data| {
+        cb_data.topup_subscriptions.subscriptions.values()
+            .filter(|s| s.subscriber.owner == caller())
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+pub fn sns_validate_controller_set_canister_status_via_canisters(canisters: Vec<Principal>) -> Result<String, String> {
+    Ok(sns_validation_string(canisters))
+}
+
+// replaces the list of the canisters that the topup-subscriptions can use for the canister_status calls. the canisters must reply to the canister_status calls.
+#[update]
+pub fn controller_set_canister_status_via_canisters(canisters: Vec<Principal>) {
+    caller_is_sns_governance_guard();
+    
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.topup_subscriptions.canister_status_via_canisters = canisters.into_iter().collect();
+    });
+}
+
+#[query]
+pub fn view_canister_status_via_canisters() -> Vec<Principal> {
+    with(&CB_DATA, |cb_data| {
+        cb_data.topup_subscriptions.canister_status_via_canisters.iter().copied().collect()
+    })
+}
+
 
 
 
@@ -621,11 +1883,33 @@ struct MintCyclesMidCallData {
     fee: Cycles,
     cmc_icp_transfer_block_height: Option<IcpBlockHeight>,
     cmc_cycles: Option<Cycles>,
+    #[serde(default)]
+    burn_icp_approved_from: Option<IcrcId>, // when some, the icp gets pulled with an icrc2_transfer_from of this account's approval instead of out of the user's icp-subaccount on the bank.
 }
 
 
 #[update]
 pub async fn mint_cycles(q: MintCyclesQuest) -> MintCyclesResult {
+    start_mint_cycles(q, None).await
+}
+
+// the same as mint_cycles without the icp-subaccount step. the bank pulls the icp with the icrc2-approval of the caller's from_subaccount.
+#[update]
+pub async fn mint_cycles_with_icp_approval(q: MintCyclesWithIcpApprovalQuest) -> MintCyclesResult {
+    let burn_icp_approved_from = IcrcId{ owner: caller(), subaccount: q.from_subaccount };
+    start_mint_cycles(
+        MintCyclesQuest{
+            burn_icp: q.burn_icp,
+            burn_icp_transfer_fee: q.burn_icp_transfer_fee,
+            to: q.to,
+            fee: q.fee,
+            memo: q.memo,
+        },
+        Some(burn_icp_approved_from)
+    ).await
+}
+
+async fn start_mint_cycles(q: MintCyclesQuest, burn_icp_approved_from: Option<IcrcId>) -> MintCyclesResult {
     if canister_balance128() < MINIMUM_CANISTER_CYCLES_BALANCE_FOR_A_START_MINT_CYCLES_CALL {
         trap("This canister is low on cycles.");
     }
@@ -663,6 +1947,7 @@ pub async fn mint_cycles(q: MintCyclesQuest) -> MintCyclesResult {
             fee: fee,
             cmc_icp_transfer_block_height: None,
             cmc_cycles: None,
+            burn_icp_approved_from,
         };
         cb_data.users_mint_cycles.insert(user_id.clone(), mid_call_data.clone());
         Ok(mid_call_data)
@@ -674,18 +1959,27 @@ pub async fn mint_cycles(q: MintCyclesQuest) -> MintCyclesResult {
 async fn mint_cycles_(user_id: Principal, mut mid_call_data: MintCyclesMidCallData) -> MintCyclesResult {   
     
     if mid_call_data.cmc_icp_transfer_block_height.is_none() {
-        match ledger_topup_cycles_cmc_icp_transfer(
-            IcpTokens::from_e8s(mid_call_data.quest.burn_icp as u64), 
-            IcpTokens::from_e8s(mid_call_data.quest.burn_icp_transfer_fee as u64),
-            Some(principal_icp_subaccount(&user_id)),
-            ic_cdk::api::id()
-        ).await {
+        let cmc_icp_transfer_result: Result<IcpBlockHeight, MintCyclesError> = match mid_call_data.burn_icp_approved_from {
+            None => ledger_topup_cycles_cmc_icp_transfer(
+                IcpTokens::from_e8s(mid_call_data.quest.burn_icp as u64), 
+                IcpTokens::from_e8s(mid_call_data.quest.burn_icp_transfer_fee as u64),
+                Some(principal_icp_subaccount(&user_id)),
+                ic_cdk::api::id()
+            ).await.map_err(MintCyclesError::LedgerTopupCyclesCmcIcpTransferError),
+            Some(burn_icp_approved_from) => ledger_topup_cycles_cmc_icp_transfer_from(
+                IcpTokens::from_e8s(mid_call_data.quest.burn_icp as u64), 
+                IcpTokens::from_e8s(mid_call_data.quest.burn_icp_transfer_fee as u64),
+                burn_icp_approved_from,
+                ic_cdk::api::id()
+            ).await.map_err(MintCyclesError::LedgerTopupCyclesCmcIcpTransferFromError),
+        };
+        match cmc_icp_transfer_result {
             Ok(block_height) => { 
                 mid_call_data.cmc_icp_transfer_block_height = Some(block_height); 
             },
-            Err(ledger_topup_cycles_cmc_icp_transfer_error) => {
+            Err(mint_cycles_error) => {
                 with_mut(&CB_DATA, |cb_data| { cb_data.users_mint_cycles.remove(&user_id); });
-                return Err(MintCyclesError::LedgerTopupCyclesCmcIcpTransferError(ledger_topup_cycles_cmc_icp_transfer_error));
+                return Err(mint_cycles_error);
             }
         }
     }
@@ -718,6 +2012,7 @@ async fn mint_cycles_(user_id: Principal, mut mid_call_data: MintCyclesMidCallDa
     with_mut(&CYCLES_BALANCES, |cycles_balances| {
         with_mut(&CB_DATA, |cb_data| {
             add_cycles_balance(cycles_balances, cb_data, mid_call_data.quest.to, mid_call_data.cmc_cycles.unwrap().saturating_sub(mid_call_data.fee));        
+            collect_fee(cycles_balances, cb_data, std::cmp::min(mid_call_data.fee, mid_call_data.cmc_cycles.unwrap()));
             cb_data.users_mint_cycles.remove(&user_id);
         });
     });
@@ -739,18 +2034,22 @@ async fn mint_cycles_(user_id: Principal, mut mid_call_data: MintCyclesMidCallDa
             
             logs.push(&log).unwrap();
             
-            set_root_hash(logs);
+            let block_height: u64 = with(&CB_DATA, |cb_data| {
+                set_root_hash(logs, cb_data);
+                
+                cb_data.archives.log_length(logs) - 1
+            });
+            
+            with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+                index_log(user_logs_index, block_height, &log);
+            });
+            
+            checkpoint_balances(block_height, &log);
             
-            logs.len() - 1
+            block_height
         })
     };
     
-    with_mut(&USER_LOGS_POINTERS, |user_logs_pointers| {
-        user_logs_pointers.entry(mid_call_data.quest.to)
-        .or_default()
-        .push(block_height as u32);        
-    });
-
     Ok(MintCyclesSuccess{
         mint_cycles: mid_call_data.cmc_cycles.unwrap().saturating_sub(mid_call_data.fee),
         mint_cycles_block_height: block_height as u128
@@ -792,6 +2091,198 @@ pub fn canister_cycles_balance_minus_total_supply() -> i128 {
 }
 
 
+// SOLVENCY-AUDIT
+
+
+#[query]
+pub fn solvency_audit_report() -> SolvencyAuditReport {
+    with(&CB_DATA, |cb_data| cb_data.solvency_audit.report.clone())
+}
+
+#[query]
+pub fn sns_validate_controller_unfreeze_outgoing_operations() -> Result<String, String> {
+    Ok(format!("Unfreeze the cycles_out and create_canister methods of the bank. Canister cycles-balance minus total-supply: {}.", canister_cycles_balance_minus_total_supply()))
+}
+
+// the solvency-audit freezes the outgoing operations when the bank is under-collateralized. the freeze stays until this call.
+#[update]
+pub fn controller_unfreeze_outgoing_operations() {
+    caller_is_sns_governance_guard();
+    
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.solvency_audit.report.outgoing_operations_freeze = None;
+    });
+}
+
+
+// FEE-COLLECTOR
+
+
+#[query]
+pub fn sns_validate_controller_set_fee_collector(fee_collector: Option<IcrcId>) -> Result<String, String> {
+    Ok(sns_validation_string(fee_collector))
+}
+
+// the new fee-collector collects the fees starting with the next block. set None to burn the fees.
+#[update]
+pub fn controller_set_fee_collector(fee_collector: Option<IcrcId>) {
+    caller_is_sns_governance_guard();
+    
+    with(&LOGS, |logs| {
+        with_mut(&CB_DATA, |cb_data| {
+            let next_block_id: u64 = cb_data.archives.log_length(logs);
+            if let Some(latest_change) = cb_data.fee_collector_changes.last_mut() {
+                if latest_change.0 == next_block_id { // there are no blocks yet with the latest change.
+                    latest_change.1 = fee_collector;
+                    return;
+                }
+            }
+            cb_data.fee_collector_changes.push((next_block_id, fee_collector));
+        });
+    });
+}
+
+#[query]
+pub fn view_fee_collector() -> Option<IcrcId> {
+    with(&CB_DATA, fee_collector)
+}
+
+
+// ARCHIVES
+
+
+#[query]
+pub fn sns_validate_controller_upload_archive_canister_code(canister_code: CanisterCode) -> Result<String, String> {
+    if canister_code.verify_module_hash().is_err() {
+        return Err("module hash is not as given".to_string());
+    }
+    Ok(format!("Upload the bank archive-canister code with the module: {}.", canister_code.module_hash_hex()))
+}
+
+// used for the creation of new archive-canisters. to upgrade the current archive-canisters use the controller_upgrade_archives method.
+#[update]
+pub fn controller_upload_archive_canister_code(canister_code: CanisterCode) {
+    caller_is_sns_governance_guard();
+    
+    if canister_code.verify_module_hash().is_err() {
+        trap("module hash is not as given");
+    }
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.archives.archive_canister_code = canister_code;
+    });
+}
+
+
+#[query]
+pub fn sns_validate_controller_set_archive_config(q: ArchiveConfig) -> Result<String, String> {
+    Ok(sns_validation_string(q))
+}
+
+#[update]
+pub fn controller_set_archive_config(q: ArchiveConfig) -> Result<(), SetArchiveConfigError> {
+    caller_is_sns_governance_guard();
+    
+    if q.num_blocks_to_archive == 0 {
+        return Err(SetArchiveConfigError::NumBlocksToArchiveMustBeGreaterThanZero);
+    }
+    if q.num_blocks_to_archive >= q.trigger_threshold {
+        return Err(SetArchiveConfigError::NumBlocksToArchiveMustBeLessThanTheTriggerThreshold);
+    }
+    if q.max_blocks_per_archive == 0 {
+        return Err(SetArchiveConfigError::MaxBlocksPerArchiveMustBeGreaterThanZero);
+    }
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.archives.config = q;
+    });
+    Ok(())
+}
+
+
+#[query]
+pub fn view_archives() -> (ArchiveConfig, Vec<ArchiveCanisterData>) {
+    with(&CB_DATA, |cb_data| {
+        (cb_data.archives.config.clone(), cb_data.archives.archive_canisters.clone())
+    })
+}
+
+
+#[query]
+pub fn sns_validate_controller_upgrade_archives(q: ControllerUpgradeCSQuest) -> Result<String, String> {
+    with(&CB_DATA, |cb_data| {
+        let module_hash_hex = {
+            if let Some(new_canister_code) = q.new_canister_code {
+                if new_canister_code.verify_module_hash().is_err() {
+                    return Err("new_canister_code module hash does not match module".to_string());
+                }
+                new_canister_code.module_hash_hex() 
+            } else {
+                cb_data.archives.archive_canister_code.module_hash_hex()
+            }
+        };
+        let mut str = format!("Upgrade the bank archive-canisters with the module: {}.", module_hash_hex); 
+        if let Some(specific_cs) = q.specific_cs {
+            str.push_str(&format!("\nSpecific archive-canisters to upgrade: "));
+            for c in specific_cs {
+                str.push_str(&format!("{}, ", c));
+            }
+        }
+        str.push_str(&format!("\npost_upgrade_arg: {}", hex::encode(&q.post_upgrade_quest)));
+        str.push_str(&format!("\ntake_canisters_snapshots: {}", q.take_canisters_snapshots));
+        Ok(str)
+    })
+}
+
+#[update]
+pub async fn controller_upgrade_archives(q: ControllerUpgradeCSQuest) -> Vec<(Principal, UpgradeOutcome)> {
+    caller_is_sns_governance_guard();
+    
+    let cc: CanisterCode = with_mut(&CB_DATA, |cb_data| {
+        if let Some(new_canister_code) = q.new_canister_code {
+            if new_canister_code.verify_module_hash().is_err() {
+                trap("new_canister_code module hash does not match module");
+            }
+            cb_data.archives.archive_canister_code = new_canister_code; 
+        }
+        cb_data.archives.archive_canister_code.clone()
+    });
+    
+    let cs: Vec<Principal> = match q.specific_cs {
+        Some(cs) => cs.into_iter().collect(),
+        None => {
+            with(&CB_DATA, |cb_data| {
+                cb_data.archives.archive_canisters.iter()
+                .filter_map(|archive_canister| {
+                    if &archive_canister.module_hash != cc.module_hash() {
+                        Some(archive_canister.canister_id.clone())
+                    } else {
+                        None
+                    }
+                })
+                .take(200)
+                .collect()
+            })
+        }
+    };
+    
+    let rs: Vec<(Principal, UpgradeOutcome)> = upgrade_canisters(cs, &cc, &q.post_upgrade_quest, q.take_canisters_snapshots).await;
+    
+    // update successes in the cb_data.
+    with_mut(&CB_DATA, |cb_data| {
+        for (c, uo) in rs.iter() {
+            if let Some(ref r) = uo.install_code_result {
+                if r.is_ok() {
+                    if let Some(archive_canister) = cb_data.archives.archive_canisters.iter_mut().find(|archive_canister| archive_canister.canister_id == *c) {
+                        archive_canister.module_hash = cc.module_hash().clone();
+                    }
+                }
+            }
+        }
+    });
+    
+    rs
+}
+
+
 // ICRC-3 METHODS
 
 
@@ -799,51 +2290,62 @@ pub fn canister_cycles_balance_minus_total_supply() -> i128 {
 // be careful because the compiler does not check to make sure that we call the ic_cdk::reply function and that we call it (only) once.
 #[query(manual_reply = true)]
 pub fn icrc3_get_blocks(q: GetBlocksArgs) -> GetBlocksResult<'static> { // return type is just for candid did file generation. we use ic_cdk::reply here.
-    use std::cmp::min;
+    use std::cmp::{min, max};
     with(&LOGS, |logs| { 
-        
-        // make sure at least one StartAndLength
-        if q.len() == 0 || q[0].start >= (logs.len() as u128) {
-            reply((GetBlocksResult {
-                log_length: logs.len() as u128,
-                blocks: vec![],
-                archived_blocks : vec![],
-            },));
-            return;
-        }
-        
-        // do first range
-        let first_range = q[0];
-        let end_first_range: u64 = min(first_range.start as u64 + min(first_range.length as u64, ICRC3_GET_BLOCKS_CHUNK_SIZE as u64), logs.len()); 
-        let mut first_chunk_logs: Vec<Log> = vec![]; 
-        for i in (first_range.start as u64)..end_first_range {
-            first_chunk_logs.push(logs.get(i).unwrap());
-        }
-        let blocks = (first_range.start..(end_first_range as u128)).zip(first_chunk_logs.iter().map(icrc3_value_of_a_block_log)).map(|(i, b)| IdAndBlock{ id: i, block: b }).collect();    
-        let mut archived_blocks_args: GetBlocksArgs = q.iter().copied().skip(1).collect(); // skip first range 
-        if end_first_range < (first_range.start + first_range.length) as u64 && end_first_range < logs.len() {
-            archived_blocks_args.insert(
-                0,
-                StartAndLength{
-                    start: end_first_range as u128,
-                    length: first_range.start + first_range.length - (end_first_range as u128),
+        with(&CB_DATA, |cb_data| {
+            let log_length: u64 = cb_data.archives.log_length(logs);
+            
+            let mut local_logs: Vec<(u64, Log)> = vec![];
+            let mut archives_args: Vec<GetBlocksArgs> = vec![vec![]; cb_data.archives.archive_canisters.len()];
+            let mut bank_args: GetBlocksArgs = vec![]; // for the local blocks that do not fit in this chunk.
+            
+            for range in q.iter() {
+                let range_start: u64 = min(range.start, log_length as u128) as u64;
+                let range_end: u64 = min(range.start.saturating_add(range.length), log_length as u128) as u64;
+                
+                // archived blocks
+                for (archive_canister, archive_args) in cb_data.archives.archive_canisters.iter().zip(archives_args.iter_mut()) {
+                    let start: u64 = max(range_start, archive_canister.start);
+                    let end: u64 = min(range_end, archive_canister.start + archive_canister.length);
+                    if start < end {
+                        archive_args.push(StartAndLength{ start: start as u128, length: (end - start) as u128 });
+                    }
                 }
-            );
-        }
-        let result = GetBlocksResult {
-            log_length: logs.len() as u128,
-            blocks: blocks,
-            archived_blocks : if archived_blocks_args.len() != 0 {
-                vec![GetBlocksArgsAndCallback{
-                    args: archived_blocks_args,
+                
+                // local blocks
+                let mut i: u64 = max(range_start, cb_data.archives.first_local_block_id);
+                while i < range_end && local_logs.len() < ICRC3_GET_BLOCKS_CHUNK_SIZE {
+                    local_logs.push((i, cb_data.archives.get_local_log(logs, i).unwrap()));
+                    i += 1;
+                }
+                if i < range_end {
+                    bank_args.push(StartAndLength{ start: i as u128, length: (range_end - i) as u128 });
+                }
+            }
+            
+            let mut archived_blocks: Vec<GetBlocksArgsAndCallback> = vec![];
+            for (archive_canister, args) in cb_data.archives.archive_canisters.iter().zip(archives_args.into_iter()) {
+                if args.len() != 0 {
+                    archived_blocks.push(GetBlocksArgsAndCallback{
+                        args,
+                        callback: Icrc3Callback::new(archive_canister.canister_id, "icrc3_get_blocks"),
+                    });
+                }
+            }
+            if bank_args.len() != 0 {
+                archived_blocks.push(GetBlocksArgsAndCallback{
+                    args: bank_args,
                     callback: Icrc3Callback::new(ic_cdk::api::id(), "icrc3_get_blocks"),
-                }]
-            } else {
-                vec![]
-            },
-        };
-        reply((result,));
-        return;
+                });
+            }
+            
+            let result = GetBlocksResult {
+                log_length: log_length as u128,
+                blocks: local_logs.iter().map(|(i, log)| IdAndBlock{ id: *i as u128, block: icrc3_value_of_a_block_log(log, fee_collector_of_the_block(&cb_data.fee_collector_changes, *i)) }).collect(),
+                archived_blocks,
+            };
+            reply((result,));
+        });
     });
     
     GetBlocksResult::placeholder()
@@ -863,20 +2365,39 @@ pub fn icrc3_supported_block_types() -> Vec<SupportBlockType> {
         SupportBlockType{
             block_type: "1xfer",
             url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3#transfer-and-transfer-from-block-schema",
-        }
+        },
+        SupportBlockType{
+            block_type: "2approve",
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3#approve-block-schema",
+        },
+        SupportBlockType{
+            block_type: "2xfer",
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3#transfer-and-transfer-from-block-schema",
+        },
     ]
 }
 
 #[query]
 pub fn icrc3_get_archives(q: GetArchivesArgs) -> GetArchivesResult {
-    let mut v = vec![];
-    if ( q.from.is_some() && q.from.unwrap() < ic_cdk::api::id() ) || q.from.is_none() {
-        v.push(ArchiveData{
-            canister_id: ic_cdk::api::id(),
-            start: 0,
-            end: with(&LOGS, |logs| logs.len() - 1) as u128,
-        });
-    }
+    let mut v: Vec<ArchiveData> = with(&CB_DATA, |cb_data| {
+        with(&LOGS, |logs| {
+            cb_data.archives.archive_canisters.iter()
+                .filter(|archive_canister| archive_canister.length != 0)
+                .map(|archive_canister| ArchiveData{
+                    canister_id: archive_canister.canister_id,
+                    start: archive_canister.start as u128,
+                    end: (archive_canister.start + archive_canister.length - 1) as u128,
+                })
+                .chain(std::iter::once(ArchiveData{
+                    canister_id: ic_cdk::api::id(),
+                    start: cb_data.archives.first_local_block_id as u128,
+                    end: cb_data.archives.log_length(logs).saturating_sub(1) as u128,
+                }))
+                .collect()
+        })
+    });
+    v.sort_by_key(|archive_data| archive_data.canister_id);
+    v.retain(|archive_data| q.from.map_or(true, |from| from < archive_data.canister_id));
     v
 }
 
@@ -884,7 +2405,7 @@ pub fn icrc3_get_archives(q: GetArchivesArgs) -> GetArchivesResult {
 pub fn icrc3_get_tip_certificate() -> Option<Icrc3DataCertificate> {
     with(&LOGS, |logs| {
         let certificate = ic_cdk::api::data_certificate()?;
-        let hash_tree = make_data_certificate_hash_tree(logs)?;
+        let hash_tree = with(&CB_DATA, |cb_data| make_data_certificate_hash_tree(logs, cb_data))?;
         Some(Icrc3DataCertificate{
             certificate: ByteBuf::from(certificate),
             hash_tree,
@@ -894,5 +2415,15 @@ pub fn icrc3_get_tip_certificate() -> Option<Icrc3DataCertificate> {
 
 
 
+// ICRC-21
+
+
+#[update]
+pub fn icrc21_canister_call_consent_message(q: Icrc21ConsentMessageRequest) -> Icrc21ConsentMessageResponse {
+    consent_messages::consent_message(&q)
+}
+
+
+
 
 ic_cdk::export_candid!();
@@ -0,0 +1,102 @@
+use cts_lib::{
+    types::{
+        Cycles,
+        bank::{PaymentStream, PaymentStreamId, PaymentStreamStatus, CreatePaymentStreamQuest},
+    },
+    icrc::IcrcId,
+    consts::NANOS_IN_A_SECOND,
+};
+use candid::{Principal, CandidType, Deserialize};
+use serde_bytes::ByteArray;
+use std::collections::BTreeMap;
+
+
+pub const MAX_PAYMENT_STREAMS: usize = 50_000;
+pub const MAX_PAYMENT_STREAMS_PER_USER: usize = 500;
+
+// the locked caps of the open payment-streams are held in this subaccount of the bank.
+pub const PAYMENT_STREAMS_SUBACCOUNT: [u8; 32] = *b"CYCLES-BANK-PAYMENT-STREAMS-ACCT";
+
+pub fn payment_streams_icrc_id() -> IcrcId {
+    IcrcId{ owner: ic_cdk::api::id(), subaccount: Some(ByteArray::new(PAYMENT_STREAMS_SUBACCOUNT)) }
+}
+
+// the blocks of a payment-stream have the stream-id as the memo.
+pub fn payment_stream_memo(stream_id: PaymentStreamId) -> Vec<u8> {
+    stream_id.to_be_bytes().to_vec()
+}
+
+
+// the cycles that accrued since the start, not counting the withdrawals.
+pub fn streamed_total(stream: &PaymentStream, now_nanos: u64) -> Cycles {
+    let elapsed_nanos: u128 = now_nanos.saturating_sub(stream.start_nanos) as u128;
+    let streamed: Cycles = stream.rate_per_second.saturating_mul(elapsed_nanos) / NANOS_IN_A_SECOND;
+    match stream.cap {
+        Some(cap) => std::cmp::min(streamed, cap),
+        None => streamed,
+    }
+}
+
+pub fn accrued(stream: &PaymentStream, now_nanos: u64) -> Cycles {
+    streamed_total(stream, now_nanos).saturating_sub(stream.withdrawn)
+}
+
+// the cycles of a stream without a cap come out of the sender's account so the withdrawable is at most the sender's balance.
+pub fn withdrawable(stream: &PaymentStream, now_nanos: u64, sender_balance: Cycles) -> Cycles {
+    match stream.cap {
+        Some(_) => accrued(stream, now_nanos),
+        None => std::cmp::min(accrued(stream, now_nanos), sender_balance),
+    }
+}
+
+// the cycles of the cap that are not streamed and not withdrawn.
+pub fn unstreamed_remainder(stream: &PaymentStream, now_nanos: u64) -> Cycles {
+    match stream.cap {
+        Some(cap) => cap.saturating_sub(streamed_total(stream, now_nanos)),
+        None => 0,
+    }
+}
+
+pub fn payment_stream_status(stream: &PaymentStream, now_nanos: u64, sender_balance: Cycles) -> PaymentStreamStatus {
+    PaymentStreamStatus{
+        stream: stream.clone(),
+        accrued: accrued(stream, now_nanos),
+        withdrawable: withdrawable(stream, now_nanos, sender_balance),
+    }
+}
+
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct PaymentStreamsData {
+    pub next_id: PaymentStreamId,
+    pub streams: BTreeMap<PaymentStreamId, PaymentStream>, // the open streams. a stream with a cap gets removed when it is fully withdrawn.
+}
+
+impl PaymentStreamsData {
+    pub fn user_streams_count(&self, user: Principal) -> usize {
+        self.streams.values().filter(|s| s.from.owner == user).count()
+    }
+    pub fn next_id(&mut self) -> PaymentStreamId {
+        let id: PaymentStreamId = self.next_id;
+        self.next_id += 1;
+        id
+    }
+    pub fn add_stream(&mut self, id: PaymentStreamId, from: IcrcId, q: CreatePaymentStreamQuest, start_nanos: u64) {
+        self.streams.insert(
+            id,
+            PaymentStream{
+                id,
+                from,
+                to: q.to,
+                rate_per_second: q.rate_per_second,
+                cap: q.cap,
+                start_nanos,
+                withdrawn: 0,
+            }
+        );
+    }
+    // the open streams that the account sends or receives.
+    pub fn account_streams(&self, icrc_id: IcrcId) -> Vec<PaymentStream> {
+        self.streams.values().filter(|s| s.from == icrc_id || s.to == icrc_id).cloned().collect()
+    }
+}
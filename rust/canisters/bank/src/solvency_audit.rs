@@ -0,0 +1,301 @@
+use crate::{CB_DATA, CYCLES_BALANCES, LOGS, CBData};
+use cts_lib::{
+    tools::{
+        localkey::refcell::{with, with_mut},
+        time_nanos_u64,
+    },
+    types::{
+        Cycles,
+        bank::{
+            SolvencyAuditReport,
+            BalancesSweepReport,
+            SolvencyCheck,
+            SolvencyAuditMismatch,
+            SolvencyAuditMismatchKind,
+            icrc3::fee_collector_of_the_block,
+        },
+    },
+    icrc::{IcrcId, StorableIcrcId},
+    consts::BILLION,
+};
+use ic_cdk::api::{canister_balance128, instruction_counter};
+use candid::{CandidType, Deserialize};
+use std::{
+    ops::Bound,
+    time::Duration,
+    collections::BTreeMap,
+};
+
+
+#[cfg(not(debug_assertions))]
+pub const SOLVENCY_AUDIT_RHYTHM: Duration = Duration::from_secs(60 * 5);
+#[cfg(debug_assertions)]
+pub const SOLVENCY_AUDIT_RHYTHM: Duration = Duration::from_secs(5);
+
+// each part of the audit stops at its instruction-limit and continues on the next tick.
+const MAX_INSTRUCTIONS_FOR_THE_LOG_REPLAY: u64 = 5 * BILLION;
+const MAX_INSTRUCTIONS_FOR_THE_BALANCES_SWEEP: u64 = 10 * BILLION;
+
+const MAX_SOLVENCY_AUDIT_MISMATCHES: usize = 100;
+
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct SolvencyAuditData {
+    pub report: SolvencyAuditReport,
+    balances_sweep: Option<BalancesSweep>,
+    log_replay: Option<LogReplay>,
+}
+
+// the sweep goes over the cycles-balances in the key order over many ticks.
+// the balance changes of the accounts that the sweep already went over get tracked in the sum so that the sum at the end of the sweep is the sum of the current balances.
+#[derive(CandidType, Deserialize)]
+struct BalancesSweep {
+    start_nanos: u64,
+    last_account: Option<IcrcId>,
+    accounts: u64,
+    balances_sum: Cycles,
+}
+
+// the total-supply by the blocks before the next_block.
+#[derive(CandidType, Deserialize)]
+struct LogReplay {
+    next_block: u64,
+    supply: Cycles,
+    #[serde(default)]
+    account_deltas: Option<AccountDeltas>, // none until the replay gets to the latest block for the first time after the upgrade with it.
+}
+
+// the balance changes of each account since the last time that the replay was at the latest block, by the replayed blocks and by the track_balance_change.
+// the tracked deltas start with the pending-debits subtracted bc those debits are in the balances but their blocks are not in the log yet.
+#[derive(CandidType, Deserialize, Default)]
+struct AccountDeltas {
+    replayed: BTreeMap<IcrcId, i128>,
+    tracked: BTreeMap<IcrcId, i128>,
+}
+
+impl AccountDeltas {
+    fn new(cb_data: &CBData) -> Self {
+        Self {
+            replayed: BTreeMap::new(),
+            tracked: cb_data.pending_debits.iter().map(|(account, pending)| (*account, 0i128.saturating_sub_unsigned(*pending))).collect(),
+        }
+    }
+}
+
+fn add_delta(deltas: &mut BTreeMap<IcrcId, i128>, account: IcrcId, credit: Cycles, debit: Cycles) {
+    let delta: &mut i128 = deltas.entry(account).or_insert(0);
+    *delta = delta.saturating_add_unsigned(credit).saturating_sub_unsigned(debit);
+}
+
+impl SolvencyAuditData {
+    // the log-replay starts at the genesis for a new bank.
+    pub fn new_bank() -> Self {
+        Self {
+            log_replay: Some(LogReplay{ next_block: 0, supply: 0, account_deltas: Some(AccountDeltas::default()) }),
+            ..Default::default()
+        }
+    }
+    fn push_mismatch(&mut self, kind: SolvencyAuditMismatchKind) {
+        ic_cdk::print(&format!("solvency-audit mismatch: {:?}", kind));
+        self.report.mismatches.push(SolvencyAuditMismatch{ timestamp_nanos: time_nanos_u64(), kind });
+        if self.report.mismatches.len() > MAX_SOLVENCY_AUDIT_MISMATCHES {
+            self.report.mismatches.remove(0);
+        }
+    }
+}
+
+
+// call this on each change of a cycles-balance.
+pub fn track_balance_change(cb_data: &mut CBData, icrc_id: StorableIcrcId, old_balance: Cycles, new_balance: Cycles) {
+    if let Some(ref mut sweep) = cb_data.solvency_audit.balances_sweep {
+        if sweep.last_account.map_or(false, |last_account| icrc_id <= StorableIcrcId(last_account)) {
+            sweep.balances_sum = sweep.balances_sum.saturating_add(new_balance).saturating_sub(old_balance);
+        }
+    }
+    if let Some(account_deltas) = cb_data.solvency_audit.log_replay.as_mut().and_then(|log_replay| log_replay.account_deltas.as_mut()) {
+        add_delta(&mut account_deltas.tracked, icrc_id.0, new_balance.saturating_sub(old_balance), old_balance.saturating_sub(new_balance));
+    }
+}
+
+// the total-supply that the log-replay must match. the cycles of the calls that are waiting on their call are subtracted from the total_supply before their block.
+fn total_supply_as_of_the_latest_block(cb_data: &CBData) -> Cycles {
+    cb_data.pending_debits.values().fold(cb_data.total_supply, |sum, pending| sum.saturating_add(*pending))
+}
+
+pub fn outgoing_operations_are_frozen(cb_data: &CBData) -> bool {
+    cb_data.solvency_audit.report.outgoing_operations_freeze.is_some()
+}
+
+// sets the log-replay to start at the next block for a bank from before the solvency-audit.
+pub fn start_log_replay_if_none(cb_data: &mut CBData, log_length: u64) {
+    if cb_data.solvency_audit.log_replay.is_none() {
+        cb_data.solvency_audit.log_replay = Some(LogReplay{
+            next_block: log_length,
+            supply: total_supply_as_of_the_latest_block(cb_data),
+            account_deltas: Some(AccountDeltas::new(cb_data)),
+        });
+    }
+}
+
+// the archiving waits for the log-replay so that the replay gets each block.
+pub fn log_replay_next_block(cb_data: &CBData) -> Option<u64> {
+    cb_data.solvency_audit.log_replay.as_ref().map(|log_replay| log_replay.next_block)
+}
+
+
+pub fn solvency_audit() {
+    check_solvency();
+    replay_logs();
+    sweep_balances();
+}
+
+fn check_solvency() {
+    with_mut(&CB_DATA, |cb_data| {
+        let check = SolvencyCheck{
+            timestamp_nanos: time_nanos_u64(),
+            canister_cycles_balance: canister_balance128(),
+            total_supply: cb_data.total_supply,
+        };
+        // the freeze stays until the controller unfreezes it.
+        if check.canister_cycles_balance < check.total_supply
+        && outgoing_operations_are_frozen(cb_data) == false {
+            cb_data.solvency_audit.push_mismatch(SolvencyAuditMismatchKind::UnderCollateralized{
+                canister_cycles_balance: check.canister_cycles_balance,
+                total_supply: check.total_supply,
+            });
+            cb_data.solvency_audit.report.outgoing_operations_freeze = Some(check.clone());
+        }
+        cb_data.solvency_audit.report.last_solvency_check = Some(check);
+    });
+}
+
+fn replay_logs() {
+    let start_instructions: u64 = instruction_counter();
+    with(&LOGS, |logs| {
+        with_mut(&CB_DATA, |cb_data| {
+            let log_length: u64 = cb_data.archives.log_length(logs);
+            let mut log_replay: LogReplay = match cb_data.solvency_audit.log_replay.take() {
+                Some(log_replay) => log_replay,
+                None => return,
+            };
+            // the archiving waits for the replay, so this is only for the blocks that got archived before the upgrade with the wait.
+            if log_replay.next_block < cb_data.archives.first_local_block_id {
+                log_replay = LogReplay{
+                    next_block: log_length,
+                    supply: total_supply_as_of_the_latest_block(cb_data),
+                    account_deltas: Some(AccountDeltas::new(cb_data)),
+                };
+            }
+            while log_replay.next_block < log_length {
+                if instruction_counter() - start_instructions >= MAX_INSTRUCTIONS_FOR_THE_LOG_REPLAY {
+                    break;
+                }
+                let log = cb_data.archives.get_local_log(logs, log_replay.next_block).unwrap();
+                let fee_is_collected: bool = fee_collector_of_the_block(&cb_data.fee_collector_changes, log_replay.next_block).is_some();
+                let (added, removed) = log.supply_change(fee_is_collected);
+                log_replay.supply = log_replay.supply.saturating_add(added).saturating_sub(removed);
+                if let Some(ref mut account_deltas) = log_replay.account_deltas {
+                    for account in log.tx.op.icrc_ids() {
+                        let (credit, debit) = log.balance_change(&account);
+                        if credit != 0 || debit != 0 {
+                            add_delta(&mut account_deltas.replayed, account, credit, debit);
+                        }
+                    }
+                    if fee_is_collected && log.effective_fee() > 0 {
+                        if let Some(fee_collector) = fee_collector_of_the_block(&cb_data.fee_collector_changes, log_replay.next_block) {
+                            add_delta(&mut account_deltas.replayed, *fee_collector, log.effective_fee(), 0);
+                        }
+                    }
+                }
+                log_replay.next_block += 1;
+            }
+            // the replay can only match the total-supply when it is at the latest block.
+            if log_replay.next_block == log_length {
+                let total_supply: Cycles = total_supply_as_of_the_latest_block(cb_data);
+                if log_replay.supply != total_supply {
+                    cb_data.solvency_audit.push_mismatch(SolvencyAuditMismatchKind::LogReplaySupplyIsNotTheTotalSupply{
+                        log_length,
+                        log_replay_supply: log_replay.supply,
+                        total_supply,
+                    });
+                    // so that the same mismatch does not get pushed on each tick.
+                    log_replay.supply = total_supply;
+                }
+                if let Some(account_deltas) = log_replay.account_deltas.take() {
+                    check_account_deltas(cb_data, account_deltas, log_length);
+                }
+                log_replay.account_deltas = Some(AccountDeltas::new(cb_data));
+            }
+            cb_data.solvency_audit.report.log_replay_next_block = Some(log_replay.next_block);
+            cb_data.solvency_audit.log_replay = Some(log_replay);
+        });
+    });
+}
+
+// the replayed change of each account must be the tracked change plus the pending-debits that are not in a block yet.
+fn check_account_deltas(cb_data: &mut CBData, account_deltas: AccountDeltas, log_length: u64) {
+    let AccountDeltas{ replayed, mut tracked } = account_deltas;
+    for (account, pending) in cb_data.pending_debits.iter() {
+        add_delta(&mut tracked, *account, *pending, 0);
+    }
+    let mut accounts: Vec<IcrcId> = replayed.keys().chain(tracked.keys()).copied().collect();
+    accounts.sort();
+    accounts.dedup();
+    for account in accounts {
+        let log_replay_change: i128 = replayed.get(&account).copied().unwrap_or(0);
+        let balance_change: i128 = tracked.get(&account).copied().unwrap_or(0);
+        if log_replay_change != balance_change {
+            cb_data.solvency_audit.push_mismatch(SolvencyAuditMismatchKind::LogReplayAccountChangeIsNotTheBalanceChange{
+                account,
+                log_length,
+                log_replay_change,
+                balance_change,
+            });
+        }
+    }
+}
+
+fn sweep_balances() {
+    let start_instructions: u64 = instruction_counter();
+    with(&CYCLES_BALANCES, |cycles_balances| {
+        with_mut(&CB_DATA, |cb_data| {
+            let mut sweep: BalancesSweep = cb_data.solvency_audit.balances_sweep.take().unwrap_or(BalancesSweep{
+                start_nanos: time_nanos_u64(),
+                last_account: None,
+                accounts: 0,
+                balances_sum: 0,
+            });
+            let start_bound: Bound<StorableIcrcId> = match sweep.last_account {
+                Some(last_account) => Bound::Excluded(StorableIcrcId(last_account)),
+                None => Bound::Unbounded,
+            };
+            let mut is_complete: bool = true;
+            for (account, balance) in cycles_balances.range((start_bound, Bound::Unbounded)) {
+                if instruction_counter() - start_instructions >= MAX_INSTRUCTIONS_FOR_THE_BALANCES_SWEEP {
+                    is_complete = false;
+                    break;
+                }
+                sweep.balances_sum = sweep.balances_sum.saturating_add(balance);
+                sweep.accounts += 1;
+                sweep.last_account = Some(account.0);
+            }
+            if is_complete == false {
+                cb_data.solvency_audit.balances_sweep = Some(sweep);
+                return;
+            }
+            if sweep.balances_sum != cb_data.total_supply {
+                cb_data.solvency_audit.push_mismatch(SolvencyAuditMismatchKind::BalancesSumIsNotTheTotalSupply{
+                    balances_sum: sweep.balances_sum,
+                    total_supply: cb_data.total_supply,
+                });
+            }
+            cb_data.solvency_audit.report.last_balances_sweep = Some(BalancesSweepReport{
+                start_nanos: sweep.start_nanos,
+                end_nanos: time_nanos_u64(),
+                accounts: sweep.accounts,
+                balances_sum: sweep.balances_sum,
+                total_supply: cb_data.total_supply,
+            });
+        });
+    });
+}
@@ -0,0 +1,196 @@
+use crate::{CB_DATA, cycles_out_};
+use cts_lib::{
+    tools::{
+        localkey::refcell::{with, with_mut},
+        time_nanos_u64,
+        call_error_as_u32_and_string,
+    },
+    types::{
+        Cycles,
+        CallError,
+        bank::{TopupSubscription, TopupSubscriptionId, AddTopupSubscriptionQuest, CyclesOutQuest, BANK_TRANSFER_FEE},
+    },
+    icrc::{IcrcId, BlockId},
+    management_canister::CanisterIdRecord,
+    consts::TRILLION,
+};
+use ic_cdk::api::call::call;
+use candid::{Principal, CandidType, Deserialize};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+
+#[cfg(not(debug_assertions))]
+pub const CHECK_TOPUP_SUBSCRIPTIONS_RHYTHM: Duration = Duration::from_secs(60 * 10);
+#[cfg(debug_assertions)]
+pub const CHECK_TOPUP_SUBSCRIPTIONS_RHYTHM: Duration = Duration::from_secs(10);
+
+pub const MINIMUM_TOPUP_CYCLES: Cycles = TRILLION / 10;
+pub const MAX_TOPUP_SUBSCRIPTIONS: usize = 10_000;
+pub const MAX_TOPUP_SUBSCRIPTIONS_PER_USER: usize = 500;
+pub const MAX_TOPUP_SUBSCRIPTION_HISTORY_LENGTH: usize = 100;
+#[cfg(not(debug_assertions))]
+const CHECK_TOPUP_SUBSCRIPTIONS_CHUNK_SIZE: usize = 100; // number of concurrent canister_status calls.
+#[cfg(debug_assertions)]
+const CHECK_TOPUP_SUBSCRIPTIONS_CHUNK_SIZE: usize = 2;
+
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct TopupSubscriptionsData {
+    pub next_id: TopupSubscriptionId,
+    pub subscriptions: BTreeMap<TopupSubscriptionId, TopupSubscription>,
+    pub check_lock: bool, // held for one chunk at a time.
+    #[serde(default)]
+    pub check_chunk_start: Option<TopupSubscriptionId>, // Some while a check of all the subscriptions is in between the chunks.
+    #[serde(default)]
+    pub canister_status_via_canisters: BTreeSet<Principal>, // the canisters that the subscriptions can use for the canister_status calls. set by the sns-governance.
+}
+
+impl TopupSubscriptionsData {
+    pub fn user_subscriptions_count(&self, user: Principal) -> usize {
+        self.subscriptions.values().filter(|s| s.subscriber.owner == user).count()
+    }
+    pub fn add_subscription(&mut self, subscriber: IcrcId, q: AddTopupSubscriptionQuest) -> TopupSubscriptionId {
+        let id: TopupSubscriptionId = self.next_id;
+        self.next_id += 1;
+        self.subscriptions.insert(
+            id,
+            TopupSubscription{
+                id,
+                subscriber,
+                canister_id: q.canister_id,
+                threshold: q.threshold,
+                topup_cycles: q.topup_cycles,
+                canister_status_via: q.canister_status_via,
+                created_at_nanos: time_nanos_u64(),
+                last_check_nanos: None,
+                last_error: None,
+                topup_blocks: Vec::new(),
+            }
+        );
+        id
+    }
+}
+
+
+// the management canister and the blackhole canisters return more fields, we only need the cycles.
+#[derive(CandidType, Deserialize)]
+struct CanisterStatusCycles {
+    cycles: Cycles,
+}
+
+// the bank waits for the reply of the canister_status call, and a call that does not return blocks the stop_canister for the bank upgrades,
+// so the canister_status_via must be on the list that the sns-governance sets.
+pub async fn canister_cycles_balance(canister_id: Principal, canister_status_via: Option<Principal>) -> Result<Cycles, CallError> {
+    call::<(CanisterIdRecord,), (CanisterStatusCycles,)>(
+        canister_status_via.unwrap_or(Principal::management_canister()),
+        "canister_status",
+        (CanisterIdRecord{ canister_id },),
+    ).await
+    .map(|(s,)| s.cycles)
+    .map_err(call_error_as_u32_and_string)
+}
+
+
+// checks one chunk of the subscriptions and sets a timer for the next chunk. the check_lock is released after each chunk.
+pub async fn check_topup_subscriptions() {
+    let (subscription_ids, next_chunk_start): (Vec<TopupSubscriptionId>, Option<TopupSubscriptionId>) = match with_mut(&CB_DATA, |cb_data| {
+        if cb_data.topup_subscriptions.check_lock == true
+        || cb_data.topup_subscriptions.subscriptions.len() == 0 {
+            return None;
+        }
+        cb_data.topup_subscriptions.check_lock = true;
+        let chunk_start: TopupSubscriptionId = cb_data.topup_subscriptions.check_chunk_start.unwrap_or(0);
+        let mut ids: Vec<TopupSubscriptionId> = cb_data.topup_subscriptions.subscriptions.range(chunk_start..)
+            .take(CHECK_TOPUP_SUBSCRIPTIONS_CHUNK_SIZE + 1)
+            .map(|(id, _)| *id)
+            .collect();
+        let next_chunk_start: Option<TopupSubscriptionId> = if ids.len() > CHECK_TOPUP_SUBSCRIPTIONS_CHUNK_SIZE { ids.pop() } else { None };
+        Some((ids, next_chunk_start))
+    }) {
+        Some(ids_and_next_chunk_start) => ids_and_next_chunk_start,
+        None => return,
+    };
+
+    futures::future::join_all(subscription_ids.into_iter().map(check_topup_subscription)).await;
+
+    with_mut(&CB_DATA, |cb_data| {
+        cb_data.topup_subscriptions.check_lock = false;
+        cb_data.topup_subscriptions.check_chunk_start = next_chunk_start;
+    });
+    
+    if next_chunk_start.is_some() {
+        ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(check_topup_subscriptions()));
+    }
+}
+
+async fn check_topup_subscription(id: TopupSubscriptionId) {
+    // the subscription can get canceled in between the awaits.
+    let subscription: TopupSubscription = match with(&CB_DATA, |cb_data| {
+        cb_data.topup_subscriptions.subscriptions.get(&id).cloned()
+    }) {
+        Some(s) => s,
+        None => return,
+    };
+
+    // the sns-governance can take a canister off the list after the subscription is added.
+    if let Some(canister_status_via) = subscription.canister_status_via {
+        if with(&CB_DATA, |cb_data| { cb_data.topup_subscriptions.canister_status_via_canisters.contains(&canister_status_via) }) == false {
+            with_mut(&CB_DATA, |cb_data| {
+                if let Some(subscription) = cb_data.topup_subscriptions.subscriptions.get_mut(&id) {
+                    subscription.last_check_nanos = Some(time_nanos_u64());
+                    subscription.last_error = Some("the canister_status_via is not on the list".to_string());
+                }
+            });
+            return;
+        }
+    }
+
+    let r: Result<Option<BlockId>, String> = match canister_cycles_balance(subscription.canister_id, subscription.canister_status_via).await {
+        Err(call_error) => Err(format!("canister_status call error: {:?}", call_error)),
+        Ok(cycles) => {
+            if cycles >= subscription.threshold {
+                Ok(None)
+            } else {
+                cycles_out_(
+                    subscription.subscriber,
+                    CyclesOutQuest{
+                        cycles: subscription.topup_cycles,
+                        fee: Some(BANK_TRANSFER_FEE),
+                        from_subaccount: subscription.subscriber.subaccount,
+                        memo: Some(ByteBuf::from(id.to_be_bytes().to_vec())),
+                        for_canister: subscription.canister_id,
+                        created_at_time: None,
+                    },
+                    None,
+                ).await
+                .map(|block_id| Some(block_id))
+                .map_err(|cycles_out_error| format!("cycles_out error: {:?}", cycles_out_error))
+            }
+        }
+    };
+
+    with_mut(&CB_DATA, |cb_data| {
+        if let Some(subscription) = cb_data.topup_subscriptions.subscriptions.get_mut(&id) {
+            subscription.last_check_nanos = Some(time_nanos_u64());
+            match r {
+                Ok(opt_block_id) => {
+                    subscription.last_error = None;
+                    if let Some(block_id) = opt_block_id {
+                        subscription.topup_blocks.push(block_id);
+                        if subscription.topup_blocks.len() > MAX_TOPUP_SUBSCRIPTION_HISTORY_LENGTH {
+                            subscription.topup_blocks.remove(0);
+                        }
+                    }
+                }
+                Err(e) => {
+                    subscription.last_error = Some(e);
+                }
+            }
+        }
+    });
+}
+
@@ -0,0 +1,69 @@
+use crate::{CB_DATA, LOGS, USER_LOGS_INDEX};
+use cts_lib::{
+    tools::localkey::refcell::{with, with_mut},
+    types::bank::log_types::{Log, UserLogKey},
+};
+use ic_stable_structures::{StableBTreeMap, memory_manager::VirtualMemory, DefaultMemoryImpl};
+use candid::{CandidType, Deserialize};
+use std::{
+    cmp::{min, max},
+    time::Duration,
+};
+
+
+#[cfg(not(debug_assertions))]
+pub const BACKFILL_USER_LOGS_INDEX_CHUNK_SIZE: u64 = 5_000;
+#[cfg(debug_assertions)]
+pub const BACKFILL_USER_LOGS_INDEX_CHUNK_SIZE: u64 = 3;
+
+
+pub type UserLogsIndex = StableBTreeMap<UserLogKey, (), VirtualMemory<DefaultMemoryImpl>>;
+
+
+pub fn index_log(user_logs_index: &mut UserLogsIndex, block: u64, log: &Log) {
+    for account in log.tx.op.icrc_ids() {
+        user_logs_index.insert(UserLogKey{ account, block }, ());
+    }
+}
+
+
+// the index is rebuilt from the LOGS after the upgrade from the heap user-logs-pointers.
+// the new blocks get indexed as they come in, so the backfill can go over a block more than once.
+#[derive(CandidType, Deserialize, Default)]
+pub struct UserLogsIndexBackfill {
+    pub is_complete: bool,
+    pub next_block: u64,
+}
+
+pub fn start_user_logs_index_backfill_if_not_complete() {
+    if with(&CB_DATA, |cb_data| cb_data.user_logs_index_backfill.is_complete) == false {
+        ic_cdk_timers::set_timer(Duration::ZERO, backfill_user_logs_index_chunk);
+    }
+}
+
+// each chunk is in its own message-execution and saves its progress so the backfill resumes after an upgrade.
+fn backfill_user_logs_index_chunk() {
+    let is_complete: bool = with_mut(&CB_DATA, |cb_data| {
+        with(&LOGS, |logs| {
+            with_mut(&USER_LOGS_INDEX, |user_logs_index| {
+                let log_length: u64 = cb_data.archives.log_length(logs);
+                // the archived blocks are not on the bank.
+                let start: u64 = max(cb_data.user_logs_index_backfill.next_block, cb_data.archives.first_local_block_id);
+                let end: u64 = min(start.saturating_add(BACKFILL_USER_LOGS_INDEX_CHUNK_SIZE), log_length);
+                for block in start..end {
+                    index_log(user_logs_index, block, &cb_data.archives.get_local_log(logs, block).unwrap());
+                }
+                cb_data.user_logs_index_backfill.next_block = end;
+                if end >= log_length {
+                    cb_data.user_logs_index_backfill.is_complete = true;
+                }
+                cb_data.user_logs_index_backfill.is_complete
+            })
+        })
+    });
+    if is_complete == false {
+        ic_cdk_timers::set_timer(Duration::ZERO, backfill_user_logs_index_chunk);
+    } else {
+        ic_cdk::print("user-logs-index backfill is complete.");
+    }
+}
@@ -0,0 +1,164 @@
+// FOR THE CYCLES-BANK ARCHIVES.
+// -----------------------------
+
+use std::cell::RefCell;
+use cts_lib::{
+    tools::localkey::refcell::{with, with_mut},
+    types::bank::{
+        log_types::Log,
+        icrc3::*,
+        archive::{BankArchiveInit, AppendBlocksQuest, AppendBlocksError},
+    },
+};
+use ic_cdk::{
+    init,
+    pre_upgrade,
+    post_upgrade,
+    update,
+    query,
+    api::{
+        caller,
+        call::reply,
+    },
+    trap,
+};
+use candid::{
+    Principal,
+    CandidType,
+    Deserialize,
+};
+use ic_stable_structures::{StableVec, memory_manager::VirtualMemory, DefaultMemoryImpl};
+use canister_tools::{
+    self,
+    MemoryId,
+    get_virtual_memory,
+};
+
+
+#[derive(CandidType, Deserialize)]
+pub struct ArchiveData {
+    bank_id: Principal,
+    first_block_id: u64,
+    max_blocks: u64,
+    fee_collector_changes: FeeCollectorChanges,
+}
+
+impl ArchiveData {
+    fn new() -> Self {
+        Self {
+            bank_id: Principal::from_slice(&[]),
+            first_block_id: 0,
+            max_blocks: 0,
+            fee_collector_changes: FeeCollectorChanges::new(),
+        }
+    }
+}
+
+type Logs = StableVec<Log, VirtualMemory<DefaultMemoryImpl>>;
+
+
+pub const ARCHIVE_DATA_MEMORY_ID: MemoryId = MemoryId::new(0);
+pub const LOGS_MEMORY_ID: MemoryId = MemoryId::new(1);
+
+pub const ICRC3_GET_BLOCKS_CHUNK_SIZE: usize = 2_000;
+
+
+thread_local!{
+    pub static ARCHIVE_DATA: RefCell<ArchiveData> = RefCell::new(ArchiveData::new());
+    // stable-structures
+    pub static LOGS: RefCell<Logs> = RefCell::new(Logs::init(get_virtual_memory(LOGS_MEMORY_ID)).unwrap());
+}
+
+
+#[init]
+fn init(q: BankArchiveInit) {
+    canister_tools::init(&ARCHIVE_DATA, ARCHIVE_DATA_MEMORY_ID);
+    with_mut(&ARCHIVE_DATA, |archive_data| {
+        archive_data.bank_id = q.bank_id;
+        archive_data.first_block_id = q.first_block_id;
+        archive_data.max_blocks = q.max_blocks;
+    });
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    canister_tools::pre_upgrade();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    canister_tools::post_upgrade(&ARCHIVE_DATA, ARCHIVE_DATA_MEMORY_ID, None::<fn(ArchiveData) -> ArchiveData>);
+}
+
+
+
+// the bank can call this more than once with the same blocks if a call-context fails after the blocks got here, so skip the blocks we already have.
+#[update]
+pub fn append_blocks(q: AppendBlocksQuest) -> Result<(), AppendBlocksError> {
+    with_mut(&ARCHIVE_DATA, |archive_data| {
+        if caller() != archive_data.bank_id {
+            trap("Caller must be the bank.");
+        }
+        merge_fee_collector_changes(&mut archive_data.fee_collector_changes, q.fee_collector_changes);
+        with_mut(&LOGS, |logs| {
+            let next_block_id: u64 = archive_data.first_block_id + logs.len();
+            if q.first_block_id > next_block_id {
+                return Err(AppendBlocksError::BlocksGap{ next_block_id });
+            }
+            let skip: usize = (next_block_id - q.first_block_id) as usize;
+            if logs.len() + (q.blocks.len().saturating_sub(skip) as u64) > archive_data.max_blocks {
+                return Err(AppendBlocksError::ArchiveIsFull);
+            }
+            for log in q.blocks.iter().skip(skip) {
+                logs.push(log).unwrap();
+            }
+            Ok(())
+        })
+    })
+}
+
+
+#[query]
+pub fn remaining_capacity() -> u64 {
+    with(&ARCHIVE_DATA, |archive_data| {
+        with(&LOGS, |logs| {
+            archive_data.max_blocks.saturating_sub(logs.len())
+        })
+    })
+}
+
+
+// we do manual-reply bc the Icrc3Value type borrows its values so we can't return a borrowed value.
+// be careful because the compiler does not check to make sure that we call the ic_cdk::reply function and that we call it (only) once.
+#[query(manual_reply = true)]
+pub fn icrc3_get_blocks(q: GetBlocksArgs) -> GetBlocksResult<'static> { // return type is just for candid did file generation. we use ic_cdk::reply here.
+    use std::cmp::{min, max};
+    with(&ARCHIVE_DATA, |archive_data| {
+        with(&LOGS, |logs| {
+            let end_of_this_archive: u128 = archive_data.first_block_id as u128 + logs.len() as u128;
+            let mut chunk_logs: Vec<(u128, Log)> = vec![];
+            for range in q.iter() {
+                let start: u128 = max(range.start, archive_data.first_block_id as u128);
+                let end: u128 = min(range.start.saturating_add(range.length), end_of_this_archive);
+                let mut i: u128 = start;
+                while i < end && chunk_logs.len() < ICRC3_GET_BLOCKS_CHUNK_SIZE {
+                    chunk_logs.push((i, logs.get((i - archive_data.first_block_id as u128) as u64).unwrap()));
+                    i += 1;
+                }
+            }
+            let result = GetBlocksResult {
+                log_length: end_of_this_archive,
+                blocks: chunk_logs.iter().map(|(i, log)| IdAndBlock{ id: *i, block: icrc3_value_of_a_block_log(log, fee_collector_of_the_block(&archive_data.fee_collector_changes, *i as u64)) }).collect(),
+                archived_blocks: vec![],
+            };
+            reply((result,));
+        });
+    });
+
+    GetBlocksResult::placeholder()
+}
+
+
+
+
+ic_cdk::export_candid!();
@@ -0,0 +1,357 @@
+// FOR THE CYCLES-BANK INDEX.
+// --------------------------
+// syncs the blocks of the bank through the icrc3_get_blocks method and serves the icrc index-ng methods.
+
+use std::{
+    cell::RefCell,
+    cmp::min,
+    time::Duration,
+};
+use cts_lib::{
+    tools::localkey::refcell::{with, with_mut},
+    icrc::{IcrcId, IcrcSubaccount, StorableIcrcId},
+    types::{
+        Cycles,
+        bank::{
+            log_types::{Log, Operation, UserLogKey},
+            icrc3::{StartAndLength, icrc3_value_of_a_block_log, block_log_of_an_icrc3_value},
+            index::*,
+        },
+    },
+    consts::{MiB, KiB},
+};
+use ic_cdk::{
+    init,
+    pre_upgrade,
+    post_upgrade,
+    query,
+    api::call::call,
+};
+use candid::{
+    Principal,
+    CandidType,
+    Deserialize,
+};
+use ic_stable_structures::{StableVec, StableBTreeMap, memory_manager::VirtualMemory, DefaultMemoryImpl};
+use canister_tools::{
+    self,
+    MemoryId,
+    get_virtual_memory,
+};
+
+
+#[derive(CandidType, Deserialize)]
+pub struct IndexData {
+    bank_id: Principal,
+    latest_block_hash: Option<[u8; 32]>, // to check the phash of the next block.
+    sync_lock: bool,
+}
+
+impl IndexData {
+    fn new() -> Self {
+        Self {
+            bank_id: Principal::from_slice(&[]),
+            latest_block_hash: None,
+            sync_lock: false,
+        }
+    }
+}
+
+type Logs = StableVec<Log, VirtualMemory<DefaultMemoryImpl>>;
+type AccountsLogs = StableBTreeMap<UserLogKey, (), VirtualMemory<DefaultMemoryImpl>>;
+type Balances = StableBTreeMap<StorableIcrcId, Cycles, VirtualMemory<DefaultMemoryImpl>>;
+
+
+pub const INDEX_DATA_MEMORY_ID: MemoryId = MemoryId::new(0);
+pub const LOGS_MEMORY_ID: MemoryId = MemoryId::new(1);
+pub const ACCOUNTS_LOGS_MEMORY_ID: MemoryId = MemoryId::new(2);
+pub const BALANCES_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+#[cfg(not(debug_assertions))]
+pub const SYNC_BLOCKS_RHYTHM: Duration = Duration::from_secs(10);
+#[cfg(debug_assertions)]
+pub const SYNC_BLOCKS_RHYTHM: Duration = Duration::from_secs(5);
+
+#[cfg(not(debug_assertions))]
+pub const SYNC_BLOCKS_CHUNK_SIZE: u128 = 2_000;
+#[cfg(debug_assertions)]
+pub const SYNC_BLOCKS_CHUNK_SIZE: u128 = 4;
+
+pub const GET_ACCOUNT_TRANSACTIONS_MAX_RESULTS: usize = (1*MiB + 512*KiB) / 400;
+pub const LIST_SUBACCOUNTS_MAX_RESULTS: usize = 1_000;
+
+
+thread_local!{
+    pub static INDEX_DATA: RefCell<IndexData> = RefCell::new(IndexData::new());
+    // stable-structures
+    pub static LOGS: RefCell<Logs> = RefCell::new(Logs::init(get_virtual_memory(LOGS_MEMORY_ID)).unwrap());
+    pub static ACCOUNTS_LOGS: RefCell<AccountsLogs> = RefCell::new(AccountsLogs::init(get_virtual_memory(ACCOUNTS_LOGS_MEMORY_ID)));
+    pub static BALANCES: RefCell<Balances> = RefCell::new(Balances::init(get_virtual_memory(BALANCES_MEMORY_ID)));
+}
+
+
+#[init]
+fn init(q: BankIndexInit) {
+    canister_tools::init(&INDEX_DATA, INDEX_DATA_MEMORY_ID);
+    with_mut(&INDEX_DATA, |index_data| {
+        index_data.bank_id = q.bank_id;
+    });
+    ic_cdk_timers::set_timer_interval(SYNC_BLOCKS_RHYTHM, || ic_cdk::spawn(sync_blocks()));
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    canister_tools::pre_upgrade();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    canister_tools::post_upgrade(&INDEX_DATA, INDEX_DATA_MEMORY_ID, None::<fn(IndexData) -> IndexData>);
+    // there are no outstanding calls during an upgrade.
+    with_mut(&INDEX_DATA, |index_data| {
+        index_data.sync_lock = false;
+    });
+    ic_cdk_timers::set_timer_interval(SYNC_BLOCKS_RHYTHM, || ic_cdk::spawn(sync_blocks()));
+}
+
+
+
+async fn sync_blocks() {
+    let bank_id: Principal = match with_mut(&INDEX_DATA, |index_data| {
+        if index_data.sync_lock == true {
+            return None;
+        }
+        index_data.sync_lock = true;
+        Some(index_data.bank_id)
+    }) {
+        Some(bank_id) => bank_id,
+        None => return,
+    };
+
+    loop {
+        let next_block: u64 = with(&LOGS, |logs| logs.len());
+
+        let sponse: Icrc3GetBlocksSponse = match icrc3_get_blocks(bank_id, "icrc3_get_blocks", vec![StartAndLength{ start: next_block as u128, length: SYNC_BLOCKS_CHUNK_SIZE }]).await {
+            Ok(s) => s,
+            Err(e) => {
+                ic_cdk::print(&format!("bank icrc3_get_blocks call error: {:?}", e));
+                break;
+            }
+        };
+
+        let mut blocks: Vec<Icrc3BlockWithId> = Vec::new();
+        let mut archive_call_error: bool = false;
+        for archived_blocks in sponse.archived_blocks {
+            match icrc3_get_blocks(archived_blocks.callback.canister_id, &archived_blocks.callback.method, archived_blocks.args).await {
+                Ok(archive_sponse) => blocks.extend(archive_sponse.blocks),
+                Err(e) => {
+                    ic_cdk::print(&format!("archive icrc3_get_blocks call error: {:?}", e));
+                    archive_call_error = true;
+                    break;
+                }
+            }
+        }
+        if archive_call_error {
+            break;
+        }
+        blocks.extend(sponse.blocks);
+        blocks.sort_by_key(|b| b.id);
+
+        match append_blocks(blocks) {
+            Ok(0) => break,
+            Ok(_) => {
+                if with(&LOGS, |logs| logs.len() as u128) >= sponse.log_length {
+                    break;
+                }
+            }
+            Err(e) => {
+                ic_cdk::print(&format!("bank-index append-blocks error: {}", e));
+                break;
+            }
+        }
+    }
+
+    with_mut(&INDEX_DATA, |index_data| {
+        index_data.sync_lock = false;
+    });
+}
+
+async fn icrc3_get_blocks(canister_id: Principal, method: &str, q: Vec<StartAndLength>) -> Result<Icrc3GetBlocksSponse, (u32, String)> {
+    call::<(Vec<StartAndLength>,), (Icrc3GetBlocksSponse,)>(canister_id, method, (q,))
+        .await
+        .map(|(s,)| s)
+        .map_err(|e| (e.0 as u32, e.1))
+}
+
+// returns the number of blocks appended. stops at the first gap.
+fn append_blocks(blocks: Vec<Icrc3BlockWithId>) -> Result<u64, String> {
+    with_mut(&INDEX_DATA, |index_data| {
+        with_mut(&LOGS, |logs| {
+            with_mut(&ACCOUNTS_LOGS, |accounts_logs| {
+                with_mut(&BALANCES, |balances| {
+                    let mut count: u64 = 0;
+                    for block in blocks.iter() {
+                        let next_block: u64 = logs.len();
+                        if block.id < next_block as u128 {
+                            continue;
+                        }
+                        if block.id > next_block as u128 {
+                            break;
+                        }
+                        let (log, fee_col): (Log, Option<IcrcId>) = block_log_of_an_icrc3_value(&block.block)
+                            .map_err(|e| format!("block {}: {}", block.id, e))?;
+                        let block_hash: [u8; 32] = block.block.clone().hash();
+                        if icrc3_value_of_a_block_log(&log, fee_col.as_ref()).hash() != block_hash {
+                            return Err(format!("block {}: the decoded block does not match the block hash.", block.id));
+                        }
+                        if log.phash.as_ref().map(|phash| **phash) != index_data.latest_block_hash {
+                            return Err(format!("block {}: the phash does not match the hash of the previous block.", block.id));
+                        }
+                        for account in log.tx.op.icrc_ids() {
+                            accounts_logs.insert(UserLogKey{ account, block: next_block }, ());
+                        }
+                        apply_block_to_the_balances(balances, &log, fee_col);
+                        logs.push(&log).unwrap();
+                        index_data.latest_block_hash = Some(block_hash);
+                        count += 1;
+                    }
+                    Ok(count)
+                })
+            })
+        })
+    })
+}
+
+fn apply_block_to_the_balances(balances: &mut Balances, log: &Log, fee_col: Option<IcrcId>) {
+    let add = |balances: &mut Balances, icrc_id: IcrcId, cycles: Cycles| {
+        let balance: Cycles = balances.get(&StorableIcrcId(icrc_id)).unwrap_or(0);
+        balances.insert(StorableIcrcId(icrc_id), balance.saturating_add(cycles));
+    };
+    let subtract = |balances: &mut Balances, icrc_id: IcrcId, cycles: Cycles| {
+        let balance: Cycles = balances.get(&StorableIcrcId(icrc_id)).unwrap_or(0);
+        balances.insert(StorableIcrcId(icrc_id), balance.saturating_sub(cycles));
+    };
+    // the bank puts the fee in the tx.fee field if the user sets it, and in the block fee field if not.
+    let fee: Cycles = log.tx.fee.or(log.fee).unwrap_or(0);
+    // every account of the block gets an entry for the list_subaccounts method, even with a zero balance.
+    for icrc_id in log.tx.op.icrc_ids() {
+        add(balances, icrc_id, 0);
+    }
+    match log.tx.op {
+        Operation::Mint{ to, .. } => add(balances, to, log.tx.amt),
+        Operation::Burn{ from, .. } => subtract(balances, from, log.tx.amt), // the burn amount includes the fee.
+        Operation::Xfer{ from, to } | Operation::XferFrom{ from, to, .. } => {
+            subtract(balances, from, log.tx.amt.saturating_add(fee));
+            add(balances, to, log.tx.amt);
+        }
+        Operation::Approve{ from, .. } => subtract(balances, from, fee),
+    }
+    if let Some(fee_col) = fee_col {
+        add(balances, fee_col, fee);
+    }
+}
+
+fn transaction_of_a_block_log(log: Log) -> Transaction {
+    let fee: Option<Cycles> = log.tx.fee.or(log.fee);
+    let mut transaction = Transaction{
+        kind: String::new(),
+        mint: None,
+        burn: None,
+        transfer: None,
+        approve: None,
+        timestamp: log.ts,
+    };
+    match log.tx.op {
+        Operation::Mint{ to, .. } => {
+            transaction.kind = "mint".to_string();
+            transaction.mint = Some(Mint{ to, amount: log.tx.amt, memo: log.tx.memo, created_at_time: log.tx.ts });
+        }
+        Operation::Burn{ from, .. } => {
+            transaction.kind = "burn".to_string();
+            transaction.burn = Some(Burn{ from, spender: None, amount: log.tx.amt, memo: log.tx.memo, created_at_time: log.tx.ts });
+        }
+        Operation::Xfer{ from, to } => {
+            transaction.kind = "transfer".to_string();
+            transaction.transfer = Some(Transfer{ from, to, spender: None, amount: log.tx.amt, fee, memo: log.tx.memo, created_at_time: log.tx.ts });
+        }
+        Operation::XferFrom{ from, to, spender } => {
+            transaction.kind = "transfer".to_string();
+            transaction.transfer = Some(Transfer{ from, to, spender: Some(spender), amount: log.tx.amt, fee, memo: log.tx.memo, created_at_time: log.tx.ts });
+        }
+        Operation::Approve{ from, spender, expected_allowance, expires_at } => {
+            transaction.kind = "approve".to_string();
+            transaction.approve = Some(Approve{ from, spender, amount: log.tx.amt, expected_allowance, expires_at, fee, memo: log.tx.memo, created_at_time: log.tx.ts });
+        }
+    }
+    transaction
+}
+
+
+
+#[query]
+pub fn get_account_transactions(q: GetAccountTransactionsArgs) -> GetTransactionsResult {
+    let max_results: usize = min(q.max_results, GET_ACCOUNT_TRANSACTIONS_MAX_RESULTS as u128) as usize;
+    let end_block: u64 = q.start.map(|start| min(start, u64::MAX as u128) as u64).unwrap_or(u64::MAX);
+    with(&LOGS, |logs| {
+        with(&ACCOUNTS_LOGS, |accounts_logs| {
+            let transactions: Vec<TransactionWithId> = accounts_logs
+                .range(UserLogKey{ account: q.account, block: 0 }..UserLogKey{ account: q.account, block: end_block })
+                .rev()
+                .take(max_results)
+                .map(|(user_log_key, ())| TransactionWithId{
+                    id: user_log_key.block as u128,
+                    transaction: transaction_of_a_block_log(logs.get(user_log_key.block).unwrap()),
+                })
+                .collect();
+            let oldest_tx_id: Option<u128> = accounts_logs
+                .range(UserLogKey{ account: q.account, block: 0 }..)
+                .next()
+                .filter(|(user_log_key, ())| user_log_key.account == q.account)
+                .map(|(user_log_key, ())| user_log_key.block as u128);
+            Ok(GetTransactions{
+                balance: icrc1_balance_of(q.account),
+                transactions,
+                oldest_tx_id,
+            })
+        })
+    })
+}
+
+#[query]
+pub fn list_subaccounts(q: ListSubaccountsArgs) -> Vec<IcrcSubaccount> {
+    let start_icrc_id = IcrcId{ owner: q.owner, subaccount: q.start };
+    with(&BALANCES, |balances| {
+        balances
+            .range(StorableIcrcId(start_icrc_id)..)
+            .map(|(storable_icrc_id, _)| storable_icrc_id.0)
+            .skip_while(|icrc_id| q.start.is_some() && *icrc_id == start_icrc_id)
+            .take_while(|icrc_id| icrc_id.owner == q.owner)
+            .take(LIST_SUBACCOUNTS_MAX_RESULTS)
+            .map(|icrc_id| *icrc_id.effective_subaccount())
+            .collect()
+    })
+}
+
+#[query]
+pub fn icrc1_balance_of(icrc_id: IcrcId) -> Cycles {
+    with(&BALANCES, |balances| {
+        balances.get(&StorableIcrcId(icrc_id)).unwrap_or(0)
+    })
+}
+
+#[query]
+pub fn status() -> Status {
+    Status{
+        num_blocks_synced: with(&LOGS, |logs| logs.len() as u128),
+    }
+}
+
+#[query]
+pub fn ledger_id() -> Principal {
+    with(&INDEX_DATA, |index_data| index_data.bank_id)
+}
+
+
+
+
+ic_cdk::export_candid!();
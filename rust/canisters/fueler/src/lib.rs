@@ -286,6 +286,7 @@ async fn fuel() {
                 fee: Some(BANK_TRANSFER_FEE),                   // set the fee here because we need to count for the fueler_bank_balance
                 from_subaccount: None,
                 memo: None,
+                created_at_time: None,
                 for_canister: for_canister,
             },)
         ).await {
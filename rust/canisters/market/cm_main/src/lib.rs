@@ -144,7 +144,9 @@ pub struct ControllerCreateIcrc1TokenTradeContractMidCallData {
     controller_create_icrc1token_trade_contract_quest: ControllerCreateIcrc1TokenTradeContractQuest,
     // options are for the steps
     icrc1token_trade_contract_canister_id: Option<Principal>,
-    icrc1token_trade_contract_data: Option<TradeContractData>
+    icrc1token_trade_contract_data: Option<TradeContractData>,
+    #[serde(default)]
+    bank_add_transfer_notification_receiver: bool,
 }
 
 
@@ -182,6 +184,7 @@ pub async fn controller_create_trade_contract(q: ControllerCreateIcrc1TokenTrade
                     controller_create_icrc1token_trade_contract_quest: q,
                     icrc1token_trade_contract_canister_id: None,
                     icrc1token_trade_contract_data: None,       
+                    bank_add_transfer_notification_receiver: false,
                 };
                 data.controller_create_icrc1token_trade_contract_mid_call_data = Some(mid_call_data.clone());
                 Ok(mid_call_data)
@@ -290,6 +293,23 @@ async fn controller_create_icrc1token_trade_contract_(mut mid_call_data: Control
         }
     }
     
+    // the bank calls the on_cycles_received method of the tc for the icrc1_transfer_and_notify transfers.
+    if mid_call_data.bank_add_transfer_notification_receiver == false {
+        match call::<(Principal,), ()>(
+            with(&CM_MAIN_DATA, |data| { data.cycles_bank_id }),
+            "cm_main_add_transfer_notification_receiver",
+            (mid_call_data.icrc1token_trade_contract_canister_id.as_ref().unwrap().clone(),),
+        ).await {
+            Ok(()) => {
+                mid_call_data.bank_add_transfer_notification_receiver = true;
+            }
+            Err(call_error) => {
+                with_mut(&CM_MAIN_DATA, |cm_main_data| { unlock_and_write_controller_create_icrc1token_trade_contract_mid_call_data(mid_call_data, cm_main_data); });
+                return Err(ControllerCreateIcrc1TokenTradeContractError::MidCallError(ControllerCreateIcrc1TokenTradeContractMidCallError::BankAddTransferNotificationReceiverCallError(call_error_as_u32_and_string(call_error))));
+            }
+        }
+    }
+    
     with_mut(&CM_MAIN_DATA, |data| {
         data.controller_create_icrc1token_trade_contract_mid_call_data = None;
         data.trade_contracts.push(
@@ -0,0 +1,365 @@
+use crate::{
+    CM_DATA,
+    CONDITIONAL_ORDERS_SUBACCOUNT,
+    MAX_MID_CALL_USER_BALANCE_LOCKS,
+    TradeCallKind,
+    _trade,
+    check_trade_quest,
+    new_id,
+    ledger_transfer::LedgerTransferReturnType,
+    traits::TradeQuest,
+};
+use cts_lib::{
+    tools::{
+        localkey::refcell::{with, with_mut},
+        principal_token_subaccount,
+        time_nanos,
+    },
+    types::cm::tc::{
+        CyclesPerToken,
+        ConditionalOrderId,
+        ConditionalOrder,
+        ConditionalOrderTrigger,
+        ConditionalTradeQuest,
+        PlaceConditionalOrderError,
+        PlaceConditionalOrderResult,
+        CancelConditionalOrderQuest,
+        CancelConditionalOrderError,
+        CancelConditionalOrderResult,
+        ListConditionalOrdersSponse,
+        LatestTradeRateData,
+        TradeCyclesQuest,
+        TradeTokensQuest,
+        TradeError,
+        TradeResult,
+    },
+    icrc::{
+        IcrcId,
+        Icrc1TransferQuest,
+        Icrc1TransferError,
+    },
+};
+use candid::Principal;
+use serde_bytes::ByteArray;
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    time::Duration,
+};
+
+
+#[cfg(not(debug_assertions))]
+pub const MAX_CONDITIONAL_ORDERS: usize = 1_000;
+#[cfg(debug_assertions)]
+pub const MAX_CONDITIONAL_ORDERS: usize = 10;
+
+#[cfg(not(debug_assertions))]
+pub const MAX_CONDITIONAL_ORDERS_PER_USER: usize = 50;
+#[cfg(debug_assertions)]
+pub const MAX_CONDITIONAL_ORDERS_PER_USER: usize = 3;
+
+#[cfg(not(debug_assertions))]
+const LIST_CONDITIONAL_ORDERS_CHUNK_SIZE: usize = 100;
+#[cfg(debug_assertions)]
+const LIST_CONDITIONAL_ORDERS_CHUNK_SIZE: usize = 2;
+
+const DO_CONDITIONAL_ORDERS_CHUNK_SIZE: usize = 10;
+const DO_CONDITIONAL_ORDERS_RETRY_RHYTHM: Duration = Duration::from_secs(10);
+
+
+// the escrow takes the quantity + the ledger-fee for the transfer out of the escrow,
+// so the transfer into the position or the refund at the cancel does not take from the quantity.
+// if the ledger-fee changes in the meantime, the difference comes out of the quantity, see escrow_transfer_out_amount.
+pub async fn place_conditional_order_<TradeQuestType: TradeQuest>(caller: Principal, trigger: ConditionalOrderTrigger, q: TradeQuestType) -> PlaceConditionalOrderResult {
+
+    check_trade_quest(&q).map_err(PlaceConditionalOrderError::TradeError)?;
+
+    match trigger {
+        ConditionalOrderTrigger::StopLimit{ stop_cycles_per_token_rate } => {
+            if stop_cycles_per_token_rate == 0 {
+                return Err(PlaceConditionalOrderError::StopRateCannotBeZero);
+            }
+        }
+        ConditionalOrderTrigger::TrailingStop{ trail_basis_points } => {
+            if trail_basis_points == 0 || trail_basis_points >= 10_000 {
+                return Err(PlaceConditionalOrderError::TrailBasisPointsMustBeBetween1And9999);
+            }
+        }
+    }
+
+    with_mut(&CM_DATA, |cm_data| {
+        if cm_data.conditional_orders.len().saturating_add(TradeQuestType::mid_call_balance_locks(cm_data).len()) >= MAX_CONDITIONAL_ORDERS {
+            return Err(PlaceConditionalOrderError::MaxConditionalOrders{ max_conditional_orders: MAX_CONDITIONAL_ORDERS as u64 });
+        }
+        if cm_data.conditional_orders.values().filter(|conditional_order| conditional_order.positor == caller).count() >= MAX_CONDITIONAL_ORDERS_PER_USER {
+            return Err(PlaceConditionalOrderError::MaxConditionalOrdersPerUser{ max_conditional_orders_per_user: MAX_CONDITIONAL_ORDERS_PER_USER as u64 });
+        }
+        if TradeQuestType::mid_call_balance_locks(cm_data).contains(&caller) {
+            return Err(PlaceConditionalOrderError::TradeError(TradeError::CallerIsInTheMiddleOfADifferentCallThatLocksTheBalance));
+        }
+        if TradeQuestType::mid_call_balance_locks(cm_data).len() >= MAX_MID_CALL_USER_BALANCE_LOCKS {
+            return Err(PlaceConditionalOrderError::TradeError(TradeError::CyclesMarketIsBusy));
+        }
+        TradeQuestType::mid_call_balance_locks(cm_data).insert(caller);
+        Ok(())
+    })?;
+
+    let escrow_ledger_transfer_fee: u128 = TradeQuestType::ledger_transfer_fee();
+
+    let transfer_result: LedgerTransferReturnType = TradeQuestType::posit_transfer(
+        Icrc1TransferQuest{
+            memo: None,
+            amount: q.quantity().saturating_add(escrow_ledger_transfer_fee),
+            fee: q.posit_transfer_ledger_fee(),
+            from_subaccount: Some(ByteArray::new(principal_token_subaccount(&caller))),
+            to: IcrcId{owner: ic_cdk::id(), subaccount: Some(ByteArray::new(*CONDITIONAL_ORDERS_SUBACCOUNT))},
+            created_at_time: None,
+        }
+    ).await;
+
+    with_mut(&CM_DATA, |cm_data| {
+        TradeQuestType::mid_call_balance_locks(cm_data).remove(&caller);
+        match transfer_result {
+            Ok(Ok(_block_id)) => {
+                let conditional_order_id: ConditionalOrderId = new_id(&mut cm_data.conditional_orders_id_counter);
+                // a trailing-stop follows the trade rates from the latest trade rate at the placement.
+                let trailing_extreme_rate: Option<CyclesPerToken> = match trigger {
+                    ConditionalOrderTrigger::TrailingStop{ .. } => Some(cm_data.latest_trade_rate_data.rate).filter(|rate| *rate != 0),
+                    ConditionalOrderTrigger::StopLimit{ .. } => None,
+                };
+                cm_data.conditional_orders.insert(
+                    conditional_order_id,
+                    ConditionalOrder{
+                        id: conditional_order_id,
+                        positor: caller,
+                        trigger,
+                        trade_quest: q.into_conditional_trade_quest(),
+                        escrow_ledger_transfer_fee,
+                        trailing_extreme_rate,
+                        triggered_at_nanos: None,
+                        trigger_trade_error: None,
+                        lock: false,
+                        timestamp_nanos: time_nanos(),
+                    }
+                );
+                Ok(conditional_order_id)
+            }
+            Ok(Err(transfer_error)) => Err(PlaceConditionalOrderError::TradeError(TradeError::CreatePositionLedgerTransferError(transfer_error))),
+            Err(call_error) => Err(PlaceConditionalOrderError::TradeError(TradeError::CreatePositionLedgerTransferCallError(call_error))),
+        }
+    })
+}
+
+
+pub async fn cancel_conditional_order_(caller: Principal, q: CancelConditionalOrderQuest) -> CancelConditionalOrderResult {
+
+    let conditional_order: ConditionalOrder = with_mut(&CM_DATA, |cm_data| {
+        let conditional_order: &mut ConditionalOrder = match cm_data.conditional_orders.get_mut(&q.conditional_order_id) {
+            Some(conditional_order) => conditional_order,
+            None => return Err(CancelConditionalOrderError::ConditionalOrderNotFound),
+        };
+        if conditional_order.positor != caller {
+            return Err(CancelConditionalOrderError::WrongCaller);
+        }
+        if conditional_order.lock == true {
+            return Err(CancelConditionalOrderError::ConditionalOrderIsInTheMiddleOfACall);
+        }
+        conditional_order.lock = true;
+        Ok(conditional_order.clone())
+    })?;
+
+    let transfer_result: LedgerTransferReturnType = match conditional_order.trade_quest {
+        ConditionalTradeQuest::TradeCycles(ref trade_quest) => escrow_refund_transfer(&conditional_order, trade_quest).await,
+        ConditionalTradeQuest::TradeTokens(ref trade_quest) => escrow_refund_transfer(&conditional_order, trade_quest).await,
+    };
+
+    with_mut(&CM_DATA, |cm_data| {
+        match transfer_result {
+            Ok(Ok(block_id)) => {
+                cm_data.conditional_orders.remove(&conditional_order.id);
+                Ok(block_id)
+            }
+            Ok(Err(transfer_error)) => {
+                unlock_conditional_order(&mut cm_data.conditional_orders, conditional_order.id);
+                Err(CancelConditionalOrderError::EscrowTransferError(transfer_error))
+            }
+            Err(call_error) => {
+                unlock_conditional_order(&mut cm_data.conditional_orders, conditional_order.id);
+                Err(CancelConditionalOrderError::EscrowTransferCallError(call_error))
+            }
+        }
+    })
+}
+
+// the refund goes back into the positor's trade-subaccount.
+async fn escrow_refund_transfer<TradeQuestType: TradeQuest>(conditional_order: &ConditionalOrder, trade_quest: &TradeQuestType) -> LedgerTransferReturnType {
+    let ledger_transfer_fee: u128 = TradeQuestType::ledger_transfer_fee();
+    TradeQuestType::posit_transfer(
+        Icrc1TransferQuest{
+            memo: None,
+            amount: escrow_transfer_out_amount(trade_quest.quantity(), conditional_order.escrow_ledger_transfer_fee, ledger_transfer_fee),
+            fee: Some(ledger_transfer_fee),
+            from_subaccount: Some(ByteArray::new(*CONDITIONAL_ORDERS_SUBACCOUNT)),
+            to: IcrcId{owner: ic_cdk::id(), subaccount: Some(ByteArray::new(principal_token_subaccount(&conditional_order.positor)))},
+            created_at_time: None,
+        }
+    ).await
+}
+
+// the escrow holds the quantity + the escrow_ledger_transfer_fee. a transfer out of the escrow pays the current ledger-fee,
+// so when the ledger-fee is not the same as at the placement, the difference goes to or comes from the quantity.
+// a BadFee error on the transfer sets the current ledger-fee for the next try.
+fn escrow_transfer_out_amount(quantity: u128, escrow_ledger_transfer_fee: u128, ledger_transfer_fee: u128) -> u128 {
+    quantity
+        .saturating_add(escrow_ledger_transfer_fee)
+        .saturating_sub(ledger_transfer_fee)
+}
+
+fn unlock_conditional_order(conditional_orders: &mut BTreeMap<ConditionalOrderId, ConditionalOrder>, conditional_order_id: ConditionalOrderId) {
+    if let Some(conditional_order) = conditional_orders.get_mut(&conditional_order_id) {
+        conditional_order.lock = false;
+    }
+}
+
+
+pub fn list_conditional_orders_(caller: Principal, opt_start_after: Option<ConditionalOrderId>) -> ListConditionalOrdersSponse {
+    with(&CM_DATA, |cm_data| {
+        let mut conditional_orders: Vec<ConditionalOrder> = cm_data.conditional_orders
+            .range((opt_start_after.map_or(Bound::Unbounded, Bound::Excluded), Bound::Unbounded))
+            .map(|(_, conditional_order)| conditional_order)
+            .filter(|conditional_order| conditional_order.positor == caller)
+            .take(LIST_CONDITIONAL_ORDERS_CHUNK_SIZE + 1)
+            .cloned()
+            .collect();
+        let is_last_chunk: bool = conditional_orders.len() <= LIST_CONDITIONAL_ORDERS_CHUNK_SIZE;
+        conditional_orders.truncate(LIST_CONDITIONAL_ORDERS_CHUNK_SIZE);
+        ListConditionalOrdersSponse{
+            conditional_orders,
+            is_last_chunk,
+        }
+    })
+}
+
+
+// runs once at the end of each match_trades call that makes trades.
+// the trades of one match_trades call walk the book one way, so the first and the latest trade rates are the min and the max rates of the call,
+// and the triggers see the same with the first and then the latest rate as with each trade rate.
+pub fn check_conditional_orders_triggers(conditional_orders: &mut BTreeMap<ConditionalOrderId, ConditionalOrder>, first_trade_rate: CyclesPerToken, latest_trade_rate_data: &LatestTradeRateData) {
+    let mut triggered_some: bool = false;
+    for conditional_order in conditional_orders.values_mut() {
+        if conditional_order.triggered_at_nanos.is_some() {
+            continue;
+        }
+        if conditional_order_triggers(conditional_order, first_trade_rate)
+        || conditional_order_triggers(conditional_order, latest_trade_rate_data.rate) {
+            conditional_order.triggered_at_nanos = Some(latest_trade_rate_data.timestamp_nanos as u128);
+            triggered_some = true;
+        }
+    }
+    if triggered_some {
+        ic_cdk_timers::set_timer(Duration::from_millis(1), || ic_cdk::spawn(do_conditional_orders()));
+    }
+}
+
+// a tokens-order is a stop on the way down, a cycles-order is a stop on the way up.
+fn conditional_order_triggers(conditional_order: &mut ConditionalOrder, trade_rate: CyclesPerToken) -> bool {
+    let is_tokens_order: bool = matches!(conditional_order.trade_quest, ConditionalTradeQuest::TradeTokens(_));
+    match conditional_order.trigger {
+        ConditionalOrderTrigger::StopLimit{ stop_cycles_per_token_rate } => {
+            if is_tokens_order {
+                trade_rate <= stop_cycles_per_token_rate
+            } else {
+                trade_rate >= stop_cycles_per_token_rate
+            }
+        }
+        ConditionalOrderTrigger::TrailingStop{ trail_basis_points } => {
+            let trailing_extreme_rate: &mut CyclesPerToken = conditional_order.trailing_extreme_rate.get_or_insert(trade_rate);
+            if is_tokens_order {
+                *trailing_extreme_rate = std::cmp::max(*trailing_extreme_rate, trade_rate);
+                trade_rate <= trailing_extreme_rate.saturating_mul(10_000 - trail_basis_points as u128) / 10_000
+            } else {
+                *trailing_extreme_rate = std::cmp::min(*trailing_extreme_rate, trade_rate);
+                trade_rate >= trailing_extreme_rate.saturating_mul(10_000 + trail_basis_points as u128) / 10_000
+            }
+        }
+    }
+}
+
+
+// the triggered orders become positions through the normal trade flow with the posit-transfer from the escrow.
+pub async fn do_conditional_orders() {
+
+    let conditional_orders: Vec<ConditionalOrder> = with_mut(&CM_DATA, |cm_data| {
+        cm_data.conditional_orders.values_mut()
+            .filter(|conditional_order| conditional_order_is_ready_to_trade(conditional_order))
+            .take(DO_CONDITIONAL_ORDERS_CHUNK_SIZE)
+            .map(|conditional_order| {
+                conditional_order.lock = true;
+                conditional_order.clone()
+            })
+            .collect()
+    });
+
+    let mut retry: bool = false;
+
+    for conditional_order in conditional_orders.into_iter() {
+        let trade_result: TradeResult = match conditional_order.trade_quest {
+            ConditionalTradeQuest::TradeCycles(trade_quest) => {
+                let ledger_transfer_fee: u128 = TradeCyclesQuest::ledger_transfer_fee();
+                _trade(
+                    conditional_order.positor,
+                    TradeCyclesQuest{
+                        cycles: escrow_transfer_out_amount(trade_quest.cycles, conditional_order.escrow_ledger_transfer_fee, ledger_transfer_fee),
+                        posit_transfer_ledger_fee: Some(ledger_transfer_fee),
+                        ..trade_quest
+                    },
+                    TradeCallKind::ConditionalOrder
+                ).await
+            }
+            ConditionalTradeQuest::TradeTokens(trade_quest) => {
+                let ledger_transfer_fee: u128 = TradeTokensQuest::ledger_transfer_fee();
+                _trade(
+                    conditional_order.positor,
+                    TradeTokensQuest{
+                        tokens: escrow_transfer_out_amount(trade_quest.tokens, conditional_order.escrow_ledger_transfer_fee, ledger_transfer_fee),
+                        posit_transfer_ledger_fee: Some(ledger_transfer_fee),
+                        ..trade_quest
+                    },
+                    TradeCallKind::ConditionalOrder
+                ).await
+            }
+        };
+        with_mut(&CM_DATA, |cm_data| {
+            match trade_result {
+                Ok(_trade_success) => {
+                    cm_data.conditional_orders.remove(&conditional_order.id);
+                }
+                Err(TradeError::CyclesMarketIsBusy)
+                | Err(TradeError::CallerIsInTheMiddleOfADifferentCallThatLocksTheBalance)
+                | Err(TradeError::CreatePositionLedgerTransferCallError(_))
+                | Err(TradeError::CreatePositionLedgerTransferError(Icrc1TransferError::BadFee{ .. })) => { // the ledger-fee is set for the retry.
+                    unlock_conditional_order(&mut cm_data.conditional_orders, conditional_order.id);
+                    retry = true;
+                }
+                Err(trade_error) => {
+                    if let Some(conditional_order) = cm_data.conditional_orders.get_mut(&conditional_order.id) {
+                        conditional_order.trigger_trade_error = Some(format!("{:?}", trade_error));
+                        conditional_order.lock = false;
+                    }
+                }
+            }
+        });
+    }
+
+    if retry {
+        ic_cdk_timers::set_timer(DO_CONDITIONAL_ORDERS_RETRY_RHYTHM, || ic_cdk::spawn(do_conditional_orders()));
+    } else if with(&CM_DATA, |cm_data| { cm_data.conditional_orders.values().any(conditional_order_is_ready_to_trade) }) {
+        ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(do_conditional_orders()));
+    }
+}
+
+fn conditional_order_is_ready_to_trade(conditional_order: &ConditionalOrder) -> bool {
+    conditional_order.triggered_at_nanos.is_some()
+    && conditional_order.trigger_trade_error.is_none()
+    && conditional_order.lock == false
+}
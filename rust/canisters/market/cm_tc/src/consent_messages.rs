@@ -0,0 +1,204 @@
+use crate::CM_DATA;
+use cts_lib::{
+    tools::{
+        localkey::refcell::with,
+        cycles_per_token_rate_as_f64,
+    },
+    icrc::icrc21::*,
+    types::cm::tc::{TradeCyclesQuest, TradeTokensQuest, TradeCyclesMarketQuest, TradeTokensMarketQuest, PlaceConditionalOrderQuest, CancelConditionalOrderQuest, ConditionalTradeQuest, ConditionalOrderTrigger, VoidPositionQuest, TransferBalanceQuest, CyclesPerToken},
+};
+use candid::{CandidType, Deserialize, Principal, decode_one};
+
+
+const CYCLES_DECIMALS: u8 = 12;
+
+
+pub fn consent_message(q: &Icrc21ConsentMessageRequest) -> Icrc21ConsentMessageResponse {
+    let language = ConsentLanguage::from_tag(&q.user_preferences.metadata.language);
+    let (token_ledger, token_decimals): (Principal, u8) = with(&CM_DATA, |cm_data| {
+        (cm_data.icrc1_token_ledger, cm_data.icrc1_token_ledger_decimal_places)
+    });
+    let token = TokenInfo{ ledger: token_ledger, decimals: token_decimals };
+    let message: ConsentMessageFields = match q.method.as_str() {
+        "trade_cycles" => trade_cycles_consent_message(decode_arg(&q.arg)?, &token, language),
+        "trade_tokens" => trade_tokens_consent_message(decode_arg(&q.arg)?, &token, language),
+        "trade_cycles_market" => trade_cycles_market_consent_message(decode_arg(&q.arg)?, &token, language),
+        "trade_tokens_market" => trade_tokens_market_consent_message(decode_arg(&q.arg)?, &token, language),
+        "place_conditional_order" => place_conditional_order_consent_message(decode_arg(&q.arg)?, &token, language),
+        "cancel_conditional_order" => cancel_conditional_order_consent_message(decode_arg(&q.arg)?, language),
+        "void_position" => void_position_consent_message(decode_arg(&q.arg)?, language),
+        "transfer_cycles_balance" => transfer_cycles_balance_consent_message(decode_arg(&q.arg)?, language),
+        "transfer_token_balance" => transfer_token_balance_consent_message(decode_arg(&q.arg)?, &token, language),
+        _ => return Err(unsupported_canister_call(format!("There is no consent message for the method: {}", q.method))),
+    };
+    Ok(consent_info(message, &q.user_preferences, language))
+}
+
+struct TokenInfo {
+    ledger: Principal,
+    decimals: u8,
+}
+
+fn decode_arg<T: CandidType + for<'de> Deserialize<'de>>(arg: &[u8]) -> Result<T, Icrc21Error> {
+    decode_one(arg).map_err(|e| unsupported_canister_call(format!("Could not decode the arg: {}", e)))
+}
+
+fn cycles_str(cycles: u128) -> String {
+    format!("{} TCY", quantums_as_token_str(cycles, CYCLES_DECIMALS))
+}
+
+fn tokens_str(tokens: u128, token: &TokenInfo) -> String {
+    format!("{} tokens", quantums_as_token_str(tokens, token.decimals))
+}
+
+fn rate_field(rate: CyclesPerToken, token: &TokenInfo, language: ConsentLanguage) -> (String, String) {
+    (
+        language.text("Rate", "Tasa").to_string(),
+        format!("{} {}", cycles_per_token_rate_as_f64(rate, token.decimals), language.text("TCY per token", "TCY por token")),
+    )
+}
+
+fn market_order_limit_fields(opt_worst_cycles_per_token_rate: Option<CyclesPerToken>, opt_max_slippage_basis_points: Option<u64>, token: &TokenInfo, language: ConsentLanguage) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    if let Some(worst_cycles_per_token_rate) = opt_worst_cycles_per_token_rate {
+        let (_, rate) = rate_field(worst_cycles_per_token_rate, token, language);
+        fields.push((language.text("Worst rate", "Peor tasa").to_string(), rate));
+    }
+    if let Some(max_slippage_basis_points) = opt_max_slippage_basis_points {
+        fields.push((language.text("Max slippage", "Deslizamiento máximo").to_string(), format!("{}%", max_slippage_basis_points as f64 / 100.0)));
+    }
+    fields
+}
+
+fn token_ledger_field(token: &TokenInfo, language: ConsentLanguage) -> (String, String) {
+    (language.text("Token ledger", "Ledger del token").to_string(), token.ledger.to_text())
+}
+
+
+fn trade_cycles_consent_message(q: TradeCyclesQuest, token: &TokenInfo, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Cycles", "Ciclos").to_string(), cycles_str(q.cycles)),
+        rate_field(q.cycles_per_token_rate, token, language),
+        token_ledger_field(token, language),
+    ];
+    if let Some(fee) = q.posit_transfer_ledger_fee {
+        fields.push((language.text("Transfer fee", "Comisión de transferencia").to_string(), cycles_str(fee)));
+    }
+    ConsentMessageFields{
+        title: language.text("Trade cycles for tokens", "Cambiar ciclos por tokens").to_string(),
+        fields,
+    }
+}
+
+fn trade_tokens_consent_message(q: TradeTokensQuest, token: &TokenInfo, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Tokens", "Tokens").to_string(), tokens_str(q.tokens, token)),
+        rate_field(q.cycles_per_token_rate, token, language),
+        token_ledger_field(token, language),
+    ];
+    if let Some(fee) = q.posit_transfer_ledger_fee {
+        fields.push((language.text("Transfer fee", "Comisión de transferencia").to_string(), tokens_str(fee, token)));
+    }
+    ConsentMessageFields{
+        title: language.text("Trade tokens for cycles", "Cambiar tokens por ciclos").to_string(),
+        fields,
+    }
+}
+
+fn trade_cycles_market_consent_message(q: TradeCyclesMarketQuest, token: &TokenInfo, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Cycles", "Ciclos").to_string(), cycles_str(q.cycles)),
+    ];
+    fields.extend(market_order_limit_fields(q.worst_cycles_per_token_rate, q.max_slippage_basis_points, token, language));
+    fields.push(token_ledger_field(token, language));
+    if let Some(fee) = q.posit_transfer_ledger_fee {
+        fields.push((language.text("Transfer fee", "Comisión de transferencia").to_string(), cycles_str(fee)));
+    }
+    ConsentMessageFields{
+        title: language.text("Market trade cycles for tokens", "Cambiar ciclos por tokens al mercado").to_string(),
+        fields,
+    }
+}
+
+fn trade_tokens_market_consent_message(q: TradeTokensMarketQuest, token: &TokenInfo, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Tokens", "Tokens").to_string(), tokens_str(q.tokens, token)),
+    ];
+    fields.extend(market_order_limit_fields(q.worst_cycles_per_token_rate, q.max_slippage_basis_points, token, language));
+    fields.push(token_ledger_field(token, language));
+    if let Some(fee) = q.posit_transfer_ledger_fee {
+        fields.push((language.text("Transfer fee", "Comisión de transferencia").to_string(), tokens_str(fee, token)));
+    }
+    ConsentMessageFields{
+        title: language.text("Market trade tokens for cycles", "Cambiar tokens por ciclos al mercado").to_string(),
+        fields,
+    }
+}
+
+fn place_conditional_order_consent_message(q: PlaceConditionalOrderQuest, token: &TokenInfo, language: ConsentLanguage) -> ConsentMessageFields {
+    let trade_message: ConsentMessageFields = match q.trade_quest {
+        ConditionalTradeQuest::TradeCycles(trade_quest) => trade_cycles_consent_message(trade_quest, token, language),
+        ConditionalTradeQuest::TradeTokens(trade_quest) => trade_tokens_consent_message(trade_quest, token, language),
+    };
+    let mut fields = trade_message.fields;
+    match q.trigger {
+        ConditionalOrderTrigger::StopLimit{ stop_cycles_per_token_rate } => {
+            let (_, rate) = rate_field(stop_cycles_per_token_rate, token, language);
+            fields.push((language.text("Stop rate", "Tasa de stop").to_string(), rate));
+        }
+        ConditionalOrderTrigger::TrailingStop{ trail_basis_points } => {
+            fields.push((language.text("Trailing stop", "Stop dinámico").to_string(), format!("{}%", trail_basis_points as f64 / 100.0)));
+        }
+    }
+    ConsentMessageFields{
+        title: format!("{}: {}", language.text("Conditional order", "Orden condicional"), trade_message.title),
+        fields,
+    }
+}
+
+fn cancel_conditional_order_consent_message(q: CancelConditionalOrderQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    ConsentMessageFields{
+        title: language.text("Cancel conditional order", "Cancelar orden condicional").to_string(),
+        fields: vec![
+            (language.text("Conditional order id", "Id de la orden condicional").to_string(), q.conditional_order_id.to_string()),
+        ],
+    }
+}
+
+fn void_position_consent_message(q: VoidPositionQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    ConsentMessageFields{
+        title: language.text("Void position", "Anular posición").to_string(),
+        fields: vec![
+            (language.text("Position id", "Id de la posición").to_string(), q.position_id.to_string()),
+        ],
+    }
+}
+
+fn transfer_cycles_balance_consent_message(q: TransferBalanceQuest, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Amount", "Cantidad").to_string(), cycles_str(q.amount)),
+        (language.text("To", "Para").to_string(), icrc_id_as_text(&q.to)),
+    ];
+    if let Some(fee) = q.ledger_transfer_fee {
+        fields.push((language.text("Transfer fee", "Comisión de transferencia").to_string(), cycles_str(fee)));
+    }
+    ConsentMessageFields{
+        title: language.text("Transfer cycles balance", "Transferir saldo de ciclos").to_string(),
+        fields,
+    }
+}
+
+fn transfer_token_balance_consent_message(q: TransferBalanceQuest, token: &TokenInfo, language: ConsentLanguage) -> ConsentMessageFields {
+    let mut fields = vec![
+        (language.text("Amount", "Cantidad").to_string(), tokens_str(q.amount, token)),
+        (language.text("To", "Para").to_string(), icrc_id_as_text(&q.to)),
+        token_ledger_field(token, language),
+    ];
+    if let Some(fee) = q.ledger_transfer_fee {
+        fields.push((language.text("Transfer fee", "Comisión de transferencia").to_string(), tokens_str(fee, token)));
+    }
+    ConsentMessageFields{
+        title: language.text("Transfer token balance", "Transferir saldo de tokens").to_string(),
+        fields,
+    }
+}
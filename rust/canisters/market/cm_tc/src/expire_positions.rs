@@ -0,0 +1,67 @@
+use crate::{
+    CM_DATA,
+    payouts::do_payouts,
+    position_book::remove_position,
+    traits::CurrentPositionTrait,
+};
+use cts_lib::{
+    tools::{
+        localkey::refcell::with_mut,
+        time_nanos,
+    },
+    types::cm::tc::{
+        PositionId,
+        storage_logs::position_log::PositionTerminationCause,
+    },
+};
+use std::time::Duration;
+
+
+#[cfg(not(debug_assertions))]
+pub const EXPIRE_POSITIONS_RHYTHM: Duration = Duration::from_secs(60);
+#[cfg(debug_assertions)]
+pub const EXPIRE_POSITIONS_RHYTHM: Duration = Duration::from_secs(5);
+
+#[cfg(not(debug_assertions))]
+const EXPIRE_POSITIONS_CHUNK_SIZE: usize = 500;
+#[cfg(debug_assertions)]
+const EXPIRE_POSITIONS_CHUNK_SIZE: usize = 2;
+
+
+// voids the expired positions with the cause TimePass. the void-positions get paid out through the normal do_payouts flow.
+// the positions that come out of the book before they expire take their entries out of the positions_expirations with them.
+pub fn expire_positions() {
+    let (expired_positions_count, more_expired_positions): (usize, bool) = with_mut(&CM_DATA, |cm_data| {
+        let now_nanos: u128 = time_nanos();
+        let mut expired_positions_count: usize = 0;
+        for _ in 0..EXPIRE_POSITIONS_CHUNK_SIZE {
+            let position_id: PositionId = match cm_data.positions_expirations.first() {
+                Some(&(expires_at_nanos, position_id)) if expires_at_nanos <= now_nanos => position_id,
+                _ => break,
+            };
+            cm_data.positions_expirations.pop_first();
+            if let Some(cycles_position) = remove_position(&mut cm_data.cycles_positions, &mut cm_data.cycles_positions_book, position_id) {
+                cm_data.void_cycles_positions.insert(
+                    position_id,
+                    cycles_position.into_void_position_type(PositionTerminationCause::TimePass)
+                );
+                expired_positions_count += 1;
+            } else if let Some(token_position) = remove_position(&mut cm_data.token_positions, &mut cm_data.token_positions_book, position_id) {
+                cm_data.void_token_positions.insert(
+                    position_id,
+                    token_position.into_void_position_type(PositionTerminationCause::TimePass)
+                );
+                expired_positions_count += 1;
+            }
+        }
+        let more_expired_positions: bool = cm_data.positions_expirations.first().is_some_and(|(expires_at_nanos, _)| *expires_at_nanos <= now_nanos);
+        (expired_positions_count, more_expired_positions)
+    });
+
+    if more_expired_positions {
+        ic_cdk_timers::set_timer(Duration::ZERO, expire_positions);
+    }
+    if expired_positions_count > 0 {
+        ic_cdk_timers::set_timer(Duration::from_millis(1), || ic_cdk::spawn(do_payouts()));
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     cell::{Cell, RefCell},
-    collections::{VecDeque, BTreeMap},
+    collections::{VecDeque, BTreeMap, BTreeSet},
     time::Duration,
     thread::LocalKey,
     ops::Bound,
@@ -40,6 +40,7 @@ use cts_lib::{
         CallError,
         canister_code::CanisterCode,
         cm::{*, tc::{*, storage_logs::{*, trade_log::*, position_log::*}}},
+        bank::OnCyclesReceivedQuest,
     },
     management_canister,
     icrc::{
@@ -49,6 +50,7 @@ use cts_lib::{
         Icrc1TransferQuest,
         BlockId,
         icrc1_transfer,
+        icrc21::{Icrc21ConsentMessageRequest, Icrc21ConsentMessageResponse},
     },
 };
 use ic_cdk::{
@@ -85,8 +87,21 @@ mod candle_counter;
 mod ledger_transfer;
 mod transfer_memo;
 mod traits;
+mod consent_messages;
+mod position_book;
+mod expire_positions;
+mod conditional_orders;
 
 use payouts::do_payouts;
+use position_book::{insert_position, remove_position, remove_position_expiration, build_positions_book, best_rate_first_levels, worst_rate_first_levels};
+use expire_positions::{expire_positions, EXPIRE_POSITIONS_RHYTHM};
+use conditional_orders::{
+    place_conditional_order_,
+    cancel_conditional_order_,
+    list_conditional_orders_,
+    check_conditional_orders_triggers,
+    do_conditional_orders,
+};
 use ledger_transfer::LedgerTransferReturnType;
 use traits::{
     TradeQuest,
@@ -110,10 +125,16 @@ mod memory_location {
     }; 
 
     pub const CYCLES_POSITIONS_MAX_STORAGE_SIZE_MiB: usize = CANISTER_DATA_STORAGE_SIZE_MiB / 6 * 1;
+    #[cfg(not(debug_assertions))]
     pub const MAX_CYCLES_POSITIONS: usize = CYCLES_POSITIONS_MAX_STORAGE_SIZE_MiB * MiB / std::mem::size_of::<CyclesPosition>();
+    #[cfg(debug_assertions)]
+    pub const MAX_CYCLES_POSITIONS: usize = 120; // a full book is reachable in the tests.
 
     pub const TOKEN_POSITIONS_MAX_STORAGE_SIZE_MiB: usize = CANISTER_DATA_STORAGE_SIZE_MiB / 6 * 1;
+    #[cfg(not(debug_assertions))]
     pub const MAX_TOKEN_POSITIONS: usize = TOKEN_POSITIONS_MAX_STORAGE_SIZE_MiB * MiB / std::mem::size_of::<TokenPosition>();
+    #[cfg(debug_assertions)]
+    pub const MAX_TOKEN_POSITIONS: usize = 120;
 
     pub const TRADE_LOGS_MAX_STORAGE_SIZE_MiB: usize = CANISTER_DATA_STORAGE_SIZE_MiB / 6 * 2;
     pub const MAX_TRADE_LOGS: usize = TRADE_LOGS_MAX_STORAGE_SIZE_MiB * MiB / std::mem::size_of::<TradeLog>();
@@ -138,14 +159,23 @@ const DO_TRADE_LOGS_TOKEN_PAYOUTS_CHUNK_SIZE: usize = 10;
 
 const MAX_MID_CALL_USER_BALANCE_LOCKS: usize = 500;
 
+const MAX_BUMP_POSITIONS_PER_TRADE_CALL: usize = 10;
+
 pub const VOID_POSITION_MINIMUM_WAIT_TIME_SECONDS: u128 = 0;
 
 const CREATE_STORAGE_CANISTER_CYCLES: Cycles = 20 * TRILLION;
 
 const POSITIONS_SUBACCOUNT: &[u8; 32] = &[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,5];
+const CONDITIONAL_ORDERS_SUBACCOUNT: &[u8; 32] = &[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,6];
 
 const MAX_INSTRUCTIONS_IN_THE_MATCH_TRADES_FN: u64 = 30 * BILLION;
 
+// a fill-or-kill that needs more trades than this cannot fill, so that the match_trades fills it before the MAX_INSTRUCTIONS_IN_THE_MATCH_TRADES_FN.
+#[cfg(not(debug_assertions))]
+const MAX_FILL_OR_KILL_TRADES: usize = 1_000;
+#[cfg(debug_assertions)]
+const MAX_FILL_OR_KILL_TRADES: usize = 3;
+
 
 thread_local! {
     
@@ -196,6 +226,8 @@ fn init(cm_init: CMIcrc1TokenTradeContractInit) {
     localkey::cell::set(&CYCLES_BANK_ID, cm_init.cycles_bank_id);
     localkey::cell::set(&CYCLES_BANK_TRANSFER_FEE, cm_init.cycles_bank_transfer_fee);
     localkey::cell::set(&CTS_ID, cm_init.cts_id);
+    
+    ic_cdk_timers::set_timer_interval(EXPIRE_POSITIONS_RHYTHM, expire_positions);
 } 
 
 // ------------------ UPGRADES ------------------------
@@ -219,8 +251,26 @@ fn post_upgrade() {
         localkey::cell::set(&CTS_ID, cm_data.cts_id);    
     });
     
+    // for the data from before the positions-books.
+    with_mut(&CM_DATA, |cm_data| {
+        if cm_data.cycles_positions_book.is_empty() {
+            cm_data.cycles_positions_book = build_positions_book(&cm_data.cycles_positions);
+        }
+        if cm_data.token_positions_book.is_empty() {
+            cm_data.token_positions_book = build_positions_book(&cm_data.token_positions);
+        }
+    });
+    
     ic_cdk_timers::set_timer(Duration::from_secs(30), || ic_cdk::spawn(do_payouts()));
-
+    
+    ic_cdk_timers::set_timer_interval(EXPIRE_POSITIONS_RHYTHM, expire_positions);
+    
+    with_mut(&CM_DATA, |cm_data| {
+        for conditional_order in cm_data.conditional_orders.values_mut() {
+            conditional_order.lock = false;
+        }
+    });
+    ic_cdk_timers::set_timer(Duration::from_secs(30), || ic_cdk::spawn(do_conditional_orders()));
 }
 
 // -----------------
@@ -245,15 +295,53 @@ pub fn minimum_cycles_match() -> Cycles {
 
 #[update]
 pub async fn trade_cycles(q: TradeCyclesQuest) -> TradeResult {
-    _trade(caller(), q).await
+    _trade(caller(), q, TradeCallKind::Limit).await
 }
 
 #[update]
 pub async fn trade_tokens(q: TradeTokensQuest) -> TradeResult {
-    _trade(caller(), q).await
+    _trade(caller(), q, TradeCallKind::Limit).await
+}
+
+// the bank calls this after an icrc1_transfer_and_notify transfer to this canister.
+// a transfer to the principal_token_subaccount of the sender with a trade_cycles_notify_memo puts a trade_cycles position with the received cycles minus the posit-transfer fee.
+// any other transfer stays in the subaccount it went to, same as an icrc1_transfer.
+#[update]
+pub fn on_cycles_received(q: OnCyclesReceivedQuest) {
+    if caller() != localkey::cell::get(&CYCLES_BANK_ID) {
+        trap("Caller must be the cycles-bank.");
+    }
+    if q.to_subaccount.as_deref() != Some(&principal_token_subaccount(&q.from.owner)) {
+        return;
+    }
+    let Some(cycles_per_token_rate) = q.memo.as_deref().and_then(|memo| trade_cycles_notify_memo_rate(memo)) else {
+        return;
+    };
+    let trade_quest = TradeCyclesQuest{
+        cycles: q.amount.saturating_sub(localkey::cell::get(&CYCLES_BANK_TRANSFER_FEE)),
+        cycles_per_token_rate,
+        posit_transfer_ledger_fee: None,
+        return_cycles_to_subaccount: q.from.subaccount.clone(),
+        payout_tokens_to_subaccount: q.from.subaccount.clone(),
+        time_in_force: None,
+        post_only: None,
+        expires_at_nanos: None,
+    };
+    // in a timer so that the bank gets the reply without waiting on the posit-transfer.
+    ic_cdk_timers::set_timer(Duration::from_millis(1), move || ic_cdk::spawn(async move {
+        let _ = _trade(q.from.owner, trade_quest, TradeCallKind::Limit).await;
+    }));
 }
 
-async fn _trade<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestType) -> TradeResult {
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TradeCallKind {
+    Limit,
+    Market, // takes the rates of the book.
+    ConditionalOrder, // the posit-transfer is from the conditional-orders escrow.
+}
+
+// the checks of a trade-quest that do not depend on the book.
+fn check_trade_quest<TradeQuestType: TradeQuest>(q: &TradeQuestType) -> Result<(), TradeError> {
     
     if q.is_less_than_minimum_position() {
         return Err(TradeError::MinimumPosition{ minimum_cycles: minimum_cycles_match(), minimum_tokens: minimum_tokens_match()});
@@ -263,6 +351,30 @@ async fn _trade<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestType
         return Err(TradeError::RateCannotBeZero);
     }    
     
+    if q.post_only().is_some() && q.time_in_force() != TimeInForce::GoodTillCancel {
+        return Err(TradeError::PostOnlyMustBeGoodTillCancel);
+    }
+    
+    if let Some(expires_at_nanos) = q.expires_at_nanos() {
+        if expires_at_nanos <= time_nanos() {
+            return Err(TradeError::ExpiresAtNanosMustBeInTheFuture);
+        }
+    }
+    
+    Ok(())
+}
+
+async fn _trade<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestType, trade_call_kind: TradeCallKind) -> TradeResult {
+    
+    check_trade_quest(&q)?;
+    
+    // check before the posit-transfer so that a fill-or-kill that cannot fill does not move the funds.
+    if let TimeInForce::FillOrKill = q.time_in_force() {
+        if with(&CM_DATA, |cm_data| { q.clone().can_fill_completely(cm_data, caller) }) == false {
+            return Err(TradeError::FillOrKillCannotFillCompletely);
+        }
+    }
+    
     #[allow(non_snake_case)]
     for LOG_STORAGE_DATA in [&POSITIONS_STORAGE_DATA, &TRADES_STORAGE_DATA] {
         with(&LOG_STORAGE_DATA, |log_storage_data| {
@@ -274,8 +386,7 @@ async fn _trade<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestType
     }
     
     with_mut(&CM_DATA, |cm_data| {
-        if TradeQuestType::matcher_positions(cm_data).len().saturating_add(TradeQuestType::mid_call_balance_locks(cm_data).len()) >= TradeQuestType::MAX_POSITIONS.saturating_sub(10)         
-        || TradeQuestType::MAX_VOID_POSITIONS
+        if TradeQuestType::MAX_VOID_POSITIONS
             .saturating_sub(TradeQuestType::matcher_void_positions(cm_data).len())
             .saturating_sub(TradeQuestType::matcher_positions(cm_data).len())
             .saturating_sub(TradeQuestType::mid_call_balance_locks(cm_data).len())
@@ -288,11 +399,24 @@ async fn _trade<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestType
         if TradeQuestType::mid_call_balance_locks(cm_data).len() >= MAX_MID_CALL_USER_BALANCE_LOCKS {
             return Err(TradeError::CyclesMarketIsBusy);
         }
+        let positions_count: usize = TradeQuestType::matcher_positions(cm_data).len().saturating_add(TradeQuestType::mid_call_balance_locks(cm_data).len());
+        let max_positions_count: usize = max_positions_count::<TradeQuestType>();
+        if positions_count >= max_positions_count {
+            // when the book is full, only a good-till-cancel position with a better rate than the worst positions on the book can get in.
+            // nothing gets bumped here, the bump is after the posit-transfer if the position rests on the book.
+            let bump_positions_count: usize = positions_count - max_positions_count + 1;
+            if q.time_in_force() != TimeInForce::GoodTillCancel
+            || trade_call_kind == TradeCallKind::Market
+            || bump_positions_count > MAX_BUMP_POSITIONS_PER_TRADE_CALL
+            || positions_to_bump::<TradeQuestType>(cm_data, q.cycles_per_token_rate(), bump_positions_count).is_none() {
+                return Err(TradeError::CyclesMarketIsBusy);
+            }
+        }
         TradeQuestType::mid_call_balance_locks(cm_data).insert(caller);
         Ok(())
     })?;    
     
-    let trade_result: TradeResult = __trade(caller, q).await;
+    let trade_result: TradeResult = __trade(caller, q, trade_call_kind).await;
     
     with_mut(&CM_DATA, |cm_data| {
         TradeQuestType::mid_call_balance_locks(cm_data).remove(&caller);
@@ -303,14 +427,28 @@ async fn _trade<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestType
     trade_result        
 }
 
-async fn __trade<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestType) -> TradeResult {
+async fn __trade<TradeQuestType: TradeQuest>(caller: Principal, mut q: TradeQuestType, trade_call_kind: TradeCallKind) -> TradeResult {
+    
+    let quest_cycles_per_token_rate: CyclesPerToken = q.cycles_per_token_rate();
     
+    // check before the posit-transfer so that a post-only position that would match does not move the funds.
+    if let Some(post_only) = q.post_only() {
+        if let Some(best_opposing_rate) = with(&CM_DATA, |cm_data| { post_only_would_match::<TradeQuestType>(cm_data, q.cycles_per_token_rate()) }) {
+            match (post_only, post_only_reprice_rate::<TradeQuestType>(best_opposing_rate)) {
+                (PostOnly::Reprice, Some(reprice_rate)) => q.set_cycles_per_token_rate(reprice_rate),
+                _ => return Err(TradeError::PostOnlyWouldMatch{ best_opposing_rate }),
+            }
+        }
+    }
+
     match TradeQuestType::posit_transfer(
         Icrc1TransferQuest{
             memo: None,
             amount: q.quantity(),
             fee: q.posit_transfer_ledger_fee(),
-            from_subaccount: Some(ByteArray::new(principal_token_subaccount(&caller))),
+            from_subaccount: Some(ByteArray::new(
+                if trade_call_kind == TradeCallKind::ConditionalOrder { *CONDITIONAL_ORDERS_SUBACCOUNT } else { principal_token_subaccount(&caller) }
+            )),
             to: IcrcId{owner: ic_cdk::id(), subaccount: Some(ByteArray::new(*POSITIONS_SUBACCOUNT))},
             created_at_time: None,
         }
@@ -332,18 +470,88 @@ async fn __trade<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestTyp
         let position_id: PositionId = new_id(&mut cm_data.positions_id_counter); 
         ic_cdk::print(&format!("creating position id: {position_id}"));
         
+        let time_in_force: TimeInForce = q.time_in_force();
+        // the book can change while the posit-transfer is in flight so check the fill-or-kill again.
+        let fill_or_kill_cannot_fill: bool = time_in_force == TimeInForce::FillOrKill && q.clone().can_fill_completely(cm_data, caller) == false;
+        
+        // same for the post-only.
+        let mut post_only_would_match_after_the_transfer: bool = false;
+        if let Some(post_only) = q.post_only() {
+            if let Some(best_opposing_rate) = post_only_would_match::<TradeQuestType>(cm_data, q.cycles_per_token_rate()) {
+                match (post_only, post_only_reprice_rate::<TradeQuestType>(best_opposing_rate)) {
+                    (PostOnly::Reprice, Some(reprice_rate)) => q.set_cycles_per_token_rate(reprice_rate),
+                    _ => post_only_would_match_after_the_transfer = true,
+                }
+            }
+        }
+        let post_only_outcome: Option<PostOnlyOutcome> = q.post_only().map(|_| {
+            if post_only_would_match_after_the_transfer {
+                PostOnlyOutcome::VoidedWouldMatch
+            } else if q.cycles_per_token_rate() != quest_cycles_per_token_rate {
+                PostOnlyOutcome::Repriced{ cycles_per_token_rate: q.cycles_per_token_rate() }
+            } else {
+                PostOnlyOutcome::Posted
+            }
+        });
+        
+        let opt_expires_at_nanos: Option<u128> = q.expires_at_nanos();
+        
         let position: TradeQuestType::MatcherPositionType = TradeQuestType::create_current_position(q, position_id, caller);
         
         with_mut(&POSITIONS_STORAGE_DATA, |positions_storage_data| {
             positions_storage_data.storage_buffer.extend(position.as_stable_memory_position_log(None).stable_memory_serialize());  
         });
         
-        TradeQuestType::matcher_positions(cm_data).insert(position_id, position);
-                
-        TradeQuestType::match_trades(cm_data, position_id);
+        let (matcher_positions, matcher_positions_book) = TradeQuestType::matcher_positions_and_book(cm_data);
+        insert_position(matcher_positions, matcher_positions_book, position);
+        
+        if fill_or_kill_cannot_fill == false && post_only_would_match_after_the_transfer == false {
+            TradeQuestType::match_trades(cm_data, position_id, trade_call_kind == TradeCallKind::Market);
+        }
+        
+        // a fill-or-kill that fills is not in the book anymore at this point.
+        debug_assert!(
+            time_in_force != TimeInForce::FillOrKill || fill_or_kill_cannot_fill || TradeQuestType::matcher_positions(cm_data).contains_key(&position_id) == false,
+            "a fill-or-kill that can fill completely is partly filled"
+        );
+        match time_in_force {
+            TimeInForce::GoodTillCancel => {},
+            TimeInForce::ImmediateOrCancel => void_the_rest_of_the_position::<TradeQuestType>(cm_data, position_id, PositionTerminationCause::ImmediateOrCancel),
+            TimeInForce::FillOrKill => void_the_rest_of_the_position::<TradeQuestType>(cm_data, position_id, PositionTerminationCause::FillOrKill),
+        }
+        if post_only_would_match_after_the_transfer {
+            void_the_rest_of_the_position::<TradeQuestType>(cm_data, position_id, PositionTerminationCause::PostOnlyWouldMatch);
+        }
+        
+        // if the position rests on a full book, it bumps the worst positions on the book, or it gets bumped itself.
+        if TradeQuestType::matcher_positions(cm_data).contains_key(&position_id) {
+            let positions_count: usize = TradeQuestType::matcher_positions(cm_data).len()
+                .saturating_add(TradeQuestType::mid_call_balance_locks(cm_data).len())
+                .saturating_sub(1); // the lock of this call.
+            let max_positions_count: usize = max_positions_count::<TradeQuestType>();
+            if positions_count > max_positions_count {
+                let bump_positions_count: usize = positions_count - max_positions_count;
+                let cycles_per_token_rate: CyclesPerToken = TradeQuestType::matcher_positions(cm_data).get(&position_id).unwrap().current_position_available_cycles_per_token_rate();
+                match positions_to_bump::<TradeQuestType>(cm_data, cycles_per_token_rate, bump_positions_count) {
+                    Some(bump_position_ids) if bump_positions_count <= MAX_BUMP_POSITIONS_PER_TRADE_CALL => {
+                        for bump_position_id in bump_position_ids {
+                            void_the_rest_of_the_position::<TradeQuestType>(cm_data, bump_position_id, PositionTerminationCause::Bump);
+                        }
+                    }
+                    _ => void_the_rest_of_the_position::<TradeQuestType>(cm_data, position_id, PositionTerminationCause::Bump),
+                }
+            }
+        }
+        
+        if let Some(expires_at_nanos) = opt_expires_at_nanos {
+            if TradeQuestType::matcher_positions(cm_data).contains_key(&position_id) {
+                cm_data.positions_expirations.insert((expires_at_nanos, position_id));
+            }
+        }
         
         TradeSuccess{
             position_id: position_id,
+            post_only_outcome,
         }
     }))    
 }
@@ -352,16 +560,22 @@ async fn __trade<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestTyp
 // ------
 
 
+// walks the matchee positions-book from the best rate, and at each rate in the time order.
 fn match_trades<MatcherPositionType: CurrentPositionTrait, MatcheePositionType: CurrentPositionTrait>(
     matcher_position_id: PositionId,
+    matcher_is_a_market_order: bool,
     matcher_positions: &mut BTreeMap<PositionId, MatcherPositionType>,  
     matchee_positions: &mut BTreeMap<PositionId, MatcheePositionType>, 
+    matcher_positions_book: &mut PositionBook,
+    matchee_positions_book: &mut PositionBook,
     matcher_void_positions: &mut BTreeMap<PositionId, MatcherPositionType::VoidPositionType>,
     matchee_void_positions: &mut BTreeMap<PositionId, MatcheePositionType::VoidPositionType>,
     trade_logs: &mut VecDeque<TradeLogAndTemporaryData>, 
     trade_logs_id_counter: &mut PurchaseId,
     candle_counter: &mut CandleCounter,
     latest_trade_rate_data: &mut LatestTradeRateData,
+    conditional_orders: &mut BTreeMap<ConditionalOrderId, ConditionalOrder>,
+    positions_expirations: &mut BTreeSet<(u128, PositionId)>,
 ) {       
     
     if MatcherPositionType::POSITION_KIND == MatcheePositionType::POSITION_KIND {
@@ -377,13 +591,25 @@ fn match_trades<MatcherPositionType: CurrentPositionTrait, MatcheePositionType:
     
     let mut remove_matchee_positions_fill: Vec<PositionId> = Vec::new();
     
-    for matchee_position in matchee_positions.values_mut() {
-        if let Some(trade_rate) = matchee_position.is_this_position_better_than_or_equal_to_the_match_rate(match_rate) {
+    let mut first_trade_rate: Option<CyclesPerToken> = None;
+    
+    'levels: for (_level_rate, level_position_ids) in best_rate_first_levels::<MatcheePositionType>(matchee_positions_book) {
+        for matchee_position_id in level_position_ids.iter() {
+            let matchee_position: &mut MatcheePositionType = match matchee_positions.get_mut(matchee_position_id) {
+                Some(p) => p,
+                None => continue,
+            };
+            let trade_rate: CyclesPerToken = match matchee_position.is_this_position_better_than_or_equal_to_the_match_rate(match_rate) {
+                // a market-order takes the rate of the book.
+                Some(_) if matcher_is_a_market_order => matchee_position.current_position_available_cycles_per_token_rate(),
+                Some(trade_rate) => trade_rate,
+                None => break 'levels, // the rest of the book is at worse rates.
+            };
             if trade_logs.len() >= MAX_TRADE_LOGS {
-                break; // we can put a timer to continue looking for matches for this position once there is space in the trade-logs queue, for now it will wait till another compatible position comes.
+                break 'levels; // we can put a timer to continue looking for matches for this position once there is space in the trade-logs queue, for now it will wait till another compatible position comes.
             }
             if ic_cdk::api::instruction_counter() >= MAX_INSTRUCTIONS_IN_THE_MATCH_TRADES_FN {
-                break; // ..
+                break 'levels; // ..
             }
                                                                                     
             let purchase_tokens: Tokens = std::cmp::min(matcher_position.current_position_tokens(trade_rate), matchee_position.current_position_tokens(trade_rate));
@@ -449,34 +675,145 @@ fn match_trades<MatcherPositionType: CurrentPositionTrait, MatcheePositionType:
                 rate: trade_rate,
                 timestamp_nanos: time_nanos_u64(),
             };
+            first_trade_rate.get_or_insert(trade_rate);
             
-            if matchee_position.current_position_tokens(matchee_position.current_position_available_cycles_per_token_rate()) < minimum_tokens_match() 
-            || tokens_transform_cycles(matchee_position.current_position_tokens(matchee_position.current_position_available_cycles_per_token_rate()), matchee_position.current_position_available_cycles_per_token_rate()) < minimum_cycles_match() {
+            if position_is_below_the_minimum_match(matchee_position) {
                 remove_matchee_positions_fill.push(matchee_position.id());
             }
             
-            if matcher_position.current_position_tokens(matcher_position.current_position_available_cycles_per_token_rate()) < minimum_tokens_match() 
-            || tokens_transform_cycles(matcher_position.current_position_tokens(matcher_position.current_position_available_cycles_per_token_rate()), matcher_position.current_position_available_cycles_per_token_rate()) < minimum_cycles_match() { 
-                let matcher_position: MatcherPositionType = match matcher_positions.remove(&matcher_position_id) { Some(p)=>p, None=>break, };
+            if position_is_below_the_minimum_match(matcher_position) { 
+                let matcher_position: MatcherPositionType = match remove_position(matcher_positions, matcher_positions_book, matcher_position_id) { Some(p)=>p, None=>break 'levels, };
+                remove_position_expiration(positions_expirations, &matcher_position);
                 matcher_void_positions.insert(
                     matcher_position.id(),
                     matcher_position.into_void_position_type(PositionTerminationCause::Fill)
                 );
-                break;
+                break 'levels;
             }    
         }
     }
     
     for remove_matchee_position_id in remove_matchee_positions_fill.into_iter() {
-        let matchee_position: MatcheePositionType = match matchee_positions.remove(&remove_matchee_position_id) {
+        let matchee_position: MatcheePositionType = match remove_position(matchee_positions, matchee_positions_book, remove_matchee_position_id) {
             Some(p) => p,
             None => continue,
         };
+        remove_position_expiration(positions_expirations, &matchee_position);
         matchee_void_positions.insert(
             matchee_position.id(),
             matchee_position.into_void_position_type(PositionTerminationCause::Fill)
         );  
     }    
+    
+    // once per call, not per trade.
+    if let Some(first_trade_rate) = first_trade_rate {
+        check_conditional_orders_triggers(conditional_orders, first_trade_rate, latest_trade_rate_data);
+    }
+}
+
+
+
+fn position_is_below_the_minimum_match<T: CurrentPositionTrait>(position: &T) -> bool {
+    let rate: CyclesPerToken = position.current_position_available_cycles_per_token_rate();
+    position.current_position_tokens(rate) < minimum_tokens_match() 
+    || tokens_transform_cycles(position.current_position_tokens(rate), rate) < minimum_cycles_match()
+}
+
+// walks the book the same as the match_trades with a new matcher position that is not in the book, without changing the book.
+// returns true if the matcher position would fill.
+fn can_fill_completely<MatcherPositionType: CurrentPositionTrait, MatcheePositionType: CurrentPositionTrait>(
+    mut matcher_position: MatcherPositionType,
+    matchee_positions: &BTreeMap<PositionId, MatcheePositionType>,
+    matchee_positions_book: &PositionBook,
+    trade_logs_len: usize,
+) -> bool {
+    let match_rate: CyclesPerToken = matcher_position.current_position_available_cycles_per_token_rate();
+    let mut trades_count: usize = 0;
+    for (_level_rate, level_position_ids) in best_rate_first_levels::<MatcheePositionType>(matchee_positions_book) {
+        for matchee_position_id in level_position_ids.iter() {
+            let matchee_position: &MatcheePositionType = match matchee_positions.get(matchee_position_id) {
+                Some(p) => p,
+                None => continue,
+            };
+            let trade_rate: CyclesPerToken = match matchee_position.is_this_position_better_than_or_equal_to_the_match_rate(match_rate) {
+                Some(trade_rate) => trade_rate,
+                None => return false,
+            };
+            if trade_logs_len.saturating_add(trades_count) >= MAX_TRADE_LOGS
+            || trades_count >= MAX_FILL_OR_KILL_TRADES {
+                return false;
+            }
+            let purchase_tokens: Tokens = std::cmp::min(matcher_position.current_position_tokens(trade_rate), matchee_position.current_position_tokens(trade_rate));
+            matcher_position.subtract_tokens(purchase_tokens, trade_rate);
+            trades_count += 1;
+            if position_is_below_the_minimum_match(&matcher_position) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn max_positions_count<TradeQuestType: TradeQuest>() -> usize {
+    TradeQuestType::MAX_POSITIONS.saturating_sub(10)
+}
+
+// the positions that a position at this rate bumps: the worst rate first and at each rate the latest first.
+// only the positions with a worse rate than this rate get bumped.
+fn positions_to_bump<TradeQuestType: TradeQuest>(cm_data: &mut CMData, cycles_per_token_rate: CyclesPerToken, bump_positions_count: usize) -> Option<Vec<PositionId>> {
+    let (_matcher_positions, matcher_positions_book) = TradeQuestType::matcher_positions_and_book(cm_data);
+    let mut bump_position_ids: Vec<PositionId> = Vec::new();
+    'levels: for (level_rate, level_position_ids) in worst_rate_first_levels::<TradeQuestType::MatcherPositionType>(matcher_positions_book) {
+        let level_is_worse: bool = match <TradeQuestType::MatcherPositionType as CurrentPositionTrait>::POSITION_KIND {
+            PositionKind::Cycles => *level_rate < cycles_per_token_rate,
+            PositionKind::Token => *level_rate > cycles_per_token_rate,
+        };
+        if level_is_worse == false {
+            break;
+        }
+        for position_id in level_position_ids.iter().rev() {
+            bump_position_ids.push(*position_id);
+            if bump_position_ids.len() >= bump_positions_count {
+                break 'levels;
+            }
+        }
+    }
+    if bump_position_ids.len() < bump_positions_count {
+        return None;
+    }
+    Some(bump_position_ids)
+}
+
+// returns the best rate of the other side if a position at this rate would match on the book.
+fn post_only_would_match<TradeQuestType: TradeQuest>(cm_data: &CMData, cycles_per_token_rate: CyclesPerToken) -> Option<CyclesPerToken> {
+    let best_opposing_rate: CyclesPerToken = best_rate_first_levels::<TradeQuestType::MatcheePositionType>(TradeQuestType::matchee_positions_book(cm_data))
+        .next()
+        .map(|(rate, _)| *rate)?;
+    let would_match: bool = match <TradeQuestType::MatcherPositionType as CurrentPositionTrait>::POSITION_KIND {
+        PositionKind::Cycles => cycles_per_token_rate >= best_opposing_rate,
+        PositionKind::Token => cycles_per_token_rate <= best_opposing_rate,
+    };
+    would_match.then_some(best_opposing_rate)
+}
+
+// one tick (one cycle per token) behind the best rate of the other side.
+fn post_only_reprice_rate<TradeQuestType: TradeQuest>(best_opposing_rate: CyclesPerToken) -> Option<CyclesPerToken> {
+    match <TradeQuestType::MatcherPositionType as CurrentPositionTrait>::POSITION_KIND {
+        PositionKind::Cycles => best_opposing_rate.checked_sub(1).filter(|rate| *rate != 0),
+        PositionKind::Token => best_opposing_rate.checked_add(1),
+    }
+}
+
+// voids the rest of the matcher position if it is still in the book.
+fn void_the_rest_of_the_position<TradeQuestType: TradeQuest>(cm_data: &mut CMData, position_id: PositionId, position_termination_cause: PositionTerminationCause) {
+    let (matcher_positions, matcher_positions_book) = TradeQuestType::matcher_positions_and_book(cm_data);
+    if let Some(position) = remove_position(matcher_positions, matcher_positions_book, position_id) {
+        remove_position_expiration(&mut cm_data.positions_expirations, &position);
+        TradeQuestType::matcher_void_positions(cm_data).insert(
+            position_id,
+            position.into_void_position_type(position_termination_cause)
+        );
+    }
 }
 
 
@@ -493,6 +830,136 @@ pub fn sns_validate_trade_tokens(q: TradeTokensQuest) -> Result<String,String> {
 
 
 
+#[update]
+pub async fn trade_cycles_market(q: TradeCyclesMarketQuest) -> TradeMarketResult {
+    let cycles_per_token_rate: CyclesPerToken = market_order_limit_rate::<TradeCyclesQuest>(q.worst_cycles_per_token_rate, q.max_slippage_basis_points)?;
+    _trade_market(
+        caller(),
+        TradeCyclesQuest{
+            cycles: q.cycles,
+            cycles_per_token_rate,
+            posit_transfer_ledger_fee: q.posit_transfer_ledger_fee,
+            return_cycles_to_subaccount: q.return_cycles_to_subaccount,
+            payout_tokens_to_subaccount: q.payout_tokens_to_subaccount,
+            time_in_force: Some(TimeInForce::ImmediateOrCancel),
+            post_only: None,
+            expires_at_nanos: None,
+        }
+    ).await
+}
+
+#[update]
+pub async fn trade_tokens_market(q: TradeTokensMarketQuest) -> TradeMarketResult {
+    let cycles_per_token_rate: CyclesPerToken = market_order_limit_rate::<TradeTokensQuest>(q.worst_cycles_per_token_rate, q.max_slippage_basis_points)?;
+    _trade_market(
+        caller(),
+        TradeTokensQuest{
+            tokens: q.tokens,
+            cycles_per_token_rate,
+            posit_transfer_ledger_fee: q.posit_transfer_ledger_fee,
+            return_tokens_to_subaccount: q.return_tokens_to_subaccount,
+            payout_cycles_to_subaccount: q.payout_cycles_to_subaccount,
+            time_in_force: Some(TimeInForce::ImmediateOrCancel),
+            post_only: None,
+            expires_at_nanos: None,
+        }
+    ).await
+}
+
+#[query]
+pub fn sns_validate_trade_cycles_market(q: TradeCyclesMarketQuest) -> Result<String,String> {
+    Ok(sns_validation_string(q))
+}
+
+#[query]
+pub fn sns_validate_trade_tokens_market(q: TradeTokensMarketQuest) -> Result<String,String> {
+    Ok(sns_validation_string(q))
+}
+
+// the worst acceptable rate of a market-order.
+fn market_order_limit_rate<TradeQuestType: TradeQuest>(opt_worst_cycles_per_token_rate: Option<CyclesPerToken>, opt_max_slippage_basis_points: Option<u64>) -> Result<CyclesPerToken, TradeMarketError> {
+    let opt_max_slippage_rate: Option<CyclesPerToken> = match opt_max_slippage_basis_points {
+        None => None,
+        Some(max_slippage_basis_points) => {
+            let best_opposing_rate: CyclesPerToken = with(&CM_DATA, |cm_data| {
+                best_rate_first_levels::<TradeQuestType::MatcheePositionType>(TradeQuestType::matchee_positions_book(cm_data))
+                    .next()
+                    .map(|(rate, _)| *rate)
+            }).ok_or(TradeMarketError::NoPositionsOnTheOtherSide)?;
+            Some(match <TradeQuestType::MatcherPositionType as CurrentPositionTrait>::POSITION_KIND {
+                PositionKind::Cycles => best_opposing_rate.saturating_mul(10_000u128.saturating_add(max_slippage_basis_points as u128)) / 10_000,
+                PositionKind::Token => best_opposing_rate.saturating_mul(10_000u128.saturating_sub(max_slippage_basis_points as u128)) / 10_000,
+            })
+        }
+    };
+    match (opt_worst_cycles_per_token_rate, opt_max_slippage_rate) {
+        (None, None) => Err(TradeMarketError::MustSetAWorstRateOrAMaxSlippage),
+        (Some(rate), None) | (None, Some(rate)) => Ok(rate),
+        (Some(worst_cycles_per_token_rate), Some(max_slippage_rate)) => Ok(
+            match <TradeQuestType::MatcherPositionType as CurrentPositionTrait>::POSITION_KIND {
+                PositionKind::Cycles => std::cmp::min(worst_cycles_per_token_rate, max_slippage_rate),
+                PositionKind::Token => std::cmp::max(worst_cycles_per_token_rate, max_slippage_rate),
+            }
+        ),
+    }
+}
+
+// a market-order is an immediate-or-cancel position that takes the rates of the book.
+async fn _trade_market<TradeQuestType: TradeQuest>(caller: Principal, q: TradeQuestType) -> TradeMarketResult {
+    let position_id: PositionId = _trade(caller, q, TradeCallKind::Market).await.map_err(TradeMarketError::TradeError)?.position_id;
+    
+    // the position is a void-position at this point, the do_payouts timer runs after this call.
+    let position_log: PositionLog = with_mut(&CM_DATA, |cm_data| {
+        TradeQuestType::matcher_void_positions(cm_data)
+            .get(&position_id)
+            .unwrap()
+            .update_storage_position_data()
+            .update_storage_position_log
+            .clone()
+    });
+    
+    Ok(TradeMarketSuccess{
+        position_id,
+        fill_quantity: position_log.fill_quantity,
+        fill_average_rate: position_log.fill_average_rate,
+        payouts_fees_sum: position_log.payouts_fees_sum,
+        unfilled_quantity: position_log.mainder_position_quantity,
+    })
+}
+
+
+
+
+
+// the funds of a conditional-order are in the escrow from the placement till the order triggers or gets canceled.
+#[update]
+pub async fn place_conditional_order(q: PlaceConditionalOrderQuest) -> PlaceConditionalOrderResult {
+    match q.trade_quest {
+        ConditionalTradeQuest::TradeCycles(trade_quest) => place_conditional_order_(caller(), q.trigger, trade_quest).await,
+        ConditionalTradeQuest::TradeTokens(trade_quest) => place_conditional_order_(caller(), q.trigger, trade_quest).await,
+    }
+}
+
+#[update]
+pub async fn cancel_conditional_order(q: CancelConditionalOrderQuest) -> CancelConditionalOrderResult {
+    cancel_conditional_order_(caller(), q).await
+}
+
+#[query]
+pub fn list_conditional_orders(opt_start_after: Option<ConditionalOrderId>) -> ListConditionalOrdersSponse {
+    list_conditional_orders_(caller(), opt_start_after)
+}
+
+#[query]
+pub fn sns_validate_place_conditional_order(q: PlaceConditionalOrderQuest) -> Result<String,String> {
+    Ok(sns_validation_string(q))
+}
+
+#[query]
+pub fn sns_validate_cancel_conditional_order(q: CancelConditionalOrderQuest) -> Result<String,String> {
+    Ok(sns_validation_string(q))
+}
+
 
 
 #[update]
@@ -521,7 +988,8 @@ fn void_position_(caller: Principal, q: VoidPositionQuest) -> VoidPositionResult
             if time_seconds().saturating_sub(cycles_position.timestamp_nanos/NANOS_IN_A_SECOND) < VOID_POSITION_MINIMUM_WAIT_TIME_SECONDS {
                 return Err(VoidPositionError::MinimumWaitTime{ minimum_wait_time_seconds: VOID_POSITION_MINIMUM_WAIT_TIME_SECONDS, position_creation_timestamp_seconds: cycles_position.timestamp_nanos/NANOS_IN_A_SECOND });
             }  
-            let cycles_position: CyclesPosition = cm_data.cycles_positions.remove(&cycles_position.id()).unwrap();
+            let cycles_position: CyclesPosition = remove_position(&mut cm_data.cycles_positions, &mut cm_data.cycles_positions_book, cycles_position.id()).unwrap();
+            remove_position_expiration(&mut cm_data.positions_expirations, &cycles_position);
             cm_data.void_cycles_positions.insert(
                 cycles_position.id(),
                 cycles_position.into_void_position_type(PositionTerminationCause::UserCallVoidPosition)
@@ -534,7 +1002,8 @@ fn void_position_(caller: Principal, q: VoidPositionQuest) -> VoidPositionResult
             if time_seconds().saturating_sub(token_position.timestamp_nanos/NANOS_IN_A_SECOND) < VOID_POSITION_MINIMUM_WAIT_TIME_SECONDS {
                 return Err(VoidPositionError::MinimumWaitTime{ minimum_wait_time_seconds: VOID_POSITION_MINIMUM_WAIT_TIME_SECONDS, position_creation_timestamp_seconds: token_position.timestamp_nanos/NANOS_IN_A_SECOND });
             }
-            let token_position: TokenPosition = cm_data.token_positions.remove(&token_position.id()).unwrap();
+            let token_position: TokenPosition = remove_position(&mut cm_data.token_positions, &mut cm_data.token_positions_book, token_position.id()).unwrap();
+            remove_position_expiration(&mut cm_data.positions_expirations, &token_position);
             cm_data.void_token_positions.insert(
                 token_position.id(),
                 token_position.into_void_position_type(PositionTerminationCause::UserCallVoidPosition)
@@ -625,49 +1094,41 @@ const MAX_POSITIONS_QUANTITIES: usize = 512*KiB*3 / std::mem::size_of::<(CyclesP
 #[query]
 pub fn view_cycles_position_book(q: ViewPositionBookQuest) -> ViewPositionBookSponse {
     with(&CM_DATA, |cm_data| {
-        view_position_book_(q, &cm_data.cycles_positions)  
+        view_position_book_(q, &cm_data.cycles_positions, &cm_data.cycles_positions_book)  
     })
 }
 
 #[query]
 pub fn view_tokens_position_book(q: ViewPositionBookQuest) -> ViewPositionBookSponse {
     with(&CM_DATA, |cm_data| {
-        view_position_book_(q, &cm_data.token_positions)  
+        view_position_book_(q, &cm_data.token_positions, &cm_data.token_positions_book)  
     })    
 }
 
 
-fn view_position_book_<T: CurrentPositionTrait>(q: ViewPositionBookQuest, current_positions: &BTreeMap<PositionId, T>) -> ViewPositionBookSponse {
+fn view_position_book_<T: CurrentPositionTrait>(q: ViewPositionBookQuest, current_positions: &BTreeMap<PositionId, T>, positions_book: &PositionBook) -> ViewPositionBookSponse {
     let mut positions_quantities: Vec<(CyclesPerToken, u128)> = vec![]; 
-    let mut cps_as_rate_and_quantity: Vec<(CyclesPerToken, u128)> = current_positions
-        .values()
-        .map(|p| {
-            let rate = p.current_position_available_cycles_per_token_rate(); 
-            (rate, p.current_position_quantity())
-        })
-        .collect();
-    cps_as_rate_and_quantity.sort_by_key(|d| d.0);
-        
-    if let Some(start_greater_than_rate) = q.opt_start_greater_than_rate {
-        let partition_point: usize = cps_as_rate_and_quantity.partition_point(|(r,_)| { r <= &start_greater_than_rate });
-        cps_as_rate_and_quantity.drain(..partition_point);    
-    }
-                
-    for (r,q) in cps_as_rate_and_quantity.iter().copied() {
-        if let Some(latest_r_q) = positions_quantities.last_mut() {
-            if latest_r_q.0 == r {
-                latest_r_q.1 += q;
-                continue;
-            } 
-        }
+    let mut is_last_chunk: bool = true;
+    
+    let start_bound: Bound<CyclesPerToken> = match q.opt_start_greater_than_rate {
+        Some(start_greater_than_rate) => Bound::Excluded(start_greater_than_rate),
+        None => Bound::Unbounded,
+    };
+    
+    for (rate, level_position_ids) in positions_book.range((start_bound, Bound::Unbounded)) {
         if positions_quantities.len() >= MAX_POSITIONS_QUANTITIES {
+            is_last_chunk = false;
             break;
         }
-        positions_quantities.push((r,q));
+        let level_quantity: u128 = level_position_ids.iter()
+            .filter_map(|position_id| current_positions.get(position_id))
+            .map(|p| p.current_position_quantity())
+            .sum();
+        positions_quantities.push((*rate, level_quantity));
     }
     
     ViewPositionBookSponse {
-        is_last_chunk: positions_quantities.len() == 0 || positions_quantities.last().unwrap().0 == cps_as_rate_and_quantity.last().unwrap().0,
+        is_last_chunk,
         positions_quantities, 
     }
     
@@ -1042,6 +1503,13 @@ pub fn quote_token() -> Principal {
     })
 } 
 
+// ICRC-21
+
+#[update]
+pub fn icrc21_canister_call_consent_message(q: Icrc21ConsentMessageRequest) -> Icrc21ConsentMessageResponse {
+    consent_messages::consent_message(&q)
+}
+
 // ICRC-45
 
 #[query]
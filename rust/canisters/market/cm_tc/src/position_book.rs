@@ -0,0 +1,60 @@
+use std::collections::{BTreeMap, BTreeSet};
+use cts_lib::types::cm::tc::{
+    PositionId,
+    PositionKind,
+    PositionBook,
+    CyclesPerToken,
+};
+use crate::traits::CurrentPositionTrait;
+
+
+// use these functions for each insert and remove of a current-position so that the book stays in sync with the positions.
+
+pub fn insert_position<T: CurrentPositionTrait>(positions: &mut BTreeMap<PositionId, T>, positions_book: &mut PositionBook, position: T) {
+    positions_book.entry(position.current_position_available_cycles_per_token_rate()).or_default().insert(position.id());
+    positions.insert(position.id(), position);
+}
+
+pub fn remove_position<T: CurrentPositionTrait>(positions: &mut BTreeMap<PositionId, T>, positions_book: &mut PositionBook, position_id: PositionId) -> Option<T> {
+    let position: T = positions.remove(&position_id)?;
+    let rate: CyclesPerToken = position.current_position_available_cycles_per_token_rate();
+    if let Some(level) = positions_book.get_mut(&rate) {
+        level.remove(&position_id);
+        if level.is_empty() {
+            positions_book.remove(&rate);
+        }
+    }
+    Some(position)
+}
+
+// a position that comes out of the book also comes out of the positions_expirations.
+pub fn remove_position_expiration<T: CurrentPositionTrait>(positions_expirations: &mut BTreeSet<(u128, PositionId)>, position: &T) {
+    if let Some(expires_at_nanos) = position.expires_at_nanos() {
+        positions_expirations.remove(&(expires_at_nanos, position.id()));
+    }
+}
+
+pub fn build_positions_book<T: CurrentPositionTrait>(positions: &BTreeMap<PositionId, T>) -> PositionBook {
+    let mut positions_book = PositionBook::new();
+    for position in positions.values() {
+        positions_book.entry(position.current_position_available_cycles_per_token_rate()).or_default().insert(position.id());
+    }
+    positions_book
+}
+
+// the levels of the book from the best rate for the other side.
+// the best token-position is the lowest rate and the best cycles-position is the highest rate.
+pub fn best_rate_first_levels<'a, T: CurrentPositionTrait>(positions_book: &'a PositionBook) -> Box<dyn Iterator<Item=(&'a CyclesPerToken, &'a BTreeSet<PositionId>)> + 'a> {
+    match T::POSITION_KIND {
+        PositionKind::Token => Box::new(positions_book.iter()),
+        PositionKind::Cycles => Box::new(positions_book.iter().rev()),
+    }
+}
+
+// the levels of the book from the worst rate for the other side.
+pub fn worst_rate_first_levels<'a, T: CurrentPositionTrait>(positions_book: &'a PositionBook) -> Box<dyn Iterator<Item=(&'a CyclesPerToken, &'a BTreeSet<PositionId>)> + 'a> {
+    match T::POSITION_KIND {
+        PositionKind::Token => Box::new(positions_book.iter().rev()),
+        PositionKind::Cycles => Box::new(positions_book.iter()),
+    }
+}
@@ -59,6 +59,8 @@ pub trait CurrentPositionTrait {
 
     //fn return_to_subaccount(&self) -> Option<IcrcSubaccount>;
     fn payout_to_subaccount(&self) -> Option<IcrcSubaccount>;
+    
+    fn expires_at_nanos(&self) -> Option<u128>;
 }
 
 
@@ -152,6 +154,9 @@ impl CurrentPositionTrait for CyclesPosition {
     fn payout_to_subaccount(&self) -> Option<IcrcSubaccount> {
         self.quest.payout_tokens_to_subaccount.clone()
     }
+    fn expires_at_nanos(&self) -> Option<u128> {
+        self.quest.expires_at_nanos
+    }
 }
 
 
@@ -240,4 +245,7 @@ impl CurrentPositionTrait for TokenPosition {
     fn payout_to_subaccount(&self) -> Option<IcrcSubaccount> {
         self.quest.payout_cycles_to_subaccount.clone()
     }
+    fn expires_at_nanos(&self) -> Option<u128> {
+        self.quest.expires_at_nanos
+    }
 }
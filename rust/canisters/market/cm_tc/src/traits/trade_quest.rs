@@ -7,10 +7,14 @@ use cts_lib::{
     types::{
         cm::tc::{
             PositionId,
+            PositionBook,
             CMData,
             CyclesPerToken,
             TradeCyclesQuest,
             TradeTokensQuest,
+            TimeInForce,
+            PostOnly,
+            ConditionalTradeQuest,
             CyclesPosition,
             TokenPosition,
 
@@ -20,6 +24,7 @@ use cts_lib::{
         cycles_transform_tokens,
         tokens_transform_cycles,
         time_nanos,
+        localkey,
     }
 };
 use super::CurrentPositionTrait;
@@ -35,12 +40,14 @@ use crate::{
     MAX_VOID_TOKEN_POSITIONS,
     minimum_cycles_match,
     minimum_tokens_match,
+    CYCLES_BANK_TRANSFER_FEE,
+    TOKEN_LEDGER_TRANSFER_FEE,
 };
 use candid::Principal;
 
 
 
-pub trait TradeQuest {
+pub trait TradeQuest: Clone {
     
     type MatcherPositionType: CurrentPositionTrait;
     type MatcheePositionType: CurrentPositionTrait;
@@ -50,14 +57,23 @@ pub trait TradeQuest {
     
     fn quantity(&self) -> u128;
     fn cycles_per_token_rate(&self) -> CyclesPerToken;
+    fn set_cycles_per_token_rate(&mut self, cycles_per_token_rate: CyclesPerToken);
     fn posit_transfer_ledger_fee(&self) -> Option<u128>;
     fn is_less_than_minimum_position(&self) -> bool;
+    fn time_in_force(&self) -> TimeInForce;
+    fn post_only(&self) -> Option<PostOnly>;
+    fn expires_at_nanos(&self) -> Option<u128>;
+    fn ledger_transfer_fee() -> u128;
+    fn into_conditional_trade_quest(self) -> ConditionalTradeQuest;
     fn mid_call_balance_locks(cm_data: &mut CMData) -> &mut HashSet<Principal>;
     fn posit_transfer(q: Icrc1TransferQuest) -> impl Future<Output=LedgerTransferReturnType>;
     fn create_current_position(self, id: PositionId, positor: Principal) -> Self::MatcherPositionType;
     fn matcher_positions(cm_data: &mut CMData) -> &mut BTreeMap<PositionId, Self::MatcherPositionType>;
+    fn matcher_positions_and_book(cm_data: &mut CMData) -> (&mut BTreeMap<PositionId, Self::MatcherPositionType>, &mut PositionBook);
     fn matcher_void_positions(cm_data: &mut CMData) -> &mut BTreeMap<PositionId, <Self::MatcherPositionType as CurrentPositionTrait>::VoidPositionType>;
-    fn match_trades(cm_data: &mut CMData, matcher_position_id: PositionId);
+    fn matchee_positions_book(cm_data: &CMData) -> &PositionBook;
+    fn match_trades(cm_data: &mut CMData, matcher_position_id: PositionId, matcher_is_a_market_order: bool);
+    fn can_fill_completely(self, cm_data: &CMData, positor: Principal) -> bool;
 } 
 
 
@@ -70,10 +86,16 @@ impl TradeQuest for TradeCyclesQuest {
     
     fn quantity(&self) -> u128 { self.cycles }
     fn cycles_per_token_rate(&self) -> CyclesPerToken { self.cycles_per_token_rate }
+    fn set_cycles_per_token_rate(&mut self, cycles_per_token_rate: CyclesPerToken) { self.cycles_per_token_rate = cycles_per_token_rate; }
     fn posit_transfer_ledger_fee(&self) -> Option<u128> { self.posit_transfer_ledger_fee }
     fn is_less_than_minimum_position(&self) -> bool {
         self.cycles < minimum_cycles_match() || cycles_transform_tokens(self.cycles, self.cycles_per_token_rate) < minimum_tokens_match() 
     }
+    fn time_in_force(&self) -> TimeInForce { self.time_in_force.unwrap_or_default() }
+    fn post_only(&self) -> Option<PostOnly> { self.post_only }
+    fn expires_at_nanos(&self) -> Option<u128> { self.expires_at_nanos }
+    fn ledger_transfer_fee() -> u128 { localkey::cell::get(&CYCLES_BANK_TRANSFER_FEE) }
+    fn into_conditional_trade_quest(self) -> ConditionalTradeQuest { ConditionalTradeQuest::TradeCycles(self) }
     fn mid_call_balance_locks(cm_data: &mut CMData) -> &mut HashSet<Principal> { &mut cm_data.mid_call_user_cycles_balance_locks }
     fn posit_transfer(q: Icrc1TransferQuest) -> impl Future<Output=LedgerTransferReturnType> { cycles_transfer(q) }
     fn create_current_position(self, id: PositionId, positor: Principal) -> Self::MatcherPositionType {
@@ -89,20 +111,35 @@ impl TradeQuest for TradeCyclesQuest {
         }
     }
     fn matcher_positions(cm_data: &mut CMData) -> &mut BTreeMap<PositionId, Self::MatcherPositionType> { &mut cm_data.cycles_positions }    
+    fn matcher_positions_and_book(cm_data: &mut CMData) -> (&mut BTreeMap<PositionId, Self::MatcherPositionType>, &mut PositionBook) { (&mut cm_data.cycles_positions, &mut cm_data.cycles_positions_book) }
     fn matcher_void_positions(cm_data: &mut CMData) -> &mut BTreeMap<PositionId, <Self::MatcherPositionType as CurrentPositionTrait>::VoidPositionType> { &mut cm_data.void_cycles_positions }
-    fn match_trades(cm_data: &mut CMData, matcher_position_id: PositionId) {
+    fn matchee_positions_book(cm_data: &CMData) -> &PositionBook { &cm_data.token_positions_book }
+    fn match_trades(cm_data: &mut CMData, matcher_position_id: PositionId, matcher_is_a_market_order: bool) {
         crate::match_trades(
             matcher_position_id,
+            matcher_is_a_market_order,
             &mut cm_data.cycles_positions,
             &mut cm_data.token_positions,
+            &mut cm_data.cycles_positions_book,
+            &mut cm_data.token_positions_book,
             &mut cm_data.void_cycles_positions,
             &mut cm_data.void_token_positions,
             &mut cm_data.trade_logs,
             &mut cm_data.trade_logs_id_counter,
             &mut cm_data.candle_counter,  
             &mut cm_data.latest_trade_rate_data,
+            &mut cm_data.conditional_orders,
+            &mut cm_data.positions_expirations,
         );
     }
+    fn can_fill_completely(self, cm_data: &CMData, positor: Principal) -> bool {
+        crate::can_fill_completely(
+            self.create_current_position(0, positor),
+            &cm_data.token_positions,
+            &cm_data.token_positions_book,
+            cm_data.trade_logs.len(),
+        )
+    }
 }
 
 impl TradeQuest for TradeTokensQuest {
@@ -114,10 +151,16 @@ impl TradeQuest for TradeTokensQuest {
     
     fn quantity(&self) -> u128 { self.tokens }
     fn cycles_per_token_rate(&self) -> CyclesPerToken { self.cycles_per_token_rate }
+    fn set_cycles_per_token_rate(&mut self, cycles_per_token_rate: CyclesPerToken) { self.cycles_per_token_rate = cycles_per_token_rate; }
     fn posit_transfer_ledger_fee(&self) -> Option<u128> { self.posit_transfer_ledger_fee }
     fn is_less_than_minimum_position(&self) -> bool {
         self.tokens < minimum_tokens_match() || tokens_transform_cycles(self.tokens, self.cycles_per_token_rate) < minimum_cycles_match()
     }
+    fn time_in_force(&self) -> TimeInForce { self.time_in_force.unwrap_or_default() }
+    fn post_only(&self) -> Option<PostOnly> { self.post_only }
+    fn expires_at_nanos(&self) -> Option<u128> { self.expires_at_nanos }
+    fn ledger_transfer_fee() -> u128 { localkey::cell::get(&TOKEN_LEDGER_TRANSFER_FEE) }
+    fn into_conditional_trade_quest(self) -> ConditionalTradeQuest { ConditionalTradeQuest::TradeTokens(self) }
     fn mid_call_balance_locks(cm_data: &mut CMData) -> &mut HashSet<Principal> { &mut cm_data.mid_call_user_token_balance_locks }
     fn posit_transfer(q: Icrc1TransferQuest) -> impl Future<Output=LedgerTransferReturnType> { token_transfer(q) }
     fn create_current_position(self, id: PositionId, positor: Principal) -> Self::MatcherPositionType {
@@ -132,20 +175,35 @@ impl TradeQuest for TradeTokensQuest {
         }
     }
     fn matcher_positions(cm_data: &mut CMData) -> &mut BTreeMap<PositionId, Self::MatcherPositionType> { &mut cm_data.token_positions }
+    fn matcher_positions_and_book(cm_data: &mut CMData) -> (&mut BTreeMap<PositionId, Self::MatcherPositionType>, &mut PositionBook) { (&mut cm_data.token_positions, &mut cm_data.token_positions_book) }
     fn matcher_void_positions(cm_data: &mut CMData) -> &mut BTreeMap<PositionId, <Self::MatcherPositionType as CurrentPositionTrait>::VoidPositionType> { &mut cm_data.void_token_positions }     
-    fn match_trades(cm_data: &mut CMData, matcher_position_id: PositionId) {
+    fn matchee_positions_book(cm_data: &CMData) -> &PositionBook { &cm_data.cycles_positions_book }
+    fn match_trades(cm_data: &mut CMData, matcher_position_id: PositionId, matcher_is_a_market_order: bool) {
         crate::match_trades(
             matcher_position_id,
+            matcher_is_a_market_order,
             &mut cm_data.token_positions,
             &mut cm_data.cycles_positions,
+            &mut cm_data.token_positions_book,
+            &mut cm_data.cycles_positions_book,
             &mut cm_data.void_token_positions,
             &mut cm_data.void_cycles_positions,
             &mut cm_data.trade_logs,
             &mut cm_data.trade_logs_id_counter,
             &mut cm_data.candle_counter,  
             &mut cm_data.latest_trade_rate_data,  
+            &mut cm_data.conditional_orders,
+            &mut cm_data.positions_expirations,
         );
     }
+    fn can_fill_completely(self, cm_data: &CMData, positor: Principal) -> bool {
+        crate::can_fill_completely(
+            self.create_current_position(0, positor),
+            &cm_data.cycles_positions,
+            &cm_data.cycles_positions_book,
+            cm_data.trade_logs.len(),
+        )
+    }
 
 }
 
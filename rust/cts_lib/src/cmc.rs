@@ -16,9 +16,14 @@ use crate::{
     tools::{
         principal_icp_subaccount,
         time_nanos_u64,
+        call_error_as_u32_and_string,
     },
+    management_canister::ManagementCanisterOptionalCanisterSettings,
+    icrc::{IcrcId, Icrc2TransferFromQuest, Icrc2TransferFromError, icrc2_transfer_from},
 };
-use ic_cdk::api::call::{call_raw128};
+use ic_cdk::api::call::{call_raw128, call_with_payment128};
+use serde::Serialize;
+use serde_bytes::{ByteBuf, ByteArray};
 
 use candid::{CandidType, Deserialize, Principal, decode_one, encode_one};
 
@@ -88,6 +93,41 @@ pub async fn ledger_topup_cycles_cmc_icp_transfer(icp: IcpTokens, icp_transfer_f
 }
 
 
+#[derive(CandidType, Deserialize, Debug)]
+pub enum LedgerTopupCyclesCmcIcpTransferFromError {
+    IcpTransferFromCallError(CallError),
+    IcpTransferFromError(Icrc2TransferFromError),
+}
+
+// pulls the icp from an icrc2-approval of the from-account straight into the cmc top-up account of the topup_canister.
+// the cmc takes the icrc1-memo as the little-endian bytes of the top-up memo.
+pub async fn ledger_topup_cycles_cmc_icp_transfer_from(icp: IcpTokens, icp_transfer_fee: IcpTokens, from: IcrcId, topup_canister: Principal) -> Result<IcpBlockHeight, LedgerTopupCyclesCmcIcpTransferFromError> {
+    
+    let cmc_icp_transfer_block_height: IcpBlockHeight = match icrc2_transfer_from(
+        MAINNET_LEDGER_CANISTER_ID,
+        Icrc2TransferFromQuest{
+            spender_subaccount: None,
+            from,
+            to: IcrcId{ owner: MAINNET_CYCLES_MINTING_CANISTER_ID, subaccount: Some(ByteArray::new(principal_icp_subaccount(&topup_canister).0)) },
+            amount: icp.e8s() as u128,
+            fee: Some(icp_transfer_fee.e8s() as u128),
+            memo: Some(ByteBuf::from(ICP_LEDGER_TOP_UP_CANISTER_MEMO.0.to_le_bytes().to_vec())),
+            created_at_time: Some(time_nanos_u64()),
+        }
+    ).await {
+        Ok(transfer_from_call_sponse) => match transfer_from_call_sponse {
+            Ok(block_index) => block_index as IcpBlockHeight,
+            Err(transfer_from_error) => {
+                return Err(LedgerTopupCyclesCmcIcpTransferFromError::IcpTransferFromError(transfer_from_error));
+            }
+        },
+        Err(transfer_from_call_error) => {
+            return Err(LedgerTopupCyclesCmcIcpTransferFromError::IcpTransferFromCallError(transfer_from_call_error));
+        }
+    };
+    
+    Ok(cmc_icp_transfer_block_height)
+}
 #[derive(CandidType, Deserialize, Debug)]
 pub enum LedgerTopupCyclesCmcNotifyError {
     CmcNotifyTopUpQuestCandidEncodeError(String),
@@ -137,3 +177,38 @@ pub async fn ledger_topup_cycles_cmc_notify(cmc_icp_transfer_block_height: IcpBl
 
 
 
+// cmc create_canister
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CmcSubnetFilter {
+    pub subnet_type: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum CmcSubnetSelection {
+    Subnet{ subnet: Principal },
+    Filter(CmcSubnetFilter),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CmcCreateCanisterArgs {
+    pub settings: Option<ManagementCanisterOptionalCanisterSettings>,
+    pub subnet_selection: Option<CmcSubnetSelection>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum CmcCreateCanisterError {
+    Refunded{ refund_amount: u128, create_error: String },
+}
+
+// the cmc keeps the cycles for the new canister and sends back the cycles that it does not use.
+pub async fn cmc_create_canister(q: &CmcCreateCanisterArgs, with_cycles: Cycles) -> Result<Result<Principal, CmcCreateCanisterError>, CallError> {
+    call_with_payment128::<(&CmcCreateCanisterArgs,), (Result<Principal, CmcCreateCanisterError>,)>(
+        MAINNET_CYCLES_MINTING_CANISTER_ID,
+        "create_canister",
+        (q,),
+        with_cycles,
+    ).await
+    .map(|(r,)| r)
+    .map_err(call_error_as_u32_and_string)
+}
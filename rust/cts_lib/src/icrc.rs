@@ -1,10 +1,12 @@
 use crate::{
     types::CallError,
-    tools::call_error_as_u32_and_string,
+    tools::{call_error_as_u32_and_string, principal_as_thirty_bytes, thirty_bytes_as_principal},
 };
 use ic_cdk::call;
 use candid::{CandidType, Deserialize, Principal};
 use serde_bytes::{ByteBuf, ByteArray};
+use ic_stable_structures::{Storable, storable::Bound};
+use std::borrow::Cow;
 
 pub use icrc_ledger_types::{
     icrc1::{
@@ -14,9 +16,15 @@ pub use icrc_ledger_types::{
         }
     },
     icrc::generic_metadata_value::MetadataValue as IcrcMetadataValue,
+    icrc2::{
+        approve::ApproveError as Icrc2ApproveError,
+        transfer_from::TransferFromError as Icrc2TransferFromError,
+    },
 };
 
 pub mod icrc3;
+pub mod icrc4;
+pub mod icrc21;
 
 
 pub type IcrcSub = ByteArray<32>;
@@ -85,6 +93,33 @@ impl From<IcrcId> for icrc_ledger_types::icrc1::account::Account {
     }
 }
 
+#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+pub struct StorableIcrcId(pub IcrcId);
+impl Storable for StorableIcrcId {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut v = Vec::<u8>::new();
+        v.extend(&principal_as_thirty_bytes(&self.0.owner));
+        v.extend(self.0.effective_subaccount());
+        Cow::Owned(v)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let owner = thirty_bytes_as_principal(&bytes[..30].try_into().unwrap());
+        let subaccount: IcrcSubaccount = ByteArray::new(bytes[30..].try_into().unwrap());
+        Self(IcrcId{ owner, subaccount: if subaccount == *ICRC_DEFAULT_SUBACCOUNT { None } else { Some(subaccount) }})
+    }
+    const BOUND: Bound = {
+        Bound::Bounded{
+            max_size: 62,
+            is_fixed_size: true
+        }
+    };
+}
+impl From<IcrcId> for StorableIcrcId {
+    fn from(icrc_id: IcrcId) -> Self {
+        Self(icrc_id)
+    }
+}
+
 #[test]
 fn test_icrc_id_serialize() {
     use icrc_ledger_types::icrc1::account::Account;    
@@ -118,6 +153,41 @@ pub struct Icrc1TransferQuest {
     pub amount: u128,
 }
 
+#[derive(CandidType, serde::Serialize, Deserialize)]
+pub struct Icrc2ApproveQuest {
+    pub from_subaccount: Option<IcrcSub>,
+    pub spender: IcrcId,
+    pub amount: u128,
+    pub expected_allowance: Option<u128>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<u128>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, serde::Serialize, Deserialize)]
+pub struct Icrc2TransferFromQuest {
+    pub spender_subaccount: Option<IcrcSub>,
+    pub from: IcrcId,
+    pub to: IcrcId,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Icrc2AllowanceQuest {
+    pub account: IcrcId,
+    pub spender: IcrcId,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Icrc2Allowance {
+    pub allowance: u128,
+    pub expires_at: Option<u64>,
+}
+
 pub use u128 as BlockId;
 pub use u128 as Tokens;
 
@@ -132,6 +202,16 @@ pub async fn icrc1_transfer(icrc1_ledger_id: Principal, q: Icrc1TransferQuest) -
     .map(|(ir,): (Result<candid::Nat, Icrc1TransferError>,)| ir.map(|nat| nat.0.try_into().unwrap_or(0)))
 }
 
+pub async fn icrc2_transfer_from(icrc2_ledger_id: Principal, q: Icrc2TransferFromQuest) -> Result<Result<BlockId, Icrc2TransferFromError>, CallError> {
+    call(
+        icrc2_ledger_id,
+        "icrc2_transfer_from",
+        (q,),
+    ).await
+    .map_err(call_error_as_u32_and_string)
+    .map(|(ir,): (Result<candid::Nat, Icrc2TransferFromError>,)| ir.map(|nat| nat.0.try_into().unwrap_or(0)))
+}
+
 pub async fn icrc1_balance_of(icrc1_ledger_id: Principal, count_id: IcrcId) -> Result<Tokens, (u32, String)> {
     call(
         icrc1_ledger_id,
@@ -0,0 +1,217 @@
+// ICRC-21 canister-call consent messages.
+
+use candid::{CandidType, Deserialize};
+use serde_bytes::ByteBuf;
+use crate::icrc::IcrcId;
+
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Icrc21ConsentMessageMetadata {
+    pub language: String,
+    pub utc_offset_minutes: Option<i16>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Icrc21DeviceSpec {
+    GenericDisplay,
+    LineDisplay{ characters_per_line: u16, lines_per_page: u16 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Icrc21ConsentMessageSpec {
+    pub metadata: Icrc21ConsentMessageMetadata,
+    pub device_spec: Option<Icrc21DeviceSpec>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Icrc21ConsentMessageRequest {
+    pub method: String,
+    pub arg: ByteBuf,
+    pub user_preferences: Icrc21ConsentMessageSpec,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Icrc21LineDisplayPage {
+    pub lines: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Icrc21ConsentMessage {
+    GenericDisplayMessage(String),
+    LineDisplayMessage{ pages: Vec<Icrc21LineDisplayPage> },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Icrc21ConsentInfo {
+    pub consent_message: Icrc21ConsentMessage,
+    pub metadata: Icrc21ConsentMessageMetadata,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Icrc21ErrorInfo {
+    pub description: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Icrc21Error {
+    UnsupportedCanisterCall(Icrc21ErrorInfo),
+    ConsentMessageUnavailable(Icrc21ErrorInfo),
+    InsufficientPayment(Icrc21ErrorInfo),
+    GenericError{ error_code: u128, description: String },
+}
+
+pub type Icrc21ConsentMessageResponse = Result<Icrc21ConsentInfo, Icrc21Error>;
+
+
+// the languages that the consent messages are written in. the other languages get the english message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConsentLanguage {
+    En,
+    Es,
+}
+
+impl ConsentLanguage {
+    // takes the bcp-47 language tag of the request.
+    pub fn from_tag(tag: &str) -> Self {
+        match tag.split('-').next().unwrap_or("").to_lowercase().as_str() {
+            "es" => Self::Es,
+            _ => Self::En,
+        }
+    }
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::Es => "es",
+        }
+    }
+    pub fn text(&self, en: &'static str, es: &'static str) -> &'static str {
+        match self {
+            Self::En => en,
+            Self::Es => es,
+        }
+    }
+}
+
+
+// a consent message is a title and a list of (label, value) fields.
+pub struct ConsentMessageFields {
+    pub title: String,
+    pub fields: Vec<(String, String)>,
+}
+
+pub fn consent_info(message: ConsentMessageFields, user_preferences: &Icrc21ConsentMessageSpec, language: ConsentLanguage) -> Icrc21ConsentInfo {
+    let consent_message = match user_preferences.device_spec {
+        Some(Icrc21DeviceSpec::LineDisplay{ characters_per_line, lines_per_page }) => {
+            let mut text_lines: Vec<String> = vec![message.title];
+            for (label, value) in message.fields {
+                text_lines.push(format!("{}:", label));
+                text_lines.push(value);
+            }
+            Icrc21ConsentMessage::LineDisplayMessage{ pages: line_display_pages(&text_lines, characters_per_line, lines_per_page) }
+        }
+        _ => {
+            let mut s = format!("# {}\n", message.title);
+            for (label, value) in message.fields {
+                s.push_str(&format!("\n**{}:**\n{}\n", label, value));
+            }
+            Icrc21ConsentMessage::GenericDisplayMessage(s)
+        }
+    };
+    Icrc21ConsentInfo{
+        consent_message,
+        metadata: Icrc21ConsentMessageMetadata{
+            language: language.tag().to_string(),
+            utc_offset_minutes: user_preferences.metadata.utc_offset_minutes,
+        }
+    }
+}
+
+fn line_display_pages(text_lines: &[String], characters_per_line: u16, lines_per_page: u16) -> Vec<Icrc21LineDisplayPage> {
+    let characters_per_line: usize = std::cmp::max(characters_per_line, 1) as usize;
+    let lines_per_page: usize = std::cmp::max(lines_per_page, 1) as usize;
+    let mut lines: Vec<String> = Vec::new();
+    for text_line in text_lines {
+        let mut line = String::new();
+        for word in text_line.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            if line.chars().count() > 0 && line.chars().count() + 1 + word.len() > characters_per_line {
+                lines.push(std::mem::take(&mut line));
+            }
+            // a word longer than a line gets split.
+            while word.len() > characters_per_line {
+                if line.chars().count() > 0 {
+                    lines.push(std::mem::take(&mut line));
+                }
+                lines.push(word.drain(..characters_per_line).collect());
+            }
+            if line.chars().count() > 0 {
+                line.push(' ');
+            }
+            line.extend(word);
+        }
+        lines.push(line);
+    }
+    lines
+        .chunks(lines_per_page)
+        .map(|chunk| Icrc21LineDisplayPage{ lines: chunk.to_vec() })
+        .collect()
+}
+
+
+// ---- for the fields ----
+
+pub fn quantums_as_token_str(quantums: u128, decimal_places: u8) -> String {
+    let decimal_places = decimal_places as usize;
+    let mut s = format!("{:0>width$}", quantums, width = decimal_places + 1);
+    if decimal_places > 0 {
+        s.insert(s.len() - decimal_places, '.');
+        while s.ends_with('0') {
+            s.pop();
+        }
+        if s.ends_with('.') {
+            s.pop();
+        }
+    }
+    s
+}
+
+pub fn icrc_id_as_text(icrc_id: &IcrcId) -> String {
+    icrc_ledger_types::icrc1::account::Account::from(*icrc_id).to_string()
+}
+
+pub fn unsupported_canister_call(description: impl Into<String>) -> Icrc21Error {
+    Icrc21Error::UnsupportedCanisterCall(Icrc21ErrorInfo{ description: description.into() })
+}
+
+
+#[test]
+fn test_quantums_as_token_str() {
+    assert_eq!(quantums_as_token_str(1_500_000_000_000, 12), "1.5");
+    assert_eq!(quantums_as_token_str(10_000_000_000, 12), "0.01");
+    assert_eq!(quantums_as_token_str(7, 8), "0.00000007");
+    assert_eq!(quantums_as_token_str(0, 8), "0");
+    assert_eq!(quantums_as_token_str(123, 0), "123");
+    assert_eq!(quantums_as_token_str(100_000_000, 8), "1");
+}
+
+#[test]
+fn test_line_display_pages() {
+    let pages = line_display_pages(&["Transfer cycles".to_string(), "Amount:".to_string(), "1.5 TCY".to_string(), "abcdefghijklmnopqrstuvwxyz".to_string()], 10, 2);
+    assert_eq!(
+        pages,
+        vec![
+            Icrc21LineDisplayPage{ lines: vec!["Transfer".to_string(), "cycles".to_string()] },
+            Icrc21LineDisplayPage{ lines: vec!["Amount:".to_string(), "1.5 TCY".to_string()] },
+            Icrc21LineDisplayPage{ lines: vec!["abcdefghij".to_string(), "klmnopqrst".to_string()] },
+            Icrc21LineDisplayPage{ lines: vec!["uvwxyz".to_string()] },
+        ]
+    );
+}
+
+#[test]
+fn test_consent_language_from_tag() {
+    assert_eq!(ConsentLanguage::from_tag("es-ES"), ConsentLanguage::Es);
+    assert_eq!(ConsentLanguage::from_tag("ES"), ConsentLanguage::Es);
+    assert_eq!(ConsentLanguage::from_tag("en-US"), ConsentLanguage::En);
+    assert_eq!(ConsentLanguage::from_tag("fr"), ConsentLanguage::En);
+}
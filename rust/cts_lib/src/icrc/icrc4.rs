@@ -0,0 +1,39 @@
+// ICRC-4 batch transfers.
+
+use candid::{CandidType, Deserialize, Nat};
+use crate::icrc::{Icrc1TransferError, BlockId};
+
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Icrc4TransferBatchError {
+    BadFee{ expected_fee: Nat },
+    BadBurn{ min_burn_amount: Nat },
+    InsufficientFunds{ balance: Nat },
+    TooOld,
+    CreatedInFuture{ ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate{ duplicate_of: Nat },
+    GenericError{ error_code: Nat, message: String },
+    GenericBatchError{ error_code: Nat, message: String },
+    TooManyRequests{ limit: Nat },
+}
+
+impl From<Icrc1TransferError> for Icrc4TransferBatchError {
+    fn from(e: Icrc1TransferError) -> Self {
+        match e {
+            Icrc1TransferError::BadFee{ expected_fee } => Self::BadFee{ expected_fee },
+            Icrc1TransferError::BadBurn{ min_burn_amount } => Self::BadBurn{ min_burn_amount },
+            Icrc1TransferError::InsufficientFunds{ balance } => Self::InsufficientFunds{ balance },
+            Icrc1TransferError::TooOld => Self::TooOld,
+            Icrc1TransferError::CreatedInFuture{ ledger_time } => Self::CreatedInFuture{ ledger_time },
+            Icrc1TransferError::TemporarilyUnavailable => Self::TemporarilyUnavailable,
+            Icrc1TransferError::Duplicate{ duplicate_of } => Self::Duplicate{ duplicate_of },
+            Icrc1TransferError::GenericError{ error_code, message } => Self::GenericError{ error_code, message },
+        }
+    }
+}
+
+pub type Icrc4TransferBatchResult = Result<BlockId, Icrc4TransferBatchError>;
+
+// one result for each transfer in the batch, in the same order. None if the transfer was not processed.
+pub type Icrc4TransferBatchResults = Vec<Option<Icrc4TransferBatchResult>>;
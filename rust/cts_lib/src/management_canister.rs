@@ -45,7 +45,7 @@ pub struct ManagementCanisterCreateCanisterQuest {
     pub settings : Option<ManagementCanisterOptionalCanisterSettings>
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct ManagementCanisterOptionalCanisterSettings {
     pub controllers : Option<Vec<Principal>>,
     pub compute_allocation : Option<u128>,
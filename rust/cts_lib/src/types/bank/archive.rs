@@ -0,0 +1,75 @@
+use candid::{CandidType, Deserialize, Principal};
+use crate::types::{Cycles, bank::{log_types::Log, icrc3::FeeCollectorChanges}};
+use crate::consts::TRILLION;
+
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveConfig {
+    // when the number of blocks held on the bank reaches this threshold, the bank moves the oldest blocks into the archive-canisters.
+    pub trigger_threshold: u64,
+    // the number of blocks to move into the archive-canisters each time the trigger_threshold is reached.
+    pub num_blocks_to_archive: u64,
+    // the max number of blocks a single archive-canister holds before the bank creates a new archive-canister.
+    pub max_blocks_per_archive: u64,
+    pub cycles_for_archive_creation: Cycles,
+}
+
+impl Default for ArchiveConfig {
+    #[cfg(not(debug_assertions))]
+    fn default() -> Self {
+        Self {
+            trigger_threshold: 200_000,
+            num_blocks_to_archive: 100_000,
+            max_blocks_per_archive: 5_000_000,
+            cycles_for_archive_creation: 10 * TRILLION,
+        }
+    }
+    #[cfg(debug_assertions)]
+    fn default() -> Self {
+        Self {
+            trigger_threshold: 20,
+            num_blocks_to_archive: 10,
+            max_blocks_per_archive: 15,
+            cycles_for_archive_creation: 1 * TRILLION,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum SetArchiveConfigError {
+    NumBlocksToArchiveMustBeLessThanTheTriggerThreshold,
+    NumBlocksToArchiveMustBeGreaterThanZero,
+    MaxBlocksPerArchiveMustBeGreaterThanZero,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveCanisterData {
+    pub canister_id: Principal,
+    pub start: u64, // the id of the first block in this archive.
+    pub length: u64,
+    pub is_full: bool,
+    pub module_hash: [u8; 32],
+}
+
+
+// archive-canister types
+
+#[derive(CandidType, Deserialize)]
+pub struct BankArchiveInit {
+    pub bank_id: Principal,
+    pub first_block_id: u64,
+    pub max_blocks: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AppendBlocksQuest {
+    pub first_block_id: u64,
+    pub blocks: Vec<Log>,
+    pub fee_collector_changes: FeeCollectorChanges, // for the fee_col field of the blocks. only the changes for the blocks of the quest.
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum AppendBlocksError {
+    ArchiveIsFull,
+    BlocksGap{ next_block_id: u64 },
+}
@@ -1,14 +1,43 @@
 use serde::Serialize;
 use candid::{CandidType, Principal, Deserialize};
-use serde_bytes::{ByteBuf, Bytes};
+use serde_bytes::{ByteBuf, ByteArray, Bytes};
 use crate::{
-    types::bank::log_types::{Log, Operation, MintKind},
+    types::bank::log_types::{Log, LogTX, Operation, MintKind},
     icrc::{IcrcId, icrc3::{Icrc3Value, Icrc3Map}},
 };
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use std::collections::BTreeMap;
 
 
+// the fee-collector is not in the stored Log so that the Log stays within its max storable size. 
+// each entry is the first block-id that the fee-collector is set for. the fee-collector of a block is the one of the latest entry at or before that block. 
+pub type FeeCollectorChanges = Vec<(u64, Option<IcrcId>)>;
 
-pub fn icrc3_value_of_a_block_log<'a>(log: &'a Log) -> Icrc3Value<'a> {
+pub fn fee_collector_of_the_block(fee_collector_changes: &FeeCollectorChanges, block_id: u64) -> Option<&IcrcId> {
+    match fee_collector_changes.partition_point(|(start_block_id, _)| *start_block_id <= block_id) {
+        0 => None,
+        i => fee_collector_changes[i - 1].1.as_ref(),
+    }
+}
+
+// the changes for the blocks first_block_id..end_block_id. the latest change at or before the first block, and the changes within the range.
+pub fn fee_collector_changes_of_the_blocks(fee_collector_changes: &FeeCollectorChanges, first_block_id: u64, end_block_id: u64) -> FeeCollectorChanges {
+    let start: usize = fee_collector_changes.partition_point(|(start_block_id, _)| *start_block_id <= first_block_id).saturating_sub(1);
+    let end: usize = fee_collector_changes.partition_point(|(start_block_id, _)| *start_block_id < end_block_id);
+    fee_collector_changes[start..std::cmp::max(start, end)].to_vec()
+}
+
+// for the archives that get the changes chunk by chunk. the new changes replace the changes at and after the first new change.
+pub fn merge_fee_collector_changes(fee_collector_changes: &mut FeeCollectorChanges, new_changes: FeeCollectorChanges) {
+    if let Some((first_new_change_block_id, _)) = new_changes.first() {
+        let keep: usize = fee_collector_changes.partition_point(|(start_block_id, _)| start_block_id < first_new_change_block_id);
+        fee_collector_changes.truncate(keep);
+        fee_collector_changes.extend(new_changes);
+    }
+}
+
+
+pub fn icrc3_value_of_a_block_log<'a>(log: &'a Log, fee_col: Option<&'a IcrcId>) -> Icrc3Value<'a> {
     let mut tx = Icrc3Map::from_iter([
         ("amt", Icrc3Value::Nat(log.tx.amt)),
     ]);
@@ -42,7 +71,22 @@ pub fn icrc3_value_of_a_block_log<'a>(log: &'a Log) -> Icrc3Value<'a> {
         }
         Operation::Xfer{ ref from, ref to } => {
             tx.insert("from", icrc3_value_of_an_icrc_id(&from));
-            tx.insert("to", icrc3_value_of_an_icrc_id(&to));            
+            tx.insert("to", icrc3_value_of_an_icrc_id(&to));
+        }
+        Operation::Approve{ ref from, ref spender, expected_allowance, expires_at } => {
+            tx.insert("from", icrc3_value_of_an_icrc_id(&from));
+            tx.insert("spender", icrc3_value_of_an_icrc_id(&spender));
+            if let Some(expected_allowance) = expected_allowance {
+                tx.insert("expected_allowance", Icrc3Value::Nat(expected_allowance));
+            }
+            if let Some(expires_at) = expires_at {
+                tx.insert("expires_at", Icrc3Value::Nat(expires_at.into()));
+            }
+        }
+        Operation::XferFrom{ ref from, ref to, ref spender } => {
+            tx.insert("from", icrc3_value_of_an_icrc_id(&from));
+            tx.insert("to", icrc3_value_of_an_icrc_id(&to));
+            tx.insert("spender", icrc3_value_of_an_icrc_id(&spender));
         }
     }
     
@@ -57,6 +101,10 @@ pub fn icrc3_value_of_a_block_log<'a>(log: &'a Log) -> Icrc3Value<'a> {
     if let Some(fee) = log.fee {
         map.insert("fee", Icrc3Value::Nat(fee));
     }
+    // the fee_col is only on the blocks that pay a fee.
+    if let Some(fee_col) = fee_col.filter(|_| log.effective_fee() > 0) {
+        map.insert("fee_col", icrc3_value_of_an_icrc_id(fee_col));
+    }
     
     Icrc3Value::Map(map)
 }
@@ -72,6 +120,102 @@ fn icrc3_value_of_an_icrc_id<'a>(icrc_id: &'a IcrcId) -> Icrc3Value<'a> {
 }
 
 
+// the other way around of the icrc3_value_of_a_block_log. for the bank-index that syncs the blocks through the icrc3_get_blocks method.
+// returns the block-log and the fee_col of the block.
+pub fn block_log_of_an_icrc3_value(value: &ICRC3Value) -> Result<(Log, Option<IcrcId>), String> {
+    let map = icrc3_map(value)?;
+    let tx = icrc3_map(map.get("tx").ok_or("missing tx")?)?;
+
+    let op: Operation = match icrc3_text(map.get("btype").ok_or("missing btype")?)? {
+        "1mint" => Operation::Mint{
+            to: icrc_id_of_an_icrc3_value(tx.get("to").ok_or("missing to")?)?,
+            kind: match icrc3_text(tx.get("kind").ok_or("missing kind")?)? {
+                "cycin" => MintKind::CyclesIn{ from_canister: icrc3_principal(tx.get("can").ok_or("missing can")?)? },
+                "cmc" => MintKind::CMC{
+                    caller: icrc3_principal(tx.get("callr").ok_or("missing callr")?)?,
+                    icp_block_height: icrc3_nat_u64(tx.get("icpb").ok_or("missing icpb")?)?,
+                },
+                kind => return Err(format!("unknown mint kind: {}", kind)),
+            },
+        },
+        "1burn" => Operation::Burn{
+            from: icrc_id_of_an_icrc3_value(tx.get("from").ok_or("missing from")?)?,
+            for_canister: icrc3_principal(tx.get("can").ok_or("missing can")?)?,
+        },
+        "1xfer" => Operation::Xfer{
+            from: icrc_id_of_an_icrc3_value(tx.get("from").ok_or("missing from")?)?,
+            to: icrc_id_of_an_icrc3_value(tx.get("to").ok_or("missing to")?)?,
+        },
+        "2approve" => Operation::Approve{
+            from: icrc_id_of_an_icrc3_value(tx.get("from").ok_or("missing from")?)?,
+            spender: icrc_id_of_an_icrc3_value(tx.get("spender").ok_or("missing spender")?)?,
+            expected_allowance: tx.get("expected_allowance").map(icrc3_nat).transpose()?,
+            expires_at: tx.get("expires_at").map(icrc3_nat_u64).transpose()?,
+        },
+        "2xfer" => Operation::XferFrom{
+            from: icrc_id_of_an_icrc3_value(tx.get("from").ok_or("missing from")?)?,
+            to: icrc_id_of_an_icrc3_value(tx.get("to").ok_or("missing to")?)?,
+            spender: icrc_id_of_an_icrc3_value(tx.get("spender").ok_or("missing spender")?)?,
+        },
+        btype => return Err(format!("unknown btype: {}", btype)),
+    };
+
+    let log = Log{
+        phash: match map.get("phash") {
+            Some(v) => Some(ByteArray::new(icrc3_blob(v)?.try_into().map_err(|_| "phash must be 32 bytes")?)),
+            None => None,
+        },
+        ts: icrc3_nat_u64(map.get("ts").ok_or("missing ts")?)?,
+        fee: map.get("fee").map(icrc3_nat).transpose()?,
+        tx: LogTX{
+            op,
+            fee: tx.get("fee").map(icrc3_nat).transpose()?,
+            amt: icrc3_nat(tx.get("amt").ok_or("missing amt")?)?,
+            memo: match tx.get("memo") {
+                Some(v) => Some(ByteBuf::from(icrc3_blob(v)?.to_vec())),
+                None => None,
+            },
+            ts: tx.get("ts").map(icrc3_nat_u64).transpose()?,
+        },
+    };
+    let fee_col: Option<IcrcId> = map.get("fee_col").map(icrc_id_of_an_icrc3_value).transpose()?;
+
+    Ok((log, fee_col))
+}
+
+fn icrc3_map(v: &ICRC3Value) -> Result<&BTreeMap<String, ICRC3Value>, String> {
+    match v { ICRC3Value::Map(m) => Ok(m), _ => Err("expected a map".to_string()) }
+}
+fn icrc3_text(v: &ICRC3Value) -> Result<&str, String> {
+    match v { ICRC3Value::Text(t) => Ok(t.as_str()), _ => Err("expected a text".to_string()) }
+}
+fn icrc3_blob(v: &ICRC3Value) -> Result<&[u8], String> {
+    match v { ICRC3Value::Blob(b) => Ok(&b[..]), _ => Err("expected a blob".to_string()) }
+}
+fn icrc3_nat(v: &ICRC3Value) -> Result<u128, String> {
+    match v { ICRC3Value::Nat(n) => u128::try_from(&n.0).map_err(|_| "nat too large".to_string()), _ => Err("expected a nat".to_string()) }
+}
+fn icrc3_nat_u64(v: &ICRC3Value) -> Result<u64, String> {
+    icrc3_nat(v)?.try_into().map_err(|_| "nat too large".to_string())
+}
+fn icrc3_principal(v: &ICRC3Value) -> Result<Principal, String> {
+    Principal::try_from_slice(icrc3_blob(v)?).map_err(|e| format!("{:?}", e))
+}
+fn icrc_id_of_an_icrc3_value(v: &ICRC3Value) -> Result<IcrcId, String> {
+    match v {
+        ICRC3Value::Array(a) => match &a[..] {
+            [owner] => Ok(IcrcId{ owner: icrc3_principal(owner)?, subaccount: None }),
+            [owner, subaccount] => Ok(IcrcId{
+                owner: icrc3_principal(owner)?,
+                subaccount: Some(ByteArray::new(icrc3_blob(subaccount)?.try_into().map_err(|_| "subaccount must be 32 bytes")?)),
+            }),
+            _ => Err("an account is an array of 1 or 2 blobs".to_string()),
+        },
+        _ => Err("expected an array".to_string()),
+    }
+}
+
+
 #[test]
 fn test_icrc3_block_hash() {
     use candid::Principal;
@@ -93,12 +237,99 @@ fn test_icrc3_block_hash() {
         }
     };
     
-    let icrc3_value = icrc3_value_of_a_block_log(&block);
+    let icrc3_value = icrc3_value_of_a_block_log(&block, None);
     
     println!("{:?}", hex::encode(icrc3_value.hash()));
     
 }
 
+#[test]
+fn test_block_log_of_an_icrc3_value() {
+    let icrc_id_1 = IcrcId{ owner: Principal::from_slice(&[0,1,2,3,4]), subaccount: None };
+    let icrc_id_2 = IcrcId{ owner: Principal::from_slice(&[5,6,7,8,9]), subaccount: Some(ByteArray::new([3; 32])) };
+    let logs = vec![
+        Log{
+            phash: None,
+            ts: 123456,
+            fee: Some(1000),
+            tx: LogTX{ op: Operation::Mint{ to: icrc_id_1, kind: MintKind::CMC{ caller: icrc_id_1.owner, icp_block_height: 55 } }, fee: None, amt: 5000000, memo: None, ts: None },
+        },
+        Log{
+            phash: Some(ByteArray::new([1; 32])),
+            ts: 123457,
+            fee: None,
+            tx: LogTX{ op: Operation::Burn{ from: icrc_id_2, for_canister: icrc_id_1.owner }, fee: Some(1000), amt: 3000, memo: Some(ByteBuf::from(vec![1,2,3])), ts: Some(123450) },
+        },
+        Log{
+            phash: Some(ByteArray::new([2; 32])),
+            ts: 123458,
+            fee: Some(1000),
+            tx: LogTX{ op: Operation::Approve{ from: icrc_id_1, spender: icrc_id_2, expected_allowance: Some(7), expires_at: Some(999999) }, fee: None, amt: 300, memo: None, ts: None },
+        },
+        Log{
+            phash: Some(ByteArray::new([3; 32])),
+            ts: 123459,
+            fee: Some(1000),
+            tx: LogTX{ op: Operation::XferFrom{ from: icrc_id_1, to: icrc_id_1, spender: icrc_id_2 }, fee: None, amt: 200, memo: None, ts: None },
+        },
+        Log{
+            phash: Some(ByteArray::new([4; 32])),
+            ts: 123460,
+            fee: Some(0),
+            tx: LogTX{ op: Operation::Xfer{ from: icrc_id_2, to: icrc_id_1 }, fee: None, amt: 100, memo: None, ts: None },
+        },
+    ];
+    for fee_col in [None, Some(icrc_id_2)] {
+        for log in logs.iter() {
+            let value = ICRC3Value::from(&icrc3_value_of_a_block_log(log, fee_col.as_ref()));
+            let (decoded_log, decoded_fee_col) = block_log_of_an_icrc3_value(&value).unwrap();
+            assert_eq!(&decoded_log, log);
+            assert_eq!(decoded_fee_col, fee_col.filter(|_| log.effective_fee() > 0));
+        }
+    }
+}
+
+#[test]
+fn test_fee_collector_of_the_block() {
+    let fee_collector_1 = IcrcId{ owner: Principal::from_slice(&[0,1,2,3,4]), subaccount: None };
+    let fee_collector_2 = IcrcId{ owner: Principal::from_slice(&[5,6,7,8,9]), subaccount: None };
+    let fee_collector_changes: FeeCollectorChanges = vec![
+        (5, Some(fee_collector_1)),
+        (9, None),
+        (12, Some(fee_collector_2)),
+    ];
+    assert_eq!(fee_collector_of_the_block(&vec![], 0), None);
+    assert_eq!(fee_collector_of_the_block(&fee_collector_changes, 4), None);
+    assert_eq!(fee_collector_of_the_block(&fee_collector_changes, 5), Some(&fee_collector_1));
+    assert_eq!(fee_collector_of_the_block(&fee_collector_changes, 8), Some(&fee_collector_1));
+    assert_eq!(fee_collector_of_the_block(&fee_collector_changes, 9), None);
+    assert_eq!(fee_collector_of_the_block(&fee_collector_changes, 12), Some(&fee_collector_2));
+    assert_eq!(fee_collector_of_the_block(&fee_collector_changes, u64::MAX), Some(&fee_collector_2));
+}
+
+#[test]
+fn test_fee_collector_changes_of_the_blocks() {
+    let fee_collector_1 = IcrcId{ owner: Principal::from_slice(&[0,1,2,3,4]), subaccount: None };
+    let fee_collector_2 = IcrcId{ owner: Principal::from_slice(&[5,6,7,8,9]), subaccount: None };
+    let fee_collector_changes: FeeCollectorChanges = vec![
+        (5, Some(fee_collector_1)),
+        (9, None),
+        (12, Some(fee_collector_2)),
+    ];
+    assert_eq!(fee_collector_changes_of_the_blocks(&fee_collector_changes, 0, 5), vec![]);
+    assert_eq!(fee_collector_changes_of_the_blocks(&fee_collector_changes, 0, 6), vec![(5, Some(fee_collector_1))]);
+    assert_eq!(fee_collector_changes_of_the_blocks(&fee_collector_changes, 6, 9), vec![(5, Some(fee_collector_1))]);
+    assert_eq!(fee_collector_changes_of_the_blocks(&fee_collector_changes, 9, 13), vec![(9, None), (12, Some(fee_collector_2))]);
+    assert_eq!(fee_collector_changes_of_the_blocks(&fee_collector_changes, 100, 200), vec![(12, Some(fee_collector_2))]);
+    
+    // the archive gets the changes chunk by chunk, and the same chunk again on a retry.
+    let mut archive_fee_collector_changes: FeeCollectorChanges = vec![];
+    for (first_block_id, end_block_id) in [(0, 6), (6, 10), (6, 10), (10, 20)] {
+        merge_fee_collector_changes(&mut archive_fee_collector_changes, fee_collector_changes_of_the_blocks(&fee_collector_changes, first_block_id, end_block_id));
+    }
+    assert_eq!(archive_fee_collector_changes, fee_collector_changes);
+}
+
 
 #[derive(CandidType, Deserialize, Copy, Clone)]
 pub struct StartAndLength{
@@ -0,0 +1,132 @@
+// types of the bank-index canister. the bank-index speaks the icrc index-ng candid.
+
+use candid::{CandidType, Deserialize, Principal};
+use serde_bytes::ByteBuf;
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use crate::{
+    types::Cycles,
+    icrc::{IcrcId, IcrcSubaccount},
+    types::bank::icrc3::{GetBlocksArgs, Icrc3Callback},
+};
+
+
+#[derive(CandidType, Deserialize)]
+pub struct BankIndexInit {
+    pub bank_id: Principal,
+}
+
+
+// index-ng types
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccountTransactionsArgs {
+    pub account: IcrcId,
+    // the id of the last transaction seen by the client. the results start with the next older transaction. none for the latest transactions.
+    pub start: Option<u128>,
+    pub max_results: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TransactionWithId {
+    pub id: u128,
+    pub transaction: Transaction,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    pub kind: String,
+    pub mint: Option<Mint>,
+    pub burn: Option<Burn>,
+    pub transfer: Option<Transfer>,
+    pub approve: Option<Approve>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Mint {
+    pub to: IcrcId,
+    pub amount: Cycles,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Burn {
+    pub from: IcrcId,
+    pub spender: Option<IcrcId>,
+    pub amount: Cycles,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    pub from: IcrcId,
+    pub to: IcrcId,
+    pub spender: Option<IcrcId>,
+    pub amount: Cycles,
+    pub fee: Option<Cycles>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Approve {
+    pub from: IcrcId,
+    pub spender: IcrcId,
+    pub amount: Cycles,
+    pub expected_allowance: Option<Cycles>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Cycles>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GetTransactions {
+    pub balance: Cycles,
+    pub transactions: Vec<TransactionWithId>,
+    // the id of the oldest transaction of the account. none if the account has no transactions.
+    pub oldest_tx_id: Option<u128>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GetTransactionsErr {
+    pub message: String,
+}
+
+pub type GetTransactionsResult = Result<GetTransactions, GetTransactionsErr>;
+
+#[derive(CandidType, Deserialize)]
+pub struct ListSubaccountsArgs {
+    pub owner: Principal,
+    // the last subaccount seen by the client. the results start after this subaccount.
+    pub start: Option<IcrcSubaccount>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct Status {
+    pub num_blocks_synced: u128,
+}
+
+
+// for the bank-index to take the icrc3_get_blocks sponses of the bank and of the archive-canisters.
+
+#[derive(CandidType, Deserialize)]
+pub struct Icrc3GetBlocksSponse {
+    pub log_length: u128,
+    pub blocks: Vec<Icrc3BlockWithId>,
+    pub archived_blocks: Vec<Icrc3ArchivedBlocks>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Icrc3BlockWithId {
+    pub id: u128,
+    pub block: ICRC3Value,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Icrc3ArchivedBlocks {
+    pub args: GetBlocksArgs,
+    pub callback: Icrc3Callback,
+}
@@ -3,13 +3,13 @@ use serde::{Serialize, Deserialize};
 use candid::{CandidType, Principal};
 use crate::types::Cycles;
 use serde_bytes::{ByteBuf, ByteArray};
-use crate::icrc::IcrcId;
+use crate::icrc::{IcrcId, StorableIcrcId};
 use ic_stable_structures::{Storable, storable::Bound};
 
 // POSTCARD-SERIALIZATION
 // Warning! postcard correctness counts on the specific sequence of the fields in the structs from top to bottom staying the same for both forwards and backwards.
 
-// CandidType is temp while we still using the get_logs_backwards method.
+// CandidType is for the get_logs_backwards method and for sending the blocks to the archive-canisters.
 
 
 #[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -20,6 +20,54 @@ pub struct Log {
     pub tx: LogTX,
 }
 
+impl Log {
+    // the fee of the block, whether the user set it in the request or not.
+    pub fn effective_fee(&self) -> Cycles {
+        self.tx.fee.or(self.fee).unwrap_or(0)
+    }
+    // the (credit, debit) of the account by this block, not counting the fee-collector.
+    // the burn amount includes the fee so the burn debit is just the amount.
+    pub fn balance_change(&self, icrc_id: &IcrcId) -> (Cycles, Cycles) {
+        let (mut credit, mut debit): (Cycles, Cycles) = (0, 0);
+        match self.tx.op {
+            Operation::Mint{ ref to, .. } => {
+                if to == icrc_id { credit += self.tx.amt; }
+            }
+            Operation::Burn{ ref from, .. } => {
+                if from == icrc_id { debit += self.tx.amt; }
+            }
+            Operation::Xfer{ ref from, ref to } | Operation::XferFrom{ ref from, ref to, .. } => {
+                if from == icrc_id { debit += self.tx.amt.saturating_add(self.effective_fee()); }
+                if to == icrc_id { credit += self.tx.amt; }
+            }
+            Operation::Approve{ ref from, .. } => {
+                if from == icrc_id { debit += self.effective_fee(); }
+            }
+        }
+        (credit, debit)
+    }
+    // the (added, removed) cycles of the total-supply by this block. 
+    // the fee gets burned unless there is a fee-collector for the block.
+    pub fn supply_change(&self, fee_is_collected: bool) -> (Cycles, Cycles) {
+        let (mut added, mut removed): (Cycles, Cycles) = match self.tx.op {
+            Operation::Mint{ .. } => (self.tx.amt, 0),
+            Operation::Burn{ .. } => (0, self.tx.amt),
+            Operation::Xfer{ .. } | Operation::XferFrom{ .. } | Operation::Approve{ .. } => (0, self.effective_fee()),
+        };
+        if fee_is_collected {
+            added = added.saturating_add(self.effective_fee());
+        }
+        if added >= removed {
+            added -= removed;
+            removed = 0;
+        } else {
+            removed -= added;
+            added = 0;
+        }
+        (added, removed)
+    }
+}
+
 #[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct LogTX {
     pub op: Operation,
@@ -33,7 +81,9 @@ pub struct LogTX {
 pub enum Operation {
     Mint{ to: IcrcId, kind: MintKind },
     Burn{ from: IcrcId, for_canister: Principal },
-    Xfer{ from: IcrcId, to: IcrcId } 
+    Xfer{ from: IcrcId, to: IcrcId },
+    Approve{ from: IcrcId, spender: IcrcId, expected_allowance: Option<Cycles>, expires_at: Option<u64> },
+    XferFrom{ from: IcrcId, to: IcrcId, spender: IcrcId },
 }
 
 impl Operation {
@@ -42,8 +92,23 @@ impl Operation {
             Self::Mint{ .. } => "1mint",
             Self::Burn{ .. } => "1burn", 
             Self::Xfer{ .. } => "1xfer",
+            Self::Approve{ .. } => "2approve",
+            Self::XferFrom{ .. } => "2xfer",
         }
     }
+    // the accounts that see this block in their transaction history.
+    pub fn icrc_ids(&self) -> Vec<IcrcId> {
+        let mut icrc_ids: Vec<IcrcId> = match *self {
+            Self::Mint{ to, .. } => vec![to],
+            Self::Burn{ from, .. } => vec![from],
+            Self::Xfer{ from, to } => vec![from, to],
+            Self::Approve{ from, spender, .. } => vec![from, spender],
+            Self::XferFrom{ from, to, spender } => vec![from, to, spender],
+        };
+        icrc_ids.sort();
+        icrc_ids.dedup();
+        icrc_ids
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -71,8 +136,80 @@ impl Storable for Log {
 }
 
 
+// key for the per-account block indexes of the bank and of the bank-index.
+#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+pub struct UserLogKey {
+    pub account: IcrcId,
+    pub block: u64,
+}
+impl Storable for UserLogKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut v = Vec::<u8>::new();
+        v.extend(StorableIcrcId(self.account).to_bytes().iter());
+        v.extend(self.block.to_be_bytes());
+        Cow::Owned(v)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            account: StorableIcrcId::from_bytes(Cow::Borrowed(&bytes[..62])).0,
+            block: u64::from_be_bytes(bytes[62..].try_into().unwrap()),
+        }
+    }
+    const BOUND: Bound = {
+        Bound::Bounded{
+            max_size: 70,
+            is_fixed_size: true
+        }
+    };
+}
+
 
+#[test]
+fn test_log_balance_change() {
+    let a = IcrcId{ owner: Principal::from_slice(&[1]), subaccount: None };
+    let b = IcrcId{ owner: Principal::from_slice(&[2]), subaccount: None };
+    let c = IcrcId{ owner: Principal::from_slice(&[3]), subaccount: None };
+    let log = |op: Operation, fee: Option<Cycles>, tx_fee: Option<Cycles>| Log{
+        phash: None,
+        ts: 0,
+        fee,
+        tx: LogTX{ op, fee: tx_fee, amt: 100, memo: None, ts: None },
+    };
+    let xfer = log(Operation::Xfer{ from: a, to: b }, Some(10), None);
+    assert_eq!(xfer.balance_change(&a), (0, 110));
+    assert_eq!(xfer.balance_change(&b), (100, 0));
+    assert_eq!(xfer.balance_change(&c), (0, 0));
+    assert_eq!(log(Operation::Xfer{ from: a, to: a }, None, Some(10)).balance_change(&a), (100, 110));
+    assert_eq!(log(Operation::XferFrom{ from: a, to: b, spender: c }, None, Some(10)).balance_change(&c), (0, 0));
+    assert_eq!(log(Operation::XferFrom{ from: a, to: b, spender: c }, None, Some(10)).balance_change(&a), (0, 110));
+    assert_eq!(log(Operation::Approve{ from: a, spender: b, expected_allowance: None, expires_at: None }, Some(10), None).balance_change(&a), (0, 10));
+    assert_eq!(log(Operation::Approve{ from: a, spender: b, expected_allowance: None, expires_at: None }, Some(10), None).balance_change(&b), (0, 0));
+    assert_eq!(log(Operation::Mint{ to: a, kind: MintKind::CyclesIn{ from_canister: c.owner } }, Some(10), None).balance_change(&a), (100, 0));
+    assert_eq!(log(Operation::Burn{ from: a, for_canister: c.owner }, Some(10), None).balance_change(&a), (0, 100));
+}
 
+#[test]
+fn test_log_supply_change() {
+    let a = IcrcId{ owner: Principal::from_slice(&[1]), subaccount: None };
+    let b = IcrcId{ owner: Principal::from_slice(&[2]), subaccount: None };
+    let log = |op: Operation, fee: Option<Cycles>, tx_fee: Option<Cycles>| Log{
+        phash: None,
+        ts: 0,
+        fee,
+        tx: LogTX{ op, fee: tx_fee, amt: 100, memo: None, ts: None },
+    };
+    let xfer = log(Operation::Xfer{ from: a, to: b }, Some(10), None);
+    assert_eq!(xfer.supply_change(false), (0, 10));
+    assert_eq!(xfer.supply_change(true), (0, 0));
+    let mint = log(Operation::Mint{ to: a, kind: MintKind::CyclesIn{ from_canister: b.owner } }, None, Some(10));
+    assert_eq!(mint.supply_change(false), (100, 0));
+    assert_eq!(mint.supply_change(true), (110, 0));
+    let burn = log(Operation::Burn{ from: a, for_canister: b.owner }, Some(10), None);
+    assert_eq!(burn.supply_change(false), (0, 100));
+    assert_eq!(burn.supply_change(true), (0, 90));
+    assert_eq!(log(Operation::Burn{ from: a, for_canister: b.owner }, None, None).supply_change(true), (0, 100));
+    assert_eq!(log(Operation::Approve{ from: a, spender: b, expected_allowance: None, expires_at: None }, Some(10), None).supply_change(false), (0, 10));
+}
 
 #[test]
 fn test_bank_log_serialization_size() {
@@ -122,4 +259,29 @@ fn test_bank_log_serialization_size() {
     println!("{}", ser.len());
     assert!(ser.len() <= LOG_STORABLE_MAX_SIZE as usize);
     
+    log.tx.op = Operation::Approve{
+        from: full_size_icrc_id,
+        spender: full_size_icrc_id,
+        expected_allowance: Some(u128::MAX),
+        expires_at: Some(u64::MAX),
+    };
+    let ser = log.to_bytes();
+    println!("{}", ser.len());
+    assert!(ser.len() <= LOG_STORABLE_MAX_SIZE as usize);
+    
+    // the 2xfer block has three accounts so it only fits with the real-world numbers. 
+    // the fee is always the BANK_TRANSFER_FEE, and the icrc2_transfer_from rejects an amount above the MAX_TRANSFER_FROM_AMOUNT.
+    // this is the worst case, with the fee set on both the block and the tx.
+    log.fee = Some(crate::types::bank::BANK_TRANSFER_FEE);
+    log.tx.fee = Some(crate::types::bank::BANK_TRANSFER_FEE);
+    log.tx.amt = crate::types::bank::MAX_TRANSFER_FROM_AMOUNT;
+    log.tx.op = Operation::XferFrom{
+        from: full_size_icrc_id,
+        to: full_size_icrc_id,
+        spender: full_size_icrc_id,
+    };
+    let ser = log.to_bytes();
+    println!("{}", ser.len());
+    assert!(ser.len() <= LOG_STORABLE_MAX_SIZE as usize);
+    
 }
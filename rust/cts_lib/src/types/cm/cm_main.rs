@@ -72,4 +72,5 @@ pub enum ControllerCreateIcrc1TokenTradeContractError {
 pub enum ControllerCreateIcrc1TokenTradeContractMidCallError {
     TCInitCandidEncodeError(String),
     InstallCodeIcrc1TokenTradeContractCallError(CallError),
+    BankAddTransferNotificationReceiverCallError(CallError),
 }
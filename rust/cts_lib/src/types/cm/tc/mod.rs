@@ -3,7 +3,7 @@ use crate::icrc::{IcrcId, Tokens, Icrc1TransferError, BlockId, IcrcSubaccount};
 use crate::types::{Cycles, CallError, canister_code::CanisterCode};
 use crate::consts::KiB;
 use serde::Serialize;
-use std::collections::{HashSet, VecDeque, BTreeMap};
+use std::collections::{HashSet, VecDeque, BTreeMap, BTreeSet};
 
 pub mod storage_logs;
 use storage_logs::{
@@ -26,6 +26,9 @@ pub type PositionId = u128;
 pub type PurchaseId = u128;
 pub type CyclesPerToken = Cycles;
 
+// the current positions of one side by the rate. the position-ids at each rate are in the id order which is the time order.
+pub type PositionBook = BTreeMap<CyclesPerToken, BTreeSet<PositionId>>;
+
 pub type VoidCyclesPositionId = PositionId;
 pub type VoidTokenPositionId = PositionId;
 
@@ -50,8 +53,36 @@ pub struct CMIcrc1TokenTradeContractInit {
     pub positions_storage_canister_code: CanisterCode,
 }
 
+// the memo of an icrc1_transfer_and_notify to the principal_token_subaccount on the tc that puts a trade_cycles position with the received cycles.
+pub const TRADE_CYCLES_NOTIFY_MEMO_PREFIX: &[u8; 12] = b"trade_cycles";
+
+pub fn trade_cycles_notify_memo(cycles_per_token_rate: CyclesPerToken) -> Vec<u8> {
+    let mut memo: Vec<u8> = TRADE_CYCLES_NOTIFY_MEMO_PREFIX.to_vec();
+    memo.extend_from_slice(&cycles_per_token_rate.to_be_bytes());
+    memo
+}
+
+pub fn trade_cycles_notify_memo_rate(memo: &[u8]) -> Option<CyclesPerToken> {
+    let rate_bytes: &[u8] = memo.strip_prefix(&TRADE_CYCLES_NOTIFY_MEMO_PREFIX[..])?;
+    Some(CyclesPerToken::from_be_bytes(rate_bytes.try_into().ok()?))
+}
+
 // ----
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancel, // the rest of the position after the matching stays on the book.
+    ImmediateOrCancel, // the rest of the position after the matching gets voided.
+    FillOrKill, // the position must fill completely with the book at the time of the trade call, or it does not trade at all.
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PostOnly {
+    Reject, // the trade gets rejected if the position would match on the book.
+    Reprice, // the position gets repriced to one tick behind the best rate of the other side if it would match on the book.
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct TradeCyclesQuest {
     pub cycles: Cycles,
@@ -59,6 +90,12 @@ pub struct TradeCyclesQuest {
     pub posit_transfer_ledger_fee: Option<Cycles>,
     pub return_cycles_to_subaccount: Option<IcrcSubaccount>,
     pub payout_tokens_to_subaccount: Option<IcrcSubaccount>,
+    #[serde(default)]
+    pub time_in_force: Option<TimeInForce>, // default: GoodTillCancel
+    #[serde(default)]
+    pub post_only: Option<PostOnly>, // a post-only position must be GoodTillCancel.
+    #[serde(default)]
+    pub expires_at_nanos: Option<u128>, // the rest of the position gets voided with the cause TimePass at this time.
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
@@ -68,11 +105,26 @@ pub struct TradeTokensQuest {
     pub posit_transfer_ledger_fee: Option<Tokens>,
     pub return_tokens_to_subaccount: Option<IcrcSubaccount>,
     pub payout_cycles_to_subaccount: Option<IcrcSubaccount>,
+    #[serde(default)]
+    pub time_in_force: Option<TimeInForce>, // default: GoodTillCancel
+    #[serde(default)]
+    pub post_only: Option<PostOnly>, // a post-only position must be GoodTillCancel.
+    #[serde(default)]
+    pub expires_at_nanos: Option<u128>, // the rest of the position gets voided with the cause TimePass at this time.
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum PostOnlyOutcome {
+    Posted,
+    Repriced{ cycles_per_token_rate: CyclesPerToken },
+    // the book changed while the posit-transfer was in flight and the position would match. the position gets voided and the funds get returned.
+    VoidedWouldMatch,
 }
 
 #[derive(CandidType, Deserialize)]
 pub struct TradeSuccess {
     pub position_id: PositionId,
+    pub post_only_outcome: Option<PostOnlyOutcome>, // Some for a post-only position.
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -82,13 +134,133 @@ pub enum TradeError {
     CallerIsInTheMiddleOfADifferentCallThatLocksTheBalance,
     CyclesMarketIsBusy,
     CreatePositionLedgerTransferCallError(CallError),
-    CreatePositionLedgerTransferError(Icrc1TransferError)
+    CreatePositionLedgerTransferError(Icrc1TransferError),
+    FillOrKillCannotFillCompletely,
+    PostOnlyMustBeGoodTillCancel,
+    PostOnlyWouldMatch{ best_opposing_rate: CyclesPerToken },
+    ExpiresAtNanosMustBeInTheFuture,
 }
 
 pub type TradeResult = Result<TradeSuccess, TradeError>;
 
 // ---
 
+// a market-order fills what it can from the best rate of the other side till the worst acceptable rate and the rest gets returned.
+// with both the worst_cycles_per_token_rate and the max_slippage_basis_points, the more restrictive one is the limit.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TradeCyclesMarketQuest {
+    pub cycles: Cycles,
+    pub worst_cycles_per_token_rate: Option<CyclesPerToken>,
+    pub max_slippage_basis_points: Option<u64>, // from the best rate of the token-positions at the time of the call.
+    pub posit_transfer_ledger_fee: Option<Cycles>,
+    pub return_cycles_to_subaccount: Option<IcrcSubaccount>,
+    pub payout_tokens_to_subaccount: Option<IcrcSubaccount>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TradeTokensMarketQuest {
+    pub tokens: Tokens,
+    pub worst_cycles_per_token_rate: Option<CyclesPerToken>,
+    pub max_slippage_basis_points: Option<u64>, // from the best rate of the cycles-positions at the time of the call.
+    pub posit_transfer_ledger_fee: Option<Tokens>,
+    pub return_tokens_to_subaccount: Option<IcrcSubaccount>,
+    pub payout_cycles_to_subaccount: Option<IcrcSubaccount>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct TradeMarketSuccess {
+    pub position_id: PositionId,
+    pub fill_quantity: u128, // if cycles market-order this is: Tokens, if tokens market-order this is: Cycles.
+    pub fill_average_rate: CyclesPerToken,
+    pub payouts_fees_sum: u128, // if cycles market-order this is: Tokens, if tokens market-order this is: Cycles.
+    pub unfilled_quantity: u128, // gets returned through the void-position payout. if cycles market-order this is: Cycles, if tokens market-order this is: Tokens.
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TradeMarketError {
+    MustSetAWorstRateOrAMaxSlippage,
+    NoPositionsOnTheOtherSide,
+    TradeError(TradeError),
+}
+
+pub type TradeMarketResult = Result<TradeMarketSuccess, TradeMarketError>;
+
+// ---
+
+pub type ConditionalOrderId = u128;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConditionalOrderTrigger {
+    // a tokens-order triggers when a trade rate is <= the stop rate, a cycles-order triggers when a trade rate is >= the stop rate.
+    StopLimit{ stop_cycles_per_token_rate: CyclesPerToken },
+    // the stop rate follows the trade rates at the trail distance. 
+    // for a tokens-order it is the highest trade rate since the placement minus the trail, for a cycles-order it is the lowest trade rate since the placement plus the trail.
+    TrailingStop{ trail_basis_points: u64 },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum ConditionalTradeQuest {
+    TradeCycles(TradeCyclesQuest),
+    TradeTokens(TradeTokensQuest),
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PlaceConditionalOrderQuest {
+    pub trigger: ConditionalOrderTrigger,
+    // becomes a position through the normal trade flow when the order triggers.
+    // the posit_transfer_ledger_fee is for the escrow transfer at the placement. 
+    pub trade_quest: ConditionalTradeQuest,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ConditionalOrder {
+    pub id: ConditionalOrderId,
+    pub positor: Principal,
+    pub trigger: ConditionalOrderTrigger,
+    pub trade_quest: ConditionalTradeQuest,
+    pub escrow_ledger_transfer_fee: u128, // the escrow holds the quantity + this fee for the transfer out of the escrow.
+    pub trailing_extreme_rate: Option<CyclesPerToken>, // for a trailing-stop.
+    pub triggered_at_nanos: Option<u128>,
+    pub trigger_trade_error: Option<String>, // when the triggered trade fails with an error that does not go away with a retry. the order stays till the positor cancels it.
+    pub lock: bool,
+    pub timestamp_nanos: u128,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum PlaceConditionalOrderError {
+    TradeError(TradeError),
+    StopRateCannotBeZero,
+    TrailBasisPointsMustBeBetween1And9999,
+    MaxConditionalOrders{ max_conditional_orders: u64 },
+    MaxConditionalOrdersPerUser{ max_conditional_orders_per_user: u64 },
+}
+
+pub type PlaceConditionalOrderResult = Result<ConditionalOrderId, PlaceConditionalOrderError>;
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct CancelConditionalOrderQuest {
+    pub conditional_order_id: ConditionalOrderId,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum CancelConditionalOrderError {
+    ConditionalOrderNotFound,
+    WrongCaller,
+    ConditionalOrderIsInTheMiddleOfACall,
+    EscrowTransferCallError(CallError),
+    EscrowTransferError(Icrc1TransferError),
+}
+
+pub type CancelConditionalOrderResult = Result<BlockId, CancelConditionalOrderError>;
+
+#[derive(CandidType, Deserialize)]
+pub struct ListConditionalOrdersSponse {
+    pub conditional_orders: Vec<ConditionalOrder>,
+    pub is_last_chunk: bool,
+}
+
+// ---
+
 #[derive(CandidType, Deserialize, Debug)]
 pub struct VoidPositionQuest {
     pub position_id: PositionId
@@ -227,6 +399,16 @@ pub struct CMData {
     pub do_payouts_errors: Vec<CallError>,
     pub candle_counter: CandleCounter,
     pub latest_trade_rate_data: LatestTradeRateData,
+    #[serde(default)]
+    pub cycles_positions_book: PositionBook,
+    #[serde(default)]
+    pub token_positions_book: PositionBook,
+    #[serde(default)]
+    pub positions_expirations: BTreeSet<(u128, PositionId)>, // (expires_at_nanos, position_id) of the cycles-positions and the token-positions.
+    #[serde(default)]
+    pub conditional_orders: BTreeMap<ConditionalOrderId, ConditionalOrder>,
+    #[serde(default)]
+    pub conditional_orders_id_counter: ConditionalOrderId,
 }
 
 impl CMData {
@@ -251,6 +433,11 @@ impl CMData {
             do_payouts_errors: Vec::new(),
             candle_counter: CandleCounter::default(),
             latest_trade_rate_data: LatestTradeRateData::default(),
+            cycles_positions_book: PositionBook::new(),
+            token_positions_book: PositionBook::new(),
+            positions_expirations: BTreeSet::new(),
+            conditional_orders: BTreeMap::new(),
+            conditional_orders_id_counter: 0,
         }
     }
 }
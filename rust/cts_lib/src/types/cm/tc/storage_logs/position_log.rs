@@ -55,6 +55,9 @@ pub enum PositionTerminationCause {
     Bump, // the position got bumped
     TimePass, // expired
     UserCallVoidPosition, // the user cancelled the position by calling void_position
+    ImmediateOrCancel, // the rest of an immediate-or-cancel position after the matching
+    FillOrKill, // a fill-or-kill position that the book could not fill completely
+    PostOnlyWouldMatch, // a post-only position that would match on the book after the posit-transfer
 }
 
 impl StorageLogTrait for PositionLog {
@@ -80,7 +83,10 @@ impl StorageLogTrait for PositionLog {
                 PositionTerminationCause::Fill => 0,
                 PositionTerminationCause::Bump => 1,
                 PositionTerminationCause::TimePass => 2,
-                PositionTerminationCause::UserCallVoidPosition => 3
+                PositionTerminationCause::UserCallVoidPosition => 3,
+                PositionTerminationCause::ImmediateOrCancel => 4,
+                PositionTerminationCause::FillOrKill => 5,
+                PositionTerminationCause::PostOnlyWouldMatch => 6,
             };
         }        
         s[163] = self.void_position_payout_dust_collection as u8;
@@ -109,6 +115,9 @@ impl StorageLogTrait for PositionLog {
                         1 => PositionTerminationCause::Bump,
                         2 => PositionTerminationCause::TimePass,
                         3 => PositionTerminationCause::UserCallVoidPosition,
+                        4 => PositionTerminationCause::ImmediateOrCancel,
+                        5 => PositionTerminationCause::FillOrKill,
+                        6 => PositionTerminationCause::PostOnlyWouldMatch,
                         _ => panic!("unknown PositionTerminationCause serialization"),
                     }
                 })
@@ -1,11 +1,18 @@
 use cts_lib::{
-    types::{CallCanisterQuest, CallError},
+    types::{CallCanisterQuest, CallError, bank::OnCyclesReceivedQuest},
     tools::call_error_as_u32_and_string
 };
 use ic_cdk::{
     update,
+    query,
+    trap,
     api::call::call_raw128,
 };
+use std::cell::RefCell;
+
+thread_local!{
+    static CYCLES_RECEIVED_NOTIFICATIONS: RefCell<Vec<OnCyclesReceivedQuest>> = RefCell::new(Vec::new());
+}
 
 #[update]
 pub async fn call_canister(q: CallCanisterQuest) -> Result<Vec<u8>, CallError> {
@@ -17,4 +24,18 @@ pub async fn call_canister(q: CallCanisterQuest) -> Result<Vec<u8>, CallError> {
     )
     .await
     .map_err(call_error_as_u32_and_string)
-}
\ No newline at end of file
+}
+
+// for the bank icrc1_transfer_and_notify tests. traps when the memo is b"trap".
+#[update]
+pub fn on_cycles_received(q: OnCyclesReceivedQuest) {
+    if q.memo.as_deref() == Some(&b"trap"[..]) {
+        trap("on_cycles_received trap");
+    }
+    CYCLES_RECEIVED_NOTIFICATIONS.with(|n| n.borrow_mut().push(q));
+}
+
+#[query]
+pub fn view_cycles_received_notifications() -> Vec<OnCyclesReceivedQuest> {
+    CYCLES_RECEIVED_NOTIFICATIONS.with(|n| n.borrow().clone())
+}
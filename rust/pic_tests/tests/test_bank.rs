@@ -1,17 +1,19 @@
 use pocket_ic::{*, common::rest::RawEffectivePrincipal};
-use candid::Principal;
+use candid::{Nat, Principal};
 use cts_lib::{
     consts::{TRILLION},
     tools::{principal_token_subaccount, tokens_transform_cycles},
     types::{
+        Cycles,
         CallError,
         CallCanisterQuest,
         bank::{*, log_types::*},
     },
-    icrc::BlockId,
+    icrc::{BlockId, IcrcId},
 };
 use icrc_ledger_types::icrc1::{account::Account, transfer::{TransferArg, TransferError}};
 use more_asserts::*;
+use std::time::Duration;
 use pic_tools::{*, bank::*};
 
 
@@ -97,6 +99,55 @@ fn test_mint_for_subaccount() {
     );
 }
 
+#[test]
+fn test_mint_cycles_with_icp_approval() {
+    use icrc_ledger_types::icrc2::approve::{ApproveArgs, ApproveError};
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user_icp_account = Account{owner: user, subaccount: Some([3u8; 32])};
+    let burn_icp: u128 = 500000000;
+    let mint_cycles_with_icp_approval = || {
+        call_candid_as::<_, (MintCyclesResult,)>(&pic, BANK, RawEffectivePrincipal::None, user, "mint_cycles_with_icp_approval", (MintCyclesWithIcpApprovalQuest{
+            from_subaccount: user_icp_account.subaccount.map(serde_bytes::ByteArray::new),
+            burn_icp,
+            burn_icp_transfer_fee: ICP_LEDGER_TRANSFER_FEE,
+            to: Account{owner: user, subaccount: None}.into(),
+            fee: None,
+            memo: None,
+        },)).unwrap().0
+    };
+    
+    mint_icp(&pic, &user_icp_account, burn_icp + 2 * ICP_LEDGER_TRANSFER_FEE);
+    
+    // without an approval.
+    assert!(matches!(
+        mint_cycles_with_icp_approval(),
+        Err(MintCyclesError::LedgerTopupCyclesCmcIcpTransferFromError(_))
+    ));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), 0);
+    
+    call_candid_as::<_, (Result<Nat, ApproveError>,)>(&pic, ICP_LEDGER, RawEffectivePrincipal::None, user, "icrc2_approve", (ApproveArgs{
+        from_subaccount: user_icp_account.subaccount,
+        spender: Account{owner: BANK, subaccount: None},
+        amount: (burn_icp + ICP_LEDGER_TRANSFER_FEE).into(),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    },)).unwrap().0.unwrap();
+    
+    let mint_cycles_mount = mint_cycles_with_icp_approval().unwrap().mint_cycles;
+    assert_eq!(mint_cycles_mount, tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE);
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), mint_cycles_mount);
+    // the icp goes straight from the user's account to the cmc.
+    assert_eq!(icrc1_balance(&pic, ICP_LEDGER, &user_icp_account), 0);
+    assert_eq!(icrc1_balance(&pic, ICP_LEDGER, &Account{owner: BANK, subaccount: Some(principal_token_subaccount(&user))}), 0);
+    let log = &get_logs_backwards(&pic, BANK, &Account{owner: user, subaccount: None}, None).logs[0].1;
+    assert_eq!(log.tx.op, Operation::Mint{ to: Account{owner: user, subaccount: None}.into(), kind: MintKind::CMC{ caller: user, icp_block_height: 3 } });
+    assert_eq!(log.tx.amt, mint_cycles_mount);
+}
+
 #[test]
 fn test_transfer() {
     let pic = set_up();
@@ -139,6 +190,479 @@ fn test_transfer() {
     }
 }
 
+#[test]
+fn test_icrc4_transfer_batch() {
+    use cts_lib::icrc::{Icrc1TransferQuest, icrc4::*};
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    let created_at_time = pic_get_time_nanos(&pic) as u64;
+    let transfer = |to: u64, amount: Cycles, fee: Option<Cycles>| Icrc1TransferQuest{
+        to: IcrcId::from(Account{owner: Principal::self_authenticating(&to.to_be_bytes()), subaccount: None}),
+        fee,
+        memo: None,
+        from_subaccount: None,
+        created_at_time: Some(created_at_time),
+        amount,
+    };
+    let transfer_batch = |q: Vec<Icrc1TransferQuest>| {
+        call_candid_as::<_, (Icrc4TransferBatchResults,)>(&pic, BANK, RawEffectivePrincipal::None, user, "icrc4_transfer_batch", (q,)).unwrap().0
+    };
+    
+    let maximum_batch_size = call_candid::<_, (Option<u128>,)>(&pic, BANK, RawEffectivePrincipal::None, "icrc4_maximum_batch_size", ()).unwrap().0.unwrap();
+    
+    let rs = transfer_batch(vec![
+        transfer(1, 1 * TRILLION, None),
+        transfer(2, 2 * TRILLION, Some(BANK_TRANSFER_FEE)),
+        transfer(1, 1 * TRILLION, None), // duplicate of the first one.
+        transfer(3, 3 * TRILLION, Some(BANK_TRANSFER_FEE + 1)),
+        transfer(4, user_balance, None),
+    ]);
+    assert_eq!(rs, vec![
+        Some(Ok(1)),
+        Some(Ok(2)),
+        Some(Err(Icrc4TransferBatchError::Duplicate{ duplicate_of: 1u64.into() })),
+        Some(Err(Icrc4TransferBatchError::BadFee{ expected_fee: BANK_TRANSFER_FEE.into() })),
+        Some(Err(Icrc4TransferBatchError::InsufficientFunds{ balance: (user_balance - 3 * TRILLION - 2 * BANK_TRANSFER_FEE).into() })),
+    ]);
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - 3 * TRILLION - 2 * BANK_TRANSFER_FEE);
+    for (to, amount) in [(1, 1 * TRILLION), (2, 2 * TRILLION), (3, 0)] {
+        assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: Principal::self_authenticating(&(to as u64).to_be_bytes()), subaccount: None}), amount);
+    }
+    
+    let rs = transfer_batch((0..(maximum_batch_size as u64 + 1)).map(|i| transfer(i, 1, None)).collect());
+    assert_eq!(rs.len(), maximum_batch_size as usize + 1);
+    assert!(rs.iter().all(|r| matches!(r, Some(Err(Icrc4TransferBatchError::TooManyRequests{ .. })))));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - 3 * TRILLION - 2 * BANK_TRANSFER_FEE);
+}
+
+#[test]
+fn test_balance_of_at() {
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user2 = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp); // block 0
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    let balance_of_at = |account: Principal, at: BlockOrTime| {
+        pocket_ic::query_candid::<_, (Result<BalanceOfAt, BalanceOfAtError>,)>(&pic, BANK, "balance_of_at", (IcrcId{ owner: account, subaccount: None }, at)).unwrap().0
+    };
+    
+    let time_before_the_transfers = pic_get_time_nanos(&pic) as u64;
+    let mut times_after_the_transfers: Vec<u64> = Vec::new();
+    for i in 1..=10u128 {
+        pic.advance_time(Duration::from_secs(1));
+        let block = icrc1_transfer(&pic, BANK, user, TransferArg{
+            from_subaccount: None,
+            to: Account{owner: user2, subaccount: None},
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount: (i * TRILLION).into(),
+        }).unwrap();
+        assert_eq!(block, Nat::from(i));
+        times_after_the_transfers.push(pic_get_time_nanos(&pic) as u64);
+    }
+    
+    let sent_after_block = |block: u128| -> Cycles { (1..=block).map(|i| i * TRILLION + BANK_TRANSFER_FEE).sum() };
+    let received_after_block = |block: u128| -> Cycles { (1..=block).map(|i| i * TRILLION).sum() };
+    
+    for block in 0..=10u128 {
+        assert_eq!(
+            balance_of_at(user, BlockOrTime::Block(block)),
+            Ok(BalanceOfAt{ balance: user_balance - sent_after_block(block), block: Some(block) }),
+        );
+        assert_eq!(
+            balance_of_at(user2, BlockOrTime::Block(block)),
+            Ok(BalanceOfAt{ balance: received_after_block(block), block: Some(block) }),
+        );
+    }
+    assert_eq!(balance_of_at(user, BlockOrTime::Block(10)).unwrap().balance, icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}));
+    
+    assert_eq!(
+        balance_of_at(user2, BlockOrTime::TimeNanos(time_before_the_transfers)),
+        Ok(BalanceOfAt{ balance: 0, block: Some(0) }),
+    );
+    for (i, time_nanos) in times_after_the_transfers.into_iter().enumerate() {
+        let block = i as u128 + 1;
+        assert_eq!(
+            balance_of_at(user2, BlockOrTime::TimeNanos(time_nanos)),
+            Ok(BalanceOfAt{ balance: received_after_block(block), block: Some(block) }),
+        );
+    }
+    assert_eq!(
+        balance_of_at(user, BlockOrTime::TimeNanos(0)),
+        Ok(BalanceOfAt{ balance: 0, block: None }),
+    );
+    
+    assert_eq!(
+        balance_of_at(user, BlockOrTime::Block(11)),
+        Err(BalanceOfAtError::BlockNotFound{ log_length: 11 }),
+    );
+}
+
+#[test]
+fn test_solvency_audit() {
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user2 = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    let fee_collector = Principal::self_authenticating(&(1000 as u64).to_be_bytes());
+    let receiving_canister = pic.create_canister();
+    let solvency_audit_report = || {
+        call_candid::<_, (SolvencyAuditReport,)>(&pic, BANK, RawEffectivePrincipal::None, "solvency_audit_report", ()).unwrap().0
+    };
+    let let_the_solvency_audit_timer_run = || {
+        pic.advance_time(Duration::from_secs(6));
+        for _ in 0..5 {
+            pic.tick();
+        }
+    };
+    
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, SNS_GOVERNANCE, "controller_set_fee_collector", (Some(IcrcId{ owner: fee_collector, subaccount: None }),)).unwrap();
+    for i in 1..=5u128 {
+        icrc1_transfer(&pic, BANK, user, TransferArg{
+            from_subaccount: None,
+            to: Account{owner: user2, subaccount: None},
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount: (i * TRILLION).into(),
+        }).unwrap();
+    }
+    call_candid_as::<_, (Result<BlockId, CyclesOutError>,)>(&pic, BANK, RawEffectivePrincipal::None, user2, "cycles_out", (CyclesOutQuest{
+        cycles: 1 * TRILLION,
+        fee: None,
+        from_subaccount: None,
+        memo: None,
+        for_canister: receiving_canister,
+        created_at_time: None,
+    },)).unwrap().0.unwrap();
+    
+    let_the_solvency_audit_timer_run();
+    
+    let (total_supply,): (Cycles,) = call_candid(&pic, BANK, RawEffectivePrincipal::None, "icrc1_total_supply", ()).unwrap();
+    let report = solvency_audit_report();
+    assert_eq!(report.mismatches.len(), 0);
+    assert_eq!(report.log_replay_next_block, Some(7));
+    let last_balances_sweep = report.last_balances_sweep.unwrap();
+    assert_eq!(last_balances_sweep.accounts, 3);
+    assert_eq!(last_balances_sweep.balances_sum, total_supply);
+    assert_eq!(last_balances_sweep.total_supply, total_supply);
+    let last_solvency_check = report.last_solvency_check.unwrap();
+    assert_ge!(last_solvency_check.canister_cycles_balance, last_solvency_check.total_supply);
+    assert!(report.outgoing_operations_freeze.is_none());
+    
+    // the fee-collector's balance at each of its fee blocks, the ones between its checkpoints too.
+    for block in 1..=6u128 {
+        assert_eq!(
+            pocket_ic::query_candid::<_, (Result<BalanceOfAt, BalanceOfAtError>,)>(&pic, BANK, "balance_of_at", (IcrcId{ owner: fee_collector, subaccount: None }, BlockOrTime::Block(block))).unwrap().0,
+            Ok(BalanceOfAt{ balance: block * BANK_TRANSFER_FEE, block: Some(block) }),
+        );
+    }
+    
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, user, "controller_unfreeze_outgoing_operations", ()).unwrap_err();
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, SNS_GOVERNANCE, "controller_unfreeze_outgoing_operations", ()).unwrap();
+}
+
+#[test]
+fn test_icrc1_transfer_and_notify() {
+    use cts_lib::icrc::Icrc1TransferQuest;
+    let pic = set_up();
+    let canister_caller = set_up_canister_caller(&pic);
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user2 = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    let receiver_subaccount = principal_token_subaccount(&user);
+    let transfer_and_notify = |to: Principal, amount: Cycles, memo: &[u8]| {
+        call_candid_as::<_, (Result<TransferAndNotifySuccess, TransferError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "icrc1_transfer_and_notify", (Icrc1TransferQuest{
+            to: IcrcId{ owner: to, subaccount: Some(receiver_subaccount.into()) },
+            fee: None,
+            memo: Some(serde_bytes::ByteBuf::from(memo)),
+            from_subaccount: None,
+            created_at_time: None,
+            amount,
+        },)).unwrap().0
+    };
+    let view_transfer_notification = |block_id: BlockId| {
+        call_candid::<_, (Option<TransferNotification>,)>(&pic, BANK, RawEffectivePrincipal::None, "view_transfer_notification", (block_id,)).unwrap().0
+    };
+    
+    // the receiver must be on the list that the sns-governance sets.
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, user, "controller_set_transfer_notification_receivers", (vec![canister_caller],)).unwrap_err();
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, SNS_GOVERNANCE, "controller_set_transfer_notification_receivers", (vec![canister_caller],)).unwrap();
+    assert_eq!(
+        call_candid::<_, (Vec<Principal>,)>(&pic, BANK, RawEffectivePrincipal::None, "view_transfer_notification_receivers", ()).unwrap().0,
+        vec![canister_caller],
+    );
+    
+    let success = transfer_and_notify(canister_caller, 5 * TRILLION, b"position").unwrap();
+    assert_eq!(success, TransferAndNotifySuccess{ block_id: 1, notification_outcome: TransferNotificationOutcome::Notified });
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: canister_caller, subaccount: Some(receiver_subaccount)}), 5 * TRILLION);
+    let notifications = call_candid::<_, (Vec<OnCyclesReceivedQuest>,)>(&pic, canister_caller, RawEffectivePrincipal::None, "view_cycles_received_notifications", ()).unwrap().0;
+    assert_eq!(notifications, vec![
+        OnCyclesReceivedQuest{
+            block_id: 1,
+            from: IcrcId{ owner: user, subaccount: None },
+            to_subaccount: Some(receiver_subaccount.into()),
+            amount: 5 * TRILLION,
+            memo: Some(serde_bytes::ByteBuf::from(&b"position"[..])),
+        }
+    ]);
+    let transfer_notification = view_transfer_notification(1).unwrap();
+    assert_eq!(transfer_notification.receiver, canister_caller);
+    assert_eq!(transfer_notification.outcome, Some(TransferNotificationOutcome::Notified));
+    
+    // the transfer stays when the notification fails.
+    let success = transfer_and_notify(canister_caller, 1 * TRILLION, b"trap").unwrap();
+    assert_eq!(success.block_id, 2);
+    assert!(matches!(success.notification_outcome, TransferNotificationOutcome::NotifyCallError(_)));
+    assert_eq!(view_transfer_notification(2).unwrap().outcome, Some(success.notification_outcome));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: canister_caller, subaccount: Some(receiver_subaccount)}), 6 * TRILLION);
+    
+    // a receiver that is not on the list gets nothing.
+    assert!(matches!(
+        call_candid_as::<_, (Result<TransferAndNotifySuccess, TransferError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "icrc1_transfer_and_notify", (Icrc1TransferQuest{
+            to: IcrcId{ owner: user2, subaccount: None },
+            fee: None,
+            memo: None,
+            from_subaccount: None,
+            created_at_time: None,
+            amount: 1 * TRILLION,
+        },)).unwrap().0,
+        Err(TransferError::GenericError{ .. }),
+    ));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user2, subaccount: None}), 0);
+    
+    assert_eq!(
+        transfer_and_notify(canister_caller, user_balance, b""), 
+        Err(TransferError::InsufficientFunds{ balance: (user_balance - 7 * TRILLION - 2 * BANK_TRANSFER_FEE).into() }),
+    );
+    assert_eq!(view_transfer_notification(3), None);
+    assert_eq!(call_candid::<_, (Vec<OnCyclesReceivedQuest>,)>(&pic, canister_caller, RawEffectivePrincipal::None, "view_cycles_received_notifications", ()).unwrap().0.len(), 1);
+}
+
+#[test]
+fn test_escrows() {
+    use cts_lib::tools::sha256;
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user2 = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    let user_icrc_id = IcrcId{ owner: user, subaccount: None };
+    let user2_icrc_id = IcrcId{ owner: user2, subaccount: None };
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    let preimage = b"secret".to_vec();
+    let escrow_lock_ = |amount: Cycles, timeout_nanos: u64, created_at_time: Option<u64>| {
+        call_candid_as::<_, (Result<EscrowLockSuccess, EscrowLockError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "escrow_lock", (EscrowLockQuest{
+            from_subaccount: None,
+            to: user2_icrc_id,
+            amount,
+            fee: None,
+            hashlock: sha256(&preimage).into(),
+            timeout_nanos,
+            created_at_time,
+        },)).unwrap().0
+    };
+    let escrow_lock = |amount: Cycles, timeout_nanos: u64| escrow_lock_(amount, timeout_nanos, None);
+    let escrow_claim = |escrow_id: EscrowId, preimage: &[u8]| {
+        call_candid_as::<_, (Result<BlockId, EscrowClaimError>,)>(&pic, BANK, RawEffectivePrincipal::None, user2, "escrow_claim", (escrow_id, serde_bytes::ByteBuf::from(preimage))).unwrap().0
+    };
+    let escrow_refund = |escrow_id: EscrowId| {
+        call_candid_as::<_, (Result<BlockId, EscrowRefundError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "escrow_refund", (escrow_id,)).unwrap().0
+    };
+    let list_escrows = |icrc_id: IcrcId| {
+        call_candid::<_, (Vec<Escrow>,)>(&pic, BANK, RawEffectivePrincipal::None, "list_escrows", (icrc_id,)).unwrap().0
+    };
+    
+    let now = pic_get_time_nanos(&pic) as u64;
+    assert!(matches!(escrow_lock(1 * TRILLION, now - 1), Err(EscrowLockError::TimeoutMustBeInTheFuture{ .. })));
+    
+    let timeout_nanos = pic_get_time_nanos(&pic) as u64 + 60 * 1_000_000_000;
+    assert_eq!(escrow_lock(5 * TRILLION, timeout_nanos), Ok(EscrowLockSuccess{ escrow_id: 0, block_id: 1 }));
+    assert_eq!(escrow_lock(3 * TRILLION, timeout_nanos), Ok(EscrowLockSuccess{ escrow_id: 1, block_id: 2 }));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - 8 * TRILLION - 2 * BANK_TRANSFER_FEE);
+    let escrows = list_escrows(user_icrc_id);
+    assert_eq!(escrows.len(), 2);
+    assert_eq!(escrows[0].amount, 5 * TRILLION);
+    assert_eq!(escrows[0].lock_block, 1);
+    assert_eq!(list_escrows(user2_icrc_id), escrows);
+    
+    assert_eq!(escrow_claim(0, b"wrong"), Err(EscrowClaimError::WrongPreimage));
+    assert_eq!(escrow_claim(0, &preimage), Ok(3));
+    assert_eq!(escrow_claim(0, &preimage), Err(EscrowClaimError::EscrowNotFound));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user2, subaccount: None}), 5 * TRILLION);
+    assert_eq!(escrow_refund(1), Err(EscrowRefundError::EscrowNotTimedOut{ timeout_nanos }));
+    
+    pic.advance_time(Duration::from_secs(61));
+    assert_eq!(escrow_claim(1, &preimage), Err(EscrowClaimError::EscrowTimedOut{ timeout_nanos }));
+    assert_eq!(escrow_refund(1), Ok(4));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - 5 * TRILLION - 2 * BANK_TRANSFER_FEE);
+    assert_eq!(list_escrows(user_icrc_id), vec![]);
+    
+    let logs = call_candid::<_, (GetLogsBackwardsSponse,)>(&pic, BANK, RawEffectivePrincipal::None, "get_logs_backwards", (user2_icrc_id, None::<u128>)).unwrap().0.logs;
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].0, 3);
+    assert!(matches!(logs[0].1.tx.op, Operation::Xfer{ to, .. } if to == user2_icrc_id));
+    assert_eq!(logs[0].1.tx.memo, Some(serde_bytes::ByteBuf::from([&ESCROW_MEMO_TAG[..], &0u64.to_be_bytes()[..]].concat())));
+    assert_eq!(escrow_id_of_a_memo(logs[0].1.tx.memo.as_ref().unwrap()), Some(0));
+    
+    // the created_at_time dedup, same as the icrc1_transfer.
+    let created_at_time = pic_get_time_nanos(&pic) as u64;
+    let timeout_nanos = created_at_time + 60 * 1_000_000_000;
+    assert_eq!(escrow_lock_(1 * TRILLION, timeout_nanos, Some(created_at_time)), Ok(EscrowLockSuccess{ escrow_id: 2, block_id: 5 }));
+    assert_eq!(escrow_lock_(1 * TRILLION, timeout_nanos, Some(created_at_time)), Err(EscrowLockError::Duplicate{ duplicate_of: 5 }));
+    assert_eq!(escrow_lock_(1 * TRILLION, timeout_nanos, Some(created_at_time - 25 * 60 * 60 * 1_000_000_000)), Err(EscrowLockError::TooOld));
+    assert_eq!(list_escrows(user_icrc_id).len(), 1);
+    
+    // the user transfers can't use the escrow-memo-tag.
+    call_candid_as::<_, (Result<BlockId, TransferError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "icrc1_transfer", (TransferArg{
+        from_subaccount: None,
+        to: Account{owner: user2, subaccount: None},
+        fee: None,
+        created_at_time: None,
+        memo: Some(icrc_ledger_types::icrc1::transfer::Memo::from([&ESCROW_MEMO_TAG[..], &7u64.to_be_bytes()[..]].concat())),
+        amount: TRILLION.into(),
+    },)).unwrap_err();
+}
+
+#[test]
+fn test_payment_streams() {
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user2 = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    let user_icrc_id = IcrcId{ owner: user, subaccount: None };
+    let user2_icrc_id = IcrcId{ owner: user2, subaccount: None };
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    let balance = |owner: Principal| icrc1_balance(&pic, BANK, &Account{owner, subaccount: None});
+    // the time between the calls can be a bit more than the advance_time.
+    let assert_about = |cycles: Cycles, expected: Cycles| {
+        assert_ge!(cycles, expected);
+        assert_le!(cycles, expected + TRILLION / 100);
+    };
+    let create_payment_stream = |cap: Option<Cycles>| {
+        call_candid_as::<_, (Result<CreatePaymentStreamSuccess, CreatePaymentStreamError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "create_payment_stream", (CreatePaymentStreamQuest{
+            from_subaccount: None,
+            to: user2_icrc_id,
+            rate_per_second: 1 * TRILLION,
+            cap,
+            fee: None,
+        },)).unwrap().0
+    };
+    let withdraw_payment_stream = |caller: Principal, stream_id: PaymentStreamId| {
+        call_candid_as::<_, (Result<BlockId, WithdrawPaymentStreamError>,)>(&pic, BANK, RawEffectivePrincipal::None, caller, "withdraw_payment_stream", (stream_id,)).unwrap().0
+    };
+    let cancel_payment_stream = |caller: Principal, stream_id: PaymentStreamId| {
+        call_candid_as::<_, (Result<CancelPaymentStreamSuccess, CancelPaymentStreamError>,)>(&pic, BANK, RawEffectivePrincipal::None, caller, "cancel_payment_stream", (stream_id,)).unwrap().0
+    };
+    let list_payment_streams = |icrc_id: IcrcId| {
+        call_candid::<_, (Vec<PaymentStreamStatus>,)>(&pic, BANK, RawEffectivePrincipal::None, "list_payment_streams", (icrc_id,)).unwrap().0
+    };
+    
+    // with a cap
+    assert_eq!(create_payment_stream(Some(10 * TRILLION)), Ok(CreatePaymentStreamSuccess{ stream_id: 0, lock_block: Some(1) }));
+    assert_eq!(balance(user), user_balance - 10 * TRILLION - BANK_TRANSFER_FEE);
+    
+    pic.advance_time(Duration::from_secs(4));
+    let statuses = list_payment_streams(user2_icrc_id);
+    assert_eq!(statuses.len(), 1);
+    assert_about(statuses[0].accrued, 4 * TRILLION);
+    assert_eq!(statuses[0].withdrawable, statuses[0].accrued);
+    
+    assert_eq!(withdraw_payment_stream(user, 0), Err(WithdrawPaymentStreamError::CallerIsNotTheRecipient));
+    assert_eq!(cancel_payment_stream(user2, 0), Err(CancelPaymentStreamError::CallerIsNotTheSender));
+    assert_eq!(withdraw_payment_stream(user2, 0), Ok(2));
+    assert_about(balance(user2), 4 * TRILLION - BANK_TRANSFER_FEE);
+    
+    pic.advance_time(Duration::from_secs(3));
+    assert_eq!(cancel_payment_stream(user, 0), Ok(CancelPaymentStreamSuccess{ withdraw_block: Some(3), refund_block: Some(4) }));
+    assert_about(balance(user2), 7 * TRILLION - 2 * BANK_TRANSFER_FEE);
+    assert_eq!(balance(user) + balance(user2) + 3 * BANK_TRANSFER_FEE, user_balance);
+    assert_eq!(list_payment_streams(user_icrc_id), vec![]);
+    assert_eq!(withdraw_payment_stream(user2, 0), Err(WithdrawPaymentStreamError::PaymentStreamNotFound));
+    
+    // without a cap, the withdrawals come out of the sender's account.
+    let user_balance = balance(user);
+    let user2_balance = balance(user2);
+    assert_eq!(create_payment_stream(None), Ok(CreatePaymentStreamSuccess{ stream_id: 1, lock_block: None }));
+    assert_eq!(balance(user), user_balance);
+    pic.advance_time(Duration::from_secs(2));
+    assert_eq!(withdraw_payment_stream(user2, 1), Ok(5));
+    assert_about(balance(user2), user2_balance + 2 * TRILLION - BANK_TRANSFER_FEE);
+    assert_eq!(balance(user) + balance(user2) + BANK_TRANSFER_FEE, user_balance + user2_balance);
+    assert!(matches!(withdraw_payment_stream(user2, 1), Err(WithdrawPaymentStreamError::WithdrawableIsNotMoreThanTheFee{ .. })));
+    
+    // the cancel settles the accrued cycles with the recipient even when they are not more than the fee, and without a fee.
+    pic.advance_time(Duration::from_millis(5));
+    let (user_balance, user2_balance) = (balance(user), balance(user2));
+    assert_eq!(cancel_payment_stream(user, 1), Ok(CancelPaymentStreamSuccess{ withdraw_block: Some(6), refund_block: None }));
+    assert_gt!(balance(user2), user2_balance);
+    assert_le!(balance(user2), user2_balance + BANK_TRANSFER_FEE);
+    assert_eq!(balance(user) + balance(user2), user_balance + user2_balance);
+    assert_eq!(list_payment_streams(user_icrc_id), vec![]);
+}
+
+#[test]
+fn test_list_subaccounts() {
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user2 = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    mint_cycles(&pic, &Account{owner: user2, subaccount: None}, burn_icp);
+    let transfer = |from_subaccount: Option<[u8; 32]>, to_subaccount: Option<[u8; 32]>, amount: Cycles| {
+        icrc1_transfer(&pic, BANK, user, TransferArg{
+            from_subaccount,
+            to: Account{owner: user, subaccount: to_subaccount},
+            fee: Some(BANK_TRANSFER_FEE.into()),
+            created_at_time: None,
+            memo: None,
+            amount: amount.into(),
+        }).unwrap();
+    };
+    let list_subaccounts = |start_after: Option<[u8; 32]>| {
+        call_candid::<_, (ListSubaccountsSponse,)>(&pic, BANK, RawEffectivePrincipal::None, "list_subaccounts", (user, start_after.map(serde_bytes::ByteArray::new))).unwrap().0
+    };
+    let list_accounts = |start_after: Option<[u8; 32]>| {
+        call_candid::<_, (ListAccountsSponse,)>(&pic, BANK, RawEffectivePrincipal::None, "list_accounts", (user, start_after.map(serde_bytes::ByteArray::new))).unwrap().0
+    };
+    
+    transfer(None, Some([3u8; 32]), 3 * TRILLION);
+    transfer(None, Some([1u8; 32]), 1 * TRILLION);
+    transfer(None, Some([2u8; 32]), 2 * TRILLION);
+    // the accounts with a zero balance are not listed.
+    transfer(Some([2u8; 32]), None, 2 * TRILLION - BANK_TRANSFER_FEE);
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE - 6 * TRILLION - 3 * BANK_TRANSFER_FEE + 2 * TRILLION - BANK_TRANSFER_FEE;
+    
+    let sponse = list_subaccounts(None);
+    assert_eq!(sponse.subaccounts, vec![serde_bytes::ByteArray::new([0u8; 32]), serde_bytes::ByteArray::new([1u8; 32])]);
+    assert_eq!(sponse.is_last_chunk, false);
+    let sponse = list_subaccounts(Some([1u8; 32]));
+    assert_eq!(sponse.subaccounts, vec![serde_bytes::ByteArray::new([3u8; 32])]);
+    assert_eq!(sponse.is_last_chunk, true);
+    
+    let sponse = list_accounts(None);
+    assert_eq!(
+        sponse.accounts,
+        vec![
+            (IcrcId{ owner: user, subaccount: None }, user_balance),
+            (IcrcId{ owner: user, subaccount: Some(serde_bytes::ByteArray::new([1u8; 32])) }, 1 * TRILLION),
+        ]
+    );
+    assert_eq!(sponse.is_last_chunk, false);
+    let sponse = list_accounts(Some([1u8; 32]));
+    assert_eq!(sponse.accounts, vec![(IcrcId{ owner: user, subaccount: Some(serde_bytes::ByteArray::new([3u8; 32])) }, 3 * TRILLION)]);
+    assert_eq!(sponse.is_last_chunk, true);
+}
+
 #[test]
 fn test_transfer_fails_when_wrong_fee_is_set() {
     let pic = set_up();
@@ -183,6 +707,138 @@ fn test_transfer_fails_when_insufficient_funds() {
     assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user2, subaccount: None}), 0);    
 }
 
+#[test]
+fn test_icrc2_approve_and_transfer_from() {
+    use icrc_ledger_types::icrc2::{
+        approve::{ApproveArgs, ApproveError},
+        transfer_from::{TransferFromArgs, TransferFromError},
+        allowance::{AllowanceArgs, Allowance},
+    };
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let spender = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    let user3 = Principal::self_authenticating(&(1000 as u64).to_be_bytes());
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    let approve_mount = 5*TRILLION;
+    let approve_block = call_candid_as::<_, (Result<Nat, ApproveError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "icrc2_approve", (ApproveArgs{
+        from_subaccount: None,
+        spender: Account{owner: spender, subaccount: None},
+        amount: approve_mount.into(),
+        expected_allowance: Some(0u128.into()),
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    },)).unwrap().0.unwrap();
+    assert_eq!(approve_block, Nat::from(1u128));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - BANK_TRANSFER_FEE);
+    let allowance = call_candid::<_, (Allowance,)>(&pic, BANK, RawEffectivePrincipal::None, "icrc2_allowance", (AllowanceArgs{
+        account: Account{owner: user, subaccount: None},
+        spender: Account{owner: spender, subaccount: None},
+    },)).unwrap().0;
+    assert_eq!(allowance.allowance, Nat::from(approve_mount));
+    
+    let transfer_from = |amount: u128| {
+        call_candid_as::<_, (Result<Nat, TransferFromError>,)>(&pic, BANK, RawEffectivePrincipal::None, spender, "icrc2_transfer_from", (TransferFromArgs{
+            spender_subaccount: None,
+            from: Account{owner: user, subaccount: None},
+            to: Account{owner: user3, subaccount: None},
+            amount: amount.into(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        },)).unwrap().0
+    };
+    assert_eq!(
+        transfer_from(approve_mount - BANK_TRANSFER_FEE + 1), 
+        Err(TransferFromError::InsufficientAllowance{ allowance: approve_mount.into() })
+    );
+    assert_eq!(transfer_from(approve_mount - BANK_TRANSFER_FEE).unwrap(), Nat::from(2u128));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - BANK_TRANSFER_FEE - approve_mount);
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user3, subaccount: None}), approve_mount - BANK_TRANSFER_FEE);
+    assert_eq!(transfer_from(1), Err(TransferFromError::InsufficientAllowance{ allowance: 0u128.into() }));
+    // the bigger amounts do not fit in a 2xfer block.
+    assert!(matches!(transfer_from(MAX_TRANSFER_FROM_AMOUNT + 1), Err(TransferFromError::GenericError{ .. })));
+    
+    let log = &get_logs_backwards(&pic, BANK, &Account{owner: spender, subaccount: None}, None).logs[1].1;
+    assert_eq!(
+        log,
+        &Log{
+            phash: log.phash,
+            ts: log.ts,
+            fee: Some(BANK_TRANSFER_FEE),
+            tx: LogTX{
+                op: Operation::XferFrom{ from: Account{owner: user, subaccount: None}.into(), to: Account{owner: user3, subaccount: None}.into(), spender: Account{owner: spender, subaccount: None}.into() },
+                fee: None,
+                amt: approve_mount - BANK_TRANSFER_FEE,
+                memo: None,
+                ts: None,
+            }
+        }
+    );
+}
+
+#[test]
+fn test_fee_collector() {
+    use icrc_ledger_types::icrc3::blocks::GetBlocksResult;
+    use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+    use cts_lib::types::bank::icrc3::StartAndLength;
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user2 = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    let fee_collector = Account{owner: Principal::self_authenticating(&(1000 as u64).to_be_bytes()), subaccount: Some([7; 32])};
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp); // block 0, the fee is burned.
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    
+    // only the sns-governance can set the fee-collector.
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, user, "controller_set_fee_collector", (Some(IcrcId::from(fee_collector.clone())),)).unwrap_err();
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, SNS_GOVERNANCE, "controller_set_fee_collector", (Some(IcrcId::from(fee_collector.clone())),)).unwrap();
+    
+    let transfer_cycles_mount = 5*TRILLION;
+    icrc1_transfer(&pic, BANK, user, TransferArg{
+        from_subaccount: None,
+        to: Account{owner: user2, subaccount: None},
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        amount: transfer_cycles_mount.into(),
+    }).unwrap(); // block 1
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - transfer_cycles_mount - BANK_TRANSFER_FEE);
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user2, subaccount: None}), transfer_cycles_mount);
+    assert_eq!(icrc1_balance(&pic, BANK, &fee_collector), BANK_TRANSFER_FEE);
+    let (total_supply,): (Cycles,) = call_candid(&pic, BANK, RawEffectivePrincipal::None, "icrc1_total_supply", ()).unwrap();
+    assert_eq!(total_supply, user_balance - BANK_TRANSFER_FEE);
+    
+    // back to burning the fees.
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, SNS_GOVERNANCE, "controller_set_fee_collector", (None::<IcrcId>,)).unwrap();
+    icrc1_transfer(&pic, BANK, user2, TransferArg{
+        from_subaccount: None,
+        to: Account{owner: user, subaccount: None},
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        amount: TRILLION.into(),
+    }).unwrap(); // block 2
+    assert_eq!(icrc1_balance(&pic, BANK, &fee_collector), BANK_TRANSFER_FEE);
+    
+    let get_blocks_sponse = call_candid::<_, (GetBlocksResult,)>(&pic, BANK, RawEffectivePrincipal::None, "icrc3_get_blocks", (vec![StartAndLength{ start: 0, length: 3 }],)).unwrap().0;
+    let fee_cols: Vec<Option<ICRC3Value>> = get_blocks_sponse.blocks.into_iter().map(|b| {
+        let ICRC3Value::Map(map) = b.block else { panic!("block must be a map") };
+        map.get("fee_col").cloned()
+    }).collect();
+    assert_eq!(
+        fee_cols,
+        vec![
+            None,
+            Some(ICRC3Value::Array(vec![ICRC3Value::Blob(fee_collector.owner.as_slice().to_vec().into()), ICRC3Value::Blob(vec![7; 32].into())])),
+            None,
+        ]
+    );
+}
+
 #[test]
 fn test_cycles_in() {
     let pic = set_up();
@@ -201,6 +857,7 @@ fn test_cycles_in() {
                 fee: Some(BANK_TRANSFER_FEE),
                 to: for_account.into(),
                 memo: None,
+                created_at_time: None,
             }).unwrap(),
             cycles: if i == 0 { 
                 cycles + BANK_TRANSFER_FEE - 1// wrong amount of cycles in the call
@@ -238,6 +895,46 @@ fn test_cycles_in() {
     assert_ge!(pic.cycle_balance(BANK), bank_cycles_balance_before + cycles - 100_000_000);    
 }
 
+#[test]
+fn test_wallet_receive() {
+    let pic = set_up();
+    let canister_caller = set_up_canister_caller(&pic);
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let for_account = Account{owner: user, subaccount: Some([5u8; 32])};
+    let wallet_receive = |arg_raw: Vec<u8>, cycles: Cycles| {
+        let r = call_candid::<_, (Result<Vec<u8>, CallError>,)>(&pic, canister_caller, RawEffectivePrincipal::None, "call_canister", (CallCanisterQuest{
+            callee: BANK,
+            method_name: "wallet_receive".to_string(),
+            arg_raw,
+            cycles,
+        },)).unwrap().0;
+        candid::decode_one::<WalletReceiveSponse>(&r.unwrap()).unwrap()
+    };
+    
+    // the legacy wallets call without an argument.
+    assert_eq!(
+        wallet_receive(candid::encode_args(()).unwrap(), 5 * TRILLION),
+        WalletReceiveSponse{ accepted: (5 * TRILLION) as u64, block_id: Some(0) },
+    );
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: canister_caller, subaccount: None}), 5 * TRILLION - BANK_TRANSFER_FEE);
+    
+    assert_eq!(
+        wallet_receive(candid::encode_one(Some(IcrcId::from(for_account))).unwrap(), 3 * TRILLION),
+        WalletReceiveSponse{ accepted: (3 * TRILLION) as u64, block_id: Some(1) },
+    );
+    assert_eq!(icrc1_balance(&pic, BANK, &for_account), 3 * TRILLION - BANK_TRANSFER_FEE);
+    let log = &get_logs_backwards(&pic, BANK, &for_account, None).logs[0].1; 
+    assert_eq!(log.tx.op, Operation::Mint{ to: for_account.into(), kind: MintKind::CyclesIn{ from_canister: canister_caller } });
+    assert_eq!(log.tx.amt, 3 * TRILLION - BANK_TRANSFER_FEE);
+    
+    // not more than the fee.
+    assert_eq!(
+        wallet_receive(candid::encode_one(Some(IcrcId::from(for_account))).unwrap(), BANK_TRANSFER_FEE),
+        WalletReceiveSponse{ accepted: 0, block_id: None },
+    );
+    assert_eq!(icrc1_balance(&pic, BANK, &for_account), 3 * TRILLION - BANK_TRANSFER_FEE);
+}
+
 #[test]
 fn test_cycles_out() {
     let pic = set_up();
@@ -257,6 +954,7 @@ fn test_cycles_out() {
         from_subaccount: Some(subaccount.into()),
         for_canister: receiving_canister,
         memo: None,
+        created_at_time: None,
     },)).unwrap().0.unwrap();
     assert_eq!(block, 1);
     assert_le!(pic.cycle_balance(BANK), bank_cycles_balance_before_cycles_out - (tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE*2));
@@ -280,6 +978,192 @@ fn test_cycles_out() {
     );
 }
 
+#[test]
+fn test_create_canister() {
+    use cts_lib::{cmc::CmcCreateCanisterArgs, management_canister::ManagementCanisterOptionalCanisterSettings};
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    let create_canister_cycles = 5*TRILLION;
+    let create_canister = || {
+        call_candid_as::<_, (Result<CreateCanisterSuccess, CreateCanisterError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "create_canister", (CreateCanisterQuest{
+            cycles: create_canister_cycles,
+            fee: None,
+            from_subaccount: None,
+            memo: None,
+            created_at_time: None,
+            creation_args: Some(CmcCreateCanisterArgs{
+                settings: Some(ManagementCanisterOptionalCanisterSettings{
+                    controllers: Some(vec![user, SNS_ROOT]),
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: None,
+                }),
+                subnet_selection: None,
+            }),
+        },)).unwrap().0
+    };
+    
+    // the cmc does not have any authorized subnets yet so the create fails and the bank refunds the user.
+    match create_canister() {
+        Err(CreateCanisterError::CmcCreateCanisterError{ refund_amount, refund_block, .. }) => {
+            assert_eq!(refund_block.is_some(), refund_amount < create_canister_cycles);
+            assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - (create_canister_cycles - refund_amount));
+        }
+        r => panic!("unexpected create_canister result: {:?}", r),
+    }
+    let user_balance = icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None});
+    
+    // with an authorized subnet the cmc creates the canister there with the cycles minus the cmc creation-fee.
+    let fiduciary_subnet = pic.topology().get_fiduciary().unwrap();
+    set_cmc_default_authorized_subnets(&pic, vec![fiduciary_subnet]);
+    
+    let create_canister_success = create_canister().unwrap();
+    assert_eq!(pic.get_subnet(create_canister_success.canister_id), Some(fiduciary_subnet));
+    assert_gt!(pic.cycle_balance(create_canister_success.canister_id), 0);
+    assert_le!(pic.cycle_balance(create_canister_success.canister_id), create_canister_cycles);
+    let mut controllers = pic.get_controllers(create_canister_success.canister_id);
+    controllers.sort();
+    let mut expected_controllers = vec![user, SNS_ROOT];
+    expected_controllers.sort();
+    assert_eq!(controllers, expected_controllers);
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - create_canister_cycles - BANK_TRANSFER_FEE);
+    let logs = get_logs_backwards(&pic, BANK, &Account{owner: user, subaccount: None}, None).logs;
+    let (b, log) = &logs[logs.len() - 1];
+    assert_eq!(b, &create_canister_success.block_id);
+    assert_eq!(
+        log.tx.op,
+        Operation::Burn{ from: Account{owner: user, subaccount: None}.into(), for_canister: create_canister_success.canister_id },
+    );
+    assert_eq!(log.tx.amt, create_canister_cycles + BANK_TRANSFER_FEE);
+}
+
+#[test]
+fn test_topup_subscriptions() {
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    
+    let canister = pic.create_canister();
+    pic.add_cycles(canister, 1 * TRILLION);
+    pic.set_controllers(canister, None, vec![Principal::anonymous(), BANK]).unwrap();
+    // the bank is not a controller of this one so the bank can't see its cycles-balance.
+    let not_controlled_canister = pic.create_canister();
+    
+    let add_topup_subscription = |canister_id: Principal, topup_cycles: Cycles| {
+        call_candid_as::<_, (Result<TopupSubscriptionId, AddTopupSubscriptionError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "add_topup_subscription", (AddTopupSubscriptionQuest{
+            canister_id,
+            threshold: 5 * TRILLION,
+            topup_cycles,
+            from_subaccount: None,
+            canister_status_via: None,
+        },)).unwrap().0
+    };
+    let list_topup_subscriptions = || {
+        call_candid_as::<_, (Vec<TopupSubscription>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "list_topup_subscriptions", ()).unwrap().0
+    };
+    
+    assert!(matches!(add_topup_subscription(canister, 1), Err(AddTopupSubscriptionError::MinimumTopupCycles{ .. })));
+    let topup_cycles = 3 * TRILLION;
+    
+    // the canister_status_via must be on the list that the sns-governance sets.
+    let add_topup_subscription_via = |canister_status_via: Principal| {
+        call_candid_as::<_, (Result<TopupSubscriptionId, AddTopupSubscriptionError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "add_topup_subscription", (AddTopupSubscriptionQuest{
+            canister_id: canister,
+            threshold: 5 * TRILLION,
+            topup_cycles,
+            from_subaccount: None,
+            canister_status_via: Some(canister_status_via),
+        },)).unwrap().0
+    };
+    let status_canister = pic.create_canister();
+    assert_eq!(add_topup_subscription_via(status_canister), Err(AddTopupSubscriptionError::CanisterStatusViaIsNotOnTheList));
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, user, "controller_set_canister_status_via_canisters", (vec![status_canister],)).unwrap_err();
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, SNS_GOVERNANCE, "controller_set_canister_status_via_canisters", (vec![status_canister],)).unwrap();
+    assert_eq!(
+        call_candid::<_, (Vec<Principal>,)>(&pic, BANK, RawEffectivePrincipal::None, "view_canister_status_via_canisters", ()).unwrap().0,
+        vec![status_canister],
+    );
+    let via_subscription_id = add_topup_subscription_via(status_canister).unwrap();
+    call_candid_as::<_, (Result<(), CancelTopupSubscriptionError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "cancel_topup_subscription", (via_subscription_id,)).unwrap().0.unwrap();
+    
+    let subscription_id = add_topup_subscription(canister, topup_cycles).unwrap();
+    let not_controlled_subscription_id = add_topup_subscription(not_controlled_canister, topup_cycles).unwrap();
+    // the debug-build checks go in chunks of 2, this one is in the second chunk.
+    let not_controlled_subscription_id_2 = add_topup_subscription(not_controlled_canister, topup_cycles).unwrap();
+    
+    pic.advance_time(Duration::from_secs(11));
+    for _ in 0..10 {
+        pic.tick();
+    }
+    
+    let subscriptions = list_topup_subscriptions();
+    assert_eq!(subscriptions.len(), 3);
+    assert!(subscriptions.iter().find(|s| s.id == not_controlled_subscription_id_2).unwrap().last_error.is_some());
+    let subscription = subscriptions.iter().find(|s| s.id == subscription_id).unwrap();
+    assert_eq!(subscription.last_error, None);
+    assert_eq!(subscription.topup_blocks.len(), 1);
+    assert_ge!(pic.cycle_balance(canister), 1 * TRILLION + topup_cycles - 100_000_000);
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - topup_cycles - BANK_TRANSFER_FEE);
+    let not_controlled_subscription = subscriptions.iter().find(|s| s.id == not_controlled_subscription_id).unwrap();
+    assert!(not_controlled_subscription.last_error.is_some());
+    assert_eq!(not_controlled_subscription.topup_blocks.len(), 0);
+    
+    // the canister is above the threshold now.
+    pic.advance_time(Duration::from_secs(11));
+    for _ in 0..5 {
+        pic.tick();
+    }
+    assert_eq!(list_topup_subscriptions().iter().find(|s| s.id == subscription_id).unwrap().topup_blocks.len(), 1);
+    
+    // only the subscriber can cancel.
+    let user2 = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    assert_eq!(
+        call_candid_as::<_, (Result<(), CancelTopupSubscriptionError>,)>(&pic, BANK, RawEffectivePrincipal::None, user2, "cancel_topup_subscription", (subscription_id,)).unwrap().0,
+        Err(CancelTopupSubscriptionError::TopupSubscriptionNotFound)
+    );
+    call_candid_as::<_, (Result<(), CancelTopupSubscriptionError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "cancel_topup_subscription", (subscription_id,)).unwrap().0.unwrap();
+    assert_eq!(list_topup_subscriptions().iter().map(|s| s.id).collect::<Vec<_>>(), vec![not_controlled_subscription_id, not_controlled_subscription_id_2]);
+}
+
+#[test]
+fn test_cycles_out_dedup() {
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let receiving_canister = pic.create_canister();
+    let burn_icp = 500000000;
+    mint_cycles(&pic, &Account{owner: user, subaccount: None}, burn_icp);
+    let user_balance = tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE;
+    let cycles_out_mount = 5*TRILLION;
+    let created_at_time = pic_get_time_nanos(&pic) as u64;
+    let cycles_out = |created_at_time: u64| {
+        call_candid_as::<_, (Result<BlockId, CyclesOutError>,)>(&pic, BANK, RawEffectivePrincipal::None, user, "cycles_out", (CyclesOutQuest{
+            cycles: cycles_out_mount,
+            fee: None,
+            from_subaccount: None,
+            for_canister: receiving_canister,
+            memo: None,
+            created_at_time: Some(created_at_time),
+        },)).unwrap().0
+    };
+    let block = cycles_out(created_at_time).unwrap();
+    assert_eq!(block, 1);
+    let receiving_canister_cycles_balance_after_the_first_cycles_out = pic.cycle_balance(receiving_canister);
+    assert_eq!(cycles_out(created_at_time), Err(CyclesOutError::Duplicate{ duplicate_of: block }));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - cycles_out_mount - BANK_TRANSFER_FEE);
+    assert_le!(pic.cycle_balance(receiving_canister), receiving_canister_cycles_balance_after_the_first_cycles_out);
+    
+    // a different created_at_time is a different request.
+    assert_eq!(cycles_out(created_at_time + 1), Ok(2));
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: user, subaccount: None}), user_balance - (cycles_out_mount + BANK_TRANSFER_FEE) * 2);
+    
+    assert!(matches!(cycles_out(created_at_time + 1_000_000_000 * 60 * 60), Err(CyclesOutError::CreatedInFuture{ .. })));
+}
+
 #[test]
 fn test_cycles_out_fails_when_not_enough_balance() {
     let pic = set_up();
@@ -295,6 +1179,7 @@ fn test_cycles_out_fails_when_not_enough_balance() {
         from_subaccount: None,
         for_canister: receiving_canister,
         memo: None,
+        created_at_time: None,
     },)).unwrap().0.unwrap_err();
     assert_eq!(cycles_out_error, CyclesOutError::InsufficientFunds{balance: tokens_transform_cycles(burn_icp, CMC_RATE) - BANK_TRANSFER_FEE});
     assert_ge!(pic.cycle_balance(BANK), bank_cycles_balance_before_cycles_out - 100_000_000);
@@ -315,6 +1200,7 @@ fn test_cycles_out_fails_when_invalid_for_canister() {
         from_subaccount: None,
         for_canister: Principal::management_canister(),
         memo: None,
+        created_at_time: None,
     },)).unwrap().0.unwrap_err();
     if let CyclesOutError::DepositCyclesCallError(_) = cycles_out_error {} else { panic!("must be CyclesOutError::DepositCyclesCallError") }
     assert_ge!(pic.cycle_balance(BANK), bank_cycles_balance_before_cycles_out - 100_000_000);
@@ -432,6 +1318,76 @@ impl LedgerEnv for PICLedgerEnv {
 }
 
 
+#[test]
+fn test_icrc21_consent_message() {
+    use cts_lib::icrc::{Icrc1TransferQuest, icrc21::*};
+    let pic = set_up();
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user2 = Principal::self_authenticating(&(900 as u64).to_be_bytes());
+    let consent_message = |method: &str, arg: Vec<u8>, language: &str, device_spec: Option<Icrc21DeviceSpec>| {
+        call_candid_as::<_, (Icrc21ConsentMessageResponse,)>(&pic, BANK, RawEffectivePrincipal::None, user, "icrc21_canister_call_consent_message", (Icrc21ConsentMessageRequest{
+            method: method.to_string(),
+            arg: serde_bytes::ByteBuf::from(arg),
+            user_preferences: Icrc21ConsentMessageSpec{
+                metadata: Icrc21ConsentMessageMetadata{ language: language.to_string(), utc_offset_minutes: None },
+                device_spec,
+            },
+        },)).unwrap().0
+    };
+    let transfer_arg_quest = || Icrc1TransferQuest{
+        to: IcrcId::from(Account{owner: user2, subaccount: None}),
+        fee: None,
+        memo: None,
+        from_subaccount: None,
+        created_at_time: None,
+        amount: 5*TRILLION / 2,
+    };
+    let transfer_arg = candid::encode_one(transfer_arg_quest()).unwrap();
+    
+    let consent_info = consent_message("icrc1_transfer", transfer_arg.clone(), "en-US", None).unwrap();
+    assert_eq!(consent_info.metadata.language, "en");
+    let Icrc21ConsentMessage::GenericDisplayMessage(message) = consent_info.consent_message else { panic!("expected a generic display message") };
+    assert!(message.starts_with("# Transfer cycles"));
+    assert!(message.contains("2.5 TCY"));
+    assert!(message.contains(&user2.to_text()));
+    
+    let consent_info = consent_message("icrc1_transfer", transfer_arg.clone(), "es", None).unwrap();
+    assert_eq!(consent_info.metadata.language, "es");
+    let Icrc21ConsentMessage::GenericDisplayMessage(message) = consent_info.consent_message else { panic!("expected a generic display message") };
+    assert!(message.starts_with("# Transferir ciclos"));
+    
+    let consent_info = consent_message("icrc1_transfer", transfer_arg.clone(), "en", Some(Icrc21DeviceSpec::LineDisplay{ characters_per_line: 20, lines_per_page: 4 })).unwrap();
+    let Icrc21ConsentMessage::LineDisplayMessage{ pages } = consent_info.consent_message else { panic!("expected a line display message") };
+    assert!(pages.iter().all(|page| page.lines.len() <= 4 && page.lines.iter().all(|line| line.chars().count() <= 20)));
+    
+    let approve_arg = candid::encode_one(cts_lib::icrc::Icrc2ApproveQuest{
+        from_subaccount: None,
+        spender: IcrcId::from(Account{owner: user2, subaccount: None}),
+        amount: 3*TRILLION,
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    }).unwrap();
+    let Icrc21ConsentMessage::GenericDisplayMessage(message) = consent_message("icrc2_approve", approve_arg, "en", None).unwrap().consent_message else { panic!("expected a generic display message") };
+    assert!(message.starts_with("# Approve a spender"));
+    assert!(message.contains("3 TCY"));
+    assert!(message.contains(&user2.to_text()));
+    
+    let Icrc21ConsentMessage::GenericDisplayMessage(message) = consent_message("icrc4_transfer_batch", candid::encode_one(vec![transfer_arg_quest(), transfer_arg_quest()]).unwrap(), "en", None).unwrap().consent_message else { panic!("expected a generic display message") };
+    assert!(message.starts_with("# Transfer cycles in a batch"));
+    assert!(message.contains("5 TCY"));
+    
+    // the methods with more than one arg.
+    let Icrc21ConsentMessage::GenericDisplayMessage(message) = consent_message("escrow_claim", candid::encode_args((5u64, serde_bytes::ByteBuf::from(vec![1,2,3]))).unwrap(), "en", None).unwrap().consent_message else { panic!("expected a generic display message") };
+    assert!(message.starts_with("# Claim an escrow"));
+    
+    assert!(matches!(consent_message("icrc1_balance_of", transfer_arg, "en", None), Err(Icrc21Error::UnsupportedCanisterCall(_))));
+    assert!(matches!(consent_message("icrc1_transfer", vec![1,2,3], "en", None), Err(Icrc21Error::UnsupportedCanisterCall(_))));
+}
+
+
 #[test]
 fn icrc1_test_suite_crate() {
     let pic = set_up();
@@ -1,5 +1,228 @@
+use pocket_ic::{*, common::rest::RawEffectivePrincipal};
+use candid::{Nat, Principal, CandidType, Deserialize};
+use cts_lib::{
+    consts::TRILLION,
+    types::{
+        CanisterCode,
+        bank::{
+            icrc3::{StartAndLength, GetArchivesArgs, ArchiveData},
+            index::{BankIndexInit, GetAccountTransactionsArgs, GetTransactionsResult, ListSubaccountsArgs, Status},
+        },
+    },
+};
+use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
+use pic_tools::{*, bank::*};
+use std::time::Duration;
+
+
+// decodes only the block ids and the archived ranges of the icrc3_get_blocks response.
+#[derive(CandidType, Deserialize)]
+struct IdOfABlock {
+    id: Nat,
+}
+#[derive(CandidType, Deserialize)]
+struct ArgsOfAnArchivedBlocksCallback {
+    args: Vec<StartAndLength>,
+}
+#[derive(CandidType, Deserialize)]
+struct GetBlocksIds {
+    log_length: Nat,
+    blocks: Vec<IdOfABlock>,
+    archived_blocks: Vec<ArgsOfAnArchivedBlocksCallback>,
+}
+
+fn icrc3_get_blocks_ids(pic: &PocketIc, canister: Principal, start: u128, length: u128) -> GetBlocksIds {
+    call_candid::<_, (GetBlocksIds,)>(pic, canister, RawEffectivePrincipal::None, "icrc3_get_blocks", (vec![StartAndLength{ start, length }],)).unwrap().0
+}
+
+fn icrc3_get_archives(pic: &PocketIc) -> Vec<ArchiveData> {
+    call_candid::<_, (Vec<ArchiveData>,)>(pic, BANK, RawEffectivePrincipal::None, "icrc3_get_archives", (GetArchivesArgs{ from: None },)).unwrap().0
+}
+
+fn let_the_archive_timer_run(pic: &PocketIc) {
+    pic.advance_time(Duration::from_secs(11));
+    for _ in 0..30 {
+        pic.tick();
+    }
+}
+
 
 #[test]
 fn icrc3_test_1() {
-    
+
+}
+
+#[test]
+fn test_archive_blocks() {
+    let pic = set_up();
+    pic.add_cycles(BANK, 10 * TRILLION);
+
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user_account = Account{ owner: user, subaccount: None };
+    let user_2 = Principal::self_authenticating(&(801 as u64).to_be_bytes());
+
+    mint_cycles(&pic, &user_account, 500000000); // block 0
+
+    let make_transfers = |n: usize| {
+        for _ in 0..n {
+            icrc1_transfer(&pic, BANK, user, TransferArg{
+                from_subaccount: None,
+                to: Account{ owner: user_2, subaccount: None },
+                fee: None,
+                created_at_time: None,
+                memo: None,
+                amount: (1 * TRILLION).into(),
+            }).unwrap();
+        }
+    };
+
+    make_transfers(24);
+
+    // no archive-canister code yet.
+    let_the_archive_timer_run(&pic);
+    assert_eq!(icrc3_get_archives(&pic).len(), 1);
+
+    let archive_module = std::fs::read(wasms_dir_dev().join("bank_archive.wasm")).unwrap();
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, SNS_GOVERNANCE, "controller_upload_archive_canister_code", (CanisterCode::new(archive_module),)).unwrap();
+
+    // the debug config archives 10 blocks when there are 20 or more blocks on the bank, with a max of 15 blocks per archive.
+    let_the_archive_timer_run(&pic);
+    let archives = icrc3_get_archives(&pic);
+    assert_eq!(archives.len(), 2);
+    let bank_range = archives.iter().find(|a| a.canister_id == BANK).unwrap();
+    assert_eq!((bank_range.start, bank_range.end), (10, 24));
+
+    make_transfers(10);
+    let_the_archive_timer_run(&pic);
+
+    let mut archives = icrc3_get_archives(&pic);
+    assert_eq!(archives.len(), 3);
+    archives.sort_by_key(|a| a.start);
+    assert_eq!((archives[0].start, archives[0].end), (0, 14));
+    assert_eq!((archives[1].start, archives[1].end), (15, 19));
+    assert_eq!((archives[2].canister_id, archives[2].start, archives[2].end), (BANK, 20, 34));
+
+    let r = icrc3_get_blocks_ids(&pic, BANK, 0, 35);
+    assert_eq!(r.log_length, Nat::from(35u64));
+    assert_eq!(r.blocks.iter().map(|b| b.id.clone()).collect::<Vec<Nat>>(), (20..35u64).map(Nat::from).collect::<Vec<Nat>>());
+    assert_eq!(r.archived_blocks.len(), 2);
+    assert_eq!((r.archived_blocks[0].args[0].start, r.archived_blocks[0].args[0].length), (0, 15));
+    assert_eq!((r.archived_blocks[1].args[0].start, r.archived_blocks[1].args[0].length), (15, 5));
+
+    // the first archive gets created with the debug cycles_for_archive_creation of 1T, and gets a 2T top-up on the next archive_blocks timer.
+    assert!(pic.cycle_balance(archives[0].canister_id) > 2 * TRILLION);
+
+    let r = icrc3_get_blocks_ids(&pic, archives[0].canister_id, 0, 15);
+    assert_eq!(r.blocks.iter().map(|b| b.id.clone()).collect::<Vec<Nat>>(), (0..15u64).map(Nat::from).collect::<Vec<Nat>>());
+    let r = icrc3_get_blocks_ids(&pic, archives[1].canister_id, 15, 5);
+    assert_eq!(r.blocks.iter().map(|b| b.id.clone()).collect::<Vec<Nat>>(), (15..20u64).map(Nat::from).collect::<Vec<Nat>>());
+
+    // get_logs_backwards only serves the blocks that are still on the bank.
+    let get_logs_backwards_sponse = get_logs_backwards(&pic, BANK, &user_account, None::<u128>);
+    assert_eq!(get_logs_backwards_sponse.logs.len(), 15);
+    assert_eq!(get_logs_backwards_sponse.logs[0].0, 20);
+    assert_eq!(get_logs_backwards_sponse.is_last_chunk, true);
+
+    // the bank keeps working on top of the archived blocks.
+    assert_eq!(
+        icrc1_transfer(&pic, BANK, user, TransferArg{
+            from_subaccount: None,
+            to: Account{ owner: user_2, subaccount: None },
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount: (1 * TRILLION).into(),
+        }).unwrap(),
+        Nat::from(35u64)
+    );
+}
+
+
+#[test]
+fn test_bank_index() {
+    let pic = set_up();
+    pic.add_cycles(BANK, 10 * TRILLION);
+
+    let archive_module = std::fs::read(wasms_dir_dev().join("bank_archive.wasm")).unwrap();
+    call_candid_as::<_, ()>(&pic, BANK, RawEffectivePrincipal::None, SNS_GOVERNANCE, "controller_upload_archive_canister_code", (CanisterCode::new(archive_module),)).unwrap();
+
+    let user = Principal::self_authenticating(&(800 as u64).to_be_bytes());
+    let user_account = Account{ owner: user, subaccount: None };
+    let user_2_account = Account{ owner: Principal::self_authenticating(&(801 as u64).to_be_bytes()), subaccount: Some([5; 32]) };
+
+    mint_cycles(&pic, &user_account, 500000000); // block 0
+    for _ in 0..24 {
+        icrc1_transfer(&pic, BANK, user, TransferArg{
+            from_subaccount: None,
+            to: user_2_account.clone(),
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount: (1 * TRILLION).into(),
+        }).unwrap();
+    }
+    // blocks 0..10 go into an archive.
+    let_the_archive_timer_run(&pic);
+    assert_eq!(icrc3_get_archives(&pic).len(), 2);
+
+    let bank_index = pic.create_canister();
+    pic.add_cycles(bank_index, 10 * TRILLION);
+    pic.install_canister(
+        bank_index,
+        std::fs::read(wasms_dir_dev().join("bank_index.wasm")).unwrap(),
+        candid::encode_one(BankIndexInit{ bank_id: BANK }).unwrap(),
+        None,
+    );
+    pic.advance_time(Duration::from_secs(6));
+    for _ in 0..30 {
+        pic.tick();
+    }
+
+    let status = call_candid::<_, (Status,)>(&pic, bank_index, RawEffectivePrincipal::None, "status", ()).unwrap().0;
+    assert_eq!(status.num_blocks_synced, 25);
+
+    for account in [&user_account, &user_2_account] {
+        assert_eq!(icrc1_balance(&pic, bank_index, account), icrc1_balance(&pic, BANK, account));
+    }
+
+    let get_account_transactions = |start: Option<u128>, max_results: u128| {
+        call_candid::<_, (GetTransactionsResult,)>(&pic, bank_index, RawEffectivePrincipal::None, "get_account_transactions", (GetAccountTransactionsArgs{
+            account: user_account.clone().into(),
+            start,
+            max_results,
+        },)).unwrap().0.unwrap()
+    };
+    let r = get_account_transactions(None, 10);
+    assert_eq!(r.balance, icrc1_balance(&pic, BANK, &user_account));
+    assert_eq!(r.oldest_tx_id, Some(0));
+    assert_eq!(r.transactions.iter().map(|t| t.id).collect::<Vec<u128>>(), (15..25).rev().collect::<Vec<u128>>());
+    assert_eq!(r.transactions[0].transaction.kind, "transfer");
+    assert_eq!(r.transactions[0].transaction.transfer.as_ref().unwrap().amount, 1 * TRILLION);
+
+    // the older transactions come from the archive.
+    let r = get_account_transactions(Some(15), 100);
+    assert_eq!(r.transactions.iter().map(|t| t.id).collect::<Vec<u128>>(), (0..15).rev().collect::<Vec<u128>>());
+    assert_eq!(r.transactions.last().unwrap().transaction.kind, "mint");
+
+    let subaccounts = call_candid::<_, (Vec<serde_bytes::ByteArray<32>>,)>(&pic, bank_index, RawEffectivePrincipal::None, "list_subaccounts", (ListSubaccountsArgs{
+        owner: user_2_account.owner,
+        start: None,
+    },)).unwrap().0;
+    assert_eq!(subaccounts, vec![serde_bytes::ByteArray::new([5; 32])]);
+
+    // the index keeps up with the new blocks.
+    icrc1_transfer(&pic, BANK, user, TransferArg{
+        from_subaccount: None,
+        to: user_2_account.clone(),
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        amount: (1 * TRILLION).into(),
+    }).unwrap();
+    pic.advance_time(Duration::from_secs(6));
+    for _ in 0..10 {
+        pic.tick();
+    }
+    assert_eq!(get_account_transactions(None, 1).transactions[0].id, 25);
+    assert_eq!(icrc1_balance(&pic, bank_index, &user_2_account), icrc1_balance(&pic, BANK, &user_2_account));
 }
@@ -41,6 +41,9 @@ fn test_1() {
             posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
             return_tokens_to_subaccount: None,
             payout_cycles_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
         },
     )).unwrap().0.unwrap().position_id;    
     assert_eq!(p1_position_id, 0);
@@ -87,6 +90,9 @@ fn test_1() {
             posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
             return_cycles_to_subaccount: None,
             payout_tokens_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
         },
     )).unwrap().0.unwrap().position_id;    
     assert_eq!(p2_position_id, 1);
@@ -359,6 +365,9 @@ fn test_candle_counter_1() {
             posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
             return_tokens_to_subaccount: None,
             payout_cycles_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
         },
     )).unwrap().0.unwrap();
     
@@ -372,6 +381,9 @@ fn test_candle_counter_1() {
             posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
             return_cycles_to_subaccount: None,
             payout_tokens_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
         },
     )).unwrap().0.unwrap();    
 
@@ -427,6 +439,9 @@ fn test_111() {
             posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
             return_cycles_to_subaccount: None,
             payout_tokens_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
         },
     )).unwrap().0.unwrap().position_id;    
     assert_eq!(p2_position_id, 0);
@@ -472,6 +487,9 @@ fn test_111() {
             posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
             return_tokens_to_subaccount: None,
             payout_cycles_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
         },
     )).unwrap().0.unwrap().position_id;    
     assert_eq!(p1_position_id, 1);
@@ -607,6 +625,590 @@ fn test_111() {
 
  
 
+#[test]
+fn test_price_time_priority() {
+    let pic = set_up();
+    let tc = set_up_tc(&pic);
+    
+    let (p1,p2,p3,p4): (Principal,Principal,Principal,Principal) = (
+        Principal::from_slice(&[1,1,1,1,1]),
+        Principal::from_slice(&[2,2,2,2,2]),
+        Principal::from_slice(&[3,3,3,3,3]),
+        Principal::from_slice(&[4,4,4,4,4]),
+    );
+    let trade_tokens = 100_000_000;
+    
+    // id 0 at the worse rate first, then two at the better rate.
+    for (p, rate) in [(p1, 90_000), (p3, 80_000), (p4, 80_000)] {
+        mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p))}, trade_tokens + ICP_LEDGER_TRANSFER_FEE);
+        call_trade_tokens(&pic, tc, p, &TradeTokensQuest{
+            tokens: trade_tokens,
+            cycles_per_token_rate: rate,
+            posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
+            return_tokens_to_subaccount: None,
+            payout_cycles_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
+        }).unwrap();
+    }
+    
+    let sponse = view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None });
+    assert_eq!(sponse.positions_quantities, vec![(80_000, 2 * trade_tokens), (90_000, trade_tokens)]);
+    assert_eq!(sponse.is_last_chunk, true);
+    let sponse = view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: Some(80_000) });
+    assert_eq!(sponse.positions_quantities, vec![(90_000, trade_tokens)]);
+    
+    // the buy at 100_000 matches the best rate first and the first position at that rate. the trade-rate is the middle rate.
+    mint_cycles(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p2))}, 500000000);
+    let p2_position_id = call_trade_cycles(&pic, tc, p2, &TradeCyclesQuest{
+        cycles: trade_tokens * 90_000,
+        cycles_per_token_rate: 100_000,
+        posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
+        return_cycles_to_subaccount: None,
+        payout_tokens_to_subaccount: None,
+        time_in_force: None,
+        post_only: None,
+        expires_at_nanos: None,
+    }).unwrap().position_id;
+    
+    let view_position_pending_trades_sponse_b = pic.query_call(tc, Principal::anonymous(), "view_position_pending_trades",
+        candid::encode_one(ViewStorageLogsQuest{
+            opt_start_before_id: None,
+            index_key: Some(p2_position_id),
+        }).unwrap(),
+    ).unwrap().unwrap();
+    assert_eq!(view_position_pending_trades_sponse_b.len(), TradeLog::STABLE_MEMORY_SERIALIZE_SIZE + 2);
+    let log = TradeLog::stable_memory_serialize_backwards(&view_position_pending_trades_sponse_b);
+    assert_eq!(log.position_id_matchee, 1);
+    assert_eq!(log.matchee_position_positor, p3);
+    assert_eq!(log.cycles_per_token_rate, 90_000);
+    assert_eq!(log.tokens, trade_tokens);
+    
+    let sponse = view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None });
+    assert_eq!(sponse.positions_quantities, vec![(80_000, trade_tokens), (90_000, trade_tokens)]);
+    assert_eq!(view_cycles_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, vec![]);
+}
+
+#[test]
+fn test_time_in_force() {
+    let pic = set_up();
+    let tc = set_up_tc(&pic);
+    
+    let (p1,p2,p3,p4): (Principal,Principal,Principal,Principal) = (
+        Principal::from_slice(&[1,1,1,1,1]),
+        Principal::from_slice(&[2,2,2,2,2]),
+        Principal::from_slice(&[3,3,3,3,3]),
+        Principal::from_slice(&[4,4,4,4,4]),
+    );
+    let trade_tokens = 100_000_000;
+    let trade_tokens_quest = TradeTokensQuest{
+        tokens: trade_tokens,
+        cycles_per_token_rate: 80_000,
+        posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
+        return_tokens_to_subaccount: None,
+        payout_cycles_to_subaccount: None,
+        time_in_force: None,
+        post_only: None,
+        expires_at_nanos: None,
+    };
+    let trade_cycles_quest = |cycles: u128, time_in_force: TimeInForce| {
+        TradeCyclesQuest{
+            cycles,
+            cycles_per_token_rate: 100_000,
+            posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
+            return_cycles_to_subaccount: None,
+            payout_tokens_to_subaccount: None,
+            time_in_force: Some(time_in_force),
+            post_only: None,
+            expires_at_nanos: None,
+        }
+    };
+    
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p1))}, trade_tokens + ICP_LEDGER_TRANSFER_FEE);
+    call_trade_tokens(&pic, tc, p1, &trade_tokens_quest).unwrap();
+    
+    // the fill-or-kill that cannot fill does not move the funds.
+    let p2_cycles_subaccount = Account{owner: tc, subaccount: Some(principal_token_subaccount(&p2))};
+    mint_cycles(&pic, &p2_cycles_subaccount, 500000000);
+    let p2_cycles_balance = icrc1_balance(&pic, BANK, &p2_cycles_subaccount);
+    assert!(matches!(
+        call_trade_cycles(&pic, tc, p2, &trade_cycles_quest(2 * trade_tokens * 90_000, TimeInForce::FillOrKill)),
+        Err(TradeError::FillOrKillCannotFillCompletely)
+    ));
+    assert_eq!(icrc1_balance(&pic, BANK, &p2_cycles_subaccount), p2_cycles_balance);
+    
+    // the immediate-or-cancel fills what it can and voids the rest.
+    call_trade_cycles(&pic, tc, p2, &trade_cycles_quest(2 * trade_tokens * 90_000, TimeInForce::ImmediateOrCancel)).unwrap();
+    assert_eq!(view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, vec![]);
+    assert_eq!(view_cycles_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, vec![]);
+    let p2_view_void_positions_pending_sponse_b = pic.query_call(tc, Principal::anonymous(), "view_void_positions_pending",
+        candid::encode_one(ViewStorageLogsQuest{
+            opt_start_before_id: None,
+            index_key: Some(p2)
+        }).unwrap(),
+    ).unwrap().unwrap();
+    let log = PositionLog::stable_memory_serialize_backwards(&p2_view_void_positions_pending_sponse_b[..(p2_view_void_positions_pending_sponse_b.len() - 1)]);
+    assert_eq!(log.position_termination.unwrap().cause, PositionTerminationCause::ImmediateOrCancel);
+    assert_eq!(log.fill_quantity, trade_tokens);
+    assert_eq!(log.mainder_position_quantity, trade_tokens * 90_000);
+    
+    // the fill-or-kill that can fill.
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p3))}, trade_tokens + ICP_LEDGER_TRANSFER_FEE);
+    call_trade_tokens(&pic, tc, p3, &trade_tokens_quest).unwrap();
+    mint_cycles(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p4))}, 500000000);
+    call_trade_cycles(&pic, tc, p4, &trade_cycles_quest(trade_tokens * 90_000, TimeInForce::FillOrKill)).unwrap();
+    assert_eq!(view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, vec![]);
+}
+
+#[test]
+fn test_fill_or_kill_max_trades() {
+    let pic = set_up();
+    let tc = set_up_tc(&pic);
+    
+    let (p1,p2): (Principal,Principal) = (
+        Principal::from_slice(&[1,1,1,1,1]),
+        Principal::from_slice(&[2,2,2,2,2]),
+    );
+    let trade_tokens = 100_000_000;
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p1))}, 4 * (trade_tokens + ICP_LEDGER_TRANSFER_FEE));
+    for _ in 0..4 {
+        call_trade_tokens(&pic, tc, p1, &TradeTokensQuest{
+            tokens: trade_tokens,
+            cycles_per_token_rate: 80_000,
+            posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
+            return_tokens_to_subaccount: None,
+            payout_cycles_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
+        }).unwrap();
+    }
+    let trade_cycles_quest = |cycles: u128| {
+        TradeCyclesQuest{
+            cycles,
+            cycles_per_token_rate: 100_000,
+            posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
+            return_cycles_to_subaccount: None,
+            payout_tokens_to_subaccount: None,
+            time_in_force: Some(TimeInForce::FillOrKill),
+            post_only: None,
+            expires_at_nanos: None,
+        }
+    };
+    
+    // the debug-build max fill-or-kill trades is 3. the book can fill this one but not within 3 trades.
+    let p2_cycles_subaccount = Account{owner: tc, subaccount: Some(principal_token_subaccount(&p2))};
+    mint_cycles(&pic, &p2_cycles_subaccount, 1000000000);
+    let p2_cycles_balance = icrc1_balance(&pic, BANK, &p2_cycles_subaccount);
+    assert!(matches!(
+        call_trade_cycles(&pic, tc, p2, &trade_cycles_quest(4 * trade_tokens * 90_000)),
+        Err(TradeError::FillOrKillCannotFillCompletely)
+    ));
+    assert_eq!(icrc1_balance(&pic, BANK, &p2_cycles_subaccount), p2_cycles_balance);
+    
+    call_trade_cycles(&pic, tc, p2, &trade_cycles_quest(3 * trade_tokens * 90_000)).unwrap();
+    assert_eq!(
+        view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities,
+        vec![(80_000, trade_tokens)],
+    );
+    assert_eq!(view_cycles_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, vec![]);
+}
+
+#[test]
+fn test_post_only() {
+    let pic = set_up();
+    let tc = set_up_tc(&pic);
+    
+    let (p1,p2): (Principal,Principal) = (
+        Principal::from_slice(&[1,1,1,1,1]),
+        Principal::from_slice(&[2,2,2,2,2]),
+    );
+    let trade_tokens = 100_000_000;
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p1))}, trade_tokens + ICP_LEDGER_TRANSFER_FEE);
+    call_trade_tokens(&pic, tc, p1, &TradeTokensQuest{
+        tokens: trade_tokens,
+        cycles_per_token_rate: 80_000,
+        posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
+        return_tokens_to_subaccount: None,
+        payout_cycles_to_subaccount: None,
+        time_in_force: None,
+        post_only: None,
+        expires_at_nanos: None,
+    }).unwrap();
+    
+    let trade_cycles_quest = |cycles_per_token_rate: u128, post_only: PostOnly| {
+        TradeCyclesQuest{
+            cycles: trade_tokens * 50_000,
+            cycles_per_token_rate,
+            posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
+            return_cycles_to_subaccount: None,
+            payout_tokens_to_subaccount: None,
+            time_in_force: None,
+            post_only: Some(post_only),
+            expires_at_nanos: None,
+        }
+    };
+    
+    let p2_cycles_subaccount = Account{owner: tc, subaccount: Some(principal_token_subaccount(&p2))};
+    mint_cycles(&pic, &p2_cycles_subaccount, 500000000);
+    let p2_cycles_balance = icrc1_balance(&pic, BANK, &p2_cycles_subaccount);
+    
+    // post-only must be good-till-cancel.
+    let mut immediate_or_cancel_quest = trade_cycles_quest(70_000, PostOnly::Reject);
+    immediate_or_cancel_quest.time_in_force = Some(TimeInForce::ImmediateOrCancel);
+    assert!(matches!(
+        call_trade_cycles(&pic, tc, p2, &immediate_or_cancel_quest),
+        Err(TradeError::PostOnlyMustBeGoodTillCancel)
+    ));
+    
+    // the reject does not move the funds.
+    assert!(matches!(
+        call_trade_cycles(&pic, tc, p2, &trade_cycles_quest(100_000, PostOnly::Reject)),
+        Err(TradeError::PostOnlyWouldMatch{ best_opposing_rate: 80_000 })
+    ));
+    assert_eq!(icrc1_balance(&pic, BANK, &p2_cycles_subaccount), p2_cycles_balance);
+    
+    // the reprice goes one tick behind the best token-position.
+    let trade_success = call_trade_cycles(&pic, tc, p2, &trade_cycles_quest(100_000, PostOnly::Reprice)).unwrap();
+    assert_eq!(trade_success.post_only_outcome, Some(PostOnlyOutcome::Repriced{ cycles_per_token_rate: 79_999 }));
+    assert_eq!(
+        view_cycles_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities,
+        vec![(79_999, trade_tokens * 50_000)],
+    );
+    assert_eq!(
+        view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities,
+        vec![(80_000, trade_tokens)],
+    );
+    
+    // a post-only that does not cross is posted as is.
+    let trade_success = call_trade_cycles(&pic, tc, p2, &trade_cycles_quest(70_000, PostOnly::Reject)).unwrap();
+    assert_eq!(trade_success.post_only_outcome, Some(PostOnlyOutcome::Posted));
+}
+
+#[test]
+fn test_expire_positions() {
+    let pic = set_up();
+    let tc = set_up_tc(&pic);
+    
+    let p1 = Principal::from_slice(&[1,1,1,1,1]);
+    let trade_tokens = 100_000_000;
+    let trade_tokens_quest = |expires_at_nanos: u128| {
+        TradeTokensQuest{
+            tokens: trade_tokens,
+            cycles_per_token_rate: 80_000,
+            posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
+            return_tokens_to_subaccount: None,
+            payout_cycles_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: Some(expires_at_nanos),
+        }
+    };
+    
+    assert!(matches!(
+        call_trade_tokens(&pic, tc, p1, &trade_tokens_quest(pic_get_time_nanos(&pic))),
+        Err(TradeError::ExpiresAtNanosMustBeInTheFuture)
+    ));
+    
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p1))}, trade_tokens + ICP_LEDGER_TRANSFER_FEE);
+    call_trade_tokens(&pic, tc, p1, &trade_tokens_quest(pic_get_time_nanos(&pic) + 60 * NANOS_IN_A_SECOND)).unwrap();
+    
+    pic.advance_time(Duration::from_secs(30));
+    for _ in 0..5 { pic.tick(); }
+    assert_eq!(
+        view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities,
+        vec![(80_000, trade_tokens)],
+    );
+    
+    pic.advance_time(Duration::from_secs(31));
+    for _ in 0..10 { pic.tick(); }
+    assert_eq!(view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, vec![]);
+    
+    // the tokens go back through the void-position payout.
+    assert_eq!(
+        icrc1_balance(&pic, ICP_LEDGER, &Account{ owner: p1, subaccount: None}),
+        trade_tokens - ICP_LEDGER_TRANSFER_FEE
+    );
+}
+
+#[test]
+fn test_market_orders() {
+    let pic = set_up();
+    let tc = set_up_tc(&pic);
+    
+    let (p1,p2): (Principal,Principal) = (
+        Principal::from_slice(&[1,1,1,1,1]),
+        Principal::from_slice(&[2,2,2,2,2]),
+    );
+    let trade_tokens = 100_000_000;
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p1))}, 2 * (trade_tokens + ICP_LEDGER_TRANSFER_FEE));
+    for cycles_per_token_rate in [80_000, 90_000] {
+        call_trade_tokens(&pic, tc, p1, &TradeTokensQuest{
+            tokens: trade_tokens,
+            cycles_per_token_rate,
+            posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
+            return_tokens_to_subaccount: None,
+            payout_cycles_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
+        }).unwrap();
+    }
+    
+    let trade_cycles = 2 * trade_tokens * 85_000;
+    let trade_cycles_market_quest = |worst_cycles_per_token_rate: Option<u128>, max_slippage_basis_points: Option<u64>| {
+        TradeCyclesMarketQuest{
+            cycles: trade_cycles,
+            worst_cycles_per_token_rate,
+            max_slippage_basis_points,
+            posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
+            return_cycles_to_subaccount: None,
+            payout_tokens_to_subaccount: None,
+        }
+    };
+    
+    assert!(matches!(
+        call_trade_cycles_market(&pic, tc, p2, &trade_cycles_market_quest(None, None)),
+        Err(TradeMarketError::MustSetAWorstRateOrAMaxSlippage)
+    ));
+    
+    mint_cycles(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p2))}, 500000000);
+    
+    // 10% slippage from 80_000 is 88_000, and the worst rate of 85_000 is more restrictive.
+    let trade_market_success = call_trade_cycles_market(&pic, tc, p2, &trade_cycles_market_quest(Some(85_000), Some(1_000))).unwrap();
+    assert_eq!(trade_market_success.fill_quantity, trade_tokens);
+    assert_eq!(trade_market_success.fill_average_rate, 80_000); // the rate of the book.
+    assert_eq!(trade_market_success.payouts_fees_sum, trade_tokens * 80_000 / 10_000 * 50 / 80_000);
+    assert_eq!(trade_market_success.unfilled_quantity, trade_cycles - trade_tokens * 80_000);
+    
+    // the unfilled remainder does not rest on the book.
+    assert_eq!(view_cycles_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, vec![]);
+    assert_eq!(
+        view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities,
+        vec![(90_000, trade_tokens)],
+    );
+}
+
+#[test]
+fn test_conditional_orders() {
+    let pic = set_up();
+    let tc = set_up_tc(&pic);
+    
+    let (p1,p2,p3): (Principal,Principal,Principal) = (
+        Principal::from_slice(&[1,1,1,1,1]),
+        Principal::from_slice(&[2,2,2,2,2]),
+        Principal::from_slice(&[3,3,3,3,3]),
+    );
+    let trade_tokens = 100_000_000;
+    let trade_tokens_quest = |cycles_per_token_rate: u128| {
+        TradeTokensQuest{
+            tokens: trade_tokens,
+            cycles_per_token_rate,
+            posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
+            return_tokens_to_subaccount: None,
+            payout_cycles_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
+        }
+    };
+    let stop_limit_quest = |stop_cycles_per_token_rate: u128| {
+        PlaceConditionalOrderQuest{
+            trigger: ConditionalOrderTrigger::StopLimit{ stop_cycles_per_token_rate },
+            trade_quest: ConditionalTradeQuest::TradeTokens(trade_tokens_quest(70_000)),
+        }
+    };
+    
+    // the escrow takes the tokens + the ledger-fee for the transfer out of the escrow.
+    let p3_tokens_subaccount = Account{owner: tc, subaccount: Some(principal_token_subaccount(&p3))};
+    mint_icp(&pic, &p3_tokens_subaccount, 2 * (trade_tokens + 2 * ICP_LEDGER_TRANSFER_FEE));
+    assert_eq!(call_place_conditional_order(&pic, tc, p3, &stop_limit_quest(75_000)).unwrap(), 0);
+    assert_eq!(icrc1_balance(&pic, ICP_LEDGER, &p3_tokens_subaccount), trade_tokens + 2 * ICP_LEDGER_TRANSFER_FEE);
+    assert_eq!(call_place_conditional_order(&pic, tc, p3, &stop_limit_quest(10_000)).unwrap(), 1);
+    assert_eq!(icrc1_balance(&pic, ICP_LEDGER, &p3_tokens_subaccount), 0);
+    
+    // the cancel refunds the escrow.
+    call_cancel_conditional_order(&pic, tc, p3, &CancelConditionalOrderQuest{ conditional_order_id: 1 }).unwrap();
+    assert_eq!(icrc1_balance(&pic, ICP_LEDGER, &p3_tokens_subaccount), trade_tokens);
+    assert!(matches!(
+        call_cancel_conditional_order(&pic, tc, p3, &CancelConditionalOrderQuest{ conditional_order_id: 1 }),
+        Err(CancelConditionalOrderError::ConditionalOrderNotFound)
+    ));
+    
+    let list_conditional_orders_sponse = list_conditional_orders(&pic, tc, p3, None);
+    assert_eq!(list_conditional_orders_sponse.conditional_orders.len(), 1);
+    assert_eq!(list_conditional_orders_sponse.conditional_orders[0].id, 0);
+    assert_eq!(list_conditional_orders_sponse.conditional_orders[0].triggered_at_nanos, None);
+    assert_eq!(list_conditional_orders(&pic, tc, p1, None).conditional_orders.len(), 0);
+    
+    // a trade at 72_000 triggers the stop at 75_000.
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p1))}, trade_tokens + ICP_LEDGER_TRANSFER_FEE);
+    call_trade_tokens(&pic, tc, p1, &trade_tokens_quest(72_000)).unwrap();
+    mint_cycles(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p2))}, 500000000);
+    call_trade_cycles(&pic, tc, p2, &TradeCyclesQuest{
+        cycles: 2 * trade_tokens * 72_000,
+        cycles_per_token_rate: 72_000,
+        posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
+        return_cycles_to_subaccount: None,
+        payout_tokens_to_subaccount: None,
+        time_in_force: None,
+        post_only: None,
+        expires_at_nanos: None,
+    }).unwrap();
+    
+    pic.advance_time(Duration::from_millis(1));
+    for _ in 0..10 { pic.tick(); }
+    
+    // the triggered order became a position and filled with the rest of the cycles-position.
+    assert_eq!(list_conditional_orders(&pic, tc, p3, None).conditional_orders.len(), 0);
+    assert_eq!(view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, vec![]);
+    
+    // the debug-build max conditional-orders per user is 3.
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p1))}, 3 * (trade_tokens + 2 * ICP_LEDGER_TRANSFER_FEE));
+    for _ in 0..3 {
+        call_place_conditional_order(&pic, tc, p1, &stop_limit_quest(10_000)).unwrap();
+    }
+    assert!(matches!(
+        call_place_conditional_order(&pic, tc, p1, &stop_limit_quest(10_000)),
+        Err(PlaceConditionalOrderError::MaxConditionalOrdersPerUser{ max_conditional_orders_per_user: 3 })
+    ));
+}
+
+#[test]
+fn test_bump_positions() {
+    let pic = set_up();
+    let tc = set_up_tc(&pic);
+    
+    let (p1,p2,p3,p4,p5,p6): (Principal,Principal,Principal,Principal,Principal,Principal) = (
+        Principal::from_slice(&[1,1,1,1,1]),
+        Principal::from_slice(&[2,2,2,2,2]),
+        Principal::from_slice(&[3,3,3,3,3]),
+        Principal::from_slice(&[4,4,4,4,4]),
+        Principal::from_slice(&[5,5,5,5,5]),
+        Principal::from_slice(&[6,6,6,6,6]),
+    );
+    let trade_tokens = 100_000_000;
+    let trade_tokens_quest = |cycles_per_token_rate: u128| {
+        TradeTokensQuest{
+            tokens: trade_tokens,
+            cycles_per_token_rate,
+            posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
+            return_tokens_to_subaccount: None,
+            payout_cycles_to_subaccount: None,
+            time_in_force: None,
+            post_only: None,
+            expires_at_nanos: None,
+        }
+    };
+    
+    // the debug-build book is full at 110 token-positions. 108 at 80_000, then two at the worst rate of 90_000.
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p1))}, 108 * (trade_tokens + ICP_LEDGER_TRANSFER_FEE));
+    for _ in 0..108 {
+        call_trade_tokens(&pic, tc, p1, &trade_tokens_quest(80_000)).unwrap();
+    }
+    for p in [p3, p4] {
+        mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p))}, trade_tokens + ICP_LEDGER_TRANSFER_FEE);
+        call_trade_tokens(&pic, tc, p, &trade_tokens_quest(90_000)).unwrap();
+    }
+    let full_book = vec![(80_000, 108 * trade_tokens), (90_000, 2 * trade_tokens)];
+    assert_eq!(view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, full_book);
+    
+    // a position with a worse rate than the worst on the book does not get in.
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p5))}, trade_tokens + ICP_LEDGER_TRANSFER_FEE);
+    assert!(matches!(
+        call_trade_tokens(&pic, tc, p5, &trade_tokens_quest(95_000)),
+        Err(TradeError::CyclesMarketIsBusy)
+    ));
+    
+    // an order that the positor cannot fund does not bump anything.
+    assert!(matches!(
+        call_trade_tokens(&pic, tc, p6, &trade_tokens_quest(85_000)),
+        Err(TradeError::CreatePositionLedgerTransferError(_))
+    ));
+    assert_eq!(view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, full_book);
+    
+    // a position with a better rate bumps the latest position at the worst rate.
+    mint_icp(&pic, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p2))}, trade_tokens + ICP_LEDGER_TRANSFER_FEE);
+    call_trade_tokens(&pic, tc, p2, &trade_tokens_quest(85_000)).unwrap();
+    assert_eq!(
+        view_tokens_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities,
+        vec![(80_000, 108 * trade_tokens), (85_000, trade_tokens), (90_000, trade_tokens)],
+    );
+    let p4_view_void_positions_pending_sponse_b = pic.query_call(tc, Principal::anonymous(), "view_void_positions_pending",
+        candid::encode_one(ViewStorageLogsQuest{
+            opt_start_before_id: None,
+            index_key: Some(p4)
+        }).unwrap(),
+    ).unwrap().unwrap();
+    let log = PositionLog::stable_memory_serialize_backwards(&p4_view_void_positions_pending_sponse_b[..(p4_view_void_positions_pending_sponse_b.len() - 1)]);
+    assert_eq!(log.positor, p4);
+    assert_eq!(log.position_termination.unwrap().cause, PositionTerminationCause::Bump);
+    
+    // the bumped positor gets the tokens back through the void-position payout.
+    pic.advance_time(Duration::from_millis(1));
+    for _ in 0..10 { pic.tick(); }
+    assert_eq!(
+        icrc1_balance(&pic, ICP_LEDGER, &Account{ owner: p4, subaccount: None}),
+        trade_tokens - ICP_LEDGER_TRANSFER_FEE
+    );
+}
+
+#[test]
+fn test_trade_cycles_through_icrc1_transfer_and_notify() {
+    use cts_lib::{types::bank::{TransferAndNotifySuccess, TransferNotificationOutcome, OnCyclesReceivedQuest}, icrc::{IcrcId, Icrc1TransferQuest}, consts::TRILLION};
+    use icrc_ledger_types::icrc1::transfer::TransferError;
+    let pic = set_up();
+    let tc = set_up_tc(&pic);
+    
+    // the cm_main adds the tc to the bank transfer-notification receivers when it creates the tc.
+    assert_eq!(
+        call_candid_::<_, (Vec<Principal>,)>(&pic, BANK, "view_transfer_notification_receivers", ()).unwrap().0,
+        vec![tc],
+    );
+    
+    let p1 = Principal::from_slice(&[1,1,1,1,1]);
+    let p1_cycles = mint_cycles(&pic, &Account{owner: p1, subaccount: None}, 500000000);
+    let trade_rate = 77777;
+    let transfer_and_notify = |amount: u128, memo: Vec<u8>| {
+        call_candid_as_::<_, (Result<TransferAndNotifySuccess, TransferError>,)>(&pic, BANK, p1, "icrc1_transfer_and_notify", (Icrc1TransferQuest{
+            to: IcrcId{ owner: tc, subaccount: Some(principal_token_subaccount(&p1).into()) },
+            fee: None,
+            memo: Some(serde_bytes::ByteBuf::from(memo)),
+            from_subaccount: None,
+            created_at_time: None,
+            amount,
+        },)).unwrap().0.unwrap()
+    };
+    
+    // a transfer without the trade-cycles memo stays in the subaccount.
+    let deposit = 1 * TRILLION;
+    assert_eq!(transfer_and_notify(deposit, b"deposit".to_vec()).notification_outcome, TransferNotificationOutcome::Notified);
+    pic.advance_time(Duration::from_millis(1));
+    for _ in 0..5 { pic.tick(); }
+    assert_eq!(view_cycles_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities, vec![]);
+    
+    // a transfer with the trade-cycles memo puts a position with the received cycles minus the posit-transfer fee.
+    let trade_amount = p1_cycles - deposit - 2 * BANK_TRANSFER_FEE;
+    assert_eq!(transfer_and_notify(trade_amount, trade_cycles_notify_memo(trade_rate)).notification_outcome, TransferNotificationOutcome::Notified);
+    pic.advance_time(Duration::from_millis(1));
+    for _ in 0..5 { pic.tick(); }
+    assert_eq!(
+        view_cycles_position_book(&pic, tc, ViewPositionBookQuest{ opt_start_greater_than_rate: None }).positions_quantities,
+        vec![(trade_rate, trade_amount - BANK_TRANSFER_FEE)],
+    );
+    assert_eq!(icrc1_balance(&pic, BANK, &Account{owner: tc, subaccount: Some(principal_token_subaccount(&p1))}), deposit);
+    
+    // only the bank can call the on_cycles_received.
+    call_candid_as_::<_, ()>(&pic, tc, p1, "on_cycles_received", (OnCyclesReceivedQuest{
+        block_id: 0,
+        from: IcrcId{ owner: p1, subaccount: None },
+        to_subaccount: Some(principal_token_subaccount(&p1).into()),
+        amount: deposit,
+        memo: Some(serde_bytes::ByteBuf::from(trade_cycles_notify_memo(trade_rate))),
+    },)).unwrap_err();
+}
+
 #[test]
 fn test_icrc45() {
     
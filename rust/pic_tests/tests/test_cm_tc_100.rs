@@ -96,6 +96,9 @@ fn test_100() {
                 posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
                 return_tokens_to_subaccount: None,
                 payout_cycles_to_subaccount: None,
+                time_in_force: None,
+                post_only: None,
+                expires_at_nanos: None,
             },
         )).unwrap().0.unwrap().position_id;  
         
@@ -121,6 +124,9 @@ fn test_100() {
                     cycles_per_token_rate: trade_tokens_rate,
                     posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
                     payout_cycles_to_subaccount: None,
+                    time_in_force: None,
+                    post_only: None,
+                    expires_at_nanos: None,
                     return_tokens_to_subaccount: None,
                 },
                 current_position_tokens: trade_tokens,
@@ -195,6 +201,9 @@ fn test_100() {
                 posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
                 return_cycles_to_subaccount: None,
                 payout_tokens_to_subaccount: None,
+                time_in_force: None,
+                post_only: None,
+                expires_at_nanos: None,
             },
         )).unwrap().0.unwrap().position_id;    
         
@@ -79,6 +79,7 @@ fn test_upgrade_1() {
         for_canister: CTS,
         cycles: 123*TRILLION,
         memo: None, 
+        created_at_time: None,
         fee: Some(BANK_TRANSFER_FEE),
         from_subaccount: None,
     },)).unwrap().0.unwrap();
@@ -92,6 +93,7 @@ fn test_upgrade_1() {
             fee: Some(BANK_TRANSFER_FEE),
             to: IcrcId{owner: canister_caller, subaccount: None},
             memo: None, 
+            created_at_time: None,
         }).unwrap(),
         cycles: 123*TRILLION + BANK_TRANSFER_FEE,
     },)).unwrap().0.unwrap();
@@ -113,6 +115,9 @@ fn test_upgrade_1() {
         posit_transfer_ledger_fee: Some(BANK_TRANSFER_FEE),
         return_cycles_to_subaccount: None,
         payout_tokens_to_subaccount: None,
+        time_in_force: None,
+        post_only: None,
+        expires_at_nanos: None,
     };
     let _p2_trade_cycles_position_id = call_trade_cycles(&pic, tc, p2, &p2_trade_cycles_quest).unwrap().position_id;
     
@@ -124,6 +129,9 @@ fn test_upgrade_1() {
         posit_transfer_ledger_fee: Some(ICP_LEDGER_TRANSFER_FEE),
         return_tokens_to_subaccount: None,
         payout_cycles_to_subaccount: None,
+        time_in_force: None,
+        post_only: None,
+        expires_at_nanos: None,
     };
     let _p1_trade_tokens_position_id = call_trade_tokens(&pic, tc, p1, &p1_trade_tokens_quest).unwrap().position_id;
 
@@ -141,6 +149,10 @@ fn test_upgrade_1() {
     
     pic.advance_time(Duration::from_secs(500));
     pic.tick();
+    // the bank rebuilds the user-logs-index on timers after the upgrade from the heap user-logs-pointers.
+    for _ in 0..5 {
+        pic.tick();
+    }
     
     // check that the data is still there
     
@@ -194,7 +206,7 @@ fn test_upgrade_1() {
     let log_1 = (
         1,
         Log{
-            phash: Some(icrc3_value_of_a_block_log(&p1_logs[0].1).hash().into()),
+            phash: Some(icrc3_value_of_a_block_log(&p1_logs[0].1, None).hash().into()),
             ts: p1_logs[1].1.ts,
             fee: Some(BANK_TRANSFER_FEE),
             tx: LogTX{
@@ -223,7 +235,7 @@ fn test_upgrade_1() {
     let log_2 = (
         2,
         Log{
-            phash: Some(icrc3_value_of_a_block_log(&log_1.1).hash().into()),
+            phash: Some(icrc3_value_of_a_block_log(&log_1.1, None).hash().into()),
             ts: p2_logs[1].1.ts,
             fee: None,
             tx: LogTX{
@@ -248,7 +260,7 @@ fn test_upgrade_1() {
     let log_3 = (
         3,
         Log{
-            phash: Some(icrc3_value_of_a_block_log(&log_2.1).hash().into()),
+            phash: Some(icrc3_value_of_a_block_log(&log_2.1, None).hash().into()),
             ts: canister_caller_logs[0].1.ts,
             fee: None,
             tx: LogTX{
@@ -269,19 +281,19 @@ fn test_upgrade_1() {
     let get_blocks_sponse = call_candid_::<_, (GetBlocksResult,)>(&pic, BANK, "icrc3_get_blocks", (vec![StartAndLength{ start: 0, length: 100000 }],)).unwrap().0;   
     assert_eq!(
         get_blocks_sponse.blocks[0],
-        BlockWithId{ id: 0u128.into(), block: (&icrc3_value_of_a_block_log(&log_0.1)).into() },
+        BlockWithId{ id: 0u128.into(), block: (&icrc3_value_of_a_block_log(&log_0.1, None)).into() },
     );
     assert_eq!(
         get_blocks_sponse.blocks[1],
-        BlockWithId{ id: 1u128.into(), block: (&icrc3_value_of_a_block_log(&log_1.1)).into() },
+        BlockWithId{ id: 1u128.into(), block: (&icrc3_value_of_a_block_log(&log_1.1, None)).into() },
     );
     assert_eq!(
         get_blocks_sponse.blocks[2],
-        BlockWithId{ id: 2u128.into(), block: (&icrc3_value_of_a_block_log(&log_2.1)).into() },
+        BlockWithId{ id: 2u128.into(), block: (&icrc3_value_of_a_block_log(&log_2.1, None)).into() },
     );
     assert_eq!(
         get_blocks_sponse.blocks[3],
-        BlockWithId{ id: 3u128.into(), block: (&icrc3_value_of_a_block_log(&log_3.1)).into() },
+        BlockWithId{ id: 3u128.into(), block: (&icrc3_value_of_a_block_log(&log_3.1, None)).into() },
     );
     
     let icrc45_pair = call_candid_::<_, (icrc45::PairResponse,)>(
@@ -112,6 +112,19 @@ pub fn mint_icp(pic: &PocketIc, to: &Account, amount: u128) {
     mint_icp_r.unwrap();
 }
 
+// the cmc creates the canisters on these subnets. the cmc has no authorized subnets at the set_up.
+pub fn set_cmc_default_authorized_subnets(pic: &PocketIc, subnets: Vec<Principal>) {
+    #[derive(CandidType, Deserialize)]
+    struct SetAuthorizedSubnetworkListArgs {
+        who: Option<Principal>,
+        subnets: Vec<Principal>,
+    }
+    call_candid_as_::<_, ()>(pic, CMC, NNS_GOVERNANCE, "set_authorized_subnetwork_list", (SetAuthorizedSubnetworkListArgs{
+        who: None,
+        subnets,
+    },)).unwrap();
+}
+
 pub fn create_and_download_state_snapshot<T: candid::CandidType + for<'a> Deserialize<'a>>(pic: &PocketIc, caller: Principal, canister: Principal, memory_id: u8) -> T {
     let (snapshot_len,): (u64,) = call_candid_as(&pic, canister, RawEffectivePrincipal::None, caller, "controller_create_state_snapshot", (memory_id,)).unwrap();
     let mut v = Vec::<u8>::new();
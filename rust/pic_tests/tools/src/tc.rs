@@ -15,4 +15,31 @@ pub fn call_trade_cycles(pic: &PocketIc, tc: Principal, caller: Principal, q: &T
 
 pub fn call_trade_tokens(pic: &PocketIc, tc: Principal, caller: Principal, q: &TradeTokensQuest) -> TradeResult {
     call_candid_as_::<_, (TradeResult,)>(&pic, tc, caller, "trade_tokens", (q,)).unwrap().0 
-}
\ No newline at end of file
+}
+
+pub fn call_trade_cycles_market(pic: &PocketIc, tc: Principal, caller: Principal, q: &TradeCyclesMarketQuest) -> TradeMarketResult {
+    call_candid_as_::<_, (TradeMarketResult,)>(&pic, tc, caller, "trade_cycles_market", (q,)).unwrap().0
+}
+
+pub fn call_trade_tokens_market(pic: &PocketIc, tc: Principal, caller: Principal, q: &TradeTokensMarketQuest) -> TradeMarketResult {
+    call_candid_as_::<_, (TradeMarketResult,)>(&pic, tc, caller, "trade_tokens_market", (q,)).unwrap().0
+}
+
+pub fn call_place_conditional_order(pic: &PocketIc, tc: Principal, caller: Principal, q: &PlaceConditionalOrderQuest) -> PlaceConditionalOrderResult {
+    call_candid_as_::<_, (PlaceConditionalOrderResult,)>(&pic, tc, caller, "place_conditional_order", (q,)).unwrap().0
+}
+
+pub fn call_cancel_conditional_order(pic: &PocketIc, tc: Principal, caller: Principal, q: &CancelConditionalOrderQuest) -> CancelConditionalOrderResult {
+    call_candid_as_::<_, (CancelConditionalOrderResult,)>(&pic, tc, caller, "cancel_conditional_order", (q,)).unwrap().0
+}
+
+pub fn list_conditional_orders(pic: &PocketIc, tc: Principal, caller: Principal, opt_start_after: Option<ConditionalOrderId>) -> ListConditionalOrdersSponse {
+    call_candid_as_::<_, (ListConditionalOrdersSponse,)>(&pic, tc, caller, "list_conditional_orders", (opt_start_after,)).unwrap().0
+}
+pub fn view_tokens_position_book(pic: &PocketIc, tc: Principal, q: ViewPositionBookQuest) -> ViewPositionBookSponse {
+    call_candid_::<_, (ViewPositionBookSponse,)>(&pic, tc, "view_tokens_position_book", (q,)).unwrap().0
+}
+
+pub fn view_cycles_position_book(pic: &PocketIc, tc: Principal, q: ViewPositionBookQuest) -> ViewPositionBookSponse {
+    call_candid_::<_, (ViewPositionBookSponse,)>(&pic, tc, "view_cycles_position_book", (q,)).unwrap().0
+}